@@ -1,10 +1,24 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use bootloader::BootInfo;
 use x86_64::structures::paging::PageTable;
-use x86_64::VirtAddr;
+use x86_64::{PhysAddr, VirtAddr};
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
 
 /// Initialize boot-time systems
-pub fn init() {
-    // Boot initialization code
-    // This will be expanded with bootloader-specific setup
+pub fn init(boot_info: &'static BootInfo) {
+    // The bootloader maps all of physical memory at this offset
+    PHYSICAL_MEMORY_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+}
+
+/// Virtual address at which the bootloader mapped physical memory
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/// Translate a physical address into its address in the physical memory mapping
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
 }
 
 /// Get the level 4 page table
@@ -1,5 +1,5 @@
 use crate::process::ProcessId;
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
 
 #[derive(Debug, Clone)]
@@ -30,13 +30,13 @@ pub struct Cgroup {
 }
 
 pub struct CgroupManager {
-    cgroups: Mutex<BTreeMap<heapless::String<64>, Cgroup>>,
+    cgroups: SpinLock<BTreeMap<heapless::String<64>, Cgroup>>,
 }
 
 impl CgroupManager {
     pub const fn new() -> Self {
         CgroupManager {
-            cgroups: Mutex::new(BTreeMap::new()),
+            cgroups: SpinLock::new(BTreeMap::new()),
         }
     }
 
@@ -73,6 +73,22 @@ impl CgroupManager {
         Ok(())
     }
 
+    pub fn remove_process(&self, pid: ProcessId) {
+        for cgroup in self.cgroups.lock().values_mut() {
+            cgroup.processes.retain(|&p| p != pid);
+        }
+    }
+
+    /// Name, memory limit and members of every cgroup that has a memory limit
+    pub fn memory_limits(&self) -> alloc::vec::Vec<(heapless::String<64>, u64, alloc::vec::Vec<ProcessId>)> {
+        self.cgroups.lock()
+            .values()
+            .filter_map(|cgroup| cgroup.limits.memory_limit.map(|limit| {
+                (cgroup.name.clone(), limit, cgroup.processes.clone())
+            }))
+            .collect()
+    }
+
     pub fn check_limits(&self, cgroup_name: &str, pid: ProcessId) -> bool {
         let name_str = heapless::String::from_str(cgroup_name).unwrap_or(heapless::String::new());
         if let Some(cgroup) = self.cgroups.lock().get(&name_str) {
@@ -1,5 +1,5 @@
 use crate::process::ProcessId;
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -19,17 +19,17 @@ pub struct Namespace {
 }
 
 pub struct NamespaceManager {
-    namespaces: Mutex<BTreeMap<(NamespaceType, u64), Namespace>>,
-    process_namespaces: Mutex<BTreeMap<ProcessId, BTreeMap<NamespaceType, u64>>>,
-    next_ns_id: Mutex<u64>,
+    namespaces: SpinLock<BTreeMap<(NamespaceType, u64), Namespace>>,
+    process_namespaces: SpinLock<BTreeMap<ProcessId, BTreeMap<NamespaceType, u64>>>,
+    next_ns_id: SpinLock<u64>,
 }
 
 impl NamespaceManager {
     pub const fn new() -> Self {
         NamespaceManager {
-            namespaces: Mutex::new(BTreeMap::new()),
-            process_namespaces: Mutex::new(BTreeMap::new()),
-            next_ns_id: Mutex::new(1),
+            namespaces: SpinLock::new(BTreeMap::new()),
+            process_namespaces: SpinLock::new(BTreeMap::new()),
+            next_ns_id: SpinLock::new(1),
         }
     }
 
@@ -1,7 +1,7 @@
 use crate::containers::namespace::{NamespaceManager, NamespaceType};
 use crate::containers::cgroup::{CgroupManager, ResourceLimits};
 use crate::process::ProcessId;
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
 
 pub struct Container {
@@ -14,15 +14,15 @@ pub struct Container {
 }
 
 pub struct ContainerRuntime {
-    containers: Mutex<BTreeMap<u64, Container>>,
-    next_container_id: Mutex<u64>,
+    containers: SpinLock<BTreeMap<u64, Container>>,
+    next_container_id: SpinLock<u64>,
 }
 
 impl ContainerRuntime {
     pub const fn new() -> Self {
         ContainerRuntime {
-            containers: Mutex::new(BTreeMap::new()),
-            next_container_id: Mutex::new(1),
+            containers: SpinLock::new(BTreeMap::new()),
+            next_container_id: SpinLock::new(1),
         }
     }
 
@@ -53,7 +53,7 @@ impl ContainerRuntime {
         let pid = crate::process::PROCESS_MANAGER.create_process(
             x86_64::VirtAddr::new(0x400000),
             x86_64::VirtAddr::new(0x800000),
-        );
+        )?;
         
         // Join namespaces
         for (ns_type, ns_id) in namespace_ids.iter() {
@@ -0,0 +1,335 @@
+use core::alloc::Layout;
+use core::arch::{asm, global_asm};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::FsBase;
+use x86_64::VirtAddr;
+use crate::memory::HEAP;
+
+/// Size of the kernel stack each task runs interrupts and system calls on
+pub const KERNEL_STACK_SIZE: usize = 16 * 1024;
+
+/// RFLAGS a task enters user mode with: interrupts enabled, plus the always-set bit 1
+const USER_RFLAGS: u64 = 0x202;
+
+// Saves the callee-saved registers on the current stack, stores the stack
+// pointer through `rdi` and resumes the task whose stack pointer is in `rsi`.
+// A fresh task's stack is laid out as if it had called this, so the `ret`
+// lands in one of the start routines below.
+global_asm!(
+    ".global switch_stacks",
+    "switch_stacks:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    "",
+    // A new user task: a `UserFrame` sits right above, at the top of the stack
+    ".global user_task_start",
+    "user_task_start:",
+    "mov rdi, rsp",
+    "call start_user_task",
+    "jmp return_to_user",
+    "",
+    // Pop a `UserFrame` and go back to ring 3
+    ".global return_to_user",
+    "return_to_user:",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "iretq",
+    "",
+    // A new kernel thread, with its entry function in r12
+    ".global kernel_task_start",
+    "kernel_task_start:",
+    "mov rdi, r12",
+    "call start_kernel_task",
+    "ud2",
+);
+
+extern "C" {
+    fn switch_stacks(save_rsp: *mut u64, load_rsp: u64);
+    fn user_task_start();
+    fn kernel_task_start();
+    pub fn return_to_user();
+}
+
+/// User registers saved at the top of a task's kernel stack on entry from ring 3,
+/// in the order the entry stubs push them
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UserFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    // Pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl UserFrame {
+    /// Registers for a task entering user mode at `entry` with `stack`, everything else zeroed
+    pub fn new(entry: VirtAddr, stack: VirtAddr) -> Self {
+        let selectors = crate::gdt::selectors();
+        UserFrame {
+            rip: entry.as_u64(),
+            cs: selectors.user_code.0 as u64,
+            rflags: USER_RFLAGS,
+            rsp: stack.as_u64(),
+            ss: selectors.user_data.0 as u64,
+            ..UserFrame::default()
+        }
+    }
+}
+
+/// Enable SSE and `fxsave`/`fxrstor` so every task's FPU state can be saved and
+/// restored, even though the kernel itself is built without SSE
+pub fn init() {
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR);
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+        asm!("fninit", options(nomem, nostack));
+    }
+}
+
+/// MXCSR bits every SSE implementation supports
+const MXCSR_MASK: u32 = 0xFFBF;
+
+/// x87, MMX and SSE registers in `fxsave` format
+#[repr(C, align(16))]
+pub struct FpuState {
+    area: [u8; FpuState::SIZE],
+}
+
+impl FpuState {
+    pub const SIZE: usize = 512;
+
+    /// The state `fninit` leaves behind, with all SSE exceptions masked
+    pub const fn new() -> Self {
+        let mut area = [0u8; Self::SIZE];
+        // x87 control word 0x037F
+        area[0] = 0x7F;
+        area[1] = 0x03;
+        // MXCSR 0x1F80
+        area[24] = 0x80;
+        area[25] = 0x1F;
+        FpuState { area }
+    }
+
+    /// State saved in user memory, such as a signal frame. MXCSR bits the
+    /// CPU may not support are cleared, since `fxrstor` faults on them.
+    pub fn from_bytes(mut area: [u8; Self::SIZE]) -> Self {
+        let mxcsr = u32::from_ne_bytes(area[24..28].try_into().unwrap()) & MXCSR_MASK;
+        area[24..28].copy_from_slice(&mxcsr.to_ne_bytes());
+        FpuState { area }
+    }
+
+    pub fn as_bytes(&self) -> &[u8; Self::SIZE] {
+        &self.area
+    }
+
+    /// Store the registers of the running task
+    pub fn save(&mut self) {
+        unsafe {
+            asm!("fxsave64 [{}]", in(reg) self.area.as_mut_ptr(), options(nostack, preserves_flags));
+        }
+    }
+
+    /// Load the registers of the running task
+    pub fn restore(&self) {
+        unsafe {
+            asm!("fxrstor64 [{}]", in(reg) self.area.as_ptr(), options(nostack, preserves_flags));
+        }
+    }
+}
+
+/// A task's kernel stack, carved out of the kernel heap
+pub struct KernelStack {
+    bottom: *mut u8,
+}
+
+unsafe impl Send for KernelStack {}
+
+impl KernelStack {
+    fn layout() -> Layout {
+        Layout::from_size_align(KERNEL_STACK_SIZE, 4096).expect("invalid kernel stack layout")
+    }
+
+    pub fn new() -> Result<Self, &'static str> {
+        let bottom = HEAP.allocate(Self::layout());
+        if bottom.is_null() {
+            return Err("Out of memory for kernel stack");
+        }
+        Ok(KernelStack { bottom })
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        VirtAddr::from_ptr(self.bottom) + KERNEL_STACK_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        // Stack redzones of the task's last frames may still be poisoned
+        #[cfg(feature = "kasan")]
+        crate::memory::kasan::unpoison_range(self.bottom as usize, KERNEL_STACK_SIZE);
+        HEAP.deallocate(self.bottom, Self::layout());
+    }
+}
+
+/// Everything needed to stop a task and resume it later. General purpose
+/// registers live on the task's kernel stack while it is switched out.
+pub struct TaskContext {
+    rsp: u64,
+    fpu: FpuState,
+    /// Thread-local storage pointer, loaded into the FS base register while the task runs
+    fs_base: u64,
+    kernel_stack: Option<KernelStack>,
+}
+
+impl TaskContext {
+    /// Context for the boot flow, which already runs on the bootloader's stack
+    pub const fn boot() -> Self {
+        TaskContext {
+            rsp: 0,
+            fpu: FpuState::new(),
+            fs_base: 0,
+            kernel_stack: None,
+        }
+    }
+
+    /// Context for a task that enters user mode with the registers in `frame` the first time it runs
+    pub fn new_user(frame: UserFrame) -> Result<Self, &'static str> {
+        let kernel_stack = KernelStack::new()?;
+        let frame_addr = kernel_stack.top().as_u64() - core::mem::size_of::<UserFrame>() as u64;
+        let rsp = Self::push_switch_frame(frame_addr, user_task_start as usize as u64, 0);
+        unsafe {
+            core::ptr::write(frame_addr as *mut UserFrame, frame);
+        }
+        Ok(TaskContext {
+            rsp,
+            fpu: FpuState::new(),
+            fs_base: 0,
+            kernel_stack: Some(kernel_stack),
+        })
+    }
+
+    /// Context for a kernel thread that runs `entry` on its own stack
+    pub fn new_kernel(entry: fn() -> !) -> Result<Self, &'static str> {
+        let kernel_stack = KernelStack::new()?;
+        let rsp = Self::push_switch_frame(kernel_stack.top().as_u64(), kernel_task_start as usize as u64, entry as usize as u64);
+        Ok(TaskContext {
+            rsp,
+            fpu: FpuState::new(),
+            fs_base: 0,
+            kernel_stack: Some(kernel_stack),
+        })
+    }
+
+    /// Lay out below `top` what `switch_stacks` pops: r15, r14, r13, r12, rbx,
+    /// rbp and the return address. Returns the saved stack pointer.
+    fn push_switch_frame(top: u64, start: u64, r12: u64) -> u64 {
+        let frame = [0, 0, 0, r12, 0, 0, start];
+        let rsp = top - core::mem::size_of_val(&frame) as u64;
+        unsafe {
+            core::ptr::write(rsp as *mut [u64; 7], frame);
+        }
+        rsp
+    }
+
+    /// The user registers saved on this task's kernel stack, valid while it is
+    /// in a system call or has not run yet
+    pub fn user_frame(&self) -> Option<*mut UserFrame> {
+        self.kernel_stack_top()
+            .map(|top| (top.as_u64() - core::mem::size_of::<UserFrame>() as u64) as *mut UserFrame)
+    }
+
+    pub fn fs_base(&self) -> u64 {
+        self.fs_base
+    }
+
+    /// Set the thread-local storage pointer of a task that is not running
+    pub fn set_fs_base(&mut self, base: u64) {
+        self.fs_base = base;
+    }
+
+    /// Top of the stack the CPU should switch to on entry from user mode
+    pub fn kernel_stack_top(&self) -> Option<VirtAddr> {
+        self.kernel_stack.as_ref().map(KernelStack::top)
+    }
+
+    /// Detach the kernel stack, for a task that is destroyed while still running on it
+    pub fn take_kernel_stack(&mut self) -> Option<KernelStack> {
+        self.kernel_stack.take()
+    }
+}
+
+/// Save the running task into `prev` and resume `next`. Returns once `prev` is switched back to.
+///
+/// Must be called with interrupts disabled, and both contexts must stay in
+/// place until then.
+pub unsafe fn switch(prev: *mut TaskContext, next: *const TaskContext) {
+    (*prev).fpu.save();
+    (*prev).fs_base = FsBase::read().as_u64();
+    (*next).fpu.restore();
+    FsBase::write(VirtAddr::new_truncate((*next).fs_base));
+    switch_stacks(core::ptr::addr_of_mut!((*prev).rsp), (*next).rsp);
+}
+
+/// First code a new user task runs, before its `UserFrame` is popped
+#[no_mangle]
+extern "C" fn start_user_task(frame: &mut UserFrame) {
+    crate::scheduler::SCHEDULER.finish_switch();
+    // Signals may have arrived before the task first ran
+    x86_64::instructions::interrupts::enable();
+    crate::signal::deliver(frame);
+}
+
+/// First code a new kernel thread runs; tasks are switched to with interrupts disabled
+#[no_mangle]
+extern "C" fn start_kernel_task(entry: usize) -> ! {
+    crate::scheduler::SCHEDULER.finish_switch();
+    x86_64::instructions::interrupts::enable();
+    let entry: fn() -> ! = unsafe { core::mem::transmute(entry) };
+    entry()
+}
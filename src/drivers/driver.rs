@@ -5,13 +5,13 @@ pub trait Driver {
 }
 
 pub struct DriverManager {
-    drivers: spin::Mutex<Vec<Box<dyn Driver>>>,
+    drivers: crate::sync::SpinLock<Vec<Box<dyn Driver>>>,
 }
 
 impl DriverManager {
     pub const fn new() -> Self {
         DriverManager {
-            drivers: spin::Mutex::new(Vec::new()),
+            drivers: crate::sync::SpinLock::new(Vec::new()),
         }
     }
 
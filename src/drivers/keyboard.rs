@@ -1,21 +1,30 @@
 use super::Driver;
+use alloc::collections::VecDeque;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::instructions::port::Port;
-use spin::Mutex;
+use crate::sync::SpinLock;
+use crate::wait::WaitQueue;
 
 const KEYBOARD_DATA_PORT: u16 = 0x60;
 const KEYBOARD_STATUS_PORT: u16 = 0x64;
 const KEYBOARD_COMMAND_PORT: u16 = 0x64;
+/// Keys typed but not read yet; further ones are dropped
+const INPUT_BUFFER_SIZE: usize = 256;
 
 pub struct KeyboardDriver {
     initialized: bool,
-    buffer: Mutex<heapless::Vec<u8, 256>>,
+    /// Filled by the IRQ1 handler
+    buffer: SpinLock<VecDeque<u8>>,
+    /// Threads blocked in `read` until a key comes in
+    readers: WaitQueue,
 }
 
 impl KeyboardDriver {
     pub const fn new() -> Self {
         KeyboardDriver {
             initialized: false,
-            buffer: Mutex::new(heapless::Vec::new()),
+            buffer: SpinLock::new(VecDeque::new()),
+            readers: WaitQueue::new(),
         }
     }
 
@@ -31,57 +40,81 @@ impl KeyboardDriver {
         }
     }
 
+    /// Buffer the key behind the scancode waiting in the controller; called from the IRQ1 handler
+    pub fn handle_interrupt(&self) {
+        let Some(key) = self.read_scancode().and_then(translate) else { return };
+        let mut buffer = self.buffer.lock();
+        if buffer.len() < INPUT_BUFFER_SIZE {
+            buffer.push_back(key);
+        }
+        drop(buffer);
+        self.readers.wake_all();
+    }
+
+    /// Next typed key, if any
     pub fn get_key(&self) -> Option<u8> {
-        if let Some(scancode) = self.read_scancode() {
-            // Basic scancode to ASCII conversion (simplified)
-            let key = match scancode {
-                0x02 => Some(b'1'),
-                0x03 => Some(b'2'),
-                0x04 => Some(b'3'),
-                0x05 => Some(b'4'),
-                0x06 => Some(b'5'),
-                0x07 => Some(b'6'),
-                0x08 => Some(b'7'),
-                0x09 => Some(b'8'),
-                0x0A => Some(b'9'),
-                0x0B => Some(b'0'),
-                0x10 => Some(b'q'),
-                0x11 => Some(b'w'),
-                0x12 => Some(b'e'),
-                0x13 => Some(b'r'),
-                0x14 => Some(b't'),
-                0x15 => Some(b'y'),
-                0x16 => Some(b'u'),
-                0x17 => Some(b'i'),
-                0x18 => Some(b'o'),
-                0x19 => Some(b'p'),
-                0x1E => Some(b'a'),
-                0x1F => Some(b's'),
-                0x20 => Some(b'd'),
-                0x21 => Some(b'f'),
-                0x22 => Some(b'g'),
-                0x23 => Some(b'h'),
-                0x24 => Some(b'j'),
-                0x25 => Some(b'k'),
-                0x26 => Some(b'l'),
-                0x2C => Some(b'z'),
-                0x2D => Some(b'x'),
-                0x2E => Some(b'c'),
-                0x2F => Some(b'v'),
-                0x30 => Some(b'b'),
-                0x31 => Some(b'n'),
-                0x32 => Some(b'm'),
-                0x1C => Some(b'\n'),
-                0x39 => Some(b' '),
-                _ => None,
-            };
-            if let Some(k) = key {
-                self.buffer.lock().push(k).ok();
-            }
-            key
-        } else {
-            None
+        without_interrupts(|| self.buffer.lock().pop_front())
+    }
+
+    /// Fill `buf` with typed keys, blocking until there is at least one
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        if buf.is_empty() {
+            return Ok(0);
         }
+        self.readers.wait_until_interruptible(|| !self.buffer.lock().is_empty())?;
+        without_interrupts(|| {
+            let mut buffer = self.buffer.lock();
+            let len = buf.len().min(buffer.len());
+            for (byte, key) in buf.iter_mut().zip(buffer.drain(..len)) {
+                *byte = key;
+            }
+            Ok(len)
+        })
+    }
+}
+
+/// Basic scancode to ASCII conversion (simplified)
+fn translate(scancode: u8) -> Option<u8> {
+    match scancode {
+        0x02 => Some(b'1'),
+        0x03 => Some(b'2'),
+        0x04 => Some(b'3'),
+        0x05 => Some(b'4'),
+        0x06 => Some(b'5'),
+        0x07 => Some(b'6'),
+        0x08 => Some(b'7'),
+        0x09 => Some(b'8'),
+        0x0A => Some(b'9'),
+        0x0B => Some(b'0'),
+        0x10 => Some(b'q'),
+        0x11 => Some(b'w'),
+        0x12 => Some(b'e'),
+        0x13 => Some(b'r'),
+        0x14 => Some(b't'),
+        0x15 => Some(b'y'),
+        0x16 => Some(b'u'),
+        0x17 => Some(b'i'),
+        0x18 => Some(b'o'),
+        0x19 => Some(b'p'),
+        0x1E => Some(b'a'),
+        0x1F => Some(b's'),
+        0x20 => Some(b'd'),
+        0x21 => Some(b'f'),
+        0x22 => Some(b'g'),
+        0x23 => Some(b'h'),
+        0x24 => Some(b'j'),
+        0x25 => Some(b'k'),
+        0x26 => Some(b'l'),
+        0x2C => Some(b'z'),
+        0x2D => Some(b'x'),
+        0x2E => Some(b'c'),
+        0x2F => Some(b'v'),
+        0x30 => Some(b'b'),
+        0x31 => Some(b'n'),
+        0x32 => Some(b'm'),
+        0x1C => Some(b'\n'),
+        0x39 => Some(b' '),
+        _ => None,
     }
 }
 
@@ -1,6 +1,6 @@
 use super::Driver;
 use volatile::Volatile;
-use spin::Mutex;
+use crate::sync::SpinLock;
 
 const VGA_WIDTH: usize = 80;
 const VGA_HEIGHT: usize = 25;
@@ -51,8 +51,8 @@ struct VgaBuffer {
 
 pub struct VgaDriver {
     initialized: bool,
-    row: Mutex<usize>,
-    column: Mutex<usize>,
+    row: SpinLock<usize>,
+    column: SpinLock<usize>,
     color_code: ColorCode,
 }
 
@@ -60,8 +60,8 @@ impl VgaDriver {
     pub const fn new() -> Self {
         VgaDriver {
             initialized: false,
-            row: Mutex::new(0),
-            column: Mutex::new(0),
+            row: SpinLock::new(0),
+            column: SpinLock::new(0),
             color_code: ColorCode::new(Color::LightGray, Color::Black),
         }
     }
@@ -82,13 +82,13 @@ impl AccessControlList {
 }
 
 pub struct AclManager {
-    acls: spin::Mutex<alloc::collections::BTreeMap<u64, AccessControlList>>,
+    acls: crate::sync::SpinLock<alloc::collections::BTreeMap<u64, AccessControlList>>,
 }
 
 impl AclManager {
     pub const fn new() -> Self {
         AclManager {
-            acls: spin::Mutex::new(alloc::collections::BTreeMap::new()),
+            acls: crate::sync::SpinLock::new(alloc::collections::BTreeMap::new()),
         }
     }
 
@@ -1,5 +1,5 @@
 use crate::security::random::SecureRandom;
-use spin::Mutex;
+use crate::sync::SpinLock;
 
 pub struct EncryptionKey {
     key: [u8; 32],
@@ -35,15 +35,15 @@ impl EncryptionKey {
 }
 
 pub struct FileSystemEncryption {
-    key: Mutex<Option<EncryptionKey>>,
-    enabled: Mutex<bool>,
+    key: SpinLock<Option<EncryptionKey>>,
+    enabled: SpinLock<bool>,
 }
 
 impl FileSystemEncryption {
     pub const fn new() -> Self {
         FileSystemEncryption {
-            key: Mutex::new(None),
-            enabled: Mutex::new(false),
+            key: SpinLock::new(None),
+            enabled: SpinLock::new(false),
         }
     }
 
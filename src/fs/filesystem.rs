@@ -1,25 +1,29 @@
 use super::{Inode, FileType, BLOCK_DEVICE, BLOCK_SIZE};
-use spin::Mutex;
+use super::inode::INODE_CACHE;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
+use crate::memory::SlabBox;
 
 pub struct FileSystem {
-    inodes: Mutex<BTreeMap<u64, Inode>>,
-    next_inode: Mutex<u64>,
+    inodes: SpinLock<BTreeMap<u64, SlabBox<Inode>>>,
+    next_inode: SpinLock<u64>,
     root_inode: u64,
 }
 
 impl FileSystem {
     pub fn new() -> Self {
         let mut fs = FileSystem {
-            inodes: Mutex::new(BTreeMap::new()),
-            next_inode: Mutex::new(1),
+            inodes: SpinLock::new(BTreeMap::new()),
+            next_inode: SpinLock::new(1),
             root_inode: 0,
         };
         
         // Create root inode
         let root = Inode::new(0, FileType::Directory);
         fs.root_inode = 0;
-        fs.inodes.lock().insert(0, root);
+        if let Ok(root) = SlabBox::new(&INODE_CACHE, root) {
+            fs.inodes.lock().insert(0, root);
+        }
         *fs.next_inode.lock() = 1;
         
         fs
@@ -33,7 +37,7 @@ impl FileSystem {
             num
         };
         
-        let inode = Inode::new(inode_number, file_type);
+        let inode = SlabBox::new(&INODE_CACHE, Inode::new(inode_number, file_type))?;
         self.inodes.lock().insert(inode_number, inode);
         Ok(inode_number)
     }
@@ -101,6 +105,11 @@ impl FileSystem {
         Ok(written)
     }
 
+    pub fn file_size(&self, inode_number: u64) -> Result<u64, &'static str> {
+        let inodes = self.inodes.lock();
+        inodes.get(&inode_number).map(|inode| inode.size).ok_or("File not found")
+    }
+
     pub fn delete_file(&self, inode_number: u64) -> Result<(), &'static str> {
         let mut inodes = self.inodes.lock();
         inodes.remove(&inode_number).ok_or("File not found")?;
@@ -112,5 +121,5 @@ impl FileSystem {
     }
 }
 
-pub static FILESYSTEM: Mutex<FileSystem> = Mutex::new(FileSystem::new());
+pub static FILESYSTEM: SpinLock<FileSystem> = SpinLock::new(FileSystem::new());
 
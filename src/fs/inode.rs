@@ -1,3 +1,6 @@
+use core::mem::{align_of, size_of};
+use crate::memory::SlabCache;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FileType {
     Regular,
@@ -20,6 +23,9 @@ pub struct Inode {
     pub ctime: u64,
 }
 
+/// Dedicated slab cache for in-memory inodes
+pub static INODE_CACHE: SlabCache = SlabCache::new("inode", size_of::<Inode>(), align_of::<Inode>());
+
 impl Inode {
     pub fn new(inode_number: u64, file_type: FileType) -> Self {
         Inode {
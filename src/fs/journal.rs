@@ -1,4 +1,4 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::VecDeque;
 use crate::fs::block::BLOCK_DEVICE;
 
@@ -19,28 +19,28 @@ pub struct JournalEntry {
 }
 
 pub struct Journal {
-    entries: Mutex<VecDeque<JournalEntry, 1024>>,
-    sequence: Mutex<u64>,
+    entries: SpinLock<VecDeque<JournalEntry, 1024>>,
+    sequence: SpinLock<u64>,
     journal_start_block: u64,
-    enabled: Mutex<bool>,
+    enabled: SpinLock<bool>,
 }
 
 impl Journal {
     pub const fn new() -> Self {
         Journal {
-            entries: Mutex::new(VecDeque::new()),
-            sequence: Mutex::new(0),
+            entries: SpinLock::new(VecDeque::new()),
+            sequence: SpinLock::new(0),
             journal_start_block: 0,
-            enabled: Mutex::new(false),
+            enabled: SpinLock::new(false),
         }
     }
 
     pub fn init(&self, journal_start: u64) {
         let mut journal = Journal {
-            entries: Mutex::new(VecDeque::new()),
-            sequence: Mutex::new(0),
+            entries: SpinLock::new(VecDeque::new()),
+            sequence: SpinLock::new(0),
             journal_start_block: journal_start,
-            enabled: Mutex::new(true),
+            enabled: SpinLock::new(true),
         };
         *self = journal;
     }
@@ -1,4 +1,4 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
 
 #[derive(Debug, Clone)]
@@ -24,17 +24,17 @@ pub struct LogicalVolume {
 }
 
 pub struct LvmManager {
-    volume_groups: Mutex<BTreeMap<heapless::String<64>, VolumeGroup>>,
-    logical_volumes: Mutex<BTreeMap<u64, LogicalVolume>>,
-    next_lv_id: Mutex<u64>,
+    volume_groups: SpinLock<BTreeMap<heapless::String<64>, VolumeGroup>>,
+    logical_volumes: SpinLock<BTreeMap<u64, LogicalVolume>>,
+    next_lv_id: SpinLock<u64>,
 }
 
 impl LvmManager {
     pub const fn new() -> Self {
         LvmManager {
-            volume_groups: Mutex::new(BTreeMap::new()),
-            logical_volumes: Mutex::new(BTreeMap::new()),
-            next_lv_id: Mutex::new(1),
+            volume_groups: SpinLock::new(BTreeMap::new()),
+            logical_volumes: SpinLock::new(BTreeMap::new()),
+            next_lv_id: SpinLock::new(1),
         }
     }
 
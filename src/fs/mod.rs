@@ -8,7 +8,7 @@ pub mod lvm;
 pub mod nfs;
 pub mod acl;
 
-pub use filesystem::FileSystem;
+pub use filesystem::{FileSystem, FILESYSTEM};
 pub use inode::{Inode, FileType};
 pub use journal::Journal;
 pub use encryption::{FileSystemEncryption, FS_ENCRYPTION};
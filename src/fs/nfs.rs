@@ -1,6 +1,6 @@
 use crate::net::socket::Socket;
 use crate::net::ip::IPAddress;
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
 
 pub struct NfsMount {
@@ -11,13 +11,13 @@ pub struct NfsMount {
 }
 
 pub struct NfsClient {
-    mounts: Mutex<BTreeMap<heapless::String<256>, NfsMount>>,
+    mounts: SpinLock<BTreeMap<heapless::String<256>, NfsMount>>,
 }
 
 impl NfsClient {
     pub const fn new() -> Self {
         NfsClient {
-            mounts: Mutex::new(BTreeMap::new()),
+            mounts: SpinLock::new(BTreeMap::new()),
         }
     }
 
@@ -1,4 +1,4 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::vec::Vec;
 use crate::fs::block::BLOCK_DEVICE;
 
@@ -16,13 +16,13 @@ pub struct RaidDevice {
 }
 
 pub struct RaidManager {
-    arrays: Mutex<Vec<RaidDevice>>,
+    arrays: SpinLock<Vec<RaidDevice>>,
 }
 
 impl RaidManager {
     pub const fn new() -> Self {
         RaidManager {
-            arrays: Mutex::new(Vec::new()),
+            arrays: SpinLock::new(Vec::new()),
         }
     }
 
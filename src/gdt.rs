@@ -0,0 +1,101 @@
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
+use x86_64::instructions::tables::{load_tss, sgdt};
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+use spin::Once;
+use crate::hardware::cpu::MAX_CPUS;
+
+/// Interrupt stack table slot the double fault handler runs on
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
+const DOUBLE_FAULT_STACK_SIZE: usize = 5 * 4096;
+
+/// Descriptor tables of one CPU. Each CPU loads its own GDT, which is also
+/// how `cpu_index` tells the CPUs apart.
+struct CpuTables {
+    gdt: GlobalDescriptorTable,
+    /// Only `privilege_stack_table[0]` changes after boot, on every context switch
+    tss: TaskStateSegment,
+    double_fault_stack: [u8; DOUBLE_FAULT_STACK_SIZE],
+}
+
+static mut CPU_TABLES: [CpuTables; MAX_CPUS] = [const {
+    CpuTables {
+        gdt: GlobalDescriptorTable::new(),
+        tss: TaskStateSegment::new(),
+        double_fault_stack: [0; DOUBLE_FAULT_STACK_SIZE],
+    }
+}; MAX_CPUS];
+
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+/// Every CPU's GDT has the same layout, so one set of selectors serves all
+static SELECTORS: Once<Selectors> = Once::new();
+
+fn tables(cpu: usize) -> *mut CpuTables {
+    unsafe { core::ptr::addr_of_mut!(CPU_TABLES[cpu]) }
+}
+
+/// Load the bootstrap processor's GDT and TSS, replacing the bootloader's
+pub fn init() {
+    init_cpu(0);
+}
+
+/// Build and load the GDT and TSS of `cpu`; runs on that CPU
+pub fn init_cpu(cpu: usize) {
+    let tables = tables(cpu);
+    let selectors = unsafe {
+        let stack_start = VirtAddr::from_ptr(core::ptr::addr_of!((*tables).double_fault_stack));
+        (*tables).tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_start + DOUBLE_FAULT_STACK_SIZE;
+
+        let gdt = &mut (*tables).gdt;
+        *gdt = GlobalDescriptorTable::new();
+        // User data sits before user code, the order `sysret` expects
+        let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+        let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code = gdt.add_entry(Descriptor::user_code_segment());
+        let tss = gdt.add_entry(Descriptor::tss_segment(&*core::ptr::addr_of!((*tables).tss)));
+        Selectors { kernel_code, kernel_data, user_data, user_code, tss }
+    };
+    let selectors = SELECTORS.call_once(|| selectors);
+
+    unsafe {
+        (*tables).gdt.load_unsafe();
+        CS::set_reg(selectors.kernel_code);
+        SS::set_reg(selectors.kernel_data);
+        DS::set_reg(selectors.kernel_data);
+        ES::set_reg(selectors.kernel_data);
+        load_tss(selectors.tss);
+    }
+}
+
+pub fn selectors() -> &'static Selectors {
+    SELECTORS.get().expect("GDT not initialized")
+}
+
+/// Index of the CPU executing this code, found from the GDT it has loaded.
+/// Early in boot, before `init`, this is the bootstrap processor.
+pub fn cpu_index() -> usize {
+    let base = sgdt().base.as_u64();
+    let first = tables(0) as u64;
+    let size = core::mem::size_of::<CpuTables>() as u64;
+    match base.checked_sub(first) {
+        Some(offset) if offset / size < MAX_CPUS as u64 => (offset / size) as usize,
+        _ => 0,
+    }
+}
+
+/// Stack the CPU switches to when an interrupt or system call arrives from user mode
+pub fn set_kernel_stack(top: VirtAddr) {
+    unsafe {
+        (*tables(cpu_index())).tss.privilege_stack_table[0] = top;
+    }
+}
@@ -1,32 +1,147 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
+use x86_64::PhysAddr;
+use crate::boot::phys_to_virt;
 
+/// Size of the header every system description table starts with
+pub const SDT_HEADER_SIZE: usize = 36;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Real-mode pointer to the Extended BIOS Data Area
+const EBDA_POINTER: u64 = 0x40E;
+const BIOS_AREA_START: u64 = 0xE0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+#[derive(Clone)]
 pub struct AcpiTable {
     pub signature: [u8; 4],
     pub length: u32,
+    /// The whole table, header included
     pub data: alloc::vec::Vec<u8>,
 }
 
+impl AcpiTable {
+    /// Iterate the type/length-prefixed structures that follow `offset`,
+    /// as found in the MADT and SRAT
+    pub fn entries(&self, offset: usize) -> impl Iterator<Item = (u8, &[u8])> {
+        let data = &self.data[..];
+        let mut pos = offset;
+        core::iter::from_fn(move || {
+            let header = data.get(pos..pos + 2)?;
+            let len = header[1] as usize;
+            if len < 2 {
+                return None;
+            }
+            let entry = data.get(pos..pos + len)?;
+            pos += len;
+            Some((header[0], entry))
+        })
+    }
+}
+
+/// Little-endian fields of a table or table entry
+pub fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+pub fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut raw = [0u8; 4];
+    raw.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(raw)
+}
+
+pub fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut raw = [0u8; 8];
+    raw.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(raw)
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Firmware memory, read through the physical memory mapping
+unsafe fn phys_bytes(addr: u64, len: usize) -> &'static [u8] {
+    core::slice::from_raw_parts(phys_to_virt(PhysAddr::new(addr)).as_ptr(), len)
+}
+
+/// Search `[start, end)` on 16-byte boundaries for a valid RSDP
+fn scan_rsdp(start: u64, end: u64) -> Option<u64> {
+    (start..end).step_by(16).find(|&addr| {
+        let candidate = unsafe { phys_bytes(addr, 20) };
+        &candidate[..8] == RSDP_SIGNATURE && checksum_ok(candidate)
+    })
+}
+
 pub struct AcpiManager {
-    tables: Mutex<alloc::collections::BTreeMap<[u8; 4], AcpiTable>>,
-    initialized: Mutex<bool>,
+    tables: SpinLock<alloc::collections::BTreeMap<[u8; 4], AcpiTable>>,
+    initialized: SpinLock<bool>,
 }
 
 impl AcpiManager {
     pub const fn new() -> Self {
         AcpiManager {
-            tables: Mutex::new(alloc::collections::BTreeMap::new()),
-            initialized: Mutex::new(false),
+            tables: SpinLock::new(alloc::collections::BTreeMap::new()),
+            initialized: SpinLock::new(false),
         }
     }
 
+    /// Locate the RSDP and register every table listed by the RSDT or XSDT
     pub fn init(&self) -> Result<(), &'static str> {
-        // TODO: Find and parse ACPI tables
-        // For now, just mark as initialized
+        let ebda = unsafe { read_u16(phys_bytes(EBDA_POINTER, 2), 0) } as u64 * 16;
+        let rsdp_addr = (if ebda != 0 { scan_rsdp(ebda, ebda + 1024) } else { None })
+            .or_else(|| scan_rsdp(BIOS_AREA_START, BIOS_AREA_END))
+            .ok_or("ACPI: RSDP not found")?;
+
+        let rsdp = unsafe { phys_bytes(rsdp_addr, 36) };
+        let revision = rsdp[15];
+        let (root, entry_size) = if revision >= 2 && checksum_ok(rsdp) {
+            (read_u64(rsdp, 24), 8)
+        } else {
+            (read_u32(rsdp, 16) as u64, 4)
+        };
+
+        let root = self.load_table(root).ok_or("ACPI: invalid root table")?;
+        let entries = (root.data.len() - SDT_HEADER_SIZE) / entry_size;
+        for i in 0..entries {
+            let offset = SDT_HEADER_SIZE + i * entry_size;
+            let addr = match entry_size {
+                8 => read_u64(&root.data, offset),
+                _ => read_u32(&root.data, offset) as u64,
+            };
+            match self.load_table(addr) {
+                Some(table) => self.register_table(table),
+                None => crate::io::println!("ACPI: skipping invalid table at {:#x}", addr),
+            }
+        }
+
         *self.initialized.lock() = true;
-        crate::io::println!("ACPI: ACPI subsystem initialized");
+        crate::io::println!("ACPI: ACPI subsystem initialized (revision {}, {} tables)", revision, self.tables.lock().len());
         Ok(())
     }
 
+    /// Copy the table at `addr` out of firmware memory if its checksum is valid
+    fn load_table(&self, addr: u64) -> Option<AcpiTable> {
+        if addr == 0 {
+            return None;
+        }
+        let header = unsafe { phys_bytes(addr, SDT_HEADER_SIZE) };
+        let length = read_u32(header, 4);
+        if (length as usize) < SDT_HEADER_SIZE {
+            return None;
+        }
+        let data = unsafe { phys_bytes(addr, length as usize) };
+        if !checksum_ok(data) {
+            return None;
+        }
+        let mut signature = [0u8; 4];
+        signature.copy_from_slice(&data[..4]);
+        Some(AcpiTable {
+            signature,
+            length,
+            data: data.to_vec(),
+        })
+    }
+
     pub fn find_table(&self, signature: &[u8; 4]) -> Option<AcpiTable> {
         self.tables.lock().get(signature).cloned()
     }
@@ -42,4 +157,3 @@ impl AcpiManager {
 }
 
 pub static ACPI_MANAGER: AcpiManager = AcpiManager::new();
-
@@ -0,0 +1,168 @@
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::memory::VMM;
+
+/// MSR holding the local APIC's physical base and global enable bit
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+/// Where the local APIC registers are mapped, uncached
+const LAPIC_VIRT: u64 = 0xFFFF_E000_0000_0000;
+
+const REG_ID: usize = 0x20;
+const REG_TPR: usize = 0x80;
+const REG_EOI: usize = 0xB0;
+const REG_SVR: usize = 0xF0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_TIMER_INITIAL: usize = 0x380;
+const REG_TIMER_CURRENT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3E0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// Divide the bus clock by 16
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+/// Vector of spurious interrupts, which need no EOI
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// The local APIC of each CPU, all mapped at the same address
+pub struct LocalApic {
+    /// Physical base, 0 until `init` has run
+    phys_base: AtomicU64,
+    /// Initial count giving one timer interrupt per PIT tick
+    ticks_per_period: AtomicU32,
+}
+
+impl LocalApic {
+    pub const fn new() -> Self {
+        LocalApic {
+            phys_base: AtomicU64::new(0),
+            ticks_per_period: AtomicU32::new(0),
+        }
+    }
+
+    /// Map the registers and enable the bootstrap processor's local APIC.
+    /// `madt_base` is the address the MADT reports, used if the MSR has none.
+    pub fn init(&self, madt_base: u64) -> Result<(), &'static str> {
+        let msr = unsafe { Msr::new(IA32_APIC_BASE).read() };
+        let phys = match msr & 0x000F_FFFF_FFFF_F000 {
+            0 => madt_base,
+            base => base,
+        };
+        if phys == 0 {
+            return Err("APIC: no local APIC");
+        }
+
+        let space = VMM.kernel_address_space().ok_or("VMM not initialized")?;
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH
+            | PageTableFlags::NO_EXECUTE;
+        let page = Page::containing_address(VirtAddr::new(LAPIC_VIRT));
+        VMM.map_to(&space, page, PhysFrame::containing_address(PhysAddr::new(phys)), flags)?;
+        self.phys_base.store(phys, Ordering::Release);
+
+        self.init_cpu();
+        Ok(())
+    }
+
+    /// Enable the calling CPU's local APIC
+    pub fn init_cpu(&self) {
+        unsafe {
+            let mut msr = Msr::new(IA32_APIC_BASE);
+            let value = msr.read();
+            msr.write(value | APIC_GLOBAL_ENABLE);
+        }
+        self.write(REG_TPR, 0);
+        self.write(REG_LVT_TIMER, LVT_MASKED);
+        self.write(REG_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.phys_base.load(Ordering::Acquire) != 0
+    }
+
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((LAPIC_VIRT as usize + reg) as *const u32) }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        unsafe { core::ptr::write_volatile((LAPIC_VIRT as usize + reg) as *mut u32, value) }
+    }
+
+    /// APIC id of the calling CPU
+    pub fn id(&self) -> u32 {
+        self.read(REG_ID) >> 24
+    }
+
+    /// Acknowledge an interrupt delivered by the local APIC
+    pub fn eoi(&self) {
+        self.write(REG_EOI, 0);
+    }
+
+    fn send(&self, apic_id: u32, command: u32) {
+        // An interrupt handler sending its own IPI would clobber the destination
+        without_interrupts(|| {
+            self.write(REG_ICR_HIGH, apic_id << 24);
+            self.write(REG_ICR_LOW, command);
+            while self.read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        })
+    }
+
+    /// Raise `vector` on the CPU with `apic_id`
+    pub fn send_ipi(&self, apic_id: u32, vector: u8) {
+        self.send(apic_id, vector as u32);
+    }
+
+    /// Reset an application processor into its wait-for-SIPI state
+    pub fn send_init(&self, apic_id: u32) {
+        self.send(apic_id, ICR_INIT | ICR_LEVEL_ASSERT);
+    }
+
+    /// Start an application processor in real mode at `page * 4096`
+    pub fn send_startup(&self, apic_id: u32, page: u8) {
+        self.send(apic_id, ICR_STARTUP | ICR_LEVEL_ASSERT | page as u32);
+    }
+
+    /// Measure the timer against `ticks` PIT periods; interrupts must be enabled
+    pub fn calibrate_timer(&self, ticks: u64) {
+        self.write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+        let start = crate::timer::get_ticks();
+        while crate::timer::get_ticks() == start {
+            core::hint::spin_loop();
+        }
+        self.write(REG_TIMER_INITIAL, u32::MAX);
+        let start = crate::timer::get_ticks();
+        while crate::timer::get_ticks() < start + ticks {
+            core::hint::spin_loop();
+        }
+        let elapsed = u32::MAX - self.read(REG_TIMER_CURRENT);
+        self.write(REG_TIMER_INITIAL, 0);
+        self.ticks_per_period.store((elapsed as u64 / ticks) as u32, Ordering::Relaxed);
+    }
+
+    /// Interrupt the calling CPU with `vector` at the PIT's rate
+    pub fn start_timer(&self, vector: u8) {
+        let count = self.ticks_per_period.load(Ordering::Relaxed);
+        if count == 0 {
+            return;
+        }
+        self.write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+        self.write(REG_LVT_TIMER, LVT_TIMER_PERIODIC | vector as u32);
+        self.write(REG_TIMER_INITIAL, count);
+    }
+}
+
+pub static LOCAL_APIC: LocalApic = LocalApic::new();
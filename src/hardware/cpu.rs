@@ -0,0 +1,160 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+/// Upper bound on CPUs the kernel keeps per-CPU state for
+pub const MAX_CPUS: usize = 16;
+
+/// Every CPU the kernel could run on, as a bit mask of CPU indices
+pub const ALL_CPUS: u64 = (1 << MAX_CPUS) - 1;
+
+/// State owned by one CPU. The bootstrap processor is CPU 0; application
+/// processors are numbered in MADT order as they are found.
+pub struct PerCpu {
+    apic_id: AtomicU32,
+    present: AtomicBool,
+    online: AtomicBool,
+    /// Physical address of the page table root this CPU has loaded
+    active_pml4: AtomicU64,
+    /// Timer and IPI interrupts taken on this CPU
+    interrupts: AtomicU64,
+    /// Spinlocks held on this CPU; while any are, it is not preempted and must not sleep
+    atomic_depth: AtomicUsize,
+    /// Nesting of interrupt handlers running on this CPU
+    irq_depth: AtomicUsize,
+    /// Raw id of the thread running here, or `NO_THREAD`; mirrors the run
+    /// queue so it can be read without taking a lock
+    current_thread: AtomicU64,
+}
+
+const NO_THREAD: u64 = u64::MAX;
+
+impl PerCpu {
+    const fn new() -> Self {
+        PerCpu {
+            apic_id: AtomicU32::new(0),
+            present: AtomicBool::new(false),
+            online: AtomicBool::new(false),
+            active_pml4: AtomicU64::new(0),
+            interrupts: AtomicU64::new(0),
+            atomic_depth: AtomicUsize::new(0),
+            irq_depth: AtomicUsize::new(0),
+            current_thread: AtomicU64::new(NO_THREAD),
+        }
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    pub fn active_pml4(&self) -> u64 {
+        self.active_pml4.load(Ordering::Acquire)
+    }
+
+    pub fn set_active_pml4(&self, addr: u64) {
+        self.active_pml4.store(addr, Ordering::Release);
+    }
+
+    pub fn count_interrupt(&self) {
+        self.interrupts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn interrupts(&self) -> u64 {
+        self.interrupts.load(Ordering::Relaxed)
+    }
+
+    pub fn enter_atomic(&self) {
+        self.atomic_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn exit_atomic(&self) {
+        self.atomic_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn atomic_depth(&self) -> usize {
+        self.atomic_depth.load(Ordering::Relaxed)
+    }
+
+    pub fn irq_enter(&self) {
+        self.irq_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn irq_exit(&self) {
+        self.irq_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn in_irq(&self) -> bool {
+        self.irq_depth.load(Ordering::Relaxed) != 0
+    }
+
+    pub fn set_current_thread(&self, tid: Option<u64>) {
+        self.current_thread.store(tid.unwrap_or(NO_THREAD), Ordering::Relaxed);
+    }
+
+    pub fn current_thread(&self) -> Option<u64> {
+        Some(self.current_thread.load(Ordering::Relaxed)).filter(|&tid| tid != NO_THREAD)
+    }
+}
+
+pub static CPUS: [PerCpu; MAX_CPUS] = [const { PerCpu::new() }; MAX_CPUS];
+
+/// CPUs found in the MADT so far, the bootstrap processor included
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+/// Index of the CPU executing this code, in `0..MAX_CPUS`
+pub fn current_cpu() -> usize {
+    crate::gdt::cpu_index()
+}
+
+/// Per-CPU data of the CPU executing this code
+pub fn this_cpu() -> &'static PerCpu {
+    &CPUS[current_cpu()]
+}
+
+/// Record the bootstrap processor, which always runs as CPU 0
+pub fn init_boot_cpu(apic_id: u32) {
+    CPUS[0].apic_id.store(apic_id, Ordering::Relaxed);
+    CPUS[0].present.store(true, Ordering::Relaxed);
+    CPUS[0].online.store(true, Ordering::Release);
+}
+
+/// Give an application processor the next free CPU index
+pub fn add_cpu(apic_id: u32) -> Option<usize> {
+    if let Some(cpu) = cpu_for_apic(apic_id) {
+        return Some(cpu);
+    }
+    let cpu = CPU_COUNT.fetch_add(1, Ordering::Relaxed);
+    if cpu >= MAX_CPUS {
+        CPU_COUNT.store(MAX_CPUS, Ordering::Relaxed);
+        return None;
+    }
+    CPUS[cpu].apic_id.store(apic_id, Ordering::Relaxed);
+    CPUS[cpu].present.store(true, Ordering::Release);
+    Some(cpu)
+}
+
+/// CPU index of a local APIC id, for CPUs listed in the MADT
+pub fn cpu_for_apic(apic_id: u32) -> Option<usize> {
+    (0..cpu_count()).find(|&cpu| CPUS[cpu].present.load(Ordering::Acquire) && CPUS[cpu].apic_id() == apic_id)
+}
+
+/// Mark the calling CPU ready to take interrupts and run threads
+pub fn set_online(cpu: usize) {
+    CPUS[cpu].online.store(true, Ordering::Release);
+}
+
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Relaxed).min(MAX_CPUS)
+}
+
+/// CPUs that are running the scheduler, as a bit mask of CPU indices.
+/// The bootstrap processor counts from the start, before SMP bring-up.
+pub fn online_mask() -> u64 {
+    (0..cpu_count()).filter(|&cpu| CPUS[cpu].is_online()).fold(1, |mask, cpu| mask | 1 << cpu)
+}
+
+pub fn online_count() -> usize {
+    online_mask().count_ones() as usize
+}
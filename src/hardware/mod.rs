@@ -3,6 +3,9 @@ pub mod pci;
 pub mod acpi;
 pub mod power;
 pub mod thermal;
+pub mod cpu;
+pub mod apic;
+pub mod smp;
 
 pub use usb::UsbManager;
 pub use pci::PciManager;
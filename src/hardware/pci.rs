@@ -1,5 +1,5 @@
 use x86_64::instructions::port::Port;
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
 
 #[derive(Debug, Clone, Copy)]
@@ -14,13 +14,13 @@ pub struct PciDevice {
 }
 
 pub struct PciManager {
-    devices: Mutex<BTreeMap<(u8, u8, u8), PciDevice>>,
+    devices: SpinLock<BTreeMap<(u8, u8, u8), PciDevice>>,
 }
 
 impl PciManager {
     pub const fn new() -> Self {
         PciManager {
-            devices: Mutex::new(BTreeMap::new()),
+            devices: SpinLock::new(BTreeMap::new()),
         }
     }
 
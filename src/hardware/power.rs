@@ -1,4 +1,4 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use core::sync::atomic::{AtomicU8, Ordering};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -14,15 +14,15 @@ pub enum PowerState {
 static CURRENT_POWER_STATE: AtomicU8 = AtomicU8::new(0); // S0
 
 pub struct PowerManager {
-    cpu_freq_mhz: Mutex<u32>,
-    enabled: Mutex<bool>,
+    cpu_freq_mhz: SpinLock<u32>,
+    enabled: SpinLock<bool>,
 }
 
 impl PowerManager {
     pub const fn new() -> Self {
         PowerManager {
-            cpu_freq_mhz: Mutex::new(0),
-            enabled: Mutex::new(true),
+            cpu_freq_mhz: SpinLock::new(0),
+            enabled: SpinLock::new(true),
         }
     }
 
@@ -0,0 +1,387 @@
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use alloc::vec::Vec;
+use crate::sync::SpinLock;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::Efer;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::boot::phys_to_virt;
+use crate::context::KernelStack;
+use crate::hardware::acpi::{read_u32, ACPI_MANAGER};
+use crate::hardware::apic::LOCAL_APIC;
+use crate::hardware::cpu::{self, current_cpu, CPUS};
+use crate::interrupts::InterruptIndex;
+use crate::memory::{PMM, VMM};
+use crate::scheduler::SCHEDULER;
+
+/// MADT: local APIC address, then flags, then the interrupt controller structures
+const MADT_LAPIC_ADDRESS: usize = 36;
+const MADT_ENTRIES: usize = 44;
+const MADT_LOCAL_APIC: u8 = 0;
+const LAPIC_ENABLED: u32 = 1 << 0;
+
+/// EFER bits an application processor copies from the bootstrap processor
+const EFER_SCE: u64 = 1 << 0;
+const EFER_LME: u64 = 1 << 8;
+const EFER_NXE: u64 = 1 << 11;
+
+/// PIT ticks to wait for an application processor to come up
+const AP_START_TIMEOUT_TICKS: u64 = 100;
+
+/// Selector of the trampoline's 64-bit code segment
+const TRAMPOLINE_CODE_SELECTOR: u16 = 0x08;
+
+// Where an application processor starts after the startup IPI: real mode,
+// at the start of the page the trampoline is copied to, with CS set to that
+// page. It loads the kernel's page tables, switches straight to long mode and
+// calls `ap_main` on the stack in `TrampolineData`. The copy runs from a
+// different address, so the data sits at a fixed offset right after the first
+// jump and the code below addresses it by number.
+global_asm!(
+    ".pushsection .rodata.ap_trampoline, \"a\"",
+    ".balign 16",
+    ".global ap_trampoline_start",
+    "ap_trampoline_start:",
+    ".code16",
+    "jmp 3f",
+    ".balign 8",
+    // TrampolineData
+    ".space 56",
+    ".global ap_trampoline_gdt",
+    "ap_trampoline_gdt:",
+    ".quad 0",
+    ".quad 0x00AF9A000000FFFF",
+    ".quad 0x00CF92000000FFFF",
+    "3:",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    "movzx ebx, ax",
+    "shl ebx, 4",
+    "lgdt [8]",
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov eax, [8 + 16]",
+    "mov cr3, eax",
+    "mov ecx, 0xC0000080",
+    "mov eax, [8 + 24]",
+    "xor edx, edx",
+    "wrmsr",
+    "mov eax, cr0",
+    "or eax, 0x80000001",
+    "mov cr0, eax",
+    // jmp far dword [8 + 8], into the 64-bit code segment
+    ".byte 0x66, 0xFF, 0x2E",
+    ".word 8 + 8",
+    ".code64",
+    ".global ap_trampoline_long_mode",
+    "ap_trampoline_long_mode:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov ebx, ebx",
+    "mov rsp, [rbx + 8 + 32]",
+    "mov rdi, [rbx + 8 + 40]",
+    "mov rax, [rbx + 8 + 48]",
+    "xor ebp, ebp",
+    "call rax",
+    "4:",
+    "hlt",
+    "jmp 4b",
+    ".global ap_trampoline_end",
+    "ap_trampoline_end:",
+    ".popsection",
+);
+
+/// Offset of `TrampolineData` in the trampoline
+const TRAMPOLINE_DATA_OFFSET: usize = 8;
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_gdt: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// Filled in by the bootstrap processor before each startup IPI; the
+/// trampoline has the field offsets hard-coded
+#[repr(C, packed)]
+struct TrampolineData {
+    gdt_limit: u16,
+    gdt_base: u32,
+    _pad0: u16,
+    long_mode_offset: u32,
+    long_mode_selector: u16,
+    _pad1: u16,
+    cr3: u64,
+    efer: u64,
+    stack_top: u64,
+    cpu: u64,
+    entry: u64,
+}
+
+fn trampoline_offset(symbol: *const u8) -> usize {
+    symbol as usize - core::ptr::addr_of!(ap_trampoline_start) as usize
+}
+
+/// Set by an application processor as soon as it runs Rust code
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Boot stacks of the application processors, which run their idle loops on them
+static AP_STACKS: SpinLock<Vec<KernelStack>> = SpinLock::new(Vec::new());
+
+/// Enable the local APIC, then start every application processor the MADT
+/// lists. Needs the timer running and interrupts enabled.
+pub fn init() -> Result<(), &'static str> {
+    let madt = ACPI_MANAGER.find_table(b"APIC").ok_or("SMP: no MADT")?;
+    LOCAL_APIC.init(read_u32(&madt.data, MADT_LAPIC_ADDRESS) as u64)?;
+    let boot_apic_id = LOCAL_APIC.id();
+    cpu::init_boot_cpu(boot_apic_id);
+    CPUS[0].set_active_pml4(Cr3::read().0.start_address().as_u64());
+    LOCAL_APIC.calibrate_timer(10);
+
+    let mut aps = Vec::new();
+    for (kind, entry) in madt.entries(MADT_ENTRIES) {
+        if kind != MADT_LOCAL_APIC || entry.len() < 8 {
+            continue;
+        }
+        let apic_id = entry[3] as u32;
+        let flags = read_u32(entry, 4);
+        if apic_id == boot_apic_id || flags & LAPIC_ENABLED == 0 {
+            continue;
+        }
+        match cpu::add_cpu(apic_id) {
+            Some(cpu) => aps.push(cpu),
+            None => crate::io::println!("SMP: ignoring CPU with APIC id {}, too many CPUs", apic_id),
+        }
+    }
+    if aps.is_empty() {
+        crate::io::println!("SMP: 1 CPU");
+        return Ok(());
+    }
+
+    let (frame, identity_mapped) = prepare_trampoline()?;
+    for cpu in aps {
+        if let Err(e) = start_ap(cpu, frame) {
+            crate::io::println!("SMP: CPU {} (APIC id {}) failed to start: {}", cpu, CPUS[cpu].apic_id(), e);
+        }
+    }
+    if let Some(space) = VMM.kernel_address_space() {
+        if !identity_mapped {
+            VMM.unmap(&space, Page::containing_address(VirtAddr::new(frame.start_address().as_u64()))).ok();
+        }
+    }
+    PMM.free_frame(frame);
+
+    crate::io::println!("SMP: {} of {} CPUs online", cpu::online_count(), cpu::cpu_count());
+    Ok(())
+}
+
+/// Copy the trampoline below 1 MiB and identity-map it, since it turns paging
+/// on while running from there. Returns the frame and whether the identity
+/// mapping was there already.
+fn prepare_trampoline() -> Result<(PhysFrame, bool), &'static str> {
+    let (cr3, _) = Cr3::read();
+    if cr3.start_address().as_u64() >= 1 << 32 {
+        return Err("SMP: kernel page tables above 4 GiB");
+    }
+
+    // Startup IPIs can only name pages below the video memory hole
+    let frame = (1..0xA0u64)
+        .map(|pfn| PhysFrame::containing_address(PhysAddr::new(pfn * 4096)))
+        .find(|&frame| PMM.claim_range(frame, 1))
+        .ok_or("SMP: no free page below 640 KiB")?;
+
+    let size = trampoline_offset(core::ptr::addr_of!(ap_trampoline_end));
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            core::ptr::addr_of!(ap_trampoline_start),
+            phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+            size,
+        );
+    }
+
+    let space = VMM.kernel_address_space().ok_or("VMM not initialized")?;
+    let addr = VirtAddr::new(frame.start_address().as_u64());
+    let identity_mapped = match VMM.translate(&space, addr) {
+        Some(phys) if phys == frame.start_address() => true,
+        Some(_) => {
+            PMM.free_frame(frame);
+            return Err("SMP: trampoline address is in use");
+        }
+        None => {
+            if let Err(e) = VMM.map_to(&space, Page::containing_address(addr), frame, PageTableFlags::WRITABLE) {
+                PMM.free_frame(frame);
+                return Err(e);
+            }
+            false
+        }
+    };
+    Ok((frame, identity_mapped))
+}
+
+/// Send INIT and startup IPIs to one application processor and wait for it to come online
+fn start_ap(cpu: usize, frame: PhysFrame) -> Result<(), &'static str> {
+    let stack = KernelStack::new()?;
+    let base = frame.start_address().as_u64();
+    let data = TrampolineData {
+        gdt_limit: 3 * 8 - 1,
+        gdt_base: (base + trampoline_offset(core::ptr::addr_of!(ap_trampoline_gdt)) as u64) as u32,
+        _pad0: 0,
+        long_mode_offset: (base + trampoline_offset(core::ptr::addr_of!(ap_trampoline_long_mode)) as u64) as u32,
+        long_mode_selector: TRAMPOLINE_CODE_SELECTOR,
+        _pad1: 0,
+        cr3: Cr3::read().0.start_address().as_u64(),
+        efer: Efer::read_raw() & (EFER_SCE | EFER_NXE) | EFER_LME,
+        stack_top: stack.top().as_u64(),
+        cpu: cpu as u64,
+        entry: ap_main as usize as u64,
+    };
+    unsafe {
+        let ptr = phys_to_virt(frame.start_address() + TRAMPOLINE_DATA_OFFSET).as_mut_ptr::<TrampolineData>();
+        core::ptr::write_unaligned(ptr, data);
+    }
+    AP_STACKS.lock().push(stack);
+    AP_STARTED.store(false, Ordering::Release);
+
+    let apic_id = CPUS[cpu].apic_id();
+    LOCAL_APIC.send_init(apic_id);
+    wait_ticks(1);
+    for _ in 0..2 {
+        LOCAL_APIC.send_startup(apic_id, (base >> 12) as u8);
+        wait_ticks(1);
+        if AP_STARTED.load(Ordering::Acquire) {
+            break;
+        }
+    }
+
+    let start = crate::timer::get_ticks();
+    while !CPUS[cpu].is_online() {
+        if crate::timer::get_ticks() - start > AP_START_TIMEOUT_TICKS {
+            return Err("timed out");
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+fn wait_ticks(ticks: u64) {
+    let start = crate::timer::get_ticks();
+    while crate::timer::get_ticks() < start + ticks {
+        core::hint::spin_loop();
+    }
+}
+
+/// First Rust code an application processor runs, on its boot stack
+extern "C" fn ap_main(cpu: usize) -> ! {
+    AP_STARTED.store(true, Ordering::Release);
+    crate::gdt::init_cpu(cpu);
+    crate::interrupts::init_ap();
+    crate::context::init();
+    LOCAL_APIC.init_cpu();
+    CPUS[cpu].set_active_pml4(Cr3::read().0.start_address().as_u64());
+    cpu::set_online(cpu);
+    LOCAL_APIC.start_timer(InterruptIndex::ApicTimer as u8);
+    x86_64::instructions::interrupts::enable();
+    SCHEDULER.idle()
+}
+
+/// Make `cpu` look for new work right away
+pub fn send_reschedule(cpu: usize) {
+    if cpu != current_cpu() && CPUS[cpu].is_online() && LOCAL_APIC.is_enabled() {
+        LOCAL_APIC.send_ipi(CPUS[cpu].apic_id(), InterruptIndex::Reschedule as u8);
+    }
+}
+
+/// `ShootdownRequest::addr` value asking for the whole TLB to be flushed
+const FLUSH_ALL: u64 = u64::MAX;
+/// `ShootdownRequest::addr` value asking CPUs to stop using `pml4`
+const LEAVE_ADDRESS_SPACE: u64 = u64::MAX - 1;
+
+/// The one TLB shootdown in flight
+struct ShootdownRequest {
+    addr: AtomicU64,
+    pml4: AtomicU64,
+    /// CPUs that have not handled the request yet
+    pending: AtomicU64,
+}
+
+static SHOOTDOWN: ShootdownRequest = ShootdownRequest {
+    addr: AtomicU64::new(0),
+    pml4: AtomicU64::new(0),
+    pending: AtomicU64::new(0),
+};
+
+static SHOOTDOWN_LOCK: SpinLock<()> = SpinLock::new(());
+
+/// Make the other CPUs drop stale translations of `addr`, or all of them
+/// for `None`. With `pml4` only CPUs running that address space are
+/// interrupted; without it every CPU is, as for kernel mappings.
+pub fn shootdown(pml4: Option<PhysFrame>, addr: Option<VirtAddr>) {
+    send_shootdown(pml4, addr.map_or(FLUSH_ALL, |addr| addr.as_u64()));
+}
+
+/// Switch every other CPU still running on `pml4` to the kernel page tables,
+/// before the address space is freed
+pub fn release_address_space(pml4: PhysFrame) {
+    send_shootdown(Some(pml4), LEAVE_ADDRESS_SPACE);
+}
+
+fn send_shootdown(pml4: Option<PhysFrame>, addr: u64) {
+    if !LOCAL_APIC.is_enabled() {
+        return;
+    }
+    // Migrating mid-request would leave this CPU out of the targets it excluded
+    without_interrupts(|| send_shootdown_from(pml4, addr));
+}
+
+fn send_shootdown_from(pml4: Option<PhysFrame>, addr: u64) {
+    let me = current_cpu();
+    let pml4 = pml4.map_or(0, |frame| frame.start_address().as_u64());
+    let targets = (0..cpu::cpu_count())
+        .filter(|&cpu| cpu != me && CPUS[cpu].is_online())
+        .filter(|&cpu| pml4 == 0 || CPUS[cpu].active_pml4() == pml4)
+        .fold(0u64, |mask, cpu| mask | 1 << cpu);
+    if targets == 0 {
+        return;
+    }
+
+    // Another CPU may be waiting for us to answer its own shootdown; spinning
+    // with interrupts disabled answers it
+    let _guard = SHOOTDOWN_LOCK.lock();
+    SHOOTDOWN.addr.store(addr, Ordering::Relaxed);
+    SHOOTDOWN.pml4.store(pml4, Ordering::Relaxed);
+    SHOOTDOWN.pending.store(targets, Ordering::Release);
+    for cpu in (0..cpu::cpu_count()).filter(|&cpu| targets & 1 << cpu != 0) {
+        LOCAL_APIC.send_ipi(CPUS[cpu].apic_id(), InterruptIndex::TlbShootdown as u8);
+    }
+    while SHOOTDOWN.pending.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Carry out the pending shootdown if it targets this CPU
+pub fn handle_shootdown() {
+    let bit = 1u64 << current_cpu();
+    if SHOOTDOWN.pending.load(Ordering::Acquire) & bit == 0 {
+        return;
+    }
+    match SHOOTDOWN.addr.load(Ordering::Relaxed) {
+        FLUSH_ALL => tlb::flush_all(),
+        LEAVE_ADDRESS_SPACE => {
+            if Cr3::read().0.start_address().as_u64() == SHOOTDOWN.pml4.load(Ordering::Relaxed) {
+                if let Some(kernel) = VMM.kernel_address_space() {
+                    VMM.switch_to(&kernel);
+                }
+            }
+        }
+        addr => tlb::flush(VirtAddr::new(addr)),
+    }
+    SHOOTDOWN.pending.fetch_and(!bit, Ordering::Release);
+}
@@ -1,4 +1,4 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use core::sync::atomic::{AtomicU8, Ordering};
 
 static CPU_TEMPERATURE: AtomicU8 = AtomicU8::new(50); // 50°C default
@@ -6,13 +6,13 @@ static THROTTLE_THRESHOLD: AtomicU8 = AtomicU8::new(90); // 90°C
 static CRITICAL_THRESHOLD: AtomicU8 = AtomicU8::new(100); // 100°C
 
 pub struct ThermalManager {
-    enabled: Mutex<bool>,
+    enabled: SpinLock<bool>,
 }
 
 impl ThermalManager {
     pub const fn new() -> Self {
         ThermalManager {
-            enabled: Mutex::new(true),
+            enabled: SpinLock::new(true),
         }
     }
 
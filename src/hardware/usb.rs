@@ -1,4 +1,4 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -20,15 +20,15 @@ pub struct UsbDevice {
 }
 
 pub struct UsbManager {
-    devices: Mutex<BTreeMap<u8, UsbDevice>>,
-    next_address: Mutex<u8>,
+    devices: SpinLock<BTreeMap<u8, UsbDevice>>,
+    next_address: SpinLock<u8>,
 }
 
 impl UsbManager {
     pub const fn new() -> Self {
         UsbManager {
-            devices: Mutex::new(BTreeMap::new()),
-            next_address: Mutex::new(1),
+            devices: SpinLock::new(BTreeMap::new()),
+            next_address: SpinLock::new(1),
         }
     }
 
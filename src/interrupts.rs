@@ -1,23 +1,207 @@
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::instructions::port::Port;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::{PrivilegeLevel, VirtAddr};
 use lazy_static::lazy_static;
+use crate::hardware::apic::{LOCAL_APIC, SPURIOUS_VECTOR};
+use crate::hardware::cpu::this_cpu;
+use crate::signal;
+
+/// Vector the master PIC's IRQ0 is remapped to, clear of the CPU exceptions
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_COMMAND: u16 = 0xA0;
+const PIC_2_DATA: u16 = 0xA1;
+const PIC_EOI: u8 = 0x20;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum InterruptIndex {
+    Timer = PIC_1_OFFSET,
+    Keyboard = PIC_1_OFFSET + 1,
+    /// Local APIC timer, which drives scheduling on the application processors
+    ApicTimer = 0x40,
+    /// Another CPU queued work for this one
+    Reschedule = 0xF0,
+    /// Another CPU changed page tables this CPU may have cached
+    TlbShootdown = 0xF1,
+}
+
+/// Vector user programs raise with `int 0x80` to make a system call
+pub const SYSCALL_VECTOR: usize = 0x80;
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        idt.divide_error.set_handler_fn(divide_error_handler);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.double_fault.set_handler_fn(double_fault_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        unsafe {
+            idt.double_fault.set_handler_fn(double_fault_handler)
+                .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt[InterruptIndex::Timer as usize].set_handler_fn(timer_interrupt_handler);
+        idt[InterruptIndex::Keyboard as usize].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::ApicTimer as usize].set_handler_fn(apic_timer_interrupt_handler);
+        idt[InterruptIndex::Reschedule as usize].set_handler_fn(reschedule_interrupt_handler);
+        idt[InterruptIndex::TlbShootdown as usize].set_handler_fn(tlb_shootdown_interrupt_handler);
+        idt[SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
+        // A trap gate: system calls run with interrupts enabled and can be preempted
+        unsafe {
+            idt[SYSCALL_VECTOR]
+                .set_handler_addr(VirtAddr::new(crate::syscall::syscall_entry as usize as u64))
+                .set_privilege_level(PrivilegeLevel::Ring3)
+                .disable_interrupts(false);
+        }
         idt
     };
 }
 
 pub fn init() {
     IDT.load();
+    init_pics();
+}
+
+/// Load the shared IDT on an application processor
+pub fn init_ap() {
+    IDT.load();
+}
+
+/// Move the 8259 PICs' vectors above the exceptions and unmask only the timer
+fn init_pics() {
+    unsafe {
+        let mut command_1: Port<u8> = Port::new(PIC_1_COMMAND);
+        let mut data_1: Port<u8> = Port::new(PIC_1_DATA);
+        let mut command_2: Port<u8> = Port::new(PIC_2_COMMAND);
+        let mut data_2: Port<u8> = Port::new(PIC_2_DATA);
+        // Writes to an unused port give the PICs time to settle between commands
+        let mut wait: Port<u8> = Port::new(0x80);
+
+        // ICW1: start initialization, ICW4 follows
+        command_1.write(0x11);
+        wait.write(0);
+        command_2.write(0x11);
+        wait.write(0);
+        // ICW2: vector offsets
+        data_1.write(PIC_1_OFFSET);
+        wait.write(0);
+        data_2.write(PIC_2_OFFSET);
+        wait.write(0);
+        // ICW3: the slave hangs off the master's IRQ2
+        data_1.write(4);
+        wait.write(0);
+        data_2.write(2);
+        wait.write(0);
+        // ICW4: 8086 mode
+        data_1.write(0x01);
+        wait.write(0);
+        data_2.write(0x01);
+        wait.write(0);
+
+        // Everything but the timer and keyboard stays masked; other devices are polled
+        data_1.write(0xFC);
+        data_2.write(0xFF);
+    }
+}
+
+/// Acknowledge an IRQ so the PIC delivers the next one
+fn end_of_interrupt(irq: u8) {
+    unsafe {
+        if irq >= 8 {
+            Port::<u8>::new(PIC_2_COMMAND).write(PIC_EOI);
+        }
+        Port::<u8>::new(PIC_1_COMMAND).write(PIC_EOI);
+    }
+}
+
+/// Run the part of an interrupt handler that deals with the device, which
+/// lock validation treats as interrupt context. It must not schedule: the
+/// task switched to would inherit the context.
+fn hardirq(handler: impl FnOnce()) {
+    this_cpu().irq_enter();
+    handler();
+    this_cpu().irq_exit();
+}
+
+extern "x86-interrupt" fn timer_interrupt_handler(mut stack_frame: InterruptStackFrame) {
+    hardirq(crate::timer::tick);
+    this_cpu().count_interrupt();
+    // Acknowledge first: the scheduler may switch to a task that does not return here for a while
+    end_of_interrupt(0);
+    crate::scheduler::SCHEDULER.timer_tick();
+    signal::interrupt_exit(&mut stack_frame);
+}
+
+extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    this_cpu().count_interrupt();
+    hardirq(|| crate::drivers::keyboard::KEYBOARD.handle_interrupt());
+    end_of_interrupt(1);
+}
+
+extern "x86-interrupt" fn apic_timer_interrupt_handler(mut stack_frame: InterruptStackFrame) {
+    this_cpu().count_interrupt();
+    LOCAL_APIC.eoi();
+    crate::scheduler::SCHEDULER.timer_tick();
+    signal::interrupt_exit(&mut stack_frame);
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+/// Also how a signal sent from another CPU gets a running thread's attention
+extern "x86-interrupt" fn reschedule_interrupt_handler(mut stack_frame: InterruptStackFrame) {
+    this_cpu().count_interrupt();
+    LOCAL_APIC.eoi();
+    crate::scheduler::SCHEDULER.reschedule_interrupt();
+    signal::interrupt_exit(&mut stack_frame);
+}
+
+extern "x86-interrupt" fn tlb_shootdown_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    this_cpu().count_interrupt();
+    hardirq(crate::hardware::smp::handle_shootdown);
+    LOCAL_APIC.eoi();
+}
+
+/// Spurious local APIC interrupts are not acknowledged
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+extern "x86-interrupt" fn breakpoint_handler(mut stack_frame: InterruptStackFrame) {
+    if stack_frame.code_segment & 3 == 3 {
+        user_fault(&mut stack_frame, signal::SIGTRAP, signal::TRAP_BRKPT);
+        return;
+    }
     crate::io::println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
+/// Raise `signo` on the user thread that caused an exception
+fn user_fault(stack_frame: &mut InterruptStackFrame, signo: u8, code: i32) {
+    signal::force_fault(signo, code, stack_frame.instruction_pointer.as_u64());
+    signal::interrupt_exit(stack_frame);
+}
+
+extern "x86-interrupt" fn divide_error_handler(mut stack_frame: InterruptStackFrame) {
+    if stack_frame.code_segment & 3 != 3 {
+        panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+    }
+    user_fault(&mut stack_frame, signal::SIGFPE, signal::FPE_INTDIV);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(mut stack_frame: InterruptStackFrame) {
+    if stack_frame.code_segment & 3 != 3 {
+        panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+    }
+    user_fault(&mut stack_frame, signal::SIGILL, signal::ILL_ILLOPN);
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(mut stack_frame: InterruptStackFrame, error_code: u64) {
+    if stack_frame.code_segment & 3 != 3 {
+        panic!("EXCEPTION: GENERAL PROTECTION FAULT ({:#x})\n{:#?}", error_code, stack_frame);
+    }
+    user_fault(&mut stack_frame, signal::SIGSEGV, signal::SI_KERNEL);
+}
+
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
@@ -28,3 +212,46 @@ extern "x86-interrupt" fn double_fault_handler(
     }
 }
 
+
+extern "x86-interrupt" fn page_fault_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let addr = Cr2::read();
+    let user_mode = error_code.contains(PageFaultErrorCode::USER_MODE);
+
+    // Kernel accesses to user memory are resolved too, as long as a process is running
+    let pid = crate::process::PROCESS_MANAGER.get_current_process();
+    let oom_progress = crate::memory::OOM_KILLER.progress();
+    let result = match pid {
+        Some(pid) if addr.as_u64() < 0x0000_8000_0000_0000 => {
+            crate::process::PROCESS_MANAGER.handle_page_fault(pid, addr, error_code)
+        }
+        _ => Err("Fault outside any process address space"),
+    };
+
+    match (result, pid) {
+        (Ok(_), _) => {}
+        // The fault ran out of memory while the OOM killer freed some; retry
+        // the access, unless the faulting process was the one killed, in
+        // which case it exits on the way out
+        (Err(_), Some(_)) if crate::memory::OOM_KILLER.progress() != oom_progress => {
+            signal::interrupt_exit(&mut stack_frame);
+        }
+        (Err(_), Some(_)) if user_mode => {
+            let code = if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+                signal::SEGV_ACCERR
+            } else {
+                signal::SEGV_MAPERR
+            };
+            signal::force_fault(signal::SIGSEGV, code, addr.as_u64());
+            signal::interrupt_exit(&mut stack_frame);
+        }
+        (Err(reason), _) => {
+            panic!(
+                "EXCEPTION: PAGE FAULT ({})\nAddress: {:#x}\nError code: {:?}\n{:#?}",
+                reason, addr.as_u64(), error_code, stack_frame
+            );
+        }
+    }
+}
@@ -1,12 +1,13 @@
 use core::fmt;
-use spin::Mutex;
+use crate::sync::SpinLock;
 use lazy_static::lazy_static;
 use x86_64::instructions::port::Port;
 
 const SERIAL_PORT: u16 = 0x3F8; // COM1
 
 lazy_static! {
-    pub static ref SERIAL: Mutex<SerialPort> = Mutex::new(SerialPort::new(SERIAL_PORT));
+    /// Untracked: panics and lock validation reports print with any lock held
+    pub static ref SERIAL: SpinLock<SerialPort> = SpinLock::new_raw(SerialPort::new(SERIAL_PORT));
 }
 
 pub struct SerialPort {
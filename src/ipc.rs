@@ -1,6 +1,12 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
-use crate::process::ProcessId;
+use alloc::vec::Vec;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+use crate::memory::deallocate_frame;
+use crate::memory::reclaim::allocate_user_frame;
+use crate::memory::vmm::frame_bytes;
+use crate::process::{Credentials, ProcessId, PROCESS_MANAGER};
 
 pub struct Message {
     pub from: ProcessId,
@@ -10,13 +16,13 @@ pub struct Message {
 }
 
 pub struct MessageQueue {
-    messages: Mutex<Vec<Message>>,
+    messages: SpinLock<Vec<Message>>,
 }
 
 impl MessageQueue {
     pub const fn new() -> Self {
         MessageQueue {
-            messages: Mutex::new(Vec::new()),
+            messages: SpinLock::new(Vec::new()),
         }
     }
 
@@ -33,50 +39,279 @@ impl MessageQueue {
             None
         }
     }
+
+    /// Drop the messages still waiting for a process that exited
+    pub fn forget_process(&self, pid: ProcessId) {
+        self.messages.lock().retain(|m| m.to != pid);
+    }
 }
 
 pub static MESSAGE_QUEUE: MessageQueue = MessageQueue::new();
 
+/// Key that always creates a new segment
+pub const IPC_PRIVATE: u64 = 0;
+/// Create the segment if no segment has the key yet
+pub const IPC_CREAT: u32 = 0o1000;
+/// With `IPC_CREAT`, fail if a segment already has the key
+pub const IPC_EXCL: u32 = 0o2000;
+/// Attach the segment read-only
+pub const SHM_RDONLY: u32 = 0o10000;
+/// `shmctl` command that removes a segment
+pub const IPC_RMID: u64 = 0;
+
 pub struct SharedMemory {
+    pub key: u64,
     pub owner: ProcessId,
+    /// Credentials of the creator, which `mode` is checked against
+    pub owner_credentials: Credentials,
+    /// rwx bits for owner, group and others, as in `shmget`
+    pub mode: u16,
     pub size: usize,
-    pub address: u64,
+    frames: Vec<PhysFrame>,
+    /// Every attachment and the address it is mapped at
+    attachments: Vec<(ProcessId, VirtAddr)>,
+    /// Attaches mapping the frames with the lock dropped, which keep them alive
+    attaching: usize,
+    /// Set once the segment is removed, after which it cannot be found or attached
+    removed: bool,
 }
 
+impl SharedMemory {
+    pub fn attach_count(&self) -> usize {
+        self.attachments.len()
+    }
+
+    fn in_use(&self) -> bool {
+        !self.attachments.is_empty() || self.attaching != 0
+    }
+
+    fn permits(&self, credentials: Credentials, write: bool) -> bool {
+        if credentials.uid == 0 {
+            return true;
+        }
+        let shift = if credentials.uid == self.owner_credentials.uid {
+            6
+        } else if credentials.gid == self.owner_credentials.gid {
+            3
+        } else {
+            0
+        };
+        let bits = (self.mode >> shift) & 0o7;
+        bits & 0o4 != 0 && (!write || bits & 0o2 != 0)
+    }
+
+    fn release(self) {
+        for frame in self.frames {
+            deallocate_frame(frame);
+        }
+    }
+}
+
+/// Nothing in here calls into the process manager with `regions` held:
+/// mapping takes a process's memory map lock, and exiting processes come
+/// back here from under it.
 pub struct SharedMemoryManager {
-    regions: Mutex<BTreeMap<u64, SharedMemory>>,
-    next_id: Mutex<u64>,
+    regions: SpinLock<BTreeMap<u64, SharedMemory>>,
+    next_id: SpinLock<u64>,
 }
 
 impl SharedMemoryManager {
     pub const fn new() -> Self {
         SharedMemoryManager {
-            regions: Mutex::new(BTreeMap::new()),
-            next_id: Mutex::new(1),
+            regions: SpinLock::new(BTreeMap::new()),
+            next_id: SpinLock::new(1),
+        }
+    }
+
+    /// Create a private segment of at least `size` bytes, zero-filled
+    pub fn create(&self, owner: ProcessId, size: usize, mode: u16) -> Result<u64, &'static str> {
+        self.create_keyed(owner, IPC_PRIVATE, size, mode)
+    }
+
+    /// Find the segment for `key` like `shmget`, creating it when `flags` has `IPC_CREAT`.
+    ///
+    /// The low nine bits of `flags` are the mode of a new segment.
+    pub fn get(&self, pid: ProcessId, key: u64, size: usize, flags: u32) -> Result<u64, &'static str> {
+        if key != IPC_PRIVATE {
+            let credentials = PROCESS_MANAGER.credentials(pid).ok_or("Process not found")?;
+            let regions = self.regions.lock();
+            if let Some((&id, region)) = regions.iter().find(|(_, r)| r.key == key && !r.removed) {
+                if flags & IPC_CREAT != 0 && flags & IPC_EXCL != 0 {
+                    return Err("Segment already exists");
+                }
+                if size > region.size {
+                    return Err("Segment smaller than requested");
+                }
+                if !region.permits(credentials, false) {
+                    return Err("Permission denied");
+                }
+                return Ok(id);
+            }
+            if flags & IPC_CREAT == 0 {
+                return Err("No such segment");
+            }
         }
+        self.create_keyed(pid, key, size, (flags & 0o777) as u16)
     }
 
-    pub fn create(&self, owner: ProcessId, size: usize) -> u64 {
+    fn create_keyed(&self, owner: ProcessId, key: u64, size: usize, mode: u16) -> Result<u64, &'static str> {
+        if size == 0 {
+            return Err("Invalid size");
+        }
+        let owner_credentials = PROCESS_MANAGER.credentials(owner).ok_or("Process not found")?;
+
+        // Allocated before taking the lock, since running out may invoke the OOM killer
+        let pages = (size + 4095) / 4096;
+        let mut frames = Vec::with_capacity(pages);
+        for _ in 0..pages {
+            match allocate_user_frame() {
+                Some(frame) => {
+                    unsafe { frame_bytes(frame) }.fill(0);
+                    frames.push(frame);
+                }
+                None => {
+                    frames.into_iter().for_each(deallocate_frame);
+                    return Err("Out of memory");
+                }
+            }
+        }
+
         let id = {
             let mut next = self.next_id.lock();
             let id = *next;
             *next += 1;
             id
         };
-        // TODO: Actually allocate memory
         let region = SharedMemory {
+            key,
             owner,
+            owner_credentials,
+            mode,
             size,
-            address: 0,
+            frames,
+            attachments: Vec::new(),
+            attaching: 0,
+            removed: false,
         };
         self.regions.lock().insert(id, region);
-        id
+        Ok(id)
     }
 
-    pub fn attach(&self, id: u64, pid: ProcessId) -> Option<u64> {
-        self.regions.lock().get(&id).map(|r| r.address)
+    /// Map segment `id` into `pid` at `addr`, or where mmap would put it when `addr` is 0
+    pub fn attach(&self, id: u64, pid: ProcessId, addr: u64, flags: u32) -> Result<VirtAddr, &'static str> {
+        let writable = flags & SHM_RDONLY == 0;
+        let credentials = PROCESS_MANAGER.credentials(pid).ok_or("Process not found")?;
+        let frames = {
+            let mut regions = self.regions.lock();
+            let region = regions.get_mut(&id).ok_or("No such segment")?;
+            if region.removed {
+                return Err("Segment has been removed");
+            }
+            if !region.permits(credentials, writable) {
+                return Err("Permission denied");
+            }
+            region.attaching += 1;
+            region.frames.clone()
+        };
+
+        let mapped = PROCESS_MANAGER.map_shared_frames(pid, addr, &frames, writable, id);
+        {
+            let mut regions = self.regions.lock();
+            let removed = regions.get_mut(&id).map_or(false, |region| {
+                region.attaching -= 1;
+                if let Ok(start) = mapped {
+                    region.attachments.push((pid, start));
+                }
+                region.removed
+            });
+            // This attach was all that kept a removed segment
+            if removed {
+                Self::release_unused(&mut regions, id);
+            }
+        }
+        // Exited while it was being mapped, after its attachments were forgotten
+        if mapped.is_ok() && PROCESS_MANAGER.mm(pid).is_err() {
+            self.forget_process(pid);
+        }
+        mapped
+    }
+
+    /// Unmap the attachment of `pid` at `addr`, removing the segment when it was the last one
+    pub fn detach(&self, pid: ProcessId, addr: VirtAddr) -> Result<(), &'static str> {
+        let (id, len) = {
+            let regions = self.regions.lock();
+            let (&id, region) = regions.iter()
+                .find(|(_, r)| r.attachments.contains(&(pid, addr)))
+                .ok_or("No segment attached at this address")?;
+            (id, region.frames.len() as u64 * 4096)
+        };
+
+        // The mapping holds its own references to the frames, so the segment
+        // may go away meanwhile
+        PROCESS_MANAGER.unmap_shared(pid, addr, len, id)?;
+        let mut regions = self.regions.lock();
+        if let Some(region) = regions.get_mut(&id) {
+            region.attachments.retain(|&attachment| attachment != (pid, addr));
+        }
+        Self::release_unused(&mut regions, id);
+        Ok(())
+    }
+
+    /// Remove segment `id` on behalf of `pid`, which must be its creator or root.
+    ///
+    /// Existing attachments stay valid; the memory is freed with the last of them.
+    pub fn remove(&self, id: u64, pid: ProcessId) -> Result<(), &'static str> {
+        let credentials = PROCESS_MANAGER.credentials(pid).ok_or("Process not found")?;
+        let mut regions = self.regions.lock();
+        let region = regions.get_mut(&id).ok_or("No such segment")?;
+        if credentials.uid != 0 && credentials.uid != region.owner_credentials.uid {
+            return Err("Permission denied");
+        }
+        region.removed = true;
+        Self::release_unused(&mut regions, id);
+        Ok(())
+    }
+
+    /// Free segment `id` if it was removed or lost its last attachment
+    fn release_unused(regions: &mut BTreeMap<u64, SharedMemory>, id: u64) {
+        let unused = regions.get(&id).map_or(false, |region| !region.in_use());
+        if unused {
+            if let Some(region) = regions.remove(&id) {
+                region.release();
+            }
+        }
+    }
+
+    /// Give a forked child the attachments of its parent, whose mappings it inherited
+    pub fn inherit(&self, parent: ProcessId, child: ProcessId) {
+        for region in self.regions.lock().values_mut() {
+            let inherited: Vec<_> = region.attachments.iter()
+                .filter(|(pid, _)| *pid == parent)
+                .map(|&(_, addr)| (child, addr))
+                .collect();
+            region.attachments.extend(inherited);
+        }
+    }
+
+    /// Drop the attachments of an exited process; its mappings went with its address space
+    pub fn forget_process(&self, pid: ProcessId) {
+        let mut regions = self.regions.lock();
+        let mut emptied = Vec::new();
+        for (&id, region) in regions.iter_mut() {
+            let before = region.attachments.len();
+            region.attachments.retain(|(owner, _)| *owner != pid);
+            if before != region.attachments.len() {
+                emptied.push(id);
+            }
+        }
+        for id in emptied {
+            Self::release_unused(&mut regions, id);
+        }
+    }
+
+    pub fn attach_count(&self, id: u64) -> Option<usize> {
+        self.regions.lock().get(&id).map(|r| r.attach_count())
     }
 }
 
 pub static SHARED_MEMORY: SharedMemoryManager = SharedMemoryManager::new();
-
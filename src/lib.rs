@@ -1,19 +1,28 @@
 #![no_std]
 #![feature(alloc_error_handler)]
+#![feature(abi_x86_interrupt)]
+#![cfg_attr(feature = "kasan", feature(sanitize))]
 
 extern crate alloc;
 
 pub mod boot;
+pub mod gdt;
 pub mod interrupts;
 pub mod memory;
 pub mod io;
 pub mod process;
 pub mod scheduler;
+pub mod realtime;
+pub mod context;
+pub mod thread;
+pub mod signal;
+pub mod wait;
 pub mod syscall;
 pub mod ipc;
 pub mod sync;
 pub mod timer;
-pub mod alloc as allocator;
+#[path = "alloc.rs"]
+pub mod allocator;
 pub mod drivers;
 pub mod fs;
 pub mod security;
@@ -1,17 +1,26 @@
 #![no_std]
 #![no_main]
 #![feature(abi_x86_interrupt)]
+#![cfg_attr(feature = "kasan", feature(sanitize))]
 #![feature(custom_test_frameworks)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
 mod boot;
+mod gdt;
 mod interrupts;
 mod memory;
 mod io;
 mod process;
 mod scheduler;
+mod realtime;
+mod context;
+mod thread;
+mod signal;
+mod wait;
+mod sync;
 mod syscall;
+mod ipc;
 mod timer;
 mod drivers;
 mod fs;
@@ -28,20 +37,33 @@ mod tools;
 mod production;
 
 use core::panic::PanicInfo;
+use bootloader::BootInfo;
 
 #[no_mangle]
-pub extern "C" fn _start() -> ! {
+pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
     // Initialize kernel subsystems
-    boot::init();
+    boot::init(boot_info);
+    gdt::init();
     interrupts::init();
-    memory::init();
+    context::init();
+    memory::init(boot_info);
+    // Lock validation keeps its dependency graph on the heap
+    stability::deadlock_detector::DEADLOCK_DETECTOR.enable();
     
     // Initialize advanced memory features
-    memory::SWAP_MANAGER.init(0, 1024 * 1024); // 512MB swap
+    // Swap to the start of the disk if it has been formatted with mkswap
+    memory::SWAP_MANAGER.swapon(0, 0).ok();
     memory::MEMORY_COMPRESSOR.enable();
     
     io::init();
     timer::init();
+    // Before other threads run, so the tests have the kernel to themselves
+    #[cfg(test)]
+    test_main();
+    // The timer now preempts the boot flow whenever a process is ready
+    x86_64::instructions::interrupts::enable();
+    thread::THREAD_MANAGER.spawn_kernel("kswapd", || memory::KSWAPD.run()).ok();
+    thread::THREAD_MANAGER.spawn_kernel("khugepaged", || memory::KHUGEPAGED.run()).ok();
     
     // Initialize security
     security::ASLR::init();
@@ -69,6 +91,9 @@ pub extern "C" fn _start() -> ! {
     // Initialize hardware
     hardware::pci::PCI_MANAGER.init();
     hardware::acpi::ACPI_MANAGER.init().ok();
+    // NUMA maps the SRAT's APIC ids onto the CPUs found here
+    hardware::smp::init().ok();
+    memory::NUMA_MANAGER.init().ok();
     hardware::power::POWER_MANAGER.init();
     hardware::thermal::THERMAL_MANAGER.init();
     
@@ -1,22 +1,37 @@
-use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
 use x86_64::PhysAddr;
+use bootloader::BootInfo;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 
 pub mod vmm;
+pub mod pmm;
+pub mod heap;
 pub mod numa;
 pub mod swap;
 pub mod huge_pages;
 pub mod oom;
 pub mod slab;
 pub mod compression;
+pub mod vma;
+pub mod mmap;
+pub mod fault;
+pub mod reclaim;
+pub mod uaccess;
+#[cfg(feature = "kasan")]
+pub mod kasan;
 
-pub use vmm::VirtualMemoryManager;
-pub use numa::{NumaManager, NUMA_MANAGER};
+pub use vmm::{AddressSpace, VirtualMemoryManager, VMM};
+pub use pmm::{PhysicalMemoryManager, ZoneType, PMM};
+pub use heap::{KernelHeap, HEAP};
+pub use numa::{MemoryPolicy, NodeId, NumaManager, NUMA_MANAGER};
 pub use swap::{SwapManager, SWAP_MANAGER};
-pub use huge_pages::{HugePageAllocator, HUGE_PAGE_ALLOCATOR};
+pub use huge_pages::{HugePageAllocator, Khugepaged, HUGE_PAGE_ALLOCATOR, KHUGEPAGED};
 pub use oom::{OomKiller, OOM_KILLER};
-pub use slab::{SlabAllocator, SLAB_ALLOCATOR};
+pub use slab::{SlabAllocator, SlabBox, SlabCache, SLAB_ALLOCATOR};
 pub use compression::{MemoryCompressor, MEMORY_COMPRESSOR};
+pub use vma::{Vma, VmaFlags, VmaKind, VmaTree};
+pub use mmap::MemoryLayout;
+pub use reclaim::{PageReclaimer, KSWAPD, PAGE_RECLAIMER};
 
 /// A frame allocator that uses the bootloader's memory map
 pub struct BootInfoFrameAllocator {
@@ -62,8 +77,50 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     }
 }
 
+/// Handle to the physical memory manager for APIs that want a `FrameAllocator`
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        allocate_frame()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        deallocate_frame(frame);
+    }
+}
+
+/// Allocate a single 4 KiB physical frame
+pub fn allocate_frame() -> Option<PhysFrame> {
+    PMM.allocate_frame()
+}
+
+/// Drop a reference to a frame, returning it to the allocator once it is unshared
+pub fn deallocate_frame(frame: PhysFrame) {
+    PMM.put_frame(frame);
+}
+
+/// Allocate a frame and clear it through the physical memory mapping
+pub fn allocate_zeroed_frame() -> Option<PhysFrame> {
+    let frame = allocate_frame()?;
+    let ptr: *mut u8 = crate::boot::phys_to_virt(frame.start_address()).as_mut_ptr();
+    unsafe {
+        core::ptr::write_bytes(ptr, 0, 4096);
+    }
+    Some(frame)
+}
+
 /// Initialize memory management
-pub fn init() {
-    // Memory initialization will be expanded when boot info is available
+pub fn init(boot_info: &'static BootInfo) {
+    unsafe { PMM.init(&boot_info.memory_map) }.expect("failed to initialize physical memory manager");
+
+    VMM.init().expect("failed to initialize virtual memory manager");
+    HEAP.init().expect("failed to initialize kernel heap");
+    slab::init();
+    HUGE_PAGE_ALLOCATOR.init(&boot_info.memory_map);
+    #[cfg(feature = "kasan")]
+    kasan::init().expect("failed to initialize KASAN");
 }
 
@@ -1,85 +1,517 @@
-use spin::Mutex;
-use alloc::collections::BTreeMap;
+use crate::sync::SpinLock;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
 use x86_64::VirtAddr;
+use crate::hardware::cpu::{current_cpu, MAX_CPUS};
+use crate::memory::{PMM, SWAP_MANAGER};
+
+const PAGE_SIZE: usize = 4096;
+
+/// Pages that do not shrink below this are not worth keeping in memory
+const MAX_COMPRESSED_SIZE: usize = PAGE_SIZE * 3 / 4;
+
+/// Worst-case LZ4 output for a page, so compression into a buffer this size always succeeds
+const MAX_LZ4_SIZE: usize = PAGE_SIZE + PAGE_SIZE / 255 + 16;
+
+/// Default share of physical memory the pool may occupy
+const DEFAULT_MAX_POOL_PERCENT: usize = 20;
+
+/// LZ4 block-format compressor and decompressor
+mod lz4 {
+    const MIN_MATCH: usize = 4;
+    /// Matches may not start within this many bytes of the end of the input
+    const MF_LIMIT: usize = 12;
+    /// The last bytes of the input are always emitted as literals
+    const LAST_LITERALS: usize = 5;
+    const HASH_LOG: u32 = 10;
+
+    fn read_u32(input: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes([input[pos], input[pos + 1], input[pos + 2], input[pos + 3]])
+    }
+
+    fn hash(sequence: u32) -> usize {
+        (sequence.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+    }
+
+    fn push(output: &mut [u8], out: &mut usize, byte: u8) -> Option<()> {
+        *output.get_mut(*out)? = byte;
+        *out += 1;
+        Some(())
+    }
+
+    /// Write the continuation bytes of a length that did not fit in the token
+    fn push_length(output: &mut [u8], out: &mut usize, mut length: usize) -> Option<()> {
+        while length >= 255 {
+            push(output, out, 255)?;
+            length -= 255;
+        }
+        push(output, out, length as u8)
+    }
+
+    fn emit_sequence(output: &mut [u8], out: &mut usize, literals: &[u8], matched: Option<(u16, usize)>) -> Option<()> {
+        let match_code = matched.map_or(0, |(_, length)| length - MIN_MATCH);
+        let token = ((literals.len().min(15) as u8) << 4) | match_code.min(15) as u8;
+        push(output, out, token)?;
+        if literals.len() >= 15 {
+            push_length(output, out, literals.len() - 15)?;
+        }
+
+        let end = *out + literals.len();
+        output.get_mut(*out..end)?.copy_from_slice(literals);
+        *out = end;
+
+        if let Some((offset, _)) = matched {
+            let [low, high] = offset.to_le_bytes();
+            push(output, out, low)?;
+            push(output, out, high)?;
+            if match_code >= 15 {
+                push_length(output, out, match_code - 15)?;
+            }
+        }
+        Some(())
+    }
+
+    /// Compress `input` into `output`, returning the compressed size or `None` if it does not fit
+    pub fn compress(input: &[u8], output: &mut [u8]) -> Option<usize> {
+        let mut table = [0u16; 1 << HASH_LOG];
+        let mut out = 0;
+        let mut anchor = 0;
+        let mut pos = 0;
+
+        if input.len() > MF_LIMIT {
+            let match_limit = input.len() - MF_LIMIT;
+            while pos < match_limit {
+                let sequence = read_u32(input, pos);
+                let slot = hash(sequence);
+                let candidate = table[slot] as usize;
+                table[slot] = pos as u16;
+
+                if candidate >= pos || pos - candidate > u16::MAX as usize || read_u32(input, candidate) != sequence {
+                    pos += 1;
+                    continue;
+                }
+
+                let max_length = input.len() - LAST_LITERALS - pos;
+                let mut length = MIN_MATCH;
+                while length < max_length && input[candidate + length] == input[pos + length] {
+                    length += 1;
+                }
+
+                let offset = (pos - candidate) as u16;
+                emit_sequence(output, &mut out, &input[anchor..pos], Some((offset, length)))?;
+                pos += length;
+                anchor = pos;
+            }
+        }
+
+        emit_sequence(output, &mut out, &input[anchor..], None)?;
+        Some(out)
+    }
+
+    fn read_length(input: &[u8], pos: &mut usize, mut length: usize) -> Result<usize, &'static str> {
+        loop {
+            let byte = *input.get(*pos).ok_or("Truncated compressed data")?;
+            *pos += 1;
+            length += byte as usize;
+            if byte != 255 {
+                return Ok(length);
+            }
+        }
+    }
+
+    /// Decompress `input` into `output`, returning the decompressed size
+    pub fn decompress(input: &[u8], output: &mut [u8]) -> Result<usize, &'static str> {
+        let mut pos = 0;
+        let mut out = 0;
+
+        loop {
+            let token = *input.get(pos).ok_or("Truncated compressed data")?;
+            pos += 1;
+
+            let mut literals = (token >> 4) as usize;
+            if literals == 15 {
+                literals = read_length(input, &mut pos, literals)?;
+            }
+            let source = input.get(pos..pos + literals).ok_or("Truncated compressed data")?;
+            output.get_mut(out..out + literals).ok_or("Decompressed data too large")?.copy_from_slice(source);
+            pos += literals;
+            out += literals;
+
+            if pos == input.len() {
+                return Ok(out);
+            }
+
+            let offset = match input.get(pos..pos + 2) {
+                Some(bytes) => u16::from_le_bytes([bytes[0], bytes[1]]) as usize,
+                None => return Err("Truncated compressed data"),
+            };
+            pos += 2;
+            if offset == 0 || offset > out {
+                return Err("Invalid match offset");
+            }
+
+            let mut length = (token & 0x0F) as usize;
+            if length == 15 {
+                length = read_length(input, &mut pos, length)?;
+            }
+            length += MIN_MATCH;
+            if out + length > output.len() {
+                return Err("Decompressed data too large");
+            }
+
+            // Byte by byte, since a match may overlap the bytes it produces
+            for i in out..out + length {
+                output[i] = output[i - offset];
+            }
+            out += length;
+        }
+    }
+}
+
+/// Where the contents behind a handle currently live
+enum Stored {
+    Zero,
+    Compressed(Box<[u8]>),
+    /// Evicted from the pool to this swap slot
+    WrittenBack(u64),
+}
 
 pub struct CompressedPage {
     pub original_vaddr: VirtAddr,
-    pub compressed_data: heapless::Vec<u8, 2048>,
-    pub original_size: usize,
+    stored: Stored,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CompressionStats {
+    pub stored_pages: usize,
+    pub zero_pages: usize,
+    pub written_back_pages: usize,
+    pub pool_bytes: usize,
+    pub max_pool_bytes: usize,
+    /// Uncompressed size of the pages currently in the pool
+    pub original_bytes: usize,
+    /// Loads served from the pool
+    pub hits: u64,
+    /// Loads that had to go to the swap device after write-back
+    pub misses: u64,
+    /// Stores refused because the page did not compress well, or the pool was
+    /// full with no swap to write back to
+    pub rejects: u64,
+    pub write_backs: u64,
+}
+
+impl CompressionStats {
+    /// Uncompressed bytes per pool byte
+    pub fn compression_ratio(&self) -> f32 {
+        if self.pool_bytes == 0 {
+            return 0.0;
+        }
+        self.original_bytes as f32 / self.pool_bytes as f32
+    }
+}
+
+struct CompressionPool {
+    pages: BTreeMap<u64, CompressedPage>,
+    /// Handles of compressed pages, oldest first
+    lru: VecDeque<u64>,
+    next_handle: u64,
+    pool_bytes: usize,
+    max_pool_percent: usize,
+    hits: u64,
+    misses: u64,
+    rejects: u64,
+    write_backs: u64,
 }
 
+impl CompressionPool {
+    const fn new() -> Self {
+        CompressionPool {
+            pages: BTreeMap::new(),
+            lru: VecDeque::new(),
+            next_handle: 0,
+            pool_bytes: 0,
+            max_pool_percent: DEFAULT_MAX_POOL_PERCENT,
+            hits: 0,
+            misses: 0,
+            rejects: 0,
+            write_backs: 0,
+        }
+    }
+
+    fn max_pool_bytes(&self) -> usize {
+        PMM.total_frame_count() * PAGE_SIZE / 100 * self.max_pool_percent
+    }
+
+    fn insert(&mut self, vaddr: VirtAddr, stored: Stored) -> u64 {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        if let Stored::Compressed(data) = &stored {
+            self.pool_bytes += data.len();
+            self.lru.push_back(handle);
+        }
+        self.pages.insert(handle, CompressedPage { original_vaddr: vaddr, stored });
+        handle
+    }
+
+    fn remove(&mut self, handle: u64) -> Option<CompressedPage> {
+        let page = self.pages.remove(&handle)?;
+        if let Stored::Compressed(data) = &page.stored {
+            self.pool_bytes -= data.len();
+            self.lru.retain(|&queued| queued != handle);
+        }
+        Some(page)
+    }
+}
+
+/// Buffers each CPU compresses and writes back through, so storing a page
+/// allocates nothing but the copy that is kept
+struct Scratch {
+    compressed: [u8; MAX_LZ4_SIZE],
+    page: [u8; PAGE_SIZE],
+}
+
+impl Scratch {
+    const fn new() -> Self {
+        Scratch { compressed: [0; MAX_LZ4_SIZE], page: [0; PAGE_SIZE] }
+    }
+}
+
+/// zswap-style compressed cache that sits in front of the swap device
 pub struct MemoryCompressor {
-    compressed_pages: Mutex<BTreeMap<VirtAddr, CompressedPage>>,
-    enabled: Mutex<bool>,
+    pool: SpinLock<CompressionPool>,
+    enabled: SpinLock<bool>,
+    /// Taken before the pool lock when both are held
+    scratch: [SpinLock<Scratch>; MAX_CPUS],
 }
 
 impl MemoryCompressor {
     pub const fn new() -> Self {
         MemoryCompressor {
-            compressed_pages: Mutex::new(BTreeMap::new()),
-            enabled: Mutex::new(false),
+            pool: SpinLock::new(CompressionPool::new()),
+            enabled: SpinLock::new(false),
+            scratch: [const { SpinLock::new(Scratch::new()) }; MAX_CPUS],
         }
     }
 
-    pub fn compress_page(&self, vaddr: VirtAddr, data: &[u8; 4096]) -> Result<(), &'static str> {
+    /// Store a page and return the handle it can be read back with.
+    ///
+    /// Fails for pages that do not compress well, leaving them to the swap device.
+    pub fn compress_page(&self, vaddr: VirtAddr, data: &[u8; 4096]) -> Result<u64, &'static str> {
         if !*self.enabled.lock() {
             return Err("Compression disabled");
         }
 
-        // Simple compression: remove zero pages
-        let mut has_data = false;
-        for &byte in data.iter() {
-            if byte != 0 {
-                has_data = true;
-                break;
-            }
+        if data.iter().all(|&byte| byte == 0) {
+            return Ok(self.pool.lock().insert(vaddr, Stored::Zero));
         }
 
-        if !has_data {
-            // Zero page - store minimal data
-            let compressed = CompressedPage {
-                original_vaddr: vaddr,
-                compressed_data: heapless::Vec::new(),
-                original_size: 4096,
-            };
-            self.compressed_pages.lock().insert(vaddr, compressed);
-            return Ok(());
+        let compressed: Option<Box<[u8]>> = {
+            let mut scratch = self.scratch[current_cpu()].lock();
+            lz4::compress(data, &mut scratch.compressed[..MAX_COMPRESSED_SIZE])
+                .map(|size| scratch.compressed[..size].into())
+        };
+        let Some(compressed) = compressed else {
+            self.pool.lock().rejects += 1;
+            return Err("Page does not compress");
+        };
+
+        loop {
+            {
+                let mut pool = self.pool.lock();
+                if pool.pool_bytes + compressed.len() <= pool.max_pool_bytes() {
+                    return Ok(pool.insert(vaddr, Stored::Compressed(compressed)));
+                }
+            }
+            // Nothing can be written back to make room, so the page goes nowhere
+            if !SWAP_MANAGER.has_room() {
+                self.pool.lock().rejects += 1;
+                return Err("Compression pool full");
+            }
+            self.write_back_oldest()?;
         }
+    }
+
+    /// Move the oldest compressed page out to swap to make room.
+    ///
+    /// The pool is unlocked during the write; the page stays readable from
+    /// the pool until it is done, and a page discarded meanwhile gives its
+    /// slot straight back.
+    fn write_back_oldest(&self) -> Result<(), &'static str> {
+        let mut scratch = self.scratch[current_cpu()].lock();
+        let handle = {
+            let mut pool = self.pool.lock();
+            loop {
+                let handle = pool.lru.pop_front().ok_or("Compression pool empty")?;
+                if let Some(CompressedPage { stored: Stored::Compressed(data), .. }) = pool.pages.get(&handle) {
+                    lz4::decompress(data, &mut scratch.page)?;
+                    break handle;
+                }
+            }
+        };
 
-        // TODO: Implement actual compression algorithm (LZ4, zlib, etc.)
-        // For now, just store the data
-        let mut compressed_data = heapless::Vec::new();
-        compressed_data.extend_from_slice(data).map_err(|_| "Compression buffer full")?;
+        let slot = match SWAP_MANAGER.swap_out(&scratch.page) {
+            Ok(slot) => slot,
+            Err(e) => {
+                let mut pool = self.pool.lock();
+                if pool.pages.contains_key(&handle) {
+                    pool.lru.push_front(handle);
+                }
+                return Err(e);
+            }
+        };
+
+        let mut guard = self.pool.lock();
+        let pool = &mut *guard;
+        match pool.pages.get_mut(&handle) {
+            Some(entry) => {
+                if let Stored::Compressed(data) = &entry.stored {
+                    pool.pool_bytes -= data.len();
+                }
+                entry.stored = Stored::WrittenBack(slot);
+                pool.write_backs += 1;
+            }
+            None => {
+                drop(guard);
+                SWAP_MANAGER.free_slot(slot);
+            }
+        }
+        Ok(())
+    }
 
-        let compressed = CompressedPage {
-            original_vaddr: vaddr,
-            compressed_data,
-            original_size: 4096,
+    /// Decompress a page into `page` without dropping the stored copy
+    pub fn read_page(&self, handle: u64, page: &mut [u8; 4096]) -> Result<(), &'static str> {
+        let slot = {
+            let mut pool = self.pool.lock();
+            let written_back = match &pool.pages.get(&handle).ok_or("Page not compressed")?.stored {
+                Stored::Zero => {
+                    page.fill(0);
+                    None
+                }
+                Stored::Compressed(data) => match lz4::decompress(data, page) {
+                    Ok(PAGE_SIZE) => None,
+                    Ok(_) => return Err("Corrupt compressed page"),
+                    Err(e) => return Err(e),
+                },
+                Stored::WrittenBack(slot) => Some(*slot),
+            };
+            let Some(slot) = written_back else {
+                pool.hits += 1;
+                return Ok(());
+            };
+            slot
         };
-        self.compressed_pages.lock().insert(vaddr, compressed);
 
+        // Read unlocked: only the owner of the handle frees its slot, and it is
+        // the one reading
+        SWAP_MANAGER.read_slot(slot, page)?;
+        self.pool.lock().misses += 1;
+        Ok(())
+    }
+
+    pub fn decompress_page(&self, handle: u64, page: &mut [u8; 4096]) -> Result<(), &'static str> {
+        self.read_page(handle, page)?;
+        self.discard(handle);
         Ok(())
     }
 
-    pub fn decompress_page(&self, vaddr: VirtAddr) -> Result<[u8; 4096], &'static str> {
-        let compressed = self.compressed_pages.lock().remove(&vaddr)
-            .ok_or("Page not compressed")?;
+    /// Drop a stored page that is no longer needed
+    pub fn discard(&self, handle: u64) {
+        let page = self.pool.lock().remove(handle);
+        if let Some(CompressedPage { stored: Stored::WrittenBack(slot), .. }) = page {
+            SWAP_MANAGER.free_slot(slot);
+        }
+    }
+
+    /// Pull pages written back to swap slots accepted by `filter` back into the pool
+    pub fn unuse_swap_slots(&self, filter: impl Fn(u64) -> bool) {
+        let handles: Vec<(u64, u64)> = self.pool.lock().pages.iter()
+            .filter_map(|(&handle, page)| match page.stored {
+                Stored::WrittenBack(slot) if filter(slot) => Some((handle, slot)),
+                _ => None,
+            })
+            .collect();
 
-        if compressed.compressed_data.is_empty() {
-            // Zero page
-            return Ok([0u8; 4096]);
+        for (handle, slot) in handles {
+            let mut scratch = self.scratch[current_cpu()].lock();
+            let scratch = &mut *scratch;
+            // Fails if the page was discarded since, freeing the slot
+            if SWAP_MANAGER.read_slot(slot, &mut scratch.page).is_err() {
+                continue;
+            }
+            let Some(size) = lz4::compress(&scratch.page, &mut scratch.compressed) else { continue };
+            let compressed: Box<[u8]> = scratch.compressed[..size].into();
+
+            // The pool may go over its cap here; there is nowhere else for the page to go
+            let mut guard = self.pool.lock();
+            let pool = &mut *guard;
+            match pool.pages.get_mut(&handle) {
+                Some(entry) if matches!(entry.stored, Stored::WrittenBack(stored) if stored == slot) => {
+                    pool.pool_bytes += compressed.len();
+                    pool.lru.push_back(handle);
+                    entry.stored = Stored::Compressed(compressed);
+                }
+                _ => continue,
+            }
+            drop(guard);
+            SWAP_MANAGER.free_slot(slot);
         }
+    }
 
-        // TODO: Implement actual decompression
-        let mut page = [0u8; 4096];
-        let copy_len = core::cmp::min(compressed.compressed_data.len(), 4096);
-        page[..copy_len].copy_from_slice(&compressed.compressed_data[..copy_len]);
+    pub fn is_compressed(&self, handle: u64) -> bool {
+        self.pool.lock().pages.contains_key(&handle)
+    }
+
+    /// Cap the pool at `percent` of physical memory, writing back pages that no longer fit
+    pub fn set_max_pool_percent(&self, percent: usize) -> Result<(), &'static str> {
+        if percent > 100 {
+            return Err("Pool size must be a percentage of memory");
+        }
+        self.pool.lock().max_pool_percent = percent;
+        loop {
+            {
+                let pool = self.pool.lock();
+                if pool.pool_bytes <= pool.max_pool_bytes() {
+                    return Ok(());
+                }
+            }
+            self.write_back_oldest()?;
+        }
+    }
 
-        Ok(page)
+    pub fn stats(&self) -> CompressionStats {
+        let pool = self.pool.lock();
+        let mut stats = CompressionStats {
+            stored_pages: 0,
+            zero_pages: 0,
+            written_back_pages: 0,
+            pool_bytes: pool.pool_bytes,
+            max_pool_bytes: pool.max_pool_bytes(),
+            original_bytes: 0,
+            hits: pool.hits,
+            misses: pool.misses,
+            rejects: pool.rejects,
+            write_backs: pool.write_backs,
+        };
+        for page in pool.pages.values() {
+            match page.stored {
+                Stored::Zero => stats.zero_pages += 1,
+                Stored::Compressed(_) => {
+                    stats.stored_pages += 1;
+                    stats.original_bytes += PAGE_SIZE;
+                }
+                Stored::WrittenBack(_) => stats.written_back_pages += 1,
+            }
+        }
+        stats
     }
 
-    pub fn is_compressed(&self, vaddr: VirtAddr) -> bool {
-        self.compressed_pages.lock().contains_key(&vaddr)
+    pub fn print_stats(&self) {
+        let stats = self.stats();
+        crate::io::println!("Compressed memory pool:");
+        crate::io::println!("  Pages: {} stored, {} zero, {} written back", stats.stored_pages, stats.zero_pages, stats.written_back_pages);
+        crate::io::println!("  Pool: {} / {} bytes (ratio {:.2})", stats.pool_bytes, stats.max_pool_bytes, stats.compression_ratio());
+        crate::io::println!("  Hits: {} / Misses: {} / Rejects: {}", stats.hits, stats.misses, stats.rejects);
     }
 
     pub fn enable(&self) {
@@ -93,3 +525,49 @@ impl MemoryCompressor {
 
 pub static MEMORY_COMPRESSOR: MemoryCompressor = MemoryCompressor::new();
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(page: &[u8; PAGE_SIZE]) -> usize {
+        let mut compressed = [0u8; MAX_LZ4_SIZE];
+        let size = lz4::compress(page, &mut compressed).expect("worst case did not fit");
+        let mut restored = [0u8; PAGE_SIZE];
+        assert_eq!(lz4::decompress(&compressed[..size], &mut restored), Ok(PAGE_SIZE));
+        assert!(restored == *page, "contents changed in the round trip");
+        size
+    }
+
+    #[test_case]
+    fn lz4_round_trip_repetitive() {
+        let mut page = [0u8; PAGE_SIZE];
+        for (i, byte) in page.iter_mut().enumerate() {
+            *byte = b"compressible "[i % 13];
+        }
+        assert!(round_trip(&page) < MAX_COMPRESSED_SIZE);
+    }
+
+    #[test_case]
+    fn lz4_round_trip_incompressible() {
+        let mut page = [0u8; PAGE_SIZE];
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        for byte in page.iter_mut() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            *byte = state as u8;
+        }
+        round_trip(&page);
+        // Too big to keep, so it is left to the swap device
+        let mut compressed = [0u8; MAX_COMPRESSED_SIZE];
+        assert!(lz4::compress(&page, &mut compressed).is_none());
+    }
+
+    #[test_case]
+    fn lz4_rejects_corrupt_input() {
+        let mut page = [0u8; PAGE_SIZE];
+        // A match reaching back before the start of the output
+        assert!(lz4::decompress(&[0x04, 0x10, 0x00], &mut page).is_err());
+        assert!(lz4::decompress(&[0xf0], &mut page).is_err());
+    }
+}
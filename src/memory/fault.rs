@@ -0,0 +1,199 @@
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{Page, PageSize, PageTableFlags, Size2MiB};
+use x86_64::VirtAddr;
+use crate::boot::phys_to_virt;
+use crate::memory::huge_pages::thp_eligible;
+use crate::memory::reclaim::{allocate_user_frame, PAGE_RECLAIMER};
+use crate::fs::FILESYSTEM;
+use crate::memory::vma::{Vma, VmaFlags, VmaKind, VmaTree};
+use crate::memory::vmm::{frame_bytes, COMPRESSED, COPY_ON_WRITE, FILE_BACKED, SHARED_MAPPING, SWAPPED};
+use crate::memory::{deallocate_frame, AddressSpace, HUGE_PAGE_ALLOCATOR, MEMORY_COMPRESSOR, PMM, SWAP_MANAGER, VMM};
+
+/// How a page fault was resolved
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaultResolution {
+    ZeroFilled,
+    /// The page was read in from the file the area maps
+    FileRead,
+    /// A whole 2 MiB region was backed by a fresh huge page
+    HugeZeroFilled,
+    SwappedIn,
+    CopiedOnWrite,
+    /// The page was already mapped by the time the fault was handled
+    Spurious,
+}
+
+/// Resolve a fault at `addr` against the areas of an address space.
+///
+/// Returns an error when the access falls outside every area or is not
+/// allowed by the area it hits; the caller decides how to punish that.
+pub fn handle_fault(
+    space: &AddressSpace,
+    vmas: &VmaTree,
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+) -> Result<FaultResolution, &'static str> {
+    let vma = vmas.find(addr).ok_or("Address not mapped")?;
+
+    if !vma.accessible() {
+        return Err("Access to PROT_NONE memory");
+    }
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && !vma.flags.contains(VmaFlags::WRITE) {
+        return Err("Write to read-only memory");
+    }
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) && !vma.flags.contains(VmaFlags::EXEC) {
+        return Err("Execute from non-executable memory");
+    }
+
+    let page = Page::containing_address(addr);
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        match VMM.translate_page(space, page) {
+            Some((_, flags)) if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && flags.contains(COPY_ON_WRITE) => {
+                VMM.break_cow(space, page)?;
+                return Ok(FaultResolution::CopiedOnWrite);
+            }
+            // Another thread's fault got there first, breaking the same copy-on-write
+            Some((_, flags)) if permits(flags, error_code) => return Ok(FaultResolution::Spurious),
+            Some(_) => return Err("Protection violation"),
+            // Evicted since the access; brought back below
+            None => {}
+        }
+    }
+
+    if VMM.swap_entry(space, page).is_some() {
+        VMM.swap_in(space, page)?;
+        PAGE_RECLAIMER.add_page(space, page);
+        return Ok(FaultResolution::SwappedIn);
+    }
+    if VMM.translate_page(space, page).is_some() {
+        return Ok(FaultResolution::Spurious);
+    }
+    if let VmaKind::SharedMemory { .. } = vma.kind {
+        // Segments are mapped whole when attached, so there is nothing to fill in
+        return Err("Shared memory page not mapped");
+    }
+    if huge_fault(space, vma, addr) {
+        return Ok(FaultResolution::HugeZeroFilled);
+    }
+
+    let frame = allocate_user_frame().ok_or("Out of memory")?;
+    let buffer = unsafe { frame_bytes(frame) };
+    buffer.fill(0);
+    let mut resolution = FaultResolution::ZeroFilled;
+    if let VmaKind::File { inode, offset } = vma.kind {
+        // Past the end of the file the page stays zero
+        let position = offset + (page.start_address() - vma.start);
+        if let Err(e) = FILESYSTEM.lock().read_file(inode, position, buffer) {
+            deallocate_frame(frame);
+            return Err(e);
+        }
+        resolution = FaultResolution::FileRead;
+    }
+    if let Err(e) = VMM.map_to(space, page, frame, vma.page_flags()) {
+        deallocate_frame(frame);
+        return Err(e);
+    }
+    PAGE_RECLAIMER.add_page(space, page);
+    Ok(resolution)
+}
+
+/// Whether a mapping with `flags` allows the access that faulted
+fn permits(flags: PageTableFlags, error_code: PageFaultErrorCode) -> bool {
+    flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE)
+        && (!error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) || flags.contains(PageTableFlags::WRITABLE))
+        && (!error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) || !flags.contains(PageTableFlags::NO_EXECUTE))
+}
+
+/// Back the empty 2 MiB region around `addr` with a zeroed huge page.
+///
+/// Returns false when the region does not qualify or no huge frame is free,
+/// in which case the fault falls back to a small page.
+fn huge_fault(space: &AddressSpace, vma: &Vma, addr: VirtAddr) -> bool {
+    let huge = Page::<Size2MiB>::containing_address(addr);
+    if !thp_eligible(vma, huge) || !VMM.huge_slot_empty(space, huge) {
+        return false;
+    }
+    let Some(frame) = HUGE_PAGE_ALLOCATOR.allocate_2mb() else {
+        return false;
+    };
+    unsafe {
+        core::ptr::write_bytes(phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, Size2MiB::SIZE as usize);
+    }
+    if VMM.map_huge_page(space, huge, frame, vma.page_flags()).is_err() {
+        HUGE_PAGE_ALLOCATOR.free_2mb(frame);
+        return false;
+    }
+    HUGE_PAGE_ALLOCATOR.count_fault_alloc();
+    true
+}
+
+/// Whether a resident page may be swapped out. A swap entry belongs to a
+/// single page table, so pages of shared mappings stay resident while
+/// another process still maps them. Shared file pages never go to swap:
+/// writeback only sees resident pages, so their data would miss the file.
+pub fn evictable(space: &AddressSpace, page: Page) -> bool {
+    VMM.translate_page(space, page).map_or(false, |(frame, flags)| {
+        flags.contains(PageTableFlags::USER_ACCESSIBLE)
+            && !(flags.contains(SHARED_MAPPING)
+                && (flags.contains(FILE_BACKED) || PMM.frame_ref_count(frame) > 1))
+    })
+}
+
+/// Whether a resident page can be dropped instead of swapped: a clean page of
+/// a file mapping, mapped by no other page table, that the next fault reads
+/// back from the file.
+pub fn droppable(space: &AddressSpace, page: Page) -> bool {
+    VMM.translate_page(space, page).map_or(false, |(frame, flags)| {
+        flags.contains(PageTableFlags::USER_ACCESSIBLE | FILE_BACKED)
+            && !flags.contains(PageTableFlags::DIRTY)
+            && PMM.frame_ref_count(frame) == 1
+    })
+}
+
+/// Free a clean file page, which the next fault reads back from the file
+pub fn drop_page(space: &AddressSpace, page: Page) -> Result<(), &'static str> {
+    let (frame, flags) = VMM.clear_mapping(space, page)?;
+    // Written since `droppable` looked, so the file lacks what is in it
+    if flags.contains(PageTableFlags::DIRTY) {
+        VMM.restore_mapping(space, page, frame, flags)?;
+        return Err("Page written before it could be dropped");
+    }
+    deallocate_frame(frame);
+    Ok(())
+}
+
+/// Evict a resident user page, preferring the compressed pool over the swap device
+pub fn swap_out_page(space: &AddressSpace, page: Page) -> Result<(), &'static str> {
+    let (_, flags) = VMM.translate_page(space, page).ok_or("Page not mapped")?;
+    if !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+        return Err("Only user pages can be swapped");
+    }
+
+    // Unmapped before it is copied, or a thread of the process still running
+    // on another CPU could write to it after the copy
+    let (frame, flags) = VMM.clear_mapping(space, page)?;
+    let data = unsafe { frame_bytes(frame) };
+    let vaddr = page.start_address();
+    let stored = match MEMORY_COMPRESSOR.compress_page(vaddr, data) {
+        Ok(handle) => Ok((COMPRESSED, handle)),
+        Err(_) => SWAP_MANAGER.swap_out(data).map(|slot| (SWAPPED, slot)),
+    };
+    let (kind, slot) = match stored {
+        Ok(stored) => stored,
+        Err(e) => {
+            VMM.restore_mapping(space, page, frame, flags)?;
+            return Err(e);
+        }
+    };
+
+    if let Err(e) = VMM.set_swap_entry(space, page, flags, kind, slot) {
+        if kind == COMPRESSED {
+            MEMORY_COMPRESSOR.discard(slot);
+        } else {
+            SWAP_MANAGER.free_slot(slot);
+        }
+        return Err(e);
+    }
+    deallocate_frame(frame);
+    Ok(())
+}
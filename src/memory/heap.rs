@@ -0,0 +1,307 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::size_of;
+use core::ptr::null_mut;
+use crate::sync::SpinLock;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+use crate::memory::{SLAB_ALLOCATOR, VMM};
+
+/// Kernel heap virtual range, in a higher-half PML4 slot shared by every address space
+pub const HEAP_START: usize = 0xFFFF_C000_0000_0000;
+pub const HEAP_INITIAL_SIZE: usize = 1024 * 1024; // 1MB
+pub const HEAP_MAX_SIZE: usize = 1024 * 1024 * 1024; // 1GB
+
+/// Minimum amount the heap grows by when it runs out of space
+const GROW_STEP: usize = 64 * 1024;
+
+/// Every block is a multiple of this size, so a free region header always fits
+const BLOCK_ALIGN: usize = 16;
+
+/// Header stored at the start of every free region
+struct FreeRegion {
+    size: usize,
+    next: *mut FreeRegion,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub heap_size: usize,
+    pub used_bytes: usize,
+    pub free_bytes: usize,
+    pub peak_used_bytes: usize,
+    pub allocations: u64,
+    pub deallocations: u64,
+    pub grow_count: u64,
+}
+
+/// Address-ordered first-fit free list over a range that grows by mapping pages
+struct LinkedListHeap {
+    head: *mut FreeRegion,
+    end: usize,
+    used: usize,
+    peak_used: usize,
+    allocations: u64,
+    deallocations: u64,
+    grow_count: u64,
+}
+
+unsafe impl Send for LinkedListHeap {}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Size and alignment actually reserved for `layout`
+fn block_layout(layout: Layout) -> (usize, usize) {
+    let size = align_up(layout.size().max(size_of::<FreeRegion>()), BLOCK_ALIGN);
+    (size, layout.align().max(BLOCK_ALIGN))
+}
+
+impl LinkedListHeap {
+    const fn new() -> Self {
+        LinkedListHeap {
+            head: null_mut(),
+            end: HEAP_START,
+            used: 0,
+            peak_used: 0,
+            allocations: 0,
+            deallocations: 0,
+            grow_count: 0,
+        }
+    }
+
+    /// Return a block to the free list, merging it with its neighbours
+    unsafe fn insert(&mut self, addr: usize, size: usize) {
+        let mut prev: *mut FreeRegion = null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < addr {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let region = addr as *mut FreeRegion;
+        region.write(FreeRegion { size, next });
+        if !next.is_null() && addr + size == next as usize {
+            (*region).size += (*next).size;
+            (*region).next = (*next).next;
+        }
+
+        if prev.is_null() {
+            self.head = region;
+        } else if prev as usize + (*prev).size == addr {
+            (*prev).size += (*region).size;
+            (*prev).next = (*region).next;
+        } else {
+            (*prev).next = region;
+        }
+    }
+
+    /// Carve a block out of the first free region that can hold it
+    unsafe fn take(&mut self, size: usize, align: usize) -> Option<usize> {
+        let mut prev: *mut FreeRegion = null_mut();
+        let mut current = self.head;
+
+        while !current.is_null() {
+            let region_start = current as usize;
+            let region_end = region_start + (*current).size;
+
+            let mut start = align_up(region_start, align);
+            if start != region_start && start - region_start < size_of::<FreeRegion>() {
+                start = align_up(region_start + size_of::<FreeRegion>(), align);
+            }
+            let end = start + size;
+            let tail = region_end.saturating_sub(end);
+
+            if end <= region_end && (tail == 0 || tail >= size_of::<FreeRegion>()) {
+                let next = (*current).next;
+                if prev.is_null() {
+                    self.head = next;
+                } else {
+                    (*prev).next = next;
+                }
+
+                if start != region_start {
+                    self.insert(region_start, start - region_start);
+                }
+                if tail != 0 {
+                    self.insert(end, tail);
+                }
+                return Some(start);
+            }
+
+            prev = current;
+            current = (*current).next;
+        }
+        None
+    }
+
+    /// Map enough fresh pages at the end of the heap to add at least `bytes`
+    fn grow(&mut self, bytes: usize) -> Result<(), &'static str> {
+        let bytes = align_up(bytes.max(GROW_STEP), 4096);
+        if self.end + bytes > HEAP_START + HEAP_MAX_SIZE {
+            return Err("Kernel heap exhausted");
+        }
+
+        let space = VMM.kernel_address_space().ok_or("VMM not initialized")?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let mut mapped = 0;
+        while mapped < bytes {
+            let page = Page::containing_address(VirtAddr::new((self.end + mapped) as u64));
+            if VMM.map_page(&space, page, flags).is_err() {
+                break;
+            }
+            mapped += 4096;
+        }
+        if mapped == 0 {
+            return Err("Out of physical memory");
+        }
+
+        let start = self.end;
+        #[cfg(feature = "kasan")]
+        crate::memory::kasan::heap_grown(start, mapped);
+        self.end += mapped;
+        self.grow_count += 1;
+        unsafe {
+            self.insert(start, mapped);
+        }
+        Ok(())
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = block_layout(layout);
+        let addr = match unsafe { self.take(size, align) } {
+            Some(addr) => addr,
+            None => {
+                if self.grow(size + align).is_err() {
+                    return null_mut();
+                }
+                match unsafe { self.take(size, align) } {
+                    Some(addr) => addr,
+                    None => return null_mut(),
+                }
+            }
+        };
+
+        self.used += size;
+        self.peak_used = self.peak_used.max(self.used);
+        self.allocations += 1;
+        addr as *mut u8
+    }
+
+    fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = block_layout(layout);
+        unsafe {
+            self.insert(ptr as usize, size);
+        }
+        self.used -= size;
+        self.deallocations += 1;
+    }
+}
+
+pub struct KernelHeap {
+    /// Untracked, since lock validation allocates
+    inner: SpinLock<LinkedListHeap>,
+}
+
+impl KernelHeap {
+    pub const fn new() -> Self {
+        KernelHeap {
+            inner: SpinLock::new_raw(LinkedListHeap::new()),
+        }
+    }
+
+    /// Map the initial heap; requires the VMM and frame allocator to be up
+    pub fn init(&self) -> Result<(), &'static str> {
+        without_interrupts(|| self.inner.lock().grow(HEAP_INITIAL_SIZE))
+    }
+
+    /// Allocate straight from the free list, bypassing the slab caches
+    pub fn allocate(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| self.inner.lock().allocate(layout))
+    }
+
+    pub fn deallocate(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| self.inner.lock().deallocate(ptr, layout))
+    }
+
+    /// Allocate `count` page-aligned pages of heap memory
+    pub fn allocate_pages(&self, count: usize) -> *mut u8 {
+        match Layout::from_size_align(count * 4096, 4096) {
+            Ok(layout) => self.allocate(layout),
+            Err(_) => null_mut(),
+        }
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        without_interrupts(|| {
+            let heap = self.inner.lock();
+            let heap_size = heap.end - HEAP_START;
+            HeapStats {
+                heap_size,
+                used_bytes: heap.used,
+                free_bytes: heap_size - heap.used,
+                peak_used_bytes: heap.peak_used,
+                allocations: heap.allocations,
+                deallocations: heap.deallocations,
+                grow_count: heap.grow_count,
+            }
+        })
+    }
+
+    pub fn print_stats(&self) {
+        let stats = self.stats();
+        crate::io::println!("Kernel Heap:");
+        crate::io::println!("  Size: {} bytes", stats.heap_size);
+        crate::io::println!("  Used: {} bytes (peak {})", stats.used_bytes, stats.peak_used_bytes);
+        crate::io::println!("  Free: {} bytes", stats.free_bytes);
+        crate::io::println!("  Allocations: {} / Deallocations: {}", stats.allocations, stats.deallocations);
+    }
+}
+
+pub static HEAP: KernelHeap = KernelHeap::new();
+
+/// Allocate without sanitizer bookkeeping: small objects go to the slab caches, everything else to the heap
+pub fn raw_allocate(layout: Layout) -> *mut u8 {
+    if SLAB_ALLOCATOR.handles(layout) {
+        SLAB_ALLOCATOR.allocate(layout)
+    } else {
+        HEAP.allocate(layout)
+    }
+}
+
+pub fn raw_deallocate(ptr: *mut u8, layout: Layout) {
+    if SLAB_ALLOCATOR.handles(layout) {
+        SLAB_ALLOCATOR.deallocate(ptr, layout);
+    } else {
+        HEAP.deallocate(ptr, layout);
+    }
+}
+
+/// Global allocator, with redzones and a quarantine when built with KASAN
+pub struct GlobalHeap;
+
+unsafe impl GlobalAlloc for GlobalHeap {
+    #[cfg(not(feature = "kasan"))]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        raw_allocate(layout)
+    }
+
+    #[cfg(not(feature = "kasan"))]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        raw_deallocate(ptr, layout)
+    }
+
+    #[cfg(feature = "kasan")]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        crate::memory::kasan::allocate(layout)
+    }
+
+    #[cfg(feature = "kasan")]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        crate::memory::kasan::deallocate(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: GlobalHeap = GlobalHeap;
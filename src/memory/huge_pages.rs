@@ -1,34 +1,133 @@
-use x86_64::structures::paging::{PageSize, Size2MiB, Size1GiB, PhysFrame};
+use core::ops::ControlFlow;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::structures::paging::{Page, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB};
 use x86_64::PhysAddr;
+use crate::memory::vma::{Vma, VmaFlags, VmaKind};
+use crate::memory::{PMM, VMM};
+use crate::process::PROCESS_MANAGER;
+
+/// 2 MiB frames reserved at boot, like the `hugepages=` kernel parameter
+const BOOT_HUGEPAGES_2MB: usize = 16;
+
+/// Usable memory per 1 GiB frame reserved at boot
+const BYTES_PER_BOOT_HUGEPAGE_1GB: u64 = 16 * Size1GiB::SIZE;
+
+/// Buddy order of a 2 MiB block
+const ORDER_2MB: usize = 9;
+
+/// Empty small pages khugepaged accepts in a region it collapses
+const DEFAULT_MAX_PTES_NONE: usize = 256;
+
+/// Time khugepaged sleeps between scans
+const KHUGEPAGED_SLEEP_MS: u64 = 10_000;
 
 pub enum HugePageSize {
     Size2MiB,
     Size1GiB,
 }
 
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HugePageStats {
+    pub free_2mb: usize,
+    pub free_1gb: usize,
+    pub reserved_2mb: usize,
+    /// Faults backed by a fresh huge page
+    pub thp_fault_alloc: u64,
+    /// Regions khugepaged merged into a huge page
+    pub thp_collapse: u64,
+    /// Huge pages broken back into small pages
+    pub thp_split: u64,
+}
+
 pub struct HugePageAllocator {
-    free_2mb: spin::Mutex<alloc::vec::Vec<PhysFrame<Size2MiB>>>,
-    free_1gb: spin::Mutex<alloc::vec::Vec<PhysFrame<Size1GiB>>>,
+    free_2mb: crate::sync::SpinLock<alloc::vec::Vec<PhysFrame<Size2MiB>>>,
+    free_1gb: crate::sync::SpinLock<alloc::vec::Vec<PhysFrame<Size1GiB>>>,
+    /// Size the 2 MiB pool is refilled up to when huge pages are freed
+    reserved_2mb: AtomicUsize,
+    thp_enabled: AtomicBool,
+    thp_fault_alloc: AtomicU64,
+    thp_collapse: AtomicU64,
+    thp_split: AtomicU64,
 }
 
 impl HugePageAllocator {
     pub const fn new() -> Self {
         HugePageAllocator {
-            free_2mb: spin::Mutex::new(alloc::vec::Vec::new()),
-            free_1gb: spin::Mutex::new(alloc::vec::Vec::new()),
+            free_2mb: crate::sync::SpinLock::new(alloc::vec::Vec::new()),
+            free_1gb: crate::sync::SpinLock::new(alloc::vec::Vec::new()),
+            reserved_2mb: AtomicUsize::new(0),
+            thp_enabled: AtomicBool::new(true),
+            thp_fault_alloc: AtomicU64::new(0),
+            thp_collapse: AtomicU64::new(0),
+            thp_split: AtomicU64::new(0),
+        }
+    }
+
+    /// Reserve aligned huge frames from the usable regions of the memory map.
+    ///
+    /// 1 GiB frames are taken first, one per 16 GiB of usable memory, so the
+    /// 2 MiB reservation does not fragment them.
+    pub fn init(&self, memory_map: &MemoryMap) {
+        let usable: u64 = memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .map(|r| r.range.end_addr() - r.range.start_addr())
+            .sum();
+
+        let mut reserved_1gb = 0;
+        for frame in Self::reserve::<Size1GiB>(memory_map, (usable / BYTES_PER_BOOT_HUGEPAGE_1GB) as usize) {
+            self.add_1gb_frame(frame);
+            reserved_1gb += 1;
+        }
+        let mut reserved_2mb = 0;
+        for frame in Self::reserve::<Size2MiB>(memory_map, BOOT_HUGEPAGES_2MB) {
+            self.add_2mb_frame(frame);
+            reserved_2mb += 1;
+        }
+        self.reserved_2mb.store(reserved_2mb, Ordering::Relaxed);
+        crate::io::println!("Huge pages: reserved {} x 2 MiB, {} x 1 GiB", reserved_2mb, reserved_1gb);
+    }
+
+    /// Claim up to `count` free, naturally aligned frames of size `S`
+    fn reserve<S: PageSize>(memory_map: &MemoryMap, count: usize) -> alloc::vec::Vec<PhysFrame<S>> {
+        let mut frames = alloc::vec::Vec::new();
+        for region in memory_map.iter().filter(|r| r.region_type == MemoryRegionType::Usable) {
+            let end = region.range.end_addr();
+            let mut addr = (region.range.start_addr() + S::SIZE - 1) & !(S::SIZE - 1);
+            while frames.len() < count && addr + S::SIZE <= end {
+                let frame = PhysFrame::<S>::containing_address(PhysAddr::new(addr));
+                let first = PhysFrame::<Size4KiB>::containing_address(frame.start_address());
+                if PMM.claim_range(first, (S::SIZE / Size4KiB::SIZE) as usize) {
+                    frames.push(frame);
+                }
+                addr += S::SIZE;
+            }
         }
+        frames
     }
 
+    /// Take a 2 MiB frame from the reserved pool, or failing that from the buddy allocator
     pub fn allocate_2mb(&self) -> Option<PhysFrame<Size2MiB>> {
-        self.free_2mb.lock().pop()
+        if let Some(frame) = self.free_2mb.lock().pop() {
+            return Some(frame);
+        }
+        PMM.allocate_frames(ORDER_2MB)
+            .map(|frame| PhysFrame::containing_address(frame.start_address()))
     }
 
     pub fn allocate_1gb(&self) -> Option<PhysFrame<Size1GiB>> {
         self.free_1gb.lock().pop()
     }
 
+    /// Return a 2 MiB frame, refilling the reserved pool before the buddy allocator
     pub fn free_2mb(&self, frame: PhysFrame<Size2MiB>) {
-        self.free_2mb.lock().push(frame);
+        let mut pool = self.free_2mb.lock();
+        if pool.len() < self.reserved_2mb.load(Ordering::Relaxed) {
+            pool.push(frame);
+        } else {
+            drop(pool);
+            PMM.free_frames(PhysFrame::containing_address(frame.start_address()), ORDER_2MB);
+        }
     }
 
     pub fn free_1gb(&self, frame: PhysFrame<Size1GiB>) {
@@ -42,7 +141,114 @@ impl HugePageAllocator {
     pub fn add_1gb_frame(&self, frame: PhysFrame<Size1GiB>) {
         self.free_1gb.lock().push(frame);
     }
+
+    pub fn thp_enabled(&self) -> bool {
+        self.thp_enabled.load(Ordering::Relaxed)
+    }
+
+    /// Turn transparent huge pages on or off for new faults and khugepaged
+    pub fn set_thp_enabled(&self, enabled: bool) {
+        self.thp_enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_fault_alloc(&self) {
+        self.thp_fault_alloc.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_split(&self) {
+        self.thp_split.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> HugePageStats {
+        HugePageStats {
+            free_2mb: self.free_2mb.lock().len(),
+            free_1gb: self.free_1gb.lock().len(),
+            reserved_2mb: self.reserved_2mb.load(Ordering::Relaxed),
+            thp_fault_alloc: self.thp_fault_alloc.load(Ordering::Relaxed),
+            thp_collapse: self.thp_collapse.load(Ordering::Relaxed),
+            thp_split: self.thp_split.load(Ordering::Relaxed),
+        }
+    }
 }
 
 pub static HUGE_PAGE_ALLOCATOR: HugePageAllocator = HugePageAllocator::new();
 
+/// Whether the 2 MiB region `huge` may be backed by a transparent huge page in `vma`
+pub fn thp_eligible(vma: &Vma, huge: Page<Size2MiB>) -> bool {
+    let start = huge.start_address();
+    HUGE_PAGE_ALLOCATOR.thp_enabled()
+        && matches!(vma.kind, VmaKind::Anonymous | VmaKind::Heap)
+        && !vma.flags.contains(VmaFlags::SHARED)
+        && start >= vma.start
+        && start + Size2MiB::SIZE <= vma.end
+}
+
+/// Background pass that collapses runs of small anonymous pages into huge pages
+pub struct Khugepaged {
+    max_ptes_none: AtomicUsize,
+    full_scans: AtomicU64,
+}
+
+impl Khugepaged {
+    pub const fn new() -> Self {
+        Khugepaged {
+            max_ptes_none: AtomicUsize::new(DEFAULT_MAX_PTES_NONE),
+            full_scans: AtomicU64::new(0),
+        }
+    }
+
+    /// How many empty small pages a region may have and still be collapsed
+    pub fn set_max_ptes_none(&self, count: usize) {
+        self.max_ptes_none.store(count.min(511), Ordering::Relaxed);
+    }
+
+    /// Scan every process once, one at a time under its own map lock, returning
+    /// the number of regions collapsed. Stops early when huge frames run out.
+    pub fn scan(&self) -> usize {
+        if !HUGE_PAGE_ALLOCATOR.thp_enabled() {
+            return 0;
+        }
+        let max_empty = self.max_ptes_none.load(Ordering::Relaxed);
+        let mut collapsed = 0;
+
+        let scanned = PROCESS_MANAGER.for_each_mm(|space, vmas| {
+            for vma in vmas.iter() {
+                let mut start = vma.start.align_up(Size2MiB::SIZE);
+                while start + Size2MiB::SIZE <= vma.end {
+                    let huge = Page::<Size2MiB>::containing_address(start);
+                    start += Size2MiB::SIZE;
+                    if !thp_eligible(vma, huge) {
+                        continue;
+                    }
+                    match VMM.collapse_huge_page(space, huge, vma.page_flags(), max_empty) {
+                        Ok(true) => collapsed += 1,
+                        Ok(false) => {}
+                        // Out of huge frames; leave the rest for the next scan
+                        Err(_) => return ControlFlow::Break(()),
+                    }
+                }
+            }
+            ControlFlow::Continue(())
+        });
+
+        HUGE_PAGE_ALLOCATOR.thp_collapse.fetch_add(collapsed as u64, Ordering::Relaxed);
+        if scanned.is_continue() {
+            self.full_scans.fetch_add(1, Ordering::Relaxed);
+        }
+        collapsed
+    }
+
+    pub fn full_scans(&self) -> u64 {
+        self.full_scans.load(Ordering::Relaxed)
+    }
+
+    /// Body of the khugepaged kernel thread
+    pub fn run(&self) -> ! {
+        loop {
+            self.scan();
+            crate::timer::sleep_ms(KHUGEPAGED_SLEEP_MS);
+        }
+    }
+}
+
+pub static KHUGEPAGED: Khugepaged = Khugepaged::new();
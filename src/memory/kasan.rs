@@ -0,0 +1,586 @@
+//! Kernel address sanitizer.
+//!
+//! Built with `make kasan`, which enables the `kasan` feature and compiles the
+//! kernel with `-Zsanitizer=kernel-address` in outline mode: every load and
+//! store calls one of the `__asan_*` hooks below, and stack redzones are
+//! poisoned through `__asan_set_shadow_*`. One shadow byte describes eight
+//! bytes of memory: 0 means all of them are valid, 1-7 that only the first
+//! few are, and the values at or above 0x80 say why none of them are.
+//!
+//! Shadow exists for the kernel heap and the physical memory mapping, where
+//! slab objects live; accesses anywhere else are not checked. Heap blocks get
+//! a left redzone holding the allocation and free sites, a right redzone, and
+//! sit in a quarantine for a while after being freed so that late accesses are
+//! still caught.
+//!
+//! The hooks run on every memory access, including those they make themselves,
+//! so everything on the checking path is built with `sanitize(address = "off")`
+//! and sticks to plain loads, stores and arithmetic that never call back into
+//! instrumented code.
+
+use core::alloc::Layout;
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::sync::SpinLock;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+use crate::boot::physical_memory_offset;
+use crate::memory::heap::{raw_allocate, raw_deallocate, HEAP_MAX_SIZE, HEAP_START};
+use crate::memory::{PMM, VMM};
+
+/// Offset the compiler adds to `addr >> 3` to form a shadow address, LLVM's default for x86_64 kernels
+pub const KASAN_SHADOW_OFFSET: usize = 0xdfff_fc00_0000_0000;
+
+/// Where the shadow of the kernel heap is mapped
+const HEAP_SHADOW_START: usize = 0xFFFF_D000_0000_0000;
+
+/// Where the shadow of the physical memory mapping is mapped
+const PHYS_SHADOW_START: usize = HEAP_SHADOW_START + (1 << 30);
+
+const GRANULE: usize = 8;
+
+pub const SHADOW_HEAP_LEFT_REDZONE: u8 = 0xFA;
+pub const SHADOW_HEAP_RIGHT_REDZONE: u8 = 0xFB;
+pub const SHADOW_FREED: u8 = 0xFD;
+pub const SHADOW_STACK_LEFT: u8 = 0xF1;
+pub const SHADOW_STACK_MID: u8 = 0xF2;
+pub const SHADOW_STACK_RIGHT: u8 = 0xF3;
+pub const SHADOW_STACK_AFTER_RETURN: u8 = 0xF5;
+pub const SHADOW_STACK_AFTER_SCOPE: u8 = 0xF8;
+pub const SHADOW_ALLOCA_LEFT: u8 = 0xCA;
+pub const SHADOW_ALLOCA_RIGHT: u8 = 0xCB;
+
+/// Return addresses kept for the allocation and the free of each block
+const STACK_DEPTH: usize = 3;
+
+/// Bytes after each heap object that must not be touched
+const RIGHT_REDZONE: usize = 32;
+
+/// Freed blocks held back before the memory is reused
+const QUARANTINE_ENTRIES: usize = 1024;
+const QUARANTINE_BYTES: usize = 1024 * 1024;
+
+/// How far below the stack pointer a noreturn call clears stack poison
+const MAX_STACK_UNPOISON: usize = 64 * 1024;
+
+const HEADER_MAGIC: usize = 0x4B41_5341_4E48_4452;
+
+/// Kept in the left redzone of every heap block
+#[repr(C)]
+struct BlockHeader {
+    magic: usize,
+    size: usize,
+    alloc_stack: [usize; STACK_DEPTH],
+    free_stack: [usize; STACK_DEPTH],
+}
+
+// Written by `init` and `heap_grown`, read on every access; atomics would
+// call back into instrumented code at low optimization levels
+static mut ENABLED: bool = false;
+static mut REPORTING: bool = false;
+static mut HEAP_SHADOW_END: usize = HEAP_START;
+static mut PHYS_START: usize = 0;
+static mut PHYS_END: usize = 0;
+
+static REPORTS: AtomicU64 = AtomicU64::new(0);
+
+struct Quarantine {
+    blocks: heapless::Deque<(usize, Layout), QUARANTINE_ENTRIES>,
+    bytes: usize,
+}
+
+static QUARANTINE: SpinLock<Quarantine> = SpinLock::new_raw(Quarantine {
+    blocks: heapless::Deque::new(),
+    bytes: 0,
+});
+
+/// Shadow byte describing the granule of `addr`, if `addr` is covered
+#[sanitize(address = "off")]
+unsafe fn shadow_of(addr: usize) -> Option<*mut u8> {
+    if addr >= HEAP_START && addr < HEAP_SHADOW_END {
+        Some((HEAP_SHADOW_START + (addr - HEAP_START) / GRANULE) as *mut u8)
+    } else if addr >= PHYS_START && addr < PHYS_END {
+        Some((PHYS_SHADOW_START + (addr - PHYS_START) / GRANULE) as *mut u8)
+    } else {
+        None
+    }
+}
+
+/// Set the shadow of the granules covering `[addr, addr + len)` to `value`
+#[sanitize(address = "off")]
+unsafe fn poison(addr: usize, len: usize, value: u8) {
+    let mut granule = addr & !(GRANULE - 1);
+    while granule < addr + len {
+        if let Some(shadow) = shadow_of(granule) {
+            *shadow = value;
+        }
+        granule += GRANULE;
+    }
+}
+
+/// Mark `[addr, addr + len)` valid; `addr` must be granule aligned
+#[sanitize(address = "off")]
+unsafe fn unpoison(addr: usize, len: usize) {
+    poison(addr, len & !(GRANULE - 1), 0);
+    if len % GRANULE != 0 {
+        if let Some(shadow) = shadow_of(addr + (len & !(GRANULE - 1))) {
+            *shadow = (len % GRANULE) as u8;
+        }
+    }
+}
+
+/// First bad byte of an access to `[addr, addr + size)`, if any
+#[sanitize(address = "off")]
+unsafe fn first_bad_byte(addr: usize, size: usize) -> Option<usize> {
+    let end = addr + size;
+    let mut granule = addr & !(GRANULE - 1);
+    while granule < end {
+        if let Some(shadow) = shadow_of(granule) {
+            let value = *shadow;
+            if value != 0 {
+                let first = if granule > addr { granule } else { addr };
+                let last = if granule + GRANULE < end { granule + GRANULE } else { end } - 1;
+                // 1-7: only the first `value` bytes of the granule are valid
+                if value >= 0x80 {
+                    return Some(first);
+                }
+                if last - granule >= value as usize {
+                    let valid_end = granule + value as usize;
+                    return Some(if first > valid_end { first } else { valid_end });
+                }
+            }
+        }
+        granule += GRANULE;
+    }
+    None
+}
+
+/// Return address of the function this is inlined into; needs frame pointers.
+/// Always inlined, so it is only as instrumented as its caller
+#[inline(always)]
+unsafe fn return_address() -> usize {
+    let rbp: usize;
+    asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    if rbp == 0 {
+        0
+    } else {
+        *((rbp + 8) as *const usize)
+    }
+}
+
+/// Walk the frame pointer chain, storing up to `STACK_DEPTH` return addresses at `out`
+#[inline(always)]
+unsafe fn capture_stack(out: *mut usize) {
+    let mut rbp: usize;
+    asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    let mut depth = 0;
+    while depth < STACK_DEPTH {
+        let mut address = 0;
+        if rbp >= 0x1000 && rbp % 8 == 0 {
+            address = *((rbp + 8) as *const usize);
+            rbp = *(rbp as *const usize);
+        }
+        *out.add(depth) = address;
+        depth += 1;
+    }
+}
+
+#[sanitize(address = "off")]
+unsafe fn check(addr: usize, size: usize, write: bool, ip: usize) {
+    if !ENABLED || REPORTING || size == 0 {
+        return;
+    }
+    if let Some(bad) = first_bad_byte(addr, size) {
+        report(addr, size, write, ip, bad);
+    }
+}
+
+/// Size of the left redzone in front of an object with this layout; it holds the header
+fn left_redzone(layout: Layout) -> usize {
+    layout.align().max(core::mem::size_of::<BlockHeader>())
+}
+
+/// Layout of the whole block behind an object: left redzone, object and right redzone
+fn block_layout(layout: Layout) -> Option<Layout> {
+    let size = left_redzone(layout) + ((layout.size() + GRANULE - 1) & !(GRANULE - 1)) + RIGHT_REDZONE;
+    Layout::from_size_align(size, layout.align().max(16)).ok()
+}
+
+/// Allocate an object surrounded by poisoned redzones
+#[sanitize(address = "off")]
+pub unsafe fn allocate(layout: Layout) -> *mut u8 {
+    let Some(block) = block_layout(layout) else {
+        return core::ptr::null_mut();
+    };
+    let base = raw_allocate(block);
+    if base.is_null() {
+        return base;
+    }
+
+    let left = left_redzone(layout);
+    let header = base as *mut BlockHeader;
+    (*header).magic = HEADER_MAGIC;
+    (*header).size = layout.size();
+    capture_stack((*header).alloc_stack.as_mut_ptr());
+    let mut i = 0;
+    while i < STACK_DEPTH {
+        (*header).free_stack[i] = 0;
+        i += 1;
+    }
+
+    let object = base as usize + left;
+    poison(base as usize, left, SHADOW_HEAP_LEFT_REDZONE);
+    poison(object, block.size() - left, SHADOW_HEAP_RIGHT_REDZONE);
+    unpoison(object, layout.size());
+    object as *mut u8
+}
+
+/// Poison a freed object and quarantine its block, releasing the oldest blocks once the quarantine is full
+#[sanitize(address = "off")]
+pub unsafe fn deallocate(ptr: *mut u8, layout: Layout) {
+    let Some(block) = block_layout(layout) else { return };
+    let base = ptr as usize - left_redzone(layout);
+    let header = base as *mut BlockHeader;
+    if (*header).magic == HEADER_MAGIC {
+        capture_stack((*header).free_stack.as_mut_ptr());
+    }
+    poison(ptr as usize, block.size() - left_redzone(layout), SHADOW_FREED);
+
+    without_interrupts(|| {
+        let mut quarantine = QUARANTINE.lock();
+        while quarantine.blocks.is_full() || quarantine.bytes + block.size() > QUARANTINE_BYTES {
+            let Some((old, old_layout)) = quarantine.blocks.pop_front() else { break };
+            quarantine.bytes -= old_layout.size();
+            // The allocator keeps its own bookkeeping in free memory
+            unpoison(old, old_layout.size());
+            raw_deallocate(old as *mut u8, old_layout);
+        }
+        if quarantine.blocks.push_back((base, block)).is_ok() {
+            quarantine.bytes += block.size();
+        } else {
+            unpoison(base, block.size());
+            raw_deallocate(base as *mut u8, block);
+        }
+    });
+}
+
+/// Find the header of the heap block around `addr` by walking the shadow back
+/// to the start of its left redzone
+#[sanitize(address = "off")]
+unsafe fn find_block(addr: usize) -> Option<usize> {
+    let mut granule = addr & !(GRANULE - 1);
+    let mut in_left_redzone = false;
+    let limit = granule.saturating_sub(HEAP_MAX_SIZE.min(1 << 20));
+    while granule > limit {
+        let value = *shadow_of(granule)?;
+        if value == SHADOW_HEAP_LEFT_REDZONE {
+            in_left_redzone = true;
+        } else if in_left_redzone {
+            break;
+        }
+        granule -= GRANULE;
+    }
+    let base = granule + GRANULE;
+    if in_left_redzone && (*(base as *const BlockHeader)).magic == HEADER_MAGIC {
+        Some(base)
+    } else {
+        None
+    }
+}
+
+fn bug_type(addr: usize, shadow: Option<u8>) -> &'static str {
+    if addr < 0x1000 {
+        return "null-ptr-deref";
+    }
+    match shadow {
+        Some(1..=7) | Some(SHADOW_HEAP_LEFT_REDZONE) | Some(SHADOW_HEAP_RIGHT_REDZONE) => "slab-out-of-bounds",
+        Some(SHADOW_FREED) => "use-after-free",
+        Some(SHADOW_STACK_LEFT) | Some(SHADOW_STACK_MID) | Some(SHADOW_STACK_RIGHT) => "stack-out-of-bounds",
+        Some(SHADOW_STACK_AFTER_RETURN) => "stack-use-after-return",
+        Some(SHADOW_STACK_AFTER_SCOPE) => "stack-use-after-scope",
+        Some(SHADOW_ALLOCA_LEFT) | Some(SHADOW_ALLOCA_RIGHT) => "alloca-out-of-bounds",
+        _ => "wild-memory-access",
+    }
+}
+
+fn print_stack(title: &str, stack: &[usize; STACK_DEPTH]) {
+    crate::io::println!("{}:", title);
+    for &address in stack.iter().take_while(|&&address| address != 0) {
+        crate::io::println!("  [<{:016x}>]", address);
+    }
+}
+
+#[sanitize(address = "off")]
+#[inline(never)]
+unsafe fn report(addr: usize, size: usize, write: bool, ip: usize, bad: usize) {
+    REPORTING = true;
+    REPORTS.fetch_add(1, Ordering::Relaxed);
+    let shadow = shadow_of(bad).map(|shadow| *shadow);
+
+    crate::io::println!("==================================================================");
+    crate::io::println!("BUG: KASAN: {} at ip {:016x}", bug_type(bad, shadow), ip);
+    crate::io::println!("{} of size {} at addr {:016x}", if write { "Write" } else { "Read" }, size, addr);
+    match crate::process::PROCESS_MANAGER.get_current_process() {
+        Some(pid) => crate::io::println!("Current process: {}", pid.as_u64()),
+        None => crate::io::println!("Current process: kernel"),
+    }
+
+    if let Some(base) = find_block(bad) {
+        let header = &*(base as *const BlockHeader);
+        let object = base + core::mem::size_of::<BlockHeader>().max(GRANULE);
+        let (alloc_stack, free_stack, object_size) = (header.alloc_stack, header.free_stack, header.size);
+        print_stack("Allocated by", &alloc_stack);
+        if free_stack[0] != 0 {
+            print_stack("Freed by", &free_stack);
+        }
+        // The object starts at the end of the left redzone
+        let mut start = object;
+        while shadow_of(start).map_or(false, |shadow| *shadow == SHADOW_HEAP_LEFT_REDZONE) {
+            start += GRANULE;
+        }
+        let location = if bad < start {
+            alloc::format!("{} bytes to the left of", start - bad)
+        } else if bad >= start + object_size {
+            alloc::format!("{} bytes to the right of", bad - (start + object_size))
+        } else {
+            alloc::format!("{} bytes inside of", bad - start)
+        };
+        crate::io::println!(
+            "The buggy address is located {} the {}-byte region [{:016x}, {:016x})",
+            location, object_size, start, start + object_size
+        );
+    }
+
+    crate::io::println!("Memory state around the buggy address:");
+    let row = (bad & !(GRANULE * 16 - 1)).saturating_sub(GRANULE * 16);
+    for line in 0..3 {
+        let line_addr = row + line * GRANULE * 16;
+        let mut text = alloc::string::String::new();
+        for i in 0..16 {
+            match shadow_of(line_addr + i * GRANULE) {
+                Some(shadow) => text.push_str(&alloc::format!(" {:02x}", *shadow)),
+                None => text.push_str(" --"),
+            }
+        }
+        let marker = if bad >= line_addr && bad < line_addr + GRANULE * 16 { ">" } else { " " };
+        crate::io::println!("{}{:016x}:{}", marker, line_addr, text);
+    }
+    crate::io::println!("==================================================================");
+    REPORTING = false;
+}
+
+/// Clear any poison left on memory that goes back to the allocator without
+/// passing through `deallocate`, such as a kernel stack
+#[sanitize(address = "off")]
+pub fn unpoison_range(addr: usize, len: usize) {
+    unsafe { poison(addr, len, 0) }
+}
+
+/// Map zeroed shadow for `[start, start + len)` of covered memory at `shadow_start`
+fn map_shadow(shadow_start: usize, len: usize) -> Result<(), &'static str> {
+    let space = VMM.kernel_address_space().ok_or("VMM not initialized")?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let first = Page::containing_address(VirtAddr::new(shadow_start as u64));
+    let last = Page::containing_address(VirtAddr::new((shadow_start + (len + GRANULE - 1) / GRANULE - 1) as u64));
+    for page in Page::range_inclusive(first, last) {
+        if VMM.translate_page(&space, page).is_none() {
+            VMM.map_page(&space, page, flags)?;
+        }
+    }
+    Ok(())
+}
+
+/// Extend the heap shadow over freshly mapped heap memory
+pub fn heap_grown(start: usize, len: usize) {
+    if map_shadow(HEAP_SHADOW_START + (start - HEAP_START) / GRANULE, len).is_ok() {
+        unsafe {
+            HEAP_SHADOW_END = start + len;
+        }
+    }
+}
+
+/// Map shadow for the physical memory mapping and start checking accesses
+pub fn init() -> Result<(), &'static str> {
+    let phys_len = PMM.frame_limit() as usize * 4096;
+    map_shadow(PHYS_SHADOW_START, phys_len)?;
+    unsafe {
+        PHYS_START = physical_memory_offset().as_u64() as usize;
+        PHYS_END = PHYS_START + phys_len;
+        ENABLED = true;
+    }
+    crate::io::println!("KASAN: enabled, {} KB of shadow for physical memory", phys_len / GRANULE / 1024);
+    Ok(())
+}
+
+pub fn report_count() -> u64 {
+    REPORTS.load(Ordering::Relaxed)
+}
+
+/// Covered address a compiler-computed shadow address describes
+#[sanitize(address = "off")]
+fn shadow_to_addr(shadow: usize) -> usize {
+    shadow.wrapping_sub(KASAN_SHADOW_OFFSET) << 3
+}
+
+/// Write `value` into `len` shadow bytes starting at the compiler's shadow address
+#[sanitize(address = "off")]
+unsafe fn set_shadow(shadow: usize, len: usize, value: u8) {
+    poison(shadow_to_addr(shadow), len * GRANULE, value);
+}
+
+/// Clear stale stack poison below `sp`, down to the redzone in front of the stack
+#[sanitize(address = "off")]
+unsafe fn unpoison_stack_below(sp: usize) {
+    let mut granule = sp & !(GRANULE - 1);
+    let bottom = sp.saturating_sub(MAX_STACK_UNPOISON);
+    while granule > bottom {
+        granule -= GRANULE;
+        let Some(shadow) = shadow_of(granule) else { return };
+        match *shadow {
+            SHADOW_STACK_LEFT | SHADOW_STACK_MID | SHADOW_STACK_RIGHT | SHADOW_STACK_AFTER_RETURN
+            | SHADOW_STACK_AFTER_SCOPE | SHADOW_ALLOCA_LEFT | SHADOW_ALLOCA_RIGHT => *shadow = 0,
+            0..=7 => {}
+            _ => return,
+        }
+    }
+}
+
+macro_rules! access_hooks {
+    ($($size:literal => $load:ident, $load_noabort:ident, $store:ident, $store_noabort:ident;)*) => {
+        $(
+            #[no_mangle]
+            #[sanitize(address = "off")]
+            pub unsafe extern "C" fn $load(addr: usize) {
+                check(addr, $size, false, return_address());
+            }
+
+            #[no_mangle]
+            #[sanitize(address = "off")]
+            pub unsafe extern "C" fn $load_noabort(addr: usize) {
+                check(addr, $size, false, return_address());
+            }
+
+            #[no_mangle]
+            #[sanitize(address = "off")]
+            pub unsafe extern "C" fn $store(addr: usize) {
+                check(addr, $size, true, return_address());
+            }
+
+            #[no_mangle]
+            #[sanitize(address = "off")]
+            pub unsafe extern "C" fn $store_noabort(addr: usize) {
+                check(addr, $size, true, return_address());
+            }
+        )*
+    };
+}
+
+access_hooks! {
+    1 => __asan_load1, __asan_load1_noabort, __asan_store1, __asan_store1_noabort;
+    2 => __asan_load2, __asan_load2_noabort, __asan_store2, __asan_store2_noabort;
+    4 => __asan_load4, __asan_load4_noabort, __asan_store4, __asan_store4_noabort;
+    8 => __asan_load8, __asan_load8_noabort, __asan_store8, __asan_store8_noabort;
+    16 => __asan_load16, __asan_load16_noabort, __asan_store16, __asan_store16_noabort;
+}
+
+#[no_mangle]
+#[sanitize(address = "off")]
+pub unsafe extern "C" fn __asan_loadN(addr: usize, size: usize) {
+    check(addr, size, false, return_address());
+}
+
+#[no_mangle]
+#[sanitize(address = "off")]
+pub unsafe extern "C" fn __asan_loadN_noabort(addr: usize, size: usize) {
+    check(addr, size, false, return_address());
+}
+
+#[no_mangle]
+#[sanitize(address = "off")]
+pub unsafe extern "C" fn __asan_storeN(addr: usize, size: usize) {
+    check(addr, size, true, return_address());
+}
+
+#[no_mangle]
+#[sanitize(address = "off")]
+pub unsafe extern "C" fn __asan_storeN_noabort(addr: usize, size: usize) {
+    check(addr, size, true, return_address());
+}
+
+macro_rules! set_shadow_hooks {
+    ($($name:ident => $value:literal;)*) => {
+        $(
+            #[no_mangle]
+            #[sanitize(address = "off")]
+            pub unsafe extern "C" fn $name(shadow: usize, len: usize) {
+                set_shadow(shadow, len, $value);
+            }
+        )*
+    };
+}
+
+set_shadow_hooks! {
+    __asan_set_shadow_00 => 0x00;
+    __asan_set_shadow_f1 => 0xF1;
+    __asan_set_shadow_f2 => 0xF2;
+    __asan_set_shadow_f3 => 0xF3;
+    __asan_set_shadow_f5 => 0xF5;
+    __asan_set_shadow_f8 => 0xF8;
+}
+
+#[no_mangle]
+#[sanitize(address = "off")]
+pub unsafe extern "C" fn __asan_handle_no_return() {
+    let sp: usize;
+    asm!("mov {}, rsp", out(reg) sp, options(nomem, nostack, preserves_flags));
+    unpoison_stack_below(sp);
+}
+
+#[no_mangle]
+#[sanitize(address = "off")]
+pub unsafe extern "C" fn __asan_alloca_poison(addr: usize, size: usize) {
+    poison(addr - 32, 32, SHADOW_ALLOCA_LEFT);
+    unpoison(addr, size);
+    let end = (addr + size + GRANULE - 1) & !(GRANULE - 1);
+    poison(end, 32, SHADOW_ALLOCA_RIGHT);
+}
+
+#[no_mangle]
+#[sanitize(address = "off")]
+pub unsafe extern "C" fn __asan_allocas_unpoison(top: usize, bottom: usize) {
+    if top < bottom {
+        poison(top, bottom - top, 0);
+    }
+}
+
+extern "C" {
+    fn memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut u8;
+    fn memmove(dest: *mut u8, src: *const u8, n: usize) -> *mut u8;
+    fn memset(dest: *mut u8, c: i32, n: usize) -> *mut u8;
+}
+
+#[no_mangle]
+#[sanitize(address = "off")]
+pub unsafe extern "C" fn __asan_memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut u8 {
+    let ip = return_address();
+    check(src as usize, n, false, ip);
+    check(dest as usize, n, true, ip);
+    memcpy(dest, src, n)
+}
+
+#[no_mangle]
+#[sanitize(address = "off")]
+pub unsafe extern "C" fn __asan_memmove(dest: *mut u8, src: *const u8, n: usize) -> *mut u8 {
+    let ip = return_address();
+    check(src as usize, n, false, ip);
+    check(dest as usize, n, true, ip);
+    memmove(dest, src, n)
+}
+
+#[no_mangle]
+#[sanitize(address = "off")]
+pub unsafe extern "C" fn __asan_memset(dest: *mut u8, c: i32, n: usize) -> *mut u8 {
+    check(dest as usize, n, true, return_address());
+    memset(dest, c, n)
+}
@@ -0,0 +1,405 @@
+use alloc::vec::Vec;
+use x86_64::structures::paging::{Page, PageSize, PhysFrame, Size2MiB, Size4KiB};
+use x86_64::VirtAddr;
+use crate::fs::FILESYSTEM;
+use crate::memory::vma::{Vma, VmaFlags, VmaKind, VmaTree};
+use crate::memory::vmm::frame_bytes;
+use crate::memory::{deallocate_frame, AddressSpace, PMM, VMM};
+use crate::security::ASLR;
+
+pub const PROT_NONE: u64 = 0x0;
+pub const PROT_READ: u64 = 0x1;
+pub const PROT_WRITE: u64 = 0x2;
+pub const PROT_EXEC: u64 = 0x4;
+
+pub const MAP_SHARED: u64 = 0x01;
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_FIXED: u64 = 0x10;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+/// Lowest address a mapping may start at, like `vm.mmap_min_addr`
+const MMAP_MIN_ADDR: u64 = 0x10000;
+
+/// End of the user half of the address space
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// mmap hands out addresses top-down from below here
+const MMAP_TOP: u64 = 0x0000_7f00_0000_0000;
+
+/// Span the mmap base is slid down by when ASLR is on
+const MMAP_RANDOM_RANGE: u64 = 1 << 40;
+
+const BRK_BASE: u64 = 0x4000_0000;
+
+/// Span the start of the heap is slid up by when ASLR is on
+const BRK_RANDOM_RANGE: u64 = 32 * 1024 * 1024;
+
+/// Where a process's mmap area and heap live
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryLayout {
+    /// Mappings without a usable hint are placed below this address
+    pub mmap_base: VirtAddr,
+    pub brk_start: VirtAddr,
+    /// Current program break
+    pub brk: VirtAddr,
+}
+
+impl MemoryLayout {
+    /// A fresh layout, slid by random offsets when ASLR is enabled
+    pub fn randomized() -> Self {
+        let brk_start = VirtAddr::new(BRK_BASE + ASLR::random_page_offset(BRK_RANDOM_RANGE));
+        MemoryLayout {
+            mmap_base: VirtAddr::new(MMAP_TOP - ASLR::random_page_offset(MMAP_RANDOM_RANGE)),
+            brk_start,
+            brk: brk_start,
+        }
+    }
+}
+
+fn page_align(len: u64) -> Option<u64> {
+    len.checked_add(PAGE_SIZE - 1).map(|len| len & !(PAGE_SIZE - 1))
+}
+
+fn vma_flags(prot: u64, shared: bool) -> Result<VmaFlags, &'static str> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err("Invalid protection");
+    }
+    let mut flags = VmaFlags::empty();
+    if prot & PROT_READ != 0 {
+        flags |= VmaFlags::READ;
+    }
+    if prot & PROT_WRITE != 0 {
+        flags |= VmaFlags::WRITE;
+    }
+    if prot & PROT_EXEC != 0 {
+        flags |= VmaFlags::EXEC;
+    }
+    if shared {
+        flags |= VmaFlags::SHARED;
+    }
+    Ok(flags)
+}
+
+/// Check that the page-aligned range `[start, start + len)` can hold user mappings
+fn user_range(space: &AddressSpace, start: u64, len: u64) -> Result<(VirtAddr, VirtAddr), &'static str> {
+    if start % PAGE_SIZE != 0 {
+        return Err("Unaligned address");
+    }
+    let end = start.checked_add(len).ok_or("Invalid length")?;
+    if start < MMAP_MIN_ADDR || end > USER_END {
+        return Err("Address outside user space");
+    }
+    let (start, end) = (VirtAddr::new(start), VirtAddr::new(end));
+    if VMM.kernel_mapping_in(space, start, end).is_some() {
+        return Err("Address range used by the kernel");
+    }
+    Ok((start, end))
+}
+
+/// Choose where a mapping without `MAP_FIXED` goes: the hint if it is free,
+/// otherwise the highest gap below the mmap base
+fn place(space: &AddressSpace, vmas: &VmaTree, layout: &MemoryLayout, hint: u64, len: u64, kind: VmaKind) -> Result<VirtAddr, &'static str> {
+    if hint != 0 {
+        if let Ok((start, end)) = user_range(space, hint & !(PAGE_SIZE - 1), len) {
+            if !vmas.overlaps(start, end) {
+                return Ok(start);
+            }
+        }
+    }
+
+    // Large anonymous mappings are 2 MiB aligned so they can use transparent huge pages
+    let align = if kind == VmaKind::Anonymous && len >= Size2MiB::SIZE { Size2MiB::SIZE } else { PAGE_SIZE };
+    let mut below = layout.mmap_base;
+    loop {
+        let start = vmas.find_gap(len, align, VirtAddr::new(MMAP_MIN_ADDR), below).ok_or("Out of address space")?;
+        match VMM.kernel_mapping_in(space, start, start + len) {
+            // Retry beneath the kernel mapping
+            Some(kernel) => below = kernel,
+            None => return Ok(start),
+        }
+    }
+}
+
+/// Map `len` bytes with `prot` and `MAP_*` `flags`, returning where the mapping went.
+///
+/// `file` is the inode and page-aligned offset backing the mapping, or `None`
+/// for anonymous memory. `max_prot` is the most `prot` or a later `mprotect`
+/// may grant. Nothing is populated until the pages fault in.
+pub fn mmap(
+    space: &AddressSpace,
+    vmas: &mut VmaTree,
+    layout: &MemoryLayout,
+    addr: u64,
+    len: u64,
+    prot: u64,
+    max_prot: u64,
+    flags: u64,
+    file: Option<(u64, u64)>,
+) -> Result<VirtAddr, &'static str> {
+    let shared = match flags & (MAP_SHARED | MAP_PRIVATE) {
+        MAP_SHARED => true,
+        MAP_PRIVATE => false,
+        _ => return Err("Exactly one of MAP_SHARED and MAP_PRIVATE is required"),
+    };
+    let max_flags = vma_flags(max_prot, shared)?;
+    let vma_flags = vma_flags(prot, shared)?;
+    if !max_flags.contains(vma_flags) {
+        return Err("Permission denied");
+    }
+    let len = page_align(len).filter(|&len| len > 0).ok_or("Invalid length")?;
+    let kind = match file {
+        Some((inode, offset)) if offset % PAGE_SIZE == 0 => VmaKind::File { inode, offset },
+        Some(_) => return Err("Unaligned file offset"),
+        None => VmaKind::Anonymous,
+    };
+
+    let start = if flags & MAP_FIXED != 0 {
+        // Whatever was mapped there before is replaced
+        let (start, end) = user_range(space, addr, len)?;
+        unmap_areas(space, vmas, start, end)?;
+        start
+    } else {
+        place(space, vmas, layout, addr, len, kind)?
+    };
+
+    vmas.insert(Vma::new(start, start + len, vma_flags, kind).with_max_flags(max_flags))?;
+    Ok(start)
+}
+
+/// Remove every mapping inside `[addr, addr + len)`
+pub fn munmap(space: &AddressSpace, vmas: &mut VmaTree, addr: u64, len: u64) -> Result<(), &'static str> {
+    let len = page_align(len).filter(|&len| len > 0).ok_or("Invalid length")?;
+    let (start, end) = user_range(space, addr, len)?;
+    unmap_areas(space, vmas, start, end)
+}
+
+fn unmap_areas(space: &AddressSpace, vmas: &mut VmaTree, start: VirtAddr, end: VirtAddr) -> Result<(), &'static str> {
+    for vma in vmas.remove_range(start, end) {
+        // The pages go either way; the file misses what could not be written
+        if let Err(e) = writeback(space, &vma) {
+            crate::io::println!("munmap: writeback to the file failed: {}", e);
+        }
+        VMM.unmap_range(space, vma.start, vma.end)?;
+    }
+    Ok(())
+}
+
+/// Map existing frames as one shared area of `kind`, at `addr` if it is non-zero,
+/// otherwise wherever mmap would place it. Every frame gains a reference.
+pub fn map_frames(
+    space: &AddressSpace,
+    vmas: &mut VmaTree,
+    layout: &MemoryLayout,
+    addr: u64,
+    frames: &[PhysFrame],
+    prot: u64,
+    kind: VmaKind,
+) -> Result<VirtAddr, &'static str> {
+    let len = frames.len() as u64 * PAGE_SIZE;
+    if len == 0 {
+        return Err("Invalid length");
+    }
+    let vma_flags = vma_flags(prot, true)?;
+    let start = if addr != 0 {
+        let (start, end) = user_range(space, addr, len)?;
+        if vmas.overlaps(start, end) {
+            return Err("Address range already mapped");
+        }
+        start
+    } else {
+        place(space, vmas, layout, 0, len, kind)?
+    };
+
+    // Nothing may later grant more than the frames were mapped with
+    let vma = Vma::new(start, start + len, vma_flags, kind).with_max_flags(vma_flags);
+    let flags = vma.page_flags();
+    vmas.insert(vma)?;
+    for (i, &frame) in frames.iter().enumerate() {
+        let page = Page::containing_address(start + i as u64 * PAGE_SIZE);
+        PMM.get_frame(frame);
+        if let Err(e) = VMM.map_to(space, page, frame, flags) {
+            deallocate_frame(frame);
+            unmap_areas(space, vmas, start, start + len).ok();
+            return Err(e);
+        }
+    }
+    Ok(start)
+}
+
+/// Unmap the parts of `[start, start + len)` that belong to areas of `kind`, leaving other mappings alone
+pub fn unmap_kind(space: &AddressSpace, vmas: &mut VmaTree, start: VirtAddr, len: u64, kind: VmaKind) -> Result<(), &'static str> {
+    let end = start + len;
+    let ranges: Vec<(VirtAddr, VirtAddr)> = vmas.iter()
+        .filter(|vma| vma.kind == kind && vma.start < end && vma.end > start)
+        .map(|vma| (vma.start.max(start), vma.end.min(end)))
+        .collect();
+    for (start, end) in ranges {
+        unmap_areas(space, vmas, start, end)?;
+    }
+    Ok(())
+}
+
+/// Change the protection of `[addr, addr + len)`, which must be fully mapped
+pub fn mprotect(space: &AddressSpace, vmas: &mut VmaTree, addr: u64, len: u64, prot: u64) -> Result<(), &'static str> {
+    let len = page_align(len).ok_or("Invalid length")?;
+    let (start, end) = user_range(space, addr, len)?;
+    if !vmas.covers(start, end) {
+        return Err("Address range not mapped");
+    }
+    let flags = vma_flags(prot, false)?;
+    if vmas.iter().filter(|vma| vma.start < end && vma.end > start).any(|vma| !vma.max_flags.contains(flags)) {
+        return Err("Permission denied");
+    }
+
+    vmas.split(start);
+    vmas.split(end);
+    for vma in vmas.range_mut(start, end) {
+        vma.flags = vma_flags(prot, vma.flags.contains(VmaFlags::SHARED))?;
+        VMM.protect_range(space, vma.start, vma.end, vma.page_flags())?;
+    }
+    vmas.merge(start, end);
+    Ok(())
+}
+
+/// Move the program break to `requested`, growing or shrinking the heap.
+///
+/// Returns the new break, or the current one if the request cannot be met,
+/// which is also how a query with `requested == 0` is answered.
+pub fn brk(space: &AddressSpace, vmas: &mut VmaTree, layout: &mut MemoryLayout, requested: u64) -> VirtAddr {
+    if requested < layout.brk_start.as_u64() || requested > USER_END {
+        return layout.brk;
+    }
+    let (Some(old_end), Some(new_end)) = (page_align(layout.brk.as_u64()), page_align(requested)) else {
+        return layout.brk;
+    };
+
+    let (old_end, new_end) = (VirtAddr::new(old_end), VirtAddr::new(new_end));
+
+    if new_end > old_end {
+        let free = user_range(space, old_end.as_u64(), new_end - old_end)
+            .map_or(false, |(start, end)| !vmas.overlaps(start, end));
+        if !free {
+            return layout.brk;
+        }
+        // Grow the top heap area rather than adding another one
+        let top = vmas.find(old_end - 1u64)
+            .filter(|vma| vma.kind == VmaKind::Heap && vma.end == old_end)
+            .map(|vma| vma.start);
+        let heap = match top.and_then(|start| vmas.remove(start)) {
+            Some(mut heap) => {
+                heap.end = new_end;
+                heap
+            }
+            None => Vma::new(old_end, new_end, VmaFlags::READ | VmaFlags::WRITE, VmaKind::Heap),
+        };
+        if vmas.insert(heap).is_err() {
+            return layout.brk;
+        }
+    } else if new_end < old_end && unmap_areas(space, vmas, new_end, old_end).is_err() {
+        return layout.brk;
+    }
+
+    layout.brk = VirtAddr::new(requested);
+    layout.brk
+}
+
+/// Write the dirty resident pages of a shared file mapping back to its file,
+/// leaving them clean.
+///
+/// Every page is tried; the first failure is returned, and the pages that
+/// failed stay dirty.
+pub fn writeback(space: &AddressSpace, vma: &Vma) -> Result<(), &'static str> {
+    let VmaKind::File { inode, offset } = vma.kind else { return Ok(()) };
+    if !vma.flags.contains(VmaFlags::SHARED) {
+        return Ok(());
+    }
+    let fs = FILESYSTEM.lock();
+    let size = fs.file_size(inode)?;
+
+    let mut result = Ok(());
+    let pages = Page::<Size4KiB>::range(Page::containing_address(vma.start), Page::containing_address(vma.end));
+    for page in pages {
+        let position = offset + (page.start_address() - vma.start);
+        if position >= size {
+            break;
+        }
+        // Cleaned first, so a write racing with the copy dirties it again
+        let Some(frame) = VMM.take_dirty(space, page) else { continue };
+        let len = (size - position).min(PAGE_SIZE) as usize;
+        let data = unsafe { frame_bytes(frame) };
+        if let Err(e) = fs.write_file(inode, position, &data[..len]) {
+            VMM.set_dirty(space, page);
+            result = result.and(Err(e));
+        }
+    }
+    result
+}
+
+/// Write back every shared file mapping of an address space that is going
+/// away, reporting the ones that could not be
+pub fn writeback_all(space: &AddressSpace, vmas: &VmaTree) {
+    for vma in vmas.iter() {
+        if let Err(e) = writeback(space, vma) {
+            crate::io::println!("writeback of {:#x}-{:#x} failed: {}", vma.start.as_u64(), vma.end.as_u64(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: u64 = 0x7e00_0000_0000;
+    const RW: VmaFlags = VmaFlags::READ.union(VmaFlags::WRITE);
+    const FILE: VmaKind = VmaKind::File { inode: 7, offset: 0 };
+
+    /// A private file mapping of four pages at `BASE`
+    fn four_pages() -> VmaTree {
+        let mut vmas = VmaTree::new();
+        vmas.insert(Vma::new(VirtAddr::new(BASE), VirtAddr::new(BASE + 4 * PAGE_SIZE), RW, FILE))
+            .expect("insert failed");
+        vmas
+    }
+
+    /// Start, end and flags of each area, relative to `BASE`
+    fn areas(vmas: &VmaTree) -> Vec<(u64, u64, VmaFlags)> {
+        vmas.iter().map(|vma| (vma.start.as_u64() - BASE, vma.end.as_u64() - BASE, vma.flags)).collect()
+    }
+
+    #[test_case]
+    fn mprotect_splits_and_merges() {
+        let space = VMM.create_address_space().expect("no address space");
+        let mut vmas = four_pages();
+
+        mprotect(&space, &mut vmas, BASE + PAGE_SIZE, 2 * PAGE_SIZE, PROT_READ).expect("mprotect failed");
+        assert_eq!(areas(&vmas), [
+            (0, PAGE_SIZE, RW),
+            (PAGE_SIZE, 3 * PAGE_SIZE, VmaFlags::READ),
+            (3 * PAGE_SIZE, 4 * PAGE_SIZE, RW),
+        ]);
+        let middle = vmas.find(VirtAddr::new(BASE + PAGE_SIZE)).expect("middle area missing");
+        assert_eq!(middle.kind, VmaKind::File { inode: 7, offset: PAGE_SIZE });
+
+        mprotect(&space, &mut vmas, BASE + PAGE_SIZE, 2 * PAGE_SIZE, PROT_READ | PROT_WRITE).expect("mprotect failed");
+        assert_eq!(areas(&vmas), [(0, 4 * PAGE_SIZE, RW)]);
+    }
+
+    #[test_case]
+    fn munmap_splits() {
+        let space = VMM.create_address_space().expect("no address space");
+        let mut vmas = four_pages();
+
+        munmap(&space, &mut vmas, BASE + PAGE_SIZE, PAGE_SIZE).expect("munmap failed");
+        assert_eq!(areas(&vmas), [(0, PAGE_SIZE, RW), (2 * PAGE_SIZE, 4 * PAGE_SIZE, RW)]);
+        let upper = vmas.find(VirtAddr::new(BASE + 2 * PAGE_SIZE)).expect("upper area missing");
+        assert_eq!(upper.kind, VmaKind::File { inode: 7, offset: 2 * PAGE_SIZE });
+
+        // Nothing to protect in the hole, and the areas around it stay apart
+        assert!(mprotect(&space, &mut vmas, BASE, 4 * PAGE_SIZE, PROT_READ).is_err());
+        mprotect(&space, &mut vmas, BASE + 2 * PAGE_SIZE, PAGE_SIZE, PROT_READ).expect("mprotect failed");
+        mprotect(&space, &mut vmas, BASE + 2 * PAGE_SIZE, PAGE_SIZE, PROT_READ | PROT_WRITE).expect("mprotect failed");
+        assert_eq!(areas(&vmas), [(0, PAGE_SIZE, RW), (2 * PAGE_SIZE, 4 * PAGE_SIZE, RW)]);
+    }
+}
@@ -1,36 +1,248 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU8, Ordering};
+use x86_64::structures::paging::PhysFrame;
 use x86_64::PhysAddr;
+use crate::hardware::acpi::{read_u32, read_u64, AcpiTable, ACPI_MANAGER};
+use crate::hardware::cpu::{cpu_for_apic, current_cpu, ALL_CPUS, MAX_CPUS};
+use crate::memory::pmm::{ZoneType, ALL_NODES, FRAME_SIZE, PMM};
+use crate::process::{ProcessId, PROCESS_MANAGER};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Nodes the frame allocator keeps separate pools for
+pub const MAX_NUMA_NODES: usize = 8;
+
+/// SLIT distance from a node to itself
+pub const LOCAL_DISTANCE: u8 = 10;
+/// Distance assumed between different nodes when there is no SLIT
+pub const REMOTE_DISTANCE: u8 = 20;
+
+/// Offset of the first affinity structure in the SRAT
+const SRAT_ENTRIES_OFFSET: usize = 48;
+const SRAT_PROCESSOR_AFFINITY: u8 = 0;
+const SRAT_MEMORY_AFFINITY: u8 = 1;
+const SRAT_X2APIC_AFFINITY: u8 = 2;
+const SRAT_ENABLED: u32 = 1 << 0;
+
+const SLIT_LOCALITIES_OFFSET: usize = 36;
+const SLIT_MATRIX_OFFSET: usize = 44;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct NodeId(pub u32);
 
+#[derive(Debug, Clone)]
 pub struct NumaNode {
     pub node_id: NodeId,
     pub memory_start: PhysAddr,
     pub memory_end: PhysAddr,
+    /// One bit per CPU index
     pub cpu_mask: u64,
 }
 
+/// Where a process's user pages are allocated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryPolicy {
+    /// The node of the faulting CPU, falling back to the nearest others
+    Local,
+    /// Round-robin over a mask of nodes
+    Interleave(u64),
+    /// Only the nodes in a mask, nearest first
+    Bind(u64),
+}
+
+struct ProcessPolicy {
+    policy: MemoryPolicy,
+    /// Node the last interleaved page came from
+    last_node: usize,
+}
+
+/// Node layout described by the SRAT
+struct Topology {
+    /// Proximity domains in order of first appearance; a node's id is its index
+    domains: Vec<u32>,
+    /// Frame ranges of each node
+    memory: Vec<(u64, u64, u8)>,
+    /// APIC id and node of each enabled CPU
+    cpus: Vec<(u32, u8)>,
+}
+
+impl Topology {
+    fn node_for(&mut self, domain: u32) -> Option<u8> {
+        if let Some(node) = self.domains.iter().position(|&d| d == domain) {
+            return Some(node as u8);
+        }
+        if self.domains.len() >= MAX_NUMA_NODES {
+            return None;
+        }
+        self.domains.push(domain);
+        Some((self.domains.len() - 1) as u8)
+    }
+
+    fn parse(srat: &AcpiTable) -> Topology {
+        let mut topology = Topology {
+            domains: Vec::new(),
+            memory: Vec::new(),
+            cpus: Vec::new(),
+        };
+
+        for (kind, entry) in srat.entries(SRAT_ENTRIES_OFFSET) {
+            match kind {
+                SRAT_PROCESSOR_AFFINITY if entry.len() >= 16 => {
+                    if read_u32(entry, 4) & SRAT_ENABLED == 0 {
+                        continue;
+                    }
+                    let domain = entry[2] as u32 | (entry[9] as u32) << 8 | (entry[10] as u32) << 16 | (entry[11] as u32) << 24;
+                    if let Some(node) = topology.node_for(domain) {
+                        topology.cpus.push((entry[3] as u32, node));
+                    }
+                }
+                SRAT_X2APIC_AFFINITY if entry.len() >= 24 => {
+                    if read_u32(entry, 12) & SRAT_ENABLED == 0 {
+                        continue;
+                    }
+                    if let Some(node) = topology.node_for(read_u32(entry, 4)) {
+                        topology.cpus.push((read_u32(entry, 8), node));
+                    }
+                }
+                SRAT_MEMORY_AFFINITY if entry.len() >= 40 => {
+                    let (base, length) = (read_u64(entry, 8), read_u64(entry, 16));
+                    if read_u32(entry, 28) & SRAT_ENABLED == 0 || length == 0 {
+                        continue;
+                    }
+                    if let Some(node) = topology.node_for(read_u32(entry, 2)) {
+                        let start = (base + FRAME_SIZE - 1) / FRAME_SIZE;
+                        topology.memory.push((start, (base + length) / FRAME_SIZE, node));
+                    }
+                }
+                _ => {}
+            }
+        }
+        topology
+    }
+}
+
+/// Distances between nodes from the SLIT, indexed by node id
+fn slit_distances(slit: Option<&AcpiTable>, domains: &[u32]) -> [[u8; MAX_NUMA_NODES]; MAX_NUMA_NODES] {
+    let mut distances = [[REMOTE_DISTANCE; MAX_NUMA_NODES]; MAX_NUMA_NODES];
+    for (node, row) in distances.iter_mut().enumerate() {
+        row[node] = LOCAL_DISTANCE;
+    }
+
+    let slit = match slit {
+        Some(slit) if slit.data.len() >= SLIT_MATRIX_OFFSET => slit,
+        _ => return distances,
+    };
+    let localities = read_u64(&slit.data, SLIT_LOCALITIES_OFFSET) as usize;
+    for (i, &from) in domains.iter().enumerate() {
+        for (j, &to) in domains.iter().enumerate() {
+            let (from, to) = (from as usize, to as usize);
+            if from >= localities || to >= localities {
+                continue;
+            }
+            if let Some(&distance) = slit.data.get(SLIT_MATRIX_OFFSET + from * localities + to) {
+                distances[i][j] = distance;
+            }
+        }
+    }
+    distances
+}
+
 pub struct NumaManager {
-    nodes: Mutex<BTreeMap<NodeId, NumaNode>>,
-    current_node: Mutex<Option<NodeId>>,
+    nodes: SpinLock<BTreeMap<NodeId, NumaNode>>,
+    distances: SpinLock<[[u8; MAX_NUMA_NODES]; MAX_NUMA_NODES]>,
+    /// Node of each CPU, indexed by CPU number
+    cpu_nodes: [AtomicU8; MAX_CPUS],
+    policies: SpinLock<BTreeMap<ProcessId, ProcessPolicy>>,
 }
 
 impl NumaManager {
     pub const fn new() -> Self {
+        const NODE_0: AtomicU8 = AtomicU8::new(0);
         NumaManager {
-            nodes: Mutex::new(BTreeMap::new()),
-            current_node: Mutex::new(None),
+            nodes: SpinLock::new(BTreeMap::new()),
+            distances: SpinLock::new([[REMOTE_DISTANCE; MAX_NUMA_NODES]; MAX_NUMA_NODES]),
+            cpu_nodes: [NODE_0; MAX_CPUS],
+            policies: SpinLock::new(BTreeMap::new()),
         }
     }
 
+    /// Discover nodes from the ACPI SRAT and SLIT and split physical memory
+    /// between them. Without an SRAT the machine is a single node.
+    pub fn init(&self) -> Result<(), &'static str> {
+        let topology = ACPI_MANAGER.find_table(b"SRAT")
+            .map(|srat| Topology::parse(&srat))
+            .filter(|topology| !topology.memory.is_empty());
+        let topology = match topology {
+            Some(topology) => topology,
+            None => {
+                self.init_single_node();
+                return Ok(());
+            }
+        };
+        let distances = slit_distances(ACPI_MANAGER.find_table(b"SLIT").as_ref(), &topology.domains);
+        let node_count = topology.domains.len();
+
+        {
+            let mut nodes = self.nodes.lock();
+            nodes.clear();
+            for node in 0..node_count {
+                let ranges = || topology.memory.iter().filter(|&&(_, _, n)| n as usize == node);
+                let start = ranges().map(|&(start, _, _)| start).min().unwrap_or(0);
+                let end = ranges().map(|&(_, end, _)| end).max().unwrap_or(0);
+                let cpu_mask = topology.cpus.iter()
+                    .filter(|&&(_, n)| n as usize == node)
+                    .filter_map(|&(apic_id, _)| cpu_for_apic(apic_id))
+                    .fold(0, |mask, cpu| mask | 1 << cpu);
+                nodes.insert(NodeId(node as u32), NumaNode {
+                    node_id: NodeId(node as u32),
+                    memory_start: PhysAddr::new(start * FRAME_SIZE),
+                    memory_end: PhysAddr::new(end * FRAME_SIZE),
+                    cpu_mask,
+                });
+            }
+        }
+        // The SRAT names CPUs by APIC id; CPUs missing from the MADT stay on node 0
+        for &(apic_id, node) in &topology.cpus {
+            if let Some(cpu) = cpu_for_apic(apic_id) {
+                self.cpu_nodes[cpu].store(node, Ordering::Relaxed);
+            }
+        }
+        *self.distances.lock() = distances;
+
+        PMM.set_nodes(&topology.memory, &distances, node_count);
+        crate::io::println!("NUMA: {} nodes, {} CPUs", node_count, topology.cpus.len());
+        Ok(())
+    }
+
+    fn init_single_node(&self) {
+        let mut nodes = self.nodes.lock();
+        nodes.clear();
+        nodes.insert(NodeId(0), NumaNode {
+            node_id: NodeId(0),
+            memory_start: PhysAddr::new(0),
+            memory_end: PhysAddr::new(PMM.frame_limit() * FRAME_SIZE),
+            cpu_mask: ALL_CPUS,
+        });
+        self.distances.lock()[0][0] = LOCAL_DISTANCE;
+        crate::io::println!("NUMA: no SRAT, using a single node");
+    }
+
     pub fn add_node(&self, node: NumaNode) {
         self.nodes.lock().insert(node.node_id, node);
     }
 
     pub fn get_node(&self, node_id: NodeId) -> Option<NumaNode> {
-        self.nodes.lock().get(&node_id).copied()
+        self.nodes.lock().get(&node_id).cloned()
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.lock().len()
+    }
+
+    /// SLIT distance between two nodes
+    pub fn distance(&self, from: NodeId, to: NodeId) -> Option<u8> {
+        let distances = self.distances.lock();
+        distances.get(from.0 as usize)?.get(to.0 as usize).copied()
     }
 
     pub fn get_nearest_node(&self, addr: PhysAddr) -> Option<NodeId> {
@@ -55,23 +267,117 @@ impl NumaManager {
         nearest
     }
 
+    /// Node whose frame pool `frame` came from
+    pub fn node_of_frame(&self, frame: PhysFrame) -> NodeId {
+        NodeId(PMM.frame_node(frame) as u32)
+    }
+
+    /// Allocate `size` bytes of physically contiguous memory from one node
     pub fn allocate_on_node(&self, node_id: NodeId, size: usize) -> Option<PhysAddr> {
-        if let Some(node) = self.get_node(node_id) {
-            // TODO: Implement actual allocation on specific node
-            Some(node.memory_start)
-        } else {
-            None
+        if node_id.0 as usize >= MAX_NUMA_NODES || size == 0 {
+            return None;
         }
+        let count = (size + FRAME_SIZE as usize - 1) / FRAME_SIZE as usize;
+        PMM.allocate_contiguous_from(count, 1 << node_id.0).map(|frame| frame.start_address())
+    }
+
+    /// Release memory returned by `allocate_on_node`
+    pub fn free_on_node(&self, addr: PhysAddr, size: usize) {
+        let count = (size + FRAME_SIZE as usize - 1) / FRAME_SIZE as usize;
+        PMM.free_contiguous(PhysFrame::containing_address(addr), count);
+    }
+
+    /// Node of the CPU executing this code, as an index
+    pub fn local_node(&self) -> usize {
+        self.cpu_nodes[current_cpu()].load(Ordering::Relaxed) as usize
     }
 
     pub fn get_current_node(&self) -> Option<NodeId> {
-        *self.current_node.lock()
+        Some(NodeId(self.local_node() as u32))
+    }
+
+    fn online_mask(&self) -> u64 {
+        self.nodes.lock().keys().fold(0, |mask, node| mask | 1 << node.0)
+    }
+
+    /// Set the policy used for `pid`'s user pages
+    pub fn set_policy(&self, pid: ProcessId, policy: MemoryPolicy) -> Result<(), &'static str> {
+        let online = self.online_mask();
+        let policy = match policy {
+            MemoryPolicy::Local => {
+                self.policies.lock().remove(&pid);
+                return Ok(());
+            }
+            MemoryPolicy::Interleave(mask) => MemoryPolicy::Interleave(mask & online),
+            MemoryPolicy::Bind(mask) => MemoryPolicy::Bind(mask & online),
+        };
+        if let MemoryPolicy::Interleave(0) | MemoryPolicy::Bind(0) = policy {
+            return Err("No online nodes in policy mask");
+        }
+        self.policies.lock().insert(pid, ProcessPolicy { policy, last_node: 63 });
+        Ok(())
     }
 
-    pub fn set_current_node(&self, node_id: NodeId) {
-        *self.current_node.lock() = Some(node_id);
+    pub fn policy(&self, pid: ProcessId) -> MemoryPolicy {
+        self.policies.lock().get(&pid).map_or(MemoryPolicy::Local, |p| p.policy)
+    }
+
+    /// Give a forked child its parent's policy
+    pub fn inherit_policy(&self, parent: ProcessId, child: ProcessId) {
+        let policy = self.policy(parent);
+        self.set_policy(child, policy).ok();
+    }
+
+    pub fn remove_policy(&self, pid: ProcessId) {
+        self.policies.lock().remove(&pid);
+    }
+
+    /// Nodes the current process's next user page may come from, and whether
+    /// it must stay on them when they are full
+    fn placement(&self) -> (u64, bool) {
+        let Some(pid) = PROCESS_MANAGER.get_current_process() else {
+            return (ALL_NODES, false);
+        };
+        let mut policies = self.policies.lock();
+        match policies.get_mut(&pid) {
+            Some(ProcessPolicy { policy: MemoryPolicy::Bind(mask), .. }) => (*mask, true),
+            Some(ProcessPolicy { policy: MemoryPolicy::Interleave(mask), last_node }) => {
+                let node = (1..=64)
+                    .map(|i| (*last_node + i) % 64)
+                    .find(|&node| *mask & (1 << node) != 0)
+                    .unwrap_or(0);
+                *last_node = node;
+                (1 << node, false)
+            }
+            _ => (ALL_NODES, false),
+        }
+    }
+
+    /// Allocate a frame for a user page of the current process, following its policy
+    pub fn allocate_user_frame(&self) -> Option<PhysFrame> {
+        let (mask, strict) = self.placement();
+        PMM.allocate_frames_from(0, ZoneType::Normal, mask)
+            .or_else(|| if strict { None } else { PMM.allocate_frame() })
+    }
+
+    pub fn print_stats(&self) {
+        let nodes = self.nodes.lock();
+        let distances = self.distances.lock();
+        for (node_id, node) in nodes.iter() {
+            let (free, managed) = PMM.node_frame_counts(node_id.0 as usize).unwrap_or((0, 0));
+            crate::io::println!(
+                "Node {}: {:#x}-{:#x}, cpus {:#x}, {} / {} frames free",
+                node_id.0, node.memory_start.as_u64(), node.memory_end.as_u64(), node.cpu_mask, free, managed
+            );
+        }
+        for from in 0..nodes.len() {
+            crate::io::print!("  distances {}:", from);
+            for to in 0..nodes.len() {
+                crate::io::print!(" {:>3}", distances[from][to]);
+            }
+            crate::io::println!();
+        }
     }
 }
 
 pub static NUMA_MANAGER: NumaManager = NumaManager::new();
-
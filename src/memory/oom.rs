@@ -1,8 +1,17 @@
-use crate::process::{ProcessId, PROCESS_MANAGER};
-use crate::scheduler::SCHEDULER;
-use spin::Mutex;
+use crate::process::{signal_status, ProcessId, ProcessMemoryInfo, PROCESS_MANAGER};
+use crate::signal;
+use crate::containers::cgroup::CGROUP_MANAGER;
+use crate::services::syslog::{LogLevel, SYSLOG};
+use crate::sync::SpinLock;
 use alloc::collections::BinaryHeap;
+use alloc::format;
+use alloc::vec::Vec;
 use core::cmp::Ordering;
+use core::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+
+/// oom_score_adj value that exempts a process from the OOM killer
+pub const OOM_SCORE_ADJ_MIN: i16 = -1000;
+pub const OOM_SCORE_ADJ_MAX: i16 = 1000;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct OomCandidate {
@@ -19,21 +28,57 @@ impl PartialOrd for OomCandidate {
 
 impl Ord for OomCandidate {
     fn cmp(&self, other: &Self) -> Ordering {
-        // Higher score = higher priority to kill
-        other.score.cmp(&self.score)
+        // Higher score = higher priority to kill; ties go to the larger process
+        self.score.cmp(&other.score).then(self.memory_usage.cmp(&other.memory_usage))
     }
 }
 
+fn report(level: LogLevel, message: &str) {
+    SYSLOG.log(level, "oom", message);
+}
+
+/// Badness of a process in pages, or `None` if it must not be killed.
+///
+/// Counts resident and swapped pages plus half of each child's, then shifts
+/// the result by `oom_score_adj` thousandths of all memory.
+fn badness(info: &ProcessMemoryInfo, processes: &[ProcessMemoryInfo], total_pages: u64) -> Option<u64> {
+    if info.oom_score_adj == OOM_SCORE_ADJ_MIN {
+        return None;
+    }
+
+    let own = (info.resident_pages + info.swapped_pages) as u64;
+    let children: u64 = processes.iter()
+        .filter(|p| p.parent == Some(info.pid))
+        .map(|p| (p.resident_pages + p.swapped_pages) as u64 / 2)
+        .sum();
+
+    let adjustment = info.oom_score_adj as i64 * total_pages as i64 / 1000;
+    let points = (own + children) as i64 + adjustment;
+    // Anything still eligible scores at least one point
+    Some(points.max(1) as u64)
+}
+
 pub struct OomKiller {
-    enabled: Mutex<bool>,
-    min_free_kb: Mutex<u64>,
+    enabled: SpinLock<bool>,
+    min_free_kb: SpinLock<u64>,
+    /// Held while a victim is chosen and killed. Another CPU that runs out of
+    /// memory meanwhile backs off rather than wait: the one choosing may need
+    /// to read the memory map it holds.
+    killing: SpinLock<()>,
+    /// Bumped whenever a kill is made or already under way, so that a fault
+    /// that ran out of memory meanwhile knows a retry may succeed
+    progress: AtomicU64,
+    kills: AtomicU64,
 }
 
 impl OomKiller {
     pub const fn new() -> Self {
         OomKiller {
-            enabled: Mutex::new(true),
-            min_free_kb: Mutex::new(16384), // 16MB minimum free
+            enabled: SpinLock::new(true),
+            min_free_kb: SpinLock::new(16384), // 16MB minimum free
+            killing: SpinLock::new(()),
+            progress: AtomicU64::new(0),
+            kills: AtomicU64::new(0),
         }
     }
 
@@ -42,37 +87,125 @@ impl OomKiller {
             return false;
         }
 
-        // TODO: Check actual free memory
-        // For now, always return false (no OOM condition)
-        false
+        let free_kb = (crate::memory::PMM.free_frame_count() as u64 * 4096) / 1024;
+        free_kb < *self.min_free_kb.lock()
     }
 
-    pub fn select_victim(&self) -> Option<ProcessId> {
+    /// Processes the victim is chosen from: the members of the cgroup furthest
+    /// over its memory limit, or every process if no cgroup is over its limit
+    fn scope(&self, processes: &[ProcessMemoryInfo]) -> Option<Vec<ProcessId>> {
+        let usage_of = |pid: &ProcessId| {
+            processes.iter()
+                .find(|p| p.pid == *pid)
+                .map_or(0, |p| (p.resident_pages + p.swapped_pages) as u64 * 4096)
+        };
+
+        let (name, usage, limit, members) = CGROUP_MANAGER.memory_limits()
+            .into_iter()
+            .map(|(name, limit, members)| {
+                let usage: u64 = members.iter().map(usage_of).sum();
+                (name, usage, limit, members)
+            })
+            .filter(|(_, usage, limit, _)| usage > limit)
+            .max_by_key(|(_, usage, limit, _)| usage - limit)?;
+
+        report(LogLevel::Error, &format!(
+            "Memory cgroup {} out of memory: usage {} KB, limit {} KB", name, usage / 1024, limit / 1024
+        ));
+        Some(members)
+    }
+
+    fn choose(&self, processes: &[ProcessMemoryInfo]) -> Option<OomCandidate> {
+        let (_, swap_total) = crate::memory::SWAP_MANAGER.get_swap_usage();
+        let total_pages = crate::memory::PMM.total_frame_count() as u64 + swap_total;
+        let scope = self.scope(processes);
+
         let mut candidates = BinaryHeap::new();
+        report(LogLevel::Error, "Out of memory: candidates");
+        report(LogLevel::Error, "    pid    rss   swap  adj  score");
+        for info in processes {
+            if info.exiting || scope.as_ref().map_or(false, |members| !members.contains(&info.pid)) {
+                continue;
+            }
+            let score = badness(info, processes, total_pages);
+            // Normalized like /proc/<pid>/oom_score
+            let oom_score = score.map_or(0, |points| (points * 1000 / total_pages.max(1)).min(1000));
+            report(LogLevel::Error, &format!(
+                "  {:>5} {:>6} {:>6} {:>4} {:>6}",
+                info.pid.as_u64(), info.resident_pages, info.swapped_pages, info.oom_score_adj, oom_score
+            ));
+
+            if let Some(score) = score {
+                candidates.push(OomCandidate {
+                    pid: info.pid,
+                    score,
+                    memory_usage: (info.resident_pages + info.swapped_pages) as u64 * 4096,
+                });
+            }
+        }
+        candidates.pop()
+    }
 
-        // TODO: Iterate through all processes and calculate OOM score
-        // For now, return None
-        None
+    pub fn select_victim(&self) -> Option<ProcessId> {
+        let processes = PROCESS_MANAGER.memory_snapshot();
+        self.choose(&processes).map(|candidate| candidate.pid)
     }
 
+    /// Kill a process with `SIGKILL`. Its threads release its memory as they
+    /// exit and leave a zombie for the parent to reap.
     pub fn kill_process(&self, pid: ProcessId) -> Result<(), &'static str> {
-        crate::io::println!("OOM Killer: Terminating process {}", pid.0);
-        
-        // TODO: Actually terminate the process
-        // For now, just log it
+        if !PROCESS_MANAGER.contains(pid) {
+            return Err("Process not found");
+        }
+        signal::group_exit(pid, signal_status(signal::SIGKILL));
+        self.kills.fetch_add(1, AtomicOrdering::Relaxed);
         Ok(())
     }
 
     pub fn handle_oom(&self) {
-        if let Some(victim) = self.select_victim() {
-            if let Err(e) = self.kill_process(victim) {
-                crate::io::println!("OOM Killer error: {}", e);
+        if !*self.enabled.lock() {
+            return;
+        }
+        let Some(_killing) = self.killing.try_lock() else {
+            self.progress.fetch_add(1, AtomicOrdering::Relaxed);
+            return;
+        };
+        let processes = PROCESS_MANAGER.memory_snapshot();
+
+        // Memory comes back once the exiting process is gone; killing another
+        // before then would be one kill too many
+        if let Some(exiting) = processes.iter().find(|p| p.exiting) {
+            report(LogLevel::Warning, &format!("Waiting for process {} to exit", exiting.pid.as_u64()));
+            self.progress.fetch_add(1, AtomicOrdering::Relaxed);
+            return;
+        }
+
+        match self.choose(&processes) {
+            Some(victim) => {
+                report(LogLevel::Error, &format!(
+                    "Killed process {} (score {}, {} KB)", victim.pid.as_u64(), victim.score, victim.memory_usage / 1024
+                ));
+                match self.kill_process(victim.pid) {
+                    Ok(()) => {
+                        self.progress.fetch_add(1, AtomicOrdering::Relaxed);
+                    }
+                    Err(e) => report(LogLevel::Error, &format!("OOM Killer error: {}", e)),
+                }
             }
-        } else {
-            crate::io::println!("OOM Killer: No suitable victim found");
+            None => report(LogLevel::Critical, "OOM Killer: No suitable victim found"),
         }
     }
 
+    /// Changes whenever a kill is made or found under way; a fault that ran
+    /// out of memory while it changed is worth retrying
+    pub fn progress(&self) -> u64 {
+        self.progress.load(AtomicOrdering::Relaxed)
+    }
+
+    pub fn kill_count(&self) -> u64 {
+        self.kills.load(AtomicOrdering::Relaxed)
+    }
+
     pub fn set_min_free(&self, kb: u64) {
         *self.min_free_kb.lock() = kb;
     }
@@ -87,4 +220,3 @@ impl OomKiller {
 }
 
 pub static OOM_KILLER: OomKiller = OomKiller::new();
-
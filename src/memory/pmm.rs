@@ -0,0 +1,692 @@
+use core::mem::{align_of, size_of};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::sync::SpinLock;
+use alloc::vec::Vec;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::structures::paging::PhysFrame;
+use x86_64::PhysAddr;
+use crate::boot::phys_to_virt;
+use crate::memory::numa::{MAX_NUMA_NODES, NUMA_MANAGER};
+
+pub const FRAME_SIZE: u64 = 4096;
+
+/// Blocks range from a single frame (order 0) up to 2^(MAX_ORDER - 1) frames (4 MiB)
+pub const MAX_ORDER: usize = 11;
+
+/// Upper bounds of the DMA and DMA32 zones, as frame numbers
+const DMA_LIMIT_PFN: u64 = (16 * 1024 * 1024) / FRAME_SIZE;
+const DMA32_LIMIT_PFN: u64 = (4 * 1024 * 1024 * 1024) / FRAME_SIZE;
+
+/// Frame map value for frames that are allocated, reserved or inside a free block
+const FRAME_USED: u8 = 0;
+/// Frame map flag for the first frame of a free block; the low bits hold its order
+const FRAME_FREE_HEAD: u8 = 0x80;
+/// Frame map flag for a free block waiting to be re-queued after the node layout changed
+const FRAME_REQUEUE: u8 = 0x40;
+const ORDER_MASK: u8 = 0x0f;
+
+/// Node mask selecting every node
+pub const ALL_NODES: u64 = u64::MAX;
+
+/// Usable memory map regions remembered for recounting managed frames
+const MAX_USABLE_RANGES: usize = 64;
+
+/// Free list terminator (frame 0 is never handed to the allocator)
+const NIL: u64 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneType {
+    Dma,
+    Dma32,
+    Normal,
+}
+
+impl ZoneType {
+    pub(crate) fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Zone a frame belongs to
+    pub fn of(frame: PhysFrame) -> ZoneType {
+        ZoneType::for_pfn(frame.start_address().as_u64() / FRAME_SIZE)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ZoneType::Dma => "DMA",
+            ZoneType::Dma32 => "DMA32",
+            ZoneType::Normal => "Normal",
+        }
+    }
+
+    fn for_pfn(pfn: u64) -> ZoneType {
+        if pfn < DMA_LIMIT_PFN {
+            ZoneType::Dma
+        } else if pfn < DMA32_LIMIT_PFN {
+            ZoneType::Dma32
+        } else {
+            ZoneType::Normal
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ZoneStats {
+    pub node: u8,
+    pub zone: ZoneType,
+    pub managed_frames: usize,
+    pub free_frames: usize,
+    /// Number of free blocks of each order
+    pub free_blocks: [usize; MAX_ORDER],
+}
+
+/// Links stored in the first frame of every free block
+#[repr(C)]
+struct FreeBlock {
+    next: u64,
+    prev: u64,
+}
+
+struct Zone {
+    zone: ZoneType,
+    free_lists: [u64; MAX_ORDER],
+    free_blocks: [usize; MAX_ORDER],
+    managed_frames: usize,
+    free_frames: usize,
+}
+
+impl Zone {
+    const fn new(zone: ZoneType) -> Self {
+        Zone {
+            zone,
+            free_lists: [NIL; MAX_ORDER],
+            free_blocks: [0; MAX_ORDER],
+            managed_frames: 0,
+            free_frames: 0,
+        }
+    }
+}
+
+fn block(pfn: u64) -> *mut FreeBlock {
+    phys_to_virt(PhysAddr::new(pfn * FRAME_SIZE)).as_mut_ptr()
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Smallest order whose block holds `count` frames
+pub fn order_for(count: usize) -> usize {
+    let mut order = 0;
+    while (1usize << order) < count {
+        order += 1;
+    }
+    order
+}
+
+struct BuddyAllocator {
+    /// Zones of each node, indexed by node and then zone type
+    nodes: [[Zone; 3]; MAX_NUMA_NODES],
+    node_count: usize,
+    /// Nodes to try for an allocation local to each node, nearest first
+    fallback: [[u8; MAX_NUMA_NODES]; MAX_NUMA_NODES],
+    /// Usable frame ranges, excluding the allocator's own maps
+    usable: heapless::Vec<(u64, u64), MAX_USABLE_RANGES>,
+    /// One byte of state per frame, indexed by frame number
+    frame_map: &'static mut [u8],
+    /// Node of each frame, indexed by frame number
+    node_map: &'static mut [u8],
+    /// Mappings of each allocated frame beyond its first, indexed by frame number
+    share_counts: &'static mut [u16],
+}
+
+impl BuddyAllocator {
+    /// Zone whose free lists hold a frame
+    fn zone_of(&mut self, pfn: u64) -> &mut Zone {
+        let node = self.node_map[pfn as usize] as usize;
+        &mut self.nodes[node][ZoneType::for_pfn(pfn).index()]
+    }
+
+    /// Whether two frames belong to the same node and zone
+    fn same_pool(&self, a: u64, b: u64) -> bool {
+        self.node_map[a as usize] == self.node_map[b as usize] && ZoneType::for_pfn(a) == ZoneType::for_pfn(b)
+    }
+
+    fn push(&mut self, pfn: u64, order: usize) {
+        let head = self.zone_of(pfn).free_lists[order];
+        unsafe {
+            block(pfn).write(FreeBlock { next: head, prev: NIL });
+            if head != NIL {
+                (*block(head)).prev = pfn;
+            }
+        }
+        let zone = self.zone_of(pfn);
+        zone.free_lists[order] = pfn;
+        zone.free_blocks[order] += 1;
+        zone.free_frames += 1 << order;
+        self.frame_map[pfn as usize] = FRAME_FREE_HEAD | order as u8;
+    }
+
+    fn unlink(&mut self, pfn: u64, order: usize) {
+        let FreeBlock { next, prev } = unsafe { block(pfn).read() };
+        unsafe {
+            if next != NIL {
+                (*block(next)).prev = prev;
+            }
+            if prev != NIL {
+                (*block(prev)).next = next;
+            }
+        }
+        let zone = self.zone_of(pfn);
+        if prev == NIL {
+            zone.free_lists[order] = next;
+        }
+        zone.free_blocks[order] -= 1;
+        zone.free_frames -= 1 << order;
+        self.frame_map[pfn as usize] = FRAME_USED;
+    }
+
+    fn allocate(&mut self, node: usize, zone: usize, order: usize) -> Option<u64> {
+        let lists = &self.nodes[node][zone].free_lists;
+        let found = (order..MAX_ORDER).find(|&o| lists[o] != NIL)?;
+        let pfn = lists[found];
+        self.unlink(pfn, found);
+
+        // Split the block, returning the upper halves to the smaller lists
+        let mut current = found;
+        while current > order {
+            current -= 1;
+            self.push(pfn + (1 << current), current);
+        }
+        Some(pfn)
+    }
+
+    fn free(&mut self, mut pfn: u64, mut order: usize) -> bool {
+        let end = pfn + (1 << order);
+        if end as usize > self.frame_map.len() {
+            return false;
+        }
+        // A free block overlapping the range either holds its first frame or starts inside it
+        if self.free_block_containing(pfn).is_some()
+            || self.frame_map[pfn as usize..end as usize].iter().any(|&state| state & FRAME_FREE_HEAD != 0)
+        {
+            crate::io::println!("PMM: double free of frame {:#x}", pfn * FRAME_SIZE);
+            return false;
+        }
+
+        // Merge with the buddy for as long as it is a free block of the same order and pool
+        while order + 1 < MAX_ORDER {
+            let buddy = pfn ^ (1 << order);
+            if buddy as usize >= self.frame_map.len()
+                || !self.same_pool(pfn, buddy)
+                || self.frame_map[buddy as usize] != FRAME_FREE_HEAD | order as u8
+            {
+                break;
+            }
+            self.unlink(buddy, order);
+            pfn &= !(1 << order);
+            order += 1;
+        }
+        self.push(pfn, order);
+        true
+    }
+
+    /// Free an arbitrary run of frames as the largest aligned blocks that fit
+    fn free_range(&mut self, mut pfn: u64, end: u64) {
+        while pfn < end {
+            let mut order = (pfn.trailing_zeros() as usize).min(MAX_ORDER - 1);
+            while pfn + (1 << order) > end || !self.single_pool(pfn, pfn + (1 << order)) {
+                order -= 1;
+            }
+            self.free(pfn, order);
+            pfn += 1 << order;
+        }
+    }
+
+    /// The free block containing `pfn`, as its first frame and order
+    fn free_block_containing(&self, pfn: u64) -> Option<(u64, usize)> {
+        (0..MAX_ORDER).find_map(|order| {
+            let head = pfn & !((1 << order) - 1);
+            (self.frame_map[head as usize] == FRAME_FREE_HEAD | order as u8).then_some((head, order))
+        })
+    }
+
+    /// Take `[start, end)` off the free lists if every frame in it is free
+    fn claim_range(&mut self, start: u64, end: u64) -> bool {
+        if end as usize > self.frame_map.len() {
+            return false;
+        }
+        let mut pfn = start;
+        while pfn < end {
+            match self.free_block_containing(pfn) {
+                Some((head, order)) => pfn = head + (1 << order),
+                None => return false,
+            }
+        }
+
+        let mut pfn = start;
+        while pfn < end {
+            let (head, order) = self.free_block_containing(pfn).expect("checked above");
+            let block_end = head + (1 << order);
+            self.unlink(head, order);
+            // Give back whatever part of the block lies outside the range
+            if head < start {
+                self.free_range(head, start);
+            }
+            if block_end > end {
+                self.free_range(end, block_end);
+            }
+            pfn = block_end;
+        }
+        true
+    }
+
+    /// Whether `[start, end)` lies within one node and zone
+    fn single_pool(&self, start: u64, end: u64) -> bool {
+        let node = self.node_map[start as usize];
+        ZoneType::for_pfn(end - 1) == ZoneType::for_pfn(start)
+            && self.node_map[start as usize..end as usize].iter().all(|&n| n == node)
+    }
+
+    /// Recount the managed frames of every zone from the usable ranges
+    fn count_managed(&mut self) {
+        for zones in self.nodes.iter_mut() {
+            for zone in zones.iter_mut() {
+                zone.managed_frames = 0;
+            }
+        }
+        for i in 0..self.usable.len() {
+            let (start, end) = self.usable[i];
+            for pfn in start..end {
+                self.zone_of(pfn).managed_frames += 1;
+            }
+        }
+    }
+
+    /// Move every free block to the pools of the nodes given by `ranges`
+    fn set_nodes(&mut self, ranges: &[(u64, u64, u8)], distances: &[[u8; MAX_NUMA_NODES]; MAX_NUMA_NODES], node_count: usize) {
+        // Hide the free blocks from merging, then requeue them under the new node map
+        for state in self.frame_map.iter_mut() {
+            if *state & FRAME_FREE_HEAD != 0 {
+                *state = FRAME_REQUEUE | (*state & ORDER_MASK);
+            }
+        }
+        for zones in self.nodes.iter_mut() {
+            for zone in zones.iter_mut() {
+                let kind = zone.zone;
+                *zone = Zone::new(kind);
+            }
+        }
+
+        self.node_map.fill(0);
+        let frames = self.node_map.len() as u64;
+        for &(start, end, node) in ranges {
+            let end = end.min(frames);
+            if start < end {
+                self.node_map[start as usize..end as usize].fill(node);
+            }
+        }
+
+        self.node_count = node_count;
+        for node in 0..node_count {
+            let mut order: heapless::Vec<u8, MAX_NUMA_NODES> = (0..node_count as u8).collect();
+            order.sort_unstable_by_key(|&other| (distances[node][other as usize], other));
+            self.fallback[node][..node_count].copy_from_slice(&order);
+        }
+        self.count_managed();
+
+        for pfn in 0..frames {
+            let state = self.frame_map[pfn as usize];
+            if state & FRAME_REQUEUE != 0 {
+                self.frame_map[pfn as usize] = FRAME_USED;
+                self.free_range(pfn, pfn + (1 << (state & ORDER_MASK)));
+            }
+        }
+    }
+}
+
+pub struct PhysicalMemoryManager {
+    allocator: SpinLock<Option<BuddyAllocator>>,
+    free_count: AtomicUsize,
+    total_count: AtomicUsize,
+}
+
+impl PhysicalMemoryManager {
+    pub const fn new() -> Self {
+        PhysicalMemoryManager {
+            allocator: SpinLock::new(None),
+            free_count: AtomicUsize::new(0),
+            total_count: AtomicUsize::new(0),
+        }
+    }
+
+    /// Seed the allocator with the usable regions of the bootloader memory map.
+    ///
+    /// The frame map and share counts are carved out of the first usable region
+    /// large enough to hold them.
+    ///
+    /// The caller must guarantee that usable regions are really unused and that
+    /// physical memory is mapped at the boot-time offset.
+    pub unsafe fn init(&self, memory_map: &MemoryMap) -> Result<(), &'static str> {
+        let usable = || {
+            memory_map.iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+                .map(|r| (r.range.start_addr() / FRAME_SIZE, r.range.end_addr() / FRAME_SIZE))
+                .map(|(start, end)| (start.max(1), end))
+                .filter(|(start, end)| start < end)
+        };
+
+        let max_pfn = usable().map(|(_, end)| end).max().ok_or("No usable memory")?;
+        let nodes_offset = max_pfn as usize;
+        let counts_offset = align_up(nodes_offset + max_pfn as usize, align_of::<u16>());
+        let map_bytes = (counts_offset + max_pfn as usize * size_of::<u16>()) as u64;
+        let map_frames = (map_bytes + FRAME_SIZE - 1) / FRAME_SIZE;
+        let (map_start, _) = usable()
+            .find(|(start, end)| end - start >= map_frames)
+            .ok_or("No region large enough for the frame map")?;
+
+        let map_base = phys_to_virt(PhysAddr::new(map_start * FRAME_SIZE)).as_mut_ptr::<u8>();
+        let frame_map = core::slice::from_raw_parts_mut(map_base, max_pfn as usize);
+        frame_map.fill(FRAME_USED);
+        let node_map = core::slice::from_raw_parts_mut(map_base.add(nodes_offset), max_pfn as usize);
+        node_map.fill(0);
+        let share_counts = core::slice::from_raw_parts_mut(
+            map_base.add(counts_offset) as *mut u16,
+            max_pfn as usize,
+        );
+        share_counts.fill(0);
+
+        // Everything starts on node 0 until the NUMA topology is known
+        let mut buddy = BuddyAllocator {
+            nodes: [(); MAX_NUMA_NODES].map(|_| [Zone::new(ZoneType::Dma), Zone::new(ZoneType::Dma32), Zone::new(ZoneType::Normal)]),
+            node_count: 1,
+            fallback: [[0; MAX_NUMA_NODES]; MAX_NUMA_NODES],
+            usable: heapless::Vec::new(),
+            frame_map,
+            node_map,
+            share_counts,
+        };
+
+        for (mut start, end) in usable() {
+            if start == map_start {
+                start += map_frames;
+            }
+            if start < end {
+                buddy.usable.push((start, end)).map_err(|_| "Too many usable memory regions")?;
+            }
+        }
+        buddy.count_managed();
+        for i in 0..buddy.usable.len() {
+            let (start, end) = buddy.usable[i];
+            buddy.free_range(start, end);
+        }
+
+        let free: usize = buddy.nodes[0].iter().map(|z| z.free_frames).sum();
+        self.free_count.store(free, Ordering::Relaxed);
+        self.total_count.store(free, Ordering::Relaxed);
+        *self.allocator.lock() = Some(buddy);
+        Ok(())
+    }
+
+    /// Allocate 2^order contiguous frames, preferring the highest zone that has room
+    pub fn allocate_frames(&self, order: usize) -> Option<PhysFrame> {
+        self.allocate_frames_below(order, ZoneType::Normal)
+    }
+
+    /// Allocate 2^order contiguous frames from `max_zone` or a lower zone,
+    /// preferring the local node and falling back to the nearest others
+    pub fn allocate_frames_below(&self, order: usize, max_zone: ZoneType) -> Option<PhysFrame> {
+        self.allocate_frames_from(order, max_zone, ALL_NODES)
+    }
+
+    /// Allocate 2^order contiguous frames from the nodes in `node_mask`, nearest first
+    pub fn allocate_frames_from(&self, order: usize, max_zone: ZoneType, node_mask: u64) -> Option<PhysFrame> {
+        if order >= MAX_ORDER {
+            return None;
+        }
+        let local = NUMA_MANAGER.local_node();
+        let mut guard = self.allocator.lock();
+        let buddy = guard.as_mut()?;
+        let fallback = buddy.fallback[local.min(buddy.node_count - 1)];
+        let pfn = fallback[..buddy.node_count]
+            .iter()
+            .map(|&node| node as usize)
+            .filter(|&node| node_mask & (1 << node) != 0)
+            .find_map(|node| (0..=max_zone.index()).rev().find_map(|zone| buddy.allocate(node, zone, order)))?;
+        self.free_count.fetch_sub(1 << order, Ordering::Relaxed);
+        Some(PhysFrame::containing_address(PhysAddr::new(pfn * FRAME_SIZE)))
+    }
+
+    pub fn allocate_frame(&self) -> Option<PhysFrame> {
+        self.allocate_frames(0)
+    }
+
+    /// Allocate `count` physically contiguous frames
+    pub fn allocate_contiguous(&self, count: usize) -> Option<PhysFrame> {
+        self.allocate_contiguous_from(count, ALL_NODES)
+    }
+
+    /// Allocate `count` physically contiguous frames from the nodes in `node_mask`
+    pub fn allocate_contiguous_from(&self, count: usize, node_mask: u64) -> Option<PhysFrame> {
+        if count == 0 {
+            return None;
+        }
+        let order = order_for(count);
+        let frame = self.allocate_frames_from(order, ZoneType::Normal, node_mask)?;
+
+        // Hand the unused tail of the block straight back
+        let start = frame.start_address().as_u64() / FRAME_SIZE;
+        let mut guard = self.allocator.lock();
+        if let Some(buddy) = guard.as_mut() {
+            buddy.free_range(start + count as u64, start + (1 << order));
+            self.free_count.fetch_add((1 << order) - count, Ordering::Relaxed);
+        }
+        Some(frame)
+    }
+
+    /// Take a specific run of `count` frames off the free lists.
+    ///
+    /// Fails without claiming anything unless every frame in the run is free.
+    pub fn claim_range(&self, frame: PhysFrame, count: usize) -> bool {
+        let start = frame.start_address().as_u64() / FRAME_SIZE;
+        let mut guard = self.allocator.lock();
+        let claimed = guard.as_mut().map_or(false, |buddy| buddy.claim_range(start, start + count as u64));
+        if claimed {
+            self.free_count.fetch_sub(count, Ordering::Relaxed);
+        }
+        claimed
+    }
+
+    /// Free 2^order frames previously returned by `allocate_frames`
+    pub fn free_frames(&self, frame: PhysFrame, order: usize) {
+        let pfn = frame.start_address().as_u64() / FRAME_SIZE;
+        if let Some(buddy) = self.allocator.lock().as_mut() {
+            if buddy.free(pfn, order) {
+                self.free_count.fetch_add(1 << order, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn free_frame(&self, frame: PhysFrame) {
+        self.free_frames(frame, 0);
+    }
+
+    /// Record another mapping of an allocated frame
+    pub fn get_frame(&self, frame: PhysFrame) {
+        let pfn = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        if let Some(buddy) = self.allocator.lock().as_mut() {
+            if let Some(count) = buddy.share_counts.get_mut(pfn) {
+                *count = count.checked_add(1).expect("frame share count overflow");
+            }
+        }
+    }
+
+    /// Drop one mapping of a frame, freeing it once nothing maps it any more
+    pub fn put_frame(&self, frame: PhysFrame) {
+        let pfn = frame.start_address().as_u64() / FRAME_SIZE;
+        if let Some(buddy) = self.allocator.lock().as_mut() {
+            match buddy.share_counts.get_mut(pfn as usize) {
+                Some(count) if *count > 0 => *count -= 1,
+                _ => {
+                    if buddy.free(pfn, 0) {
+                        self.free_count.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Number of mappings of an allocated frame
+    pub fn frame_ref_count(&self, frame: PhysFrame) -> usize {
+        let pfn = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        let guard = self.allocator.lock();
+        guard.as_ref()
+            .and_then(|buddy| buddy.share_counts.get(pfn))
+            .map_or(1, |&count| count as usize + 1)
+    }
+
+    /// Free `count` frames previously returned by `allocate_contiguous`
+    pub fn free_contiguous(&self, frame: PhysFrame, count: usize) {
+        let pfn = frame.start_address().as_u64() / FRAME_SIZE;
+        if let Some(buddy) = self.allocator.lock().as_mut() {
+            buddy.free_range(pfn, pfn + count as u64);
+            self.free_count.fetch_add(count, Ordering::Relaxed);
+        }
+    }
+
+    pub fn free_frame_count(&self) -> usize {
+        self.free_count.load(Ordering::Relaxed)
+    }
+
+    pub fn total_frame_count(&self) -> usize {
+        self.total_count.load(Ordering::Relaxed)
+    }
+
+    /// Frame number one past the highest usable frame
+    pub fn frame_limit(&self) -> u64 {
+        let guard = self.allocator.lock();
+        guard.as_ref().map_or(0, |buddy| buddy.frame_map.len() as u64)
+    }
+
+    pub fn used_frame_count(&self) -> usize {
+        self.total_frame_count() - self.free_frame_count()
+    }
+
+    /// Free and managed frames of one zone, summed over all nodes
+    pub fn zone_frame_counts(&self, zone: ZoneType) -> Option<(usize, usize)> {
+        let guard = self.allocator.lock();
+        let buddy = guard.as_ref()?;
+        Some(buddy.nodes[..buddy.node_count].iter()
+            .map(|zones| &zones[zone.index()])
+            .fold((0, 0), |(free, managed), z| (free + z.free_frames, managed + z.managed_frames)))
+    }
+
+    /// Free and managed frames of one node
+    pub fn node_frame_counts(&self, node: usize) -> Option<(usize, usize)> {
+        let guard = self.allocator.lock();
+        let buddy = guard.as_ref()?;
+        let zones = buddy.nodes[..buddy.node_count].get(node)?;
+        Some(zones.iter().fold((0, 0), |(free, managed), z| (free + z.free_frames, managed + z.managed_frames)))
+    }
+
+    /// Node a frame belongs to
+    pub fn frame_node(&self, frame: PhysFrame) -> usize {
+        let pfn = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        let guard = self.allocator.lock();
+        guard.as_ref()
+            .and_then(|buddy| buddy.node_map.get(pfn))
+            .map_or(0, |&node| node as usize)
+    }
+
+    /// Split memory into nodes once the NUMA topology is known.
+    ///
+    /// `ranges` assigns frame ranges to nodes; frames outside every range stay
+    /// on node 0. `distances` orders each node's fallback list.
+    pub fn set_nodes(&self, ranges: &[(u64, u64, u8)], distances: &[[u8; MAX_NUMA_NODES]; MAX_NUMA_NODES], node_count: usize) {
+        if let Some(buddy) = self.allocator.lock().as_mut() {
+            buddy.set_nodes(ranges, distances, node_count.clamp(1, MAX_NUMA_NODES));
+        }
+    }
+
+    pub fn zone_stats(&self) -> Vec<ZoneStats> {
+        let guard = self.allocator.lock();
+        match guard.as_ref() {
+            Some(buddy) => buddy.nodes[..buddy.node_count].iter()
+                .enumerate()
+                .flat_map(|(node, zones)| zones.iter().map(move |z| (node, z)))
+                .filter(|(_, z)| z.managed_frames > 0)
+                .map(|(node, z)| ZoneStats {
+                    node: node as u8,
+                    zone: z.zone,
+                    managed_frames: z.managed_frames,
+                    free_frames: z.free_frames,
+                    free_blocks: z.free_blocks,
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Print free block counts per zone and order, like /proc/buddyinfo
+    pub fn print_stats(&self) {
+        crate::io::println!("Physical memory: {} / {} frames free", self.free_frame_count(), self.total_frame_count());
+        for stats in self.zone_stats() {
+            crate::io::print!("  Node {}, zone {:>6}:", stats.node, stats.zone.name());
+            for count in stats.free_blocks.iter() {
+                crate::io::print!(" {:>5}", count);
+            }
+            crate::io::println!();
+        }
+    }
+}
+
+pub static PMM: PhysicalMemoryManager = PhysicalMemoryManager::new();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pfn(frame: PhysFrame) -> u64 {
+        frame.start_address().as_u64() / FRAME_SIZE
+    }
+
+    fn frame_at(pfn: u64) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(pfn * FRAME_SIZE))
+    }
+
+    #[test_case]
+    fn allocate_and_free() {
+        let free = PMM.free_frame_count();
+        let frame = PMM.allocate_frames(3).expect("out of memory");
+        assert_eq!(pfn(frame) % 8, 0, "block not aligned to its size");
+        assert_eq!(PMM.free_frame_count(), free - 8);
+        PMM.free_frames(frame, 3);
+        assert_eq!(PMM.free_frame_count(), free);
+    }
+
+    #[test_case]
+    fn buddies_coalesce() {
+        let frame = PMM.allocate_frames(1).expect("out of memory");
+        let head = pfn(frame);
+        PMM.free_frames(frame_at(head), 0);
+        PMM.free_frames(frame_at(head + 1), 0);
+
+        let guard = PMM.allocator.lock();
+        let buddy = guard.as_ref().expect("allocator not initialized");
+        let (start, order) = buddy.free_block_containing(head).expect("frames not free");
+        assert!(order >= 1, "halves left as separate blocks");
+        assert!(start <= head && head + 2 <= start + (1 << order));
+    }
+
+    #[test_case]
+    fn double_free_rejected() {
+        let frame = PMM.allocate_frames(1).expect("out of memory");
+        PMM.free_frames(frame, 1);
+        let free = PMM.free_frame_count();
+
+        // The block may have merged with its buddy, so neither frame need head a free block
+        PMM.free_frames(frame, 1);
+        PMM.free_frames(frame_at(pfn(frame) + 1), 0);
+        assert_eq!(PMM.free_frame_count(), free);
+    }
+}
@@ -0,0 +1,390 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use x86_64::structures::paging::{Page, PhysFrame};
+use x86_64::VirtAddr;
+use crate::memory::fault::{drop_page, droppable, evictable, swap_out_page};
+use crate::memory::{AddressSpace, ZoneType, NUMA_MANAGER, OOM_KILLER, PMM, VMM};
+use crate::process::PROCESS_MANAGER;
+use crate::sync::SpinLock;
+use crate::wait::WaitQueue;
+
+/// Pages reclaimed per batch, like Linux's SWAP_CLUSTER_MAX
+const RECLAIM_BATCH: usize = 32;
+
+/// Direct reclaim passes before the allocator gives up and calls the OOM killer
+const MAX_RECLAIM_RETRIES: usize = 16;
+
+const ZONES: [ZoneType; 3] = [ZoneType::Dma, ZoneType::Dma32, ZoneType::Normal];
+
+/// A user page on the LRU lists, identified by the PML4 that maps it
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct LruPage {
+    pml4_frame: PhysFrame,
+    page: Page,
+}
+
+/// Free frame thresholds for a zone
+#[derive(Debug, Clone, Copy)]
+pub struct Watermarks {
+    /// Below this, allocations reclaim directly before succeeding
+    pub min: usize,
+    /// Below this, kswapd is woken
+    pub low: usize,
+    /// kswapd reclaims until the zone is back above this
+    pub high: usize,
+}
+
+impl Watermarks {
+    fn for_zone(zone: ZoneType) -> Watermarks {
+        let managed = PMM.zone_frame_counts(zone).map_or(0, |(_, managed)| managed);
+        let min = (managed / 256).max(16);
+        Watermarks {
+            min,
+            low: min + min / 4,
+            high: min + min / 2,
+        }
+    }
+}
+
+struct ZoneLru {
+    active: VecDeque<LruPage>,
+    inactive: VecDeque<LruPage>,
+}
+
+impl ZoneLru {
+    const fn new() -> Self {
+        ZoneLru {
+            active: VecDeque::new(),
+            inactive: VecDeque::new(),
+        }
+    }
+}
+
+/// Pages isolated for a reclaim pass, by where they go back
+#[derive(Default)]
+struct Putback {
+    active: Vec<LruPage>,
+    inactive: Vec<LruPage>,
+    /// Left alone this pass; they go back to the front of the inactive list
+    unscanned: Vec<LruPage>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReclaimStats {
+    pub active_pages: usize,
+    pub inactive_pages: usize,
+    pub scanned: u64,
+    pub reclaimed: u64,
+    pub activated: u64,
+    pub deactivated: u64,
+    pub kswapd_runs: u64,
+    pub direct_reclaims: u64,
+}
+
+/// Per-zone active/inactive lists of user pages, aged with the accessed bit
+pub struct PageReclaimer {
+    zones: SpinLock<[ZoneLru; 3]>,
+    stats: SpinLock<ReclaimStats>,
+}
+
+impl PageReclaimer {
+    pub const fn new() -> Self {
+        PageReclaimer {
+            zones: SpinLock::new([ZoneLru::new(), ZoneLru::new(), ZoneLru::new()]),
+            stats: SpinLock::new(ReclaimStats {
+                active_pages: 0,
+                inactive_pages: 0,
+                scanned: 0,
+                reclaimed: 0,
+                activated: 0,
+                deactivated: 0,
+                kswapd_runs: 0,
+                direct_reclaims: 0,
+            }),
+        }
+    }
+
+    /// Start tracking a freshly mapped user page; new pages begin on the inactive list
+    pub fn add_page(&self, space: &AddressSpace, page: Page) {
+        if let Some((frame, _)) = VMM.translate_page(space, page) {
+            let entry = LruPage { pml4_frame: space.pml4_frame(), page };
+            self.zones.lock()[ZoneType::of(frame).index()].inactive.push_back(entry);
+        }
+    }
+
+    /// Track every resident user page of an address space
+    pub fn add_address_space(&self, space: &AddressSpace) {
+        for (page, frame) in VMM.resident_pages(space) {
+            let entry = LruPage { pml4_frame: space.pml4_frame(), page };
+            self.zones.lock()[ZoneType::of(frame).index()].inactive.push_back(entry);
+        }
+    }
+
+    /// Stop tracking the pages of `space` in `[start, end)`, as when a huge page replaces them
+    pub fn forget_range(&self, space: &AddressSpace, start: VirtAddr, end: VirtAddr) {
+        let pml4_frame = space.pml4_frame();
+        let inside = |p: &LruPage| p.pml4_frame == pml4_frame && (start..end).contains(&p.page.start_address());
+        for lru in self.zones.lock().iter_mut() {
+            lru.active.retain(|p| !inside(p));
+            lru.inactive.retain(|p| !inside(p));
+        }
+    }
+
+    /// Drop every page of an address space that is being torn down
+    pub fn forget_address_space(&self, pml4_frame: PhysFrame) {
+        for lru in self.zones.lock().iter_mut() {
+            lru.active.retain(|p| p.pml4_frame != pml4_frame);
+            lru.inactive.retain(|p| p.pml4_frame != pml4_frame);
+        }
+    }
+
+    /// Take up to `count` pages off the front of one list of a zone
+    fn isolate(&self, zone: ZoneType, count: usize, active: bool) -> Vec<LruPage> {
+        let mut zones = self.zones.lock();
+        let lru = &mut zones[zone.index()];
+        let list = if active { &mut lru.active } else { &mut lru.inactive };
+        let count = count.min(list.len());
+        list.drain(..count).collect()
+    }
+
+    /// Return isolated pages to their zone's lists
+    fn putback(&self, zone: ZoneType, back: Putback) {
+        let mut zones = self.zones.lock();
+        let lru = &mut zones[zone.index()];
+        lru.active.extend(back.active);
+        lru.inactive.extend(back.inactive);
+        for entry in back.unscanned.into_iter().rev() {
+            lru.inactive.push_front(entry);
+        }
+    }
+
+    /// Age the active list: recently used pages rotate, the rest become inactive
+    fn shrink_active(&self, zone: ZoneType, count: usize, stats: &mut ReclaimStats) {
+        let mut back = Putback::default();
+        for entry in self.isolate(zone, count, true) {
+            // Gone with its process
+            let Some(mm) = PROCESS_MANAGER.mm_of(entry.pml4_frame) else { continue };
+            let Some(_map) = mm.map.try_lock() else {
+                back.active.push(entry);
+                continue;
+            };
+            match VMM.test_and_clear_accessed(&mm.address_space, entry.page) {
+                Some(true) => back.active.push(entry),
+                Some(false) => {
+                    back.inactive.push(entry);
+                    stats.deactivated += 1;
+                }
+                // No longer resident, so nothing to reclaim
+                None => {}
+            }
+        }
+        self.putback(zone, back);
+    }
+
+    /// Evict up to `target` unused pages from the inactive list.
+    ///
+    /// Pages are isolated a batch at a time so that compression, swap writes
+    /// and shootdowns run without the LRU lock.
+    fn shrink_inactive(&self, zone: ZoneType, target: usize, stats: &mut ReclaimStats) -> usize {
+        let mut reclaimed = 0;
+        let mut remaining = self.zones.lock()[zone.index()].inactive.len();
+        let mut swap_full = false;
+        while reclaimed < target && remaining > 0 && !swap_full {
+            let batch = self.isolate(zone, (target - reclaimed).min(remaining).min(RECLAIM_BATCH), false);
+            if batch.is_empty() {
+                break;
+            }
+            remaining -= batch.len();
+
+            let mut back = Putback::default();
+            for entry in batch {
+                if swap_full {
+                    back.unscanned.push(entry);
+                    continue;
+                }
+                stats.scanned += 1;
+
+                let Some(mm) = PROCESS_MANAGER.mm_of(entry.pml4_frame) else { continue };
+                // A page whose process is busy changing its mappings is left
+                // for the next pass. So is one of the process this CPU is
+                // changing, as in direct reclaim from a fault: a copy-on-write
+                // break may have read the frame it is about to replace.
+                let Some(_map) = mm.map.try_lock() else {
+                    back.inactive.push(entry);
+                    continue;
+                };
+                let space = &mm.address_space;
+                match VMM.test_and_clear_accessed(space, entry.page) {
+                    Some(true) => {
+                        back.active.push(entry);
+                        stats.activated += 1;
+                    }
+                    // The file still holds a clean page, so it is freed rather than swapped
+                    Some(false) if droppable(space, entry.page) => match drop_page(space, entry.page) {
+                        Ok(()) => reclaimed += 1,
+                        // Written meanwhile, so in use after all
+                        Err(_) => back.active.push(entry),
+                    },
+                    // Shared or kernel pages cannot be swapped, so keep them out of the way
+                    Some(false) if !evictable(space, entry.page) => back.active.push(entry),
+                    Some(false) => match swap_out_page(space, entry.page) {
+                        Ok(()) => reclaimed += 1,
+                        // Swap is full; keep the page and stop trying
+                        Err(_) => {
+                            back.inactive.push(entry);
+                            swap_full = true;
+                        }
+                    },
+                    None => {}
+                }
+            }
+            self.putback(zone, back);
+        }
+        reclaimed
+    }
+
+    /// Reclaim up to `target` pages from one zone
+    fn shrink_zone(&self, zone: ZoneType, target: usize) -> usize {
+        let mut stats = ReclaimStats::default();
+
+        // Keep the inactive list at least as long as the active one
+        let (active, inactive) = {
+            let zones = self.zones.lock();
+            (zones[zone.index()].active.len(), zones[zone.index()].inactive.len())
+        };
+        if inactive < active {
+            self.shrink_active(zone, (active - inactive).max(target), &mut stats);
+        }
+        let reclaimed = self.shrink_inactive(zone, target, &mut stats);
+
+        let mut totals = self.stats.lock();
+        totals.scanned += stats.scanned;
+        totals.reclaimed += reclaimed as u64;
+        totals.activated += stats.activated;
+        totals.deactivated += stats.deactivated;
+        reclaimed
+    }
+
+    /// Synchronously reclaim up to `target` pages from any zone
+    pub fn direct_reclaim(&self, target: usize) -> usize {
+        self.stats.lock().direct_reclaims += 1;
+        let mut reclaimed = 0;
+        for zone in ZONES.iter().rev() {
+            if reclaimed >= target {
+                break;
+            }
+            reclaimed += self.shrink_zone(*zone, target - reclaimed);
+        }
+        reclaimed
+    }
+
+    /// Reclaim from every zone below its high watermark until it is above it again
+    pub fn balance(&self) -> usize {
+        self.stats.lock().kswapd_runs += 1;
+        let mut total = 0;
+        for zone in ZONES {
+            let high = Watermarks::for_zone(zone).high;
+            while zone_free(zone) < high {
+                let reclaimed = self.shrink_zone(zone, RECLAIM_BATCH);
+                if reclaimed == 0 {
+                    break;
+                }
+                total += reclaimed;
+            }
+        }
+        total
+    }
+
+    pub fn stats(&self) -> ReclaimStats {
+        let mut stats = *self.stats.lock();
+        let zones = self.zones.lock();
+        stats.active_pages = zones.iter().map(|lru| lru.active.len()).sum();
+        stats.inactive_pages = zones.iter().map(|lru| lru.inactive.len()).sum();
+        stats
+    }
+}
+
+fn zone_free(zone: ZoneType) -> usize {
+    PMM.zone_frame_counts(zone).map_or(0, |(free, _)| free)
+}
+
+/// Zones with memory that have dropped below the given watermark
+fn any_zone_below(mark: fn(Watermarks) -> usize) -> bool {
+    ZONES.iter().any(|&zone| {
+        PMM.zone_frame_counts(zone).map_or(false, |(free, managed)| managed > 0 && free < mark(Watermarks::for_zone(zone)))
+    })
+}
+
+pub static PAGE_RECLAIMER: PageReclaimer = PageReclaimer::new();
+
+/// Background reclaimer, woken when a zone drops below its low watermark
+pub struct Kswapd {
+    pending: AtomicBool,
+    wakeups: AtomicU64,
+    /// The kswapd thread while it sleeps
+    waiters: WaitQueue,
+}
+
+impl Kswapd {
+    pub const fn new() -> Self {
+        Kswapd {
+            pending: AtomicBool::new(false),
+            wakeups: AtomicU64::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn wake(&self) {
+        if !self.pending.swap(true, Ordering::AcqRel) {
+            self.wakeups.fetch_add(1, Ordering::Relaxed);
+            self.waiters.wake_one();
+        }
+    }
+
+    /// Run one round of background reclaim if woken
+    pub fn run_once(&self) -> usize {
+        if !self.pending.swap(false, Ordering::AcqRel) {
+            return 0;
+        }
+        PAGE_RECLAIMER.balance()
+    }
+
+    /// Body of the kswapd kernel thread: sleeps until woken
+    pub fn run(&self) -> ! {
+        loop {
+            self.run_once();
+            self.waiters.wait_until(|| self.pending.load(Ordering::Acquire));
+        }
+    }
+}
+
+pub static KSWAPD: Kswapd = Kswapd::new();
+
+/// Allocate a frame for a user page, reclaiming memory and finally invoking
+/// the OOM killer when physical memory runs out
+pub fn allocate_user_frame() -> Option<PhysFrame> {
+    if any_zone_below(|w| w.min) {
+        PAGE_RECLAIMER.direct_reclaim(RECLAIM_BATCH);
+    }
+    if let Some(frame) = NUMA_MANAGER.allocate_user_frame() {
+        if any_zone_below(|w| w.low) {
+            KSWAPD.wake();
+        }
+        return Some(frame);
+    }
+
+    KSWAPD.wake();
+    for _ in 0..MAX_RECLAIM_RETRIES {
+        let reclaimed = PAGE_RECLAIMER.direct_reclaim(RECLAIM_BATCH);
+        if let Some(frame) = NUMA_MANAGER.allocate_user_frame() {
+            return Some(frame);
+        }
+        if reclaimed == 0 {
+            break;
+        }
+    }
+
+    OOM_KILLER.handle_oom();
+    NUMA_MANAGER.allocate_user_frame()
+}
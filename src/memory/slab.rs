@@ -1,31 +1,295 @@
-use spin::Mutex;
-use alloc::collections::BTreeMap;
-use core::alloc::{GlobalAlloc, Layout};
+use crate::sync::SpinLock;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::mem::{align_of, size_of};
+use core::ops::{Deref, DerefMut};
+use core::ptr::{null_mut, NonNull};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::boot::{physical_memory_offset, phys_to_virt};
+use crate::hardware::cpu::{current_cpu, MAX_CPUS};
+use crate::memory::PMM;
+
+const PAGE_SIZE: usize = 4096;
+
+/// Objects each CPU keeps cached in front of the shared slab lists
+const MAGAZINE_SIZE: usize = 16;
+
+/// Empty slabs a cache holds on to before handing frames back to the PMM
+const MAX_EMPTY_SLABS: usize = 1;
+
+/// Slabs grow up to this order to fit at least `MIN_OBJECTS_PER_SLAB` objects
+const MAX_SLAB_ORDER: usize = 3;
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+/// Link stored in every free object
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Descriptor kept at the start of every slab
+struct Slab {
+    next: *mut Slab,
+    prev: *mut Slab,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+struct SlabList {
+    head: *mut Slab,
+    len: usize,
+}
+
+impl SlabList {
+    const fn new() -> Self {
+        SlabList {
+            head: null_mut(),
+            len: 0,
+        }
+    }
+
+    unsafe fn push(&mut self, slab: *mut Slab) {
+        (*slab).prev = null_mut();
+        (*slab).next = self.head;
+        if !self.head.is_null() {
+            (*self.head).prev = slab;
+        }
+        self.head = slab;
+        self.len += 1;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut Slab) {
+        if !(*slab).prev.is_null() {
+            (*(*slab).prev).next = (*slab).next;
+        } else {
+            self.head = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+        self.len -= 1;
+    }
+}
+
+struct CacheState {
+    partial: SlabList,
+    full: SlabList,
+    empty: SlabList,
+    /// Objects taken out of slabs, including those parked in magazines
+    objects_out: usize,
+}
+
+unsafe impl Send for CacheState {}
+
+struct Magazine {
+    objects: [*mut u8; MAGAZINE_SIZE],
+    count: usize,
+    allocations: u64,
+    frees: u64,
+}
+
+unsafe impl Send for Magazine {}
+
+const EMPTY_MAGAZINE: SpinLock<Magazine> = SpinLock::new_raw(Magazine {
+    objects: [null_mut(); MAGAZINE_SIZE],
+    count: 0,
+    allocations: 0,
+    frees: 0,
+});
+
+/// A `/proc/slabinfo` row
+#[derive(Debug, Clone)]
+pub struct SlabInfo {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub active_objects: usize,
+    pub total_objects: usize,
+    pub objects_per_slab: usize,
+    pub pages_per_slab: usize,
+    pub active_slabs: usize,
+    pub total_slabs: usize,
+    pub cached_objects: usize,
+    pub allocations: u64,
+    pub frees: u64,
+}
+
+const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
 
 pub struct SlabCache {
+    name: &'static str,
     object_size: usize,
-    free_list: Mutex<alloc::vec::Vec<*mut u8>>,
-    total_objects: Mutex<usize>,
+    align: usize,
+    order: usize,
+    first_object: usize,
+    objects_per_slab: usize,
+    /// Untracked like the heap's, as the caches back the global allocator
+    state: SpinLock<CacheState>,
+    magazines: [SpinLock<Magazine>; MAX_CPUS],
 }
 
 impl SlabCache {
-    pub fn new(object_size: usize) -> Self {
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        let align = if align < size_of::<FreeObject>() { size_of::<FreeObject>() } else { align };
+        let object_size = align_up(if size < size_of::<FreeObject>() { size_of::<FreeObject>() } else { size }, align);
+        let first_object = align_up(size_of::<Slab>(), align);
+
+        let mut order = 0;
+        while order < MAX_SLAB_ORDER && ((PAGE_SIZE << order) - first_object) / object_size < MIN_OBJECTS_PER_SLAB {
+            order += 1;
+        }
+
         SlabCache {
+            name,
             object_size,
-            free_list: Mutex::new(alloc::vec::Vec::new()),
-            total_objects: Mutex::new(0),
+            align,
+            order,
+            first_object,
+            objects_per_slab: ((PAGE_SIZE << order) - first_object) / object_size,
+            state: SpinLock::new_raw(CacheState {
+                partial: SlabList::new(),
+                full: SlabList::new(),
+                empty: SlabList::new(),
+                objects_out: 0,
+            }),
+            magazines: [EMPTY_MAGAZINE; MAX_CPUS],
         }
     }
 
-    pub fn allocate(&self) -> Option<*mut u8> {
-        let mut free = self.free_list.lock();
-        if let Some(ptr) = free.pop() {
-            return Some(ptr);
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    pub fn align(&self) -> usize {
+        self.align
+    }
+
+    fn slab_bytes(&self) -> usize {
+        PAGE_SIZE << self.order
+    }
+
+    /// Slabs are naturally aligned buddy blocks, so masking finds the descriptor
+    fn slab_of(&self, object: *mut u8) -> *mut Slab {
+        (object as usize & !(self.slab_bytes() - 1)) as *mut Slab
+    }
+
+    /// Carve a new slab out of freshly allocated frames
+    fn new_slab(&self) -> Option<*mut Slab> {
+        let frame = PMM.allocate_frames(self.order)?;
+        let base = phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+        let slab = base as *mut Slab;
+
+        let mut free: *mut FreeObject = null_mut();
+        for i in (0..self.objects_per_slab).rev() {
+            let object = unsafe { base.add(self.first_object + i * self.object_size) } as *mut FreeObject;
+            unsafe {
+                object.write(FreeObject { next: free });
+            }
+            free = object;
+        }
+
+        unsafe {
+            slab.write(Slab {
+                next: null_mut(),
+                prev: null_mut(),
+                free,
+                in_use: 0,
+            });
+        }
+        Some(slab)
+    }
+
+    fn release_slab(&self, slab: *mut Slab) {
+        let virt = VirtAddr::new(slab as u64);
+        let phys = PhysAddr::new(virt.as_u64() - physical_memory_offset().as_u64());
+        PMM.free_frames(PhysFrame::containing_address(phys), self.order);
+    }
+
+    /// Take one object out of the slab lists
+    fn take_object(&self, state: &mut CacheState) -> Option<*mut u8> {
+        let slab = if !state.partial.head.is_null() {
+            state.partial.head
+        } else {
+            let slab = if !state.empty.head.is_null() {
+                let slab = state.empty.head;
+                unsafe { state.empty.remove(slab) };
+                slab
+            } else {
+                self.new_slab()?
+            };
+            unsafe { state.partial.push(slab) };
+            slab
+        };
+
+        unsafe {
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            (*slab).in_use += 1;
+            if (*slab).in_use == self.objects_per_slab {
+                state.partial.remove(slab);
+                state.full.push(slab);
+            }
+            state.objects_out += 1;
+            Some(object as *mut u8)
         }
+    }
+
+    /// Put an object back into its slab, releasing the slab if it became empty
+    fn return_object(&self, state: &mut CacheState, ptr: *mut u8) {
+        let slab = self.slab_of(ptr);
+        unsafe {
+            let object = ptr as *mut FreeObject;
+            object.write(FreeObject { next: (*slab).free });
+            (*slab).free = object;
+
+            if (*slab).in_use == self.objects_per_slab {
+                state.full.remove(slab);
+                state.partial.push(slab);
+            }
+            (*slab).in_use -= 1;
+            state.objects_out -= 1;
 
-        // Allocate new object
-        // TODO: Implement actual allocation from page allocator
-        None
+            if (*slab).in_use == 0 {
+                state.partial.remove(slab);
+                if state.empty.len < MAX_EMPTY_SLABS {
+                    state.empty.push(slab);
+                } else {
+                    self.release_slab(slab);
+                }
+            }
+        }
+    }
+
+    pub fn allocate(&self) -> Option<*mut u8> {
+        without_interrupts(|| {
+            let mut magazine = self.magazines[current_cpu()].lock();
+            if magazine.count == 0 {
+                let mut state = self.state.lock();
+                while magazine.count < MAGAZINE_SIZE / 2 {
+                    match self.take_object(&mut state) {
+                        Some(object) => {
+                            let count = magazine.count;
+                            magazine.objects[count] = object;
+                            magazine.count += 1;
+                        }
+                        None => break,
+                    }
+                }
+            }
+
+            if magazine.count == 0 {
+                return None;
+            }
+            magazine.count -= 1;
+            magazine.allocations += 1;
+            Some(magazine.objects[magazine.count])
+        })
     }
 
     pub fn deallocate(&self, ptr: *mut u8) {
@@ -33,39 +297,242 @@ impl SlabCache {
         unsafe {
             core::ptr::write_bytes(ptr, 0, self.object_size);
         }
-        self.free_list.lock().push(ptr);
+
+        without_interrupts(|| {
+            let mut magazine = self.magazines[current_cpu()].lock();
+            if magazine.count == MAGAZINE_SIZE {
+                // Hand the older half back to the slabs
+                let mut state = self.state.lock();
+                for i in 0..MAGAZINE_SIZE / 2 {
+                    self.return_object(&mut state, magazine.objects[i]);
+                }
+                magazine.objects.copy_within(MAGAZINE_SIZE / 2.., 0);
+                magazine.count -= MAGAZINE_SIZE / 2;
+            }
+
+            let count = magazine.count;
+            magazine.objects[count] = ptr;
+            magazine.count += 1;
+            magazine.frees += 1;
+        });
+    }
+
+    /// Flush every magazine and free all empty slabs; returns the number of frames released
+    pub fn shrink(&self) -> usize {
+        without_interrupts(|| {
+            for magazine in self.magazines.iter() {
+                let mut magazine = magazine.lock();
+                let mut state = self.state.lock();
+                for i in 0..magazine.count {
+                    self.return_object(&mut state, magazine.objects[i]);
+                }
+                magazine.count = 0;
+            }
+
+            let mut state = self.state.lock();
+            let mut released = 0;
+            while !state.empty.head.is_null() {
+                let slab = state.empty.head;
+                unsafe { state.empty.remove(slab) };
+                self.release_slab(slab);
+                released += 1 << self.order;
+            }
+            released
+        })
+    }
+
+    pub fn info(&self) -> SlabInfo {
+        without_interrupts(|| {
+            let (mut cached_objects, mut allocations, mut frees) = (0, 0, 0);
+            for magazine in self.magazines.iter() {
+                let magazine = magazine.lock();
+                cached_objects += magazine.count;
+                allocations += magazine.allocations;
+                frees += magazine.frees;
+            }
+            let state = self.state.lock();
+            let total_slabs = state.partial.len + state.full.len + state.empty.len;
+            SlabInfo {
+                name: self.name,
+                object_size: self.object_size,
+                active_objects: state.objects_out - cached_objects,
+                total_objects: total_slabs * self.objects_per_slab,
+                objects_per_slab: self.objects_per_slab,
+                pages_per_slab: 1 << self.order,
+                active_slabs: state.partial.len + state.full.len,
+                total_slabs,
+                cached_objects,
+                allocations,
+                frees,
+            }
+        })
+    }
+}
+
+/// An owned `T` stored in a slab cache
+pub struct SlabBox<T> {
+    ptr: NonNull<T>,
+    cache: &'static SlabCache,
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Sync> Sync for SlabBox<T> {}
+
+impl<T> SlabBox<T> {
+    pub fn new(cache: &'static SlabCache, value: T) -> Result<Self, &'static str> {
+        if size_of::<T>() > cache.object_size || align_of::<T>() > cache.align {
+            return Err("Object does not fit slab cache");
+        }
+        let ptr = cache.allocate().ok_or("Slab cache exhausted")? as *mut T;
+        unsafe {
+            ptr.write(value);
+            Ok(SlabBox {
+                ptr: NonNull::new_unchecked(ptr),
+                cache,
+            })
+        }
+    }
+
+    /// Move the object out and return its slot to the cache
+    pub fn into_inner(self) -> T {
+        let value = unsafe { self.ptr.as_ptr().read() };
+        self.cache.deallocate(self.ptr.as_ptr() as *mut u8);
+        core::mem::forget(self);
+        value
     }
 }
 
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            core::ptr::drop_in_place(self.ptr.as_ptr());
+        }
+        self.cache.deallocate(self.ptr.as_ptr() as *mut u8);
+    }
+}
+
+/// Object sizes served by the general-purpose caches; larger requests go to the heap
+pub const SLAB_SIZE_CLASSES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
 pub struct SlabAllocator {
-    caches: Mutex<BTreeMap<usize, SlabCache>>,
+    caches: [SlabCache; 9],
+    named_caches: SpinLock<heapless::Vec<&'static SlabCache, 32>>,
 }
 
 impl SlabAllocator {
     pub const fn new() -> Self {
         SlabAllocator {
-            caches: Mutex::new(BTreeMap::new()),
+            caches: [
+                SlabCache::new("kmalloc-8", 8, 8),
+                SlabCache::new("kmalloc-16", 16, 16),
+                SlabCache::new("kmalloc-32", 32, 32),
+                SlabCache::new("kmalloc-64", 64, 64),
+                SlabCache::new("kmalloc-128", 128, 128),
+                SlabCache::new("kmalloc-256", 256, 256),
+                SlabCache::new("kmalloc-512", 512, 512),
+                SlabCache::new("kmalloc-1024", 1024, 1024),
+                SlabCache::new("kmalloc-2048", 2048, 2048),
+            ],
+            named_caches: SpinLock::new_raw(heapless::Vec::new()),
         }
     }
 
-    pub fn get_cache(&self, size: usize) -> &SlabCache {
-        let mut caches = self.caches.lock();
-        caches.entry(size).or_insert_with(|| SlabCache::new(size))
+    /// Smallest size class that satisfies both the size and alignment of `layout`
+    pub fn get_cache(&self, layout: Layout) -> Option<&SlabCache> {
+        self.caches.iter()
+            .find(|cache| cache.object_size >= layout.size() && cache.align >= layout.align())
+    }
+
+    pub fn handles(&self, layout: Layout) -> bool {
+        self.get_cache(layout).is_some()
     }
 
     pub fn allocate(&self, layout: Layout) -> *mut u8 {
-        let cache = self.get_cache(layout.size());
-        cache.allocate().unwrap_or(core::ptr::null_mut())
+        match self.get_cache(layout) {
+            Some(cache) => cache.allocate().unwrap_or(null_mut()),
+            None => null_mut(),
+        }
     }
 
     pub fn deallocate(&self, ptr: *mut u8, layout: Layout) {
         if ptr.is_null() {
             return;
         }
-        let cache = self.get_cache(layout.size());
-        cache.deallocate(ptr);
+        if let Some(cache) = self.get_cache(layout) {
+            cache.deallocate(ptr);
+        }
+    }
+
+    /// Make a dedicated object cache visible in `slabinfo` and `shrink`
+    pub fn register_cache(&self, cache: &'static SlabCache) -> Result<(), &'static str> {
+        let mut named = self.named_caches.lock();
+        if named.iter().any(|c| core::ptr::eq(*c, cache)) {
+            return Ok(());
+        }
+        named.push(cache).map_err(|_| "Too many slab caches")
+    }
+
+    /// Release cached objects and empty slabs from every cache; returns frames freed
+    pub fn shrink(&self) -> usize {
+        let named = self.named_caches.lock().clone();
+        self.caches.iter().map(|c| c.shrink()).sum::<usize>()
+            + named.iter().map(|c| c.shrink()).sum::<usize>()
+    }
+
+    pub fn slabinfo(&self) -> Vec<SlabInfo> {
+        let named = self.named_caches.lock().clone();
+        let mut info = Vec::with_capacity(self.caches.len() + named.len());
+        for cache in named.iter() {
+            let row = cache.info();
+            info.push(row);
+        }
+        for cache in self.caches.iter() {
+            let row = cache.info();
+            info.push(row);
+        }
+        info
+    }
+
+    pub fn print_slabinfo(&self) {
+        crate::io::println!("# name            <active_objs> <num_objs> <objsize> <objperslab> <pagesperslab> : <active_slabs> <num_slabs>");
+        for row in self.slabinfo() {
+            crate::io::println!(
+                "{:<17} {:>13} {:>10} {:>9} {:>12} {:>14} : {:>14} {:>11}",
+                row.name,
+                row.active_objects,
+                row.total_objects,
+                row.object_size,
+                row.objects_per_slab,
+                row.pages_per_slab,
+                row.active_slabs,
+                row.total_slabs,
+            );
+        }
     }
 }
 
 pub static SLAB_ALLOCATOR: SlabAllocator = SlabAllocator::new();
 
+/// Register the dedicated caches for hot kernel objects
+pub fn init() {
+    for cache in [
+        &crate::process::PROCESS_CACHE,
+        &crate::fs::inode::INODE_CACHE,
+        &crate::net::socket::SOCKET_CACHE,
+    ] {
+        SLAB_ALLOCATOR.register_cache(cache).ok();
+    }
+}
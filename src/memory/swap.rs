@@ -1,85 +1,326 @@
-use spin::Mutex;
-use alloc::collections::BTreeMap;
-use x86_64::{PhysAddr, VirtAddr};
-use crate::fs::block::BLOCK_DEVICE;
-
-pub struct SwapEntry {
-    pub page_vaddr: VirtAddr,
-    pub swap_slot: u64,
-    pub dirty: bool,
+use crate::sync::SpinLock;
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::fs::block::{BLOCK_DEVICE, BLOCK_SIZE};
+use crate::memory::{MEMORY_COMPRESSOR, VMM};
+
+const PAGE_SIZE: usize = 4096;
+const SECTORS_PER_PAGE: u64 = (PAGE_SIZE / BLOCK_SIZE) as u64;
+
+pub const MAX_SWAP_AREAS: usize = 8;
+
+/// Signature at the end of the header page, as written by mkswap
+const SWAP_SIGNATURE: &[u8; 10] = b"SWAPSPACE2";
+const SWAP_VERSION: u32 = 1;
+const HEADER_VERSION_OFFSET: usize = 1024;
+const HEADER_LAST_PAGE_OFFSET: usize = 1028;
+
+/// Slots encode the area in their upper bits and the page within it below
+const AREA_SHIFT: u32 = 32;
+
+fn make_slot(area: usize, offset: u64) -> u64 {
+    ((area as u64) << AREA_SHIFT) | offset
+}
+
+fn split_slot(slot: u64) -> (usize, u64) {
+    ((slot >> AREA_SHIFT) as usize, slot & ((1 << AREA_SHIFT) - 1))
+}
+
+fn read_page(sector: u64, page: &mut [u8; PAGE_SIZE]) -> Result<(), &'static str> {
+    for (i, chunk) in page.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+        let block: &mut [u8; BLOCK_SIZE] = chunk.try_into().map_err(|_| "Bad sector size")?;
+        BLOCK_DEVICE.read_block(sector + i as u64, block)?;
+    }
+    Ok(())
+}
+
+fn write_page(sector: u64, page: &[u8; PAGE_SIZE]) -> Result<(), &'static str> {
+    for (i, chunk) in page.chunks_exact(BLOCK_SIZE).enumerate() {
+        let block: &[u8; BLOCK_SIZE] = chunk.try_into().map_err(|_| "Bad sector size")?;
+        BLOCK_DEVICE.write_block(sector + i as u64, block)?;
+    }
+    Ok(())
+}
+
+/// An active region of the block device holding swapped pages
+struct SwapArea {
+    start_sector: u64,
+    /// Pages in the area, including the header page
+    pages: u64,
+    /// One bit per page; the header page is permanently in use
+    bitmap: Vec<u64>,
+    used: u64,
+    priority: i16,
+    /// Cleared while the area is being switched off so it takes no new pages
+    active: bool,
+    next_hint: u64,
+}
+
+impl SwapArea {
+    fn new(start_sector: u64, pages: u64, priority: i16) -> Self {
+        let mut area = SwapArea {
+            start_sector,
+            pages,
+            bitmap: vec![0; ((pages + 63) / 64) as usize],
+            used: 0,
+            priority,
+            active: true,
+            next_hint: 1,
+        };
+        area.set(0, true);
+        area
+    }
+
+    fn is_used(&self, offset: u64) -> bool {
+        self.bitmap[(offset / 64) as usize] & (1 << (offset % 64)) != 0
+    }
+
+    fn set(&mut self, offset: u64, used: bool) {
+        let word = &mut self.bitmap[(offset / 64) as usize];
+        if used {
+            *word |= 1 << (offset % 64);
+        } else {
+            *word &= !(1 << (offset % 64));
+        }
+    }
+
+    fn has_free(&self) -> bool {
+        self.active && self.used < self.pages - 1
+    }
+
+    fn allocate(&mut self) -> Option<u64> {
+        let offset = (0..self.pages)
+            .map(|i| (self.next_hint + i) % self.pages)
+            .find(|&offset| !self.is_used(offset))?;
+        self.set(offset, true);
+        self.used += 1;
+        self.next_hint = offset + 1;
+        Some(offset)
+    }
+
+    /// Release a page; the header and pages not in use are left alone
+    fn free(&mut self, offset: u64) {
+        if offset != 0 && offset < self.pages && self.is_used(offset) {
+            self.set(offset, false);
+            self.used -= 1;
+        }
+    }
+
+    fn sector_of(&self, offset: u64) -> u64 {
+        self.start_sector + offset * SECTORS_PER_PAGE
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SwapAreaInfo {
+    pub id: usize,
+    pub start_sector: u64,
+    pub pages: u64,
+    pub used_pages: u64,
+    pub priority: i16,
 }
 
 pub struct SwapManager {
-    entries: Mutex<BTreeMap<VirtAddr, SwapEntry>>,
-    swap_device_start: u64,
-    swap_size_blocks: u64,
-    next_slot: Mutex<u64>,
+    areas: SpinLock<[Option<SwapArea>; MAX_SWAP_AREAS]>,
 }
 
 impl SwapManager {
     pub const fn new() -> Self {
+        const NO_AREA: Option<SwapArea> = None;
         SwapManager {
-            entries: Mutex::new(BTreeMap::new()),
-            swap_device_start: 0,
-            swap_size_blocks: 0,
-            next_slot: Mutex::new(0),
+            areas: SpinLock::new([NO_AREA; MAX_SWAP_AREAS]),
         }
     }
 
-    pub fn init(&self, swap_start: u64, swap_size_blocks: u64) {
-        let mut manager = SwapManager {
-            entries: Mutex::new(BTreeMap::new()),
-            swap_device_start: swap_start,
-            swap_size_blocks,
-            next_slot: Mutex::new(0),
-        };
-        *self = manager;
+    /// Write a swap header covering `pages` pages starting at `start_sector`
+    pub fn mkswap(&self, start_sector: u64, pages: u64) -> Result<(), &'static str> {
+        if pages < 2 || pages > u32::MAX as u64 {
+            return Err("Invalid swap area size");
+        }
+        let mut header = vec![0u8; PAGE_SIZE];
+        header[HEADER_VERSION_OFFSET..HEADER_VERSION_OFFSET + 4].copy_from_slice(&SWAP_VERSION.to_le_bytes());
+        header[HEADER_LAST_PAGE_OFFSET..HEADER_LAST_PAGE_OFFSET + 4]
+            .copy_from_slice(&((pages - 1) as u32).to_le_bytes());
+        header[PAGE_SIZE - SWAP_SIGNATURE.len()..].copy_from_slice(SWAP_SIGNATURE);
+
+        let header: &[u8; PAGE_SIZE] = header.as_slice().try_into().map_err(|_| "Bad page size")?;
+        write_page(start_sector, header)
+    }
+
+    /// Start swapping to the area at `start_sector`, which must carry a swap header.
+    ///
+    /// Pages go to the highest-priority area with room. Returns the area id.
+    pub fn swapon(&self, start_sector: u64, priority: i16) -> Result<usize, &'static str> {
+        let mut header = vec![0u8; PAGE_SIZE];
+        let header_page: &mut [u8; PAGE_SIZE] = header.as_mut_slice().try_into().map_err(|_| "Bad page size")?;
+        read_page(start_sector, header_page)?;
+
+        if &header[PAGE_SIZE - SWAP_SIGNATURE.len()..] != SWAP_SIGNATURE {
+            return Err("No swap signature found");
+        }
+        let field = |offset: usize| u32::from_le_bytes([header[offset], header[offset + 1], header[offset + 2], header[offset + 3]]);
+        if field(HEADER_VERSION_OFFSET) != SWAP_VERSION {
+            return Err("Unsupported swap header version");
+        }
+        let pages = field(HEADER_LAST_PAGE_OFFSET) as u64 + 1;
+        if pages < 2 {
+            return Err("Swap area too small");
+        }
+
+        let mut areas = self.areas.lock();
+        if areas.iter().flatten().any(|area| area.start_sector == start_sector) {
+            return Err("Swap area already active");
+        }
+        let id = areas.iter().position(|area| area.is_none()).ok_or("Too many swap areas")?;
+
+        areas[id] = Some(SwapArea::new(start_sector, pages, priority));
+        Ok(id)
     }
 
-    pub fn swap_out(&self, vaddr: VirtAddr, page_data: &[u8; 4096]) -> Result<u64, &'static str> {
-        let slot = {
-            let mut next = self.next_slot.lock();
-            if *next >= self.swap_size_blocks {
-                return Err("Swap space full");
+    /// Stop swapping to an area, bringing every page stored there back into memory
+    pub fn swapoff(&self, id: usize) -> Result<(), &'static str> {
+        {
+            let mut areas = self.areas.lock();
+            let area = areas.get_mut(id).and_then(|a| a.as_mut()).ok_or("No such swap area")?;
+            area.active = false;
+        }
+
+        let in_area = |slot: u64| split_slot(slot).0 == id;
+        crate::process::PROCESS_MANAGER.for_each_address_space(|space| {
+            for (page, _) in VMM.swapped_pages(space, in_area) {
+                if let Err(e) = VMM.swap_in(space, page) {
+                    crate::io::println!("swapoff: failed to swap in {:#x}: {}", page.start_address().as_u64(), e);
+                }
             }
-            let slot = *next;
-            *next += 1;
-            slot
-        };
+        });
+        MEMORY_COMPRESSOR.unuse_swap_slots(in_area);
 
-        let block_num = self.swap_device_start + slot;
-        BLOCK_DEVICE.write_block(block_num, page_data)?;
+        let mut areas = self.areas.lock();
+        let area = areas[id].as_mut().ok_or("No such swap area")?;
+        if area.used > 0 {
+            area.active = true;
+            return Err("Swap area still in use");
+        }
+        areas[id] = None;
+        Ok(())
+    }
 
-        let entry = SwapEntry {
-            page_vaddr: vaddr,
-            swap_slot: slot,
-            dirty: true,
+    /// Write a page to the highest-priority area with room and return its slot
+    pub fn swap_out(&self, page_data: &[u8; 4096]) -> Result<u64, &'static str> {
+        let (slot, sector) = {
+            let mut areas = self.areas.lock();
+            let (id, area) = areas.iter_mut()
+                .enumerate()
+                .filter_map(|(id, area)| area.as_mut().map(|area| (id, area)))
+                .filter(|(_, area)| area.has_free())
+                .max_by_key(|(id, area)| (area.priority, core::cmp::Reverse(*id)))
+                .ok_or("Swap space full")?;
+            let offset = area.allocate().ok_or("Swap space full")?;
+            (make_slot(id, offset), area.sector_of(offset))
         };
-        self.entries.lock().insert(vaddr, entry);
 
+        if let Err(e) = write_page(sector, page_data) {
+            self.free_slot(slot);
+            return Err(e);
+        }
         Ok(slot)
     }
 
-    pub fn swap_in(&self, vaddr: VirtAddr) -> Result<[u8; 4096], &'static str> {
-        let entry = self.entries.lock().remove(&vaddr)
-            .ok_or("Page not in swap")?;
+    fn sector_of(&self, slot: u64) -> Result<u64, &'static str> {
+        let (id, offset) = split_slot(slot);
+        let areas = self.areas.lock();
+        match areas.get(id).and_then(|a| a.as_ref()) {
+            Some(area) if offset != 0 && offset < area.pages && area.is_used(offset) => Ok(area.sector_of(offset)),
+            _ => Err("Page not in swap"),
+        }
+    }
+
+    /// Read a swapped page without releasing its slot
+    pub fn read_slot(&self, slot: u64, page_data: &mut [u8; 4096]) -> Result<(), &'static str> {
+        read_page(self.sector_of(slot)?, page_data)
+    }
 
-        let block_num = self.swap_device_start + entry.swap_slot;
-        let mut page_data = [0u8; 4096];
-        BLOCK_DEVICE.read_block(block_num, &mut page_data)?;
+    /// Release a slot whose page is no longer needed
+    pub fn free_slot(&self, slot: u64) {
+        let (id, offset) = split_slot(slot);
+        let mut areas = self.areas.lock();
+        if let Some(area) = areas.get_mut(id).and_then(|a| a.as_mut()) {
+            area.free(offset);
+        }
+    }
 
-        Ok(page_data)
+    pub fn swap_in(&self, slot: u64, page_data: &mut [u8; 4096]) -> Result<(), &'static str> {
+        self.read_slot(slot, page_data)?;
+        self.free_slot(slot);
+        Ok(())
     }
 
-    pub fn is_swapped(&self, vaddr: VirtAddr) -> bool {
-        self.entries.lock().contains_key(&vaddr)
+    pub fn is_swapped(&self, slot: u64) -> bool {
+        self.sector_of(slot).is_ok()
     }
 
+    /// Whether some area could take another page
+    pub fn has_room(&self) -> bool {
+        self.areas.lock().iter().flatten().any(|area| area.has_free())
+    }
+
+    /// Used and total swap pages across all active areas
     pub fn get_swap_usage(&self) -> (u64, u64) {
-        let used = self.entries.lock().len() as u64;
-        (used, self.swap_size_blocks)
+        let areas = self.areas.lock();
+        areas.iter().flatten().fold((0, 0), |(used, total), area| (used + area.used, total + area.pages - 1))
+    }
+
+    pub fn areas(&self) -> Vec<SwapAreaInfo> {
+        let areas = self.areas.lock();
+        areas.iter()
+            .enumerate()
+            .filter_map(|(id, area)| area.as_ref().map(|area| SwapAreaInfo {
+                id,
+                start_sector: area.start_sector,
+                pages: area.pages - 1,
+                used_pages: area.used,
+                priority: area.priority,
+            }))
+            .collect()
     }
 }
 
 pub static SWAP_MANAGER: SwapManager = SwapManager::new();
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn slot_encoding() {
+        assert_eq!(split_slot(make_slot(3, 0x1234)), (3, 0x1234));
+    }
+
+    #[test_case]
+    fn bitmap_allocate_until_full() {
+        // Spans three bitmap words
+        let mut area = SwapArea::new(0, 130, 0);
+        let mut seen = vec![false; 130];
+        for _ in 1..130 {
+            let offset = area.allocate().expect("area filled early");
+            assert!(offset != 0, "header page handed out");
+            assert!(!seen[offset as usize], "page handed out twice");
+            seen[offset as usize] = true;
+        }
+        assert!(!area.has_free());
+        assert_eq!(area.allocate(), None);
+    }
+
+    #[test_case]
+    fn bitmap_free_and_reuse() {
+        let mut area = SwapArea::new(0, 130, 0);
+        while area.allocate().is_some() {}
+        area.free(70);
+        // Neither the header nor a page already free changes the count
+        area.free(0);
+        area.free(70);
+        assert_eq!(area.used, 128);
+        assert_eq!(area.allocate(), Some(70));
+        assert_eq!(area.allocate(), None);
+    }
+}
@@ -0,0 +1,74 @@
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+use crate::boot::phys_to_virt;
+use crate::memory::fault::handle_fault;
+use crate::memory::mmap::USER_END;
+use crate::memory::vmm::FILE_BACKED;
+use crate::memory::{AddressSpace, VmaTree, VMM};
+
+/// Frame behind the user page at `addr`, faulting it in the way a user access would
+fn user_frame(space: &AddressSpace, vmas: &VmaTree, addr: VirtAddr, write: bool) -> Result<PhysFrame, &'static str> {
+    let page = Page::containing_address(addr);
+    loop {
+        let mut error_code = PageFaultErrorCode::USER_MODE;
+        if write {
+            error_code |= PageFaultErrorCode::CAUSED_BY_WRITE;
+        }
+        match VMM.translate_page(space, page) {
+            Some((frame, flags))
+                if flags.contains(PageTableFlags::USER_ACCESSIBLE)
+                    && (!write || flags.contains(PageTableFlags::WRITABLE)) => {
+                // The write goes through the kernel mapping, which leaves the
+                // user entry clean; reclaim would drop a file page's new data
+                if write && flags.contains(FILE_BACKED) && !flags.contains(PageTableFlags::DIRTY) {
+                    VMM.update_flags(space, page, flags | PageTableFlags::DIRTY)?;
+                }
+                return Ok(frame);
+            }
+            Some(_) => error_code |= PageFaultErrorCode::PROTECTION_VIOLATION,
+            None => {}
+        }
+        handle_fault(space, vmas, addr, error_code)?;
+    }
+}
+
+/// Walk `[addr, addr + len)` page by page, handing `f` the offset into the range and the kernel view of each piece
+fn for_each_chunk(
+    space: &AddressSpace,
+    vmas: &VmaTree,
+    addr: u64,
+    len: usize,
+    write: bool,
+    mut f: impl FnMut(usize, *mut u8, usize),
+) -> Result<(), &'static str> {
+    let end = addr.checked_add(len as u64).ok_or("Bad user address")?;
+    if end > USER_END {
+        return Err("Bad user address");
+    }
+    let mut done = 0;
+    while done < len {
+        let at = VirtAddr::new(addr + done as u64);
+        let frame = user_frame(space, vmas, at, write)?;
+        let offset = at.as_u64() & 0xfff;
+        let chunk = ((4096 - offset) as usize).min(len - done);
+        let ptr: *mut u8 = phys_to_virt(frame.start_address() + offset).as_mut_ptr();
+        f(done, ptr, chunk);
+        done += chunk;
+    }
+    Ok(())
+}
+
+/// Copy `bytes` into user memory at `addr`, with the permissions of a user write
+pub fn copy_to_user(space: &AddressSpace, vmas: &VmaTree, addr: u64, bytes: &[u8]) -> Result<(), &'static str> {
+    for_each_chunk(space, vmas, addr, bytes.len(), true, |done, ptr, chunk| unsafe {
+        core::ptr::copy_nonoverlapping(bytes[done..].as_ptr(), ptr, chunk);
+    })
+}
+
+/// Fill `buf` from user memory at `addr`, with the permissions of a user read
+pub fn copy_from_user(space: &AddressSpace, vmas: &VmaTree, addr: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+    for_each_chunk(space, vmas, addr, buf.len(), false, |done, ptr, chunk| unsafe {
+        core::ptr::copy_nonoverlapping(ptr, buf[done..].as_mut_ptr(), chunk);
+    })
+}
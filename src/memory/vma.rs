@@ -0,0 +1,230 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+use crate::memory::vmm::{FILE_BACKED, SHARED_MAPPING};
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct VmaFlags: u32 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXEC = 1 << 2;
+        /// Writes are seen by every process mapping the area and reach its file
+        const SHARED = 1 << 3;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaKind {
+    Anonymous,
+    Stack,
+    Heap,
+    /// Pages of a file, `offset` being the file position of the area's start
+    File { inode: u64, offset: u64 },
+    /// An attachment of an IPC shared memory segment, mapped up front
+    SharedMemory { id: u64 },
+}
+
+/// A range of user virtual memory the process is allowed to touch
+#[derive(Debug, Clone)]
+pub struct Vma {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub flags: VmaFlags,
+    /// The most `mprotect` may grant, fixed when the area is mapped
+    pub max_flags: VmaFlags,
+    pub kind: VmaKind,
+}
+
+impl Vma {
+    pub fn new(start: VirtAddr, end: VirtAddr, flags: VmaFlags, kind: VmaKind) -> Self {
+        Vma { start, end, flags, max_flags: VmaFlags::all(), kind }
+    }
+
+    pub fn with_max_flags(mut self, max_flags: VmaFlags) -> Self {
+        self.max_flags = max_flags;
+        self
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    /// Whether the area may be touched at all, which `PROT_NONE` forbids
+    pub fn accessible(&self) -> bool {
+        self.flags.intersects(VmaFlags::READ | VmaFlags::WRITE | VmaFlags::EXEC)
+    }
+
+    /// Page table flags for pages mapped inside this area
+    pub fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.accessible() {
+            flags |= PageTableFlags::USER_ACCESSIBLE;
+        }
+        if self.flags.contains(VmaFlags::WRITE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.flags.contains(VmaFlags::EXEC) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        if self.flags.contains(VmaFlags::SHARED) {
+            flags |= SHARED_MAPPING;
+        }
+        if let VmaKind::File { .. } = self.kind {
+            flags |= FILE_BACKED;
+        }
+        flags
+    }
+
+    /// Cut the area at `addr`, keeping the lower part and returning the upper one
+    fn split_off(&mut self, addr: VirtAddr) -> Vma {
+        let mut upper = self.clone();
+        upper.start = addr;
+        if let VmaKind::File { offset, .. } = &mut upper.kind {
+            *offset += addr - self.start;
+        }
+        self.end = addr;
+        upper
+    }
+
+    /// Whether `next`, starting where this area ends, can be folded into it
+    fn can_absorb(&self, next: &Vma) -> bool {
+        self.flags == next.flags
+            && self.max_flags == next.max_flags
+            && match (self.kind, next.kind) {
+                (VmaKind::File { inode, offset }, VmaKind::File { inode: next_inode, offset: next_offset }) => {
+                    inode == next_inode && offset + (self.end - self.start) == next_offset
+                }
+                // Each attachment is detached on its own
+                (VmaKind::SharedMemory { .. }, _) => false,
+                (kind, next_kind) => kind == next_kind,
+            }
+    }
+}
+
+/// Non-overlapping areas of an address space, keyed by start address
+#[derive(Clone)]
+pub struct VmaTree {
+    areas: BTreeMap<u64, Vma>,
+}
+
+impl VmaTree {
+    pub const fn new() -> Self {
+        VmaTree {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, vma: Vma) -> Result<(), &'static str> {
+        if vma.start >= vma.end || vma.start.as_u64() % 4096 != 0 || vma.end.as_u64() % 4096 != 0 {
+            return Err("Invalid memory area");
+        }
+        if self.overlaps(vma.start, vma.end) {
+            return Err("Memory area overlaps an existing mapping");
+        }
+        self.areas.insert(vma.start.as_u64(), vma);
+        Ok(())
+    }
+
+    /// The area containing `addr`, if any
+    pub fn find(&self, addr: VirtAddr) -> Option<&Vma> {
+        self.areas
+            .range(..=addr.as_u64())
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+    }
+
+    pub fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        self.areas
+            .range(..end.as_u64())
+            .next_back()
+            .map_or(false, |(_, vma)| vma.end > start)
+    }
+
+    pub fn remove(&mut self, start: VirtAddr) -> Option<Vma> {
+        self.areas.remove(&start.as_u64())
+    }
+
+    /// Split the area straddling `addr`, if any, so that an area boundary falls on it
+    pub fn split(&mut self, addr: VirtAddr) {
+        let upper = match self.areas.range_mut(..addr.as_u64()).next_back() {
+            Some((_, vma)) if vma.end > addr => vma.split_off(addr),
+            _ => return,
+        };
+        self.areas.insert(addr.as_u64(), upper);
+    }
+
+    /// Join areas that differ in nothing but their range, at every boundary
+    /// from `start` to `end` inclusive; undoes `split` once flags match again
+    pub fn merge(&mut self, start: VirtAddr, end: VirtAddr) {
+        let keys: Vec<u64> = self.areas.range(start.as_u64()..=end.as_u64()).map(|(&key, _)| key).collect();
+        for key in keys {
+            let Some(upper) = self.areas.get(&key) else { continue };
+            let joins = self.areas.range(..key).next_back()
+                .map_or(false, |(_, lower)| lower.end.as_u64() == key && lower.can_absorb(upper));
+            if !joins {
+                continue;
+            }
+            if let Some(upper) = self.areas.remove(&key) {
+                if let Some((_, lower)) = self.areas.range_mut(..key).next_back() {
+                    lower.end = upper.end;
+                }
+            }
+        }
+    }
+
+    /// Remove everything inside `[start, end)`, trimming areas that extend past
+    /// either edge, and return the removed pieces
+    pub fn remove_range(&mut self, start: VirtAddr, end: VirtAddr) -> Vec<Vma> {
+        self.split(start);
+        self.split(end);
+        let keys: Vec<u64> = self.areas.range(start.as_u64()..end.as_u64()).map(|(&key, _)| key).collect();
+        keys.iter().filter_map(|key| self.areas.remove(key)).collect()
+    }
+
+    /// Whether `[start, end)` is mapped without holes
+    pub fn covers(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        let mut addr = start;
+        while addr < end {
+            match self.find(addr) {
+                Some(vma) => addr = vma.end,
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Areas starting inside `[start, end)`; split at both edges first to get exactly the range
+    pub fn range_mut(&mut self, start: VirtAddr, end: VirtAddr) -> impl Iterator<Item = &mut Vma> {
+        self.areas.range_mut(start.as_u64()..end.as_u64()).map(|(_, vma)| vma)
+    }
+
+    /// Highest `align`-aligned free range of `len` bytes between `min` and `below`
+    pub fn find_gap(&self, len: u64, align: u64, min: VirtAddr, below: VirtAddr) -> Option<VirtAddr> {
+        let fits = |top: u64, bottom: u64| {
+            top.checked_sub(len)
+                .map(|start| start & !(align - 1))
+                .filter(|&start| start >= bottom && start >= min.as_u64())
+        };
+        let mut top = below.as_u64();
+        for vma in self.areas.range(..below.as_u64()).rev().map(|(_, vma)| vma) {
+            if vma.end.as_u64() < top {
+                if let Some(start) = fits(top, vma.end.as_u64()) {
+                    return Some(VirtAddr::new(start));
+                }
+            }
+            top = top.min(vma.start.as_u64());
+            if top < min.as_u64() {
+                return None;
+            }
+        }
+        fits(top, min.as_u64()).map(VirtAddr::new)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+}
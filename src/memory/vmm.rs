@@ -1,37 +1,1010 @@
 use x86_64::{
+    instructions::{interrupts::without_interrupts, tlb},
+    registers::control::{Cr3, Cr3Flags},
     structures::paging::{
-        FrameAllocator, Mapper, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+        mapper::{MapToError, TranslateResult, UnmapError},
+        page_table::PageTableEntry,
+        Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PageTableIndex, PhysFrame,
+        Size2MiB, Size4KiB, Translate,
     },
     VirtAddr, PhysAddr,
 };
-use crate::memory::BootInfoFrameAllocator;
+use core::mem::ManuallyDrop;
+use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::vec::Vec;
+use crate::hardware::cpu::this_cpu;
+use crate::hardware::smp;
+use crate::sync::SeqLock;
+use crate::boot::{active_level_4_table, physical_memory_offset, phys_to_virt};
+use crate::memory::{allocate_frame, allocate_zeroed_frame, deallocate_frame, GlobalFrameAllocator};
+use crate::memory::reclaim::{allocate_user_frame, PAGE_RECLAIMER};
+use crate::memory::{HUGE_PAGE_ALLOCATOR, MEMORY_COMPRESSOR, PMM, SWAP_MANAGER};
+
+/// First PML4 slot of the higher half, shared by every address space
+pub const KERNEL_PML4_START: usize = 256;
+
+/// Marks lower-half entries that point at kernel frames rather than user frames
+pub const KERNEL_SHARED: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Marks a non-present entry whose address bits hold a swap slot
+pub const SWAPPED: PageTableFlags = PageTableFlags::BIT_10;
+
+/// Marks a non-present entry whose address bits hold a compressed page handle
+pub const COMPRESSED: PageTableFlags = PageTableFlags::BIT_11;
+
+/// Marks a read-only user page that becomes writable by copying it on the first write
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_52;
+
+/// Marks a page of a `MAP_SHARED` mapping, which fork shares instead of copying
+pub const SHARED_MAPPING: PageTableFlags = PageTableFlags::BIT_53;
+
+/// Marks a page of a file mapping, whose contents the file can provide again
+pub const FILE_BACKED: PageTableFlags = PageTableFlags::BIT_54;
+
+/// A top-level page table together with everything reachable from its user half
+#[derive(Debug)]
+pub struct AddressSpace {
+    pml4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    pub fn pml4_frame(&self) -> PhysFrame {
+        self.pml4_frame
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.pml4_frame
+    }
+
+    /// Build a mapper for this address space through the physical memory mapping.
+    ///
+    /// Callers must not hold two mappers for the same address space at once.
+    unsafe fn mapper(&self) -> OffsetPageTable<'static> {
+        OffsetPageTable::new(table_at(self.pml4_frame), physical_memory_offset())
+    }
+}
+
+/// View an address space through its PML4 without owning it.
+///
+/// The caller must make sure the address space outlives the view.
+pub(crate) unsafe fn borrow_address_space(pml4_frame: PhysFrame) -> ManuallyDrop<AddressSpace> {
+    ManuallyDrop::new(AddressSpace { pml4_frame })
+}
+
+/// Dropping an address space frees its page tables and user pages; the kernel's is never freed
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        VMM.teardown(self.pml4_frame);
+    }
+}
+
+/// Kernel-half mappings are live in every address space, so they always need a TLB flush
+fn needs_flush<S: PageSize>(space: &AddressSpace, page: Page<S>) -> bool {
+    space.is_active() || is_kernel_page(page)
+}
+
+fn is_kernel_page<S: PageSize>(page: Page<S>) -> bool {
+    page.p4_index() >= PageTableIndex::new(KERNEL_PML4_START as u16)
+}
+
+/// Drop a changed or removed mapping from this CPU's TLB and from every
+/// other CPU that may have cached it
+fn flush_page<S: PageSize>(space: &AddressSpace, page: Page<S>) {
+    if needs_flush(space, page) {
+        tlb::flush(page.start_address());
+    }
+    let target = if is_kernel_page(page) { None } else { Some(space.pml4_frame) };
+    smp::shootdown(target, Some(page.start_address()));
+}
+
+/// Flush every user mapping of `space` on all CPUs running it
+fn flush_space(space: &AddressSpace) {
+    if space.is_active() {
+        tlb::flush_all();
+    }
+    smp::shootdown(Some(space.pml4_frame), None);
+}
+
+unsafe fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>()
+}
+
+/// View the contents of a physical frame through the physical memory mapping
+pub(crate) unsafe fn frame_bytes(frame: PhysFrame) -> &'static mut [u8; 4096] {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<[u8; 4096]>()
+}
+
+/// Fill `frame` with the page stored behind a swapped or compressed entry
+fn read_swapped(flags: PageTableFlags, slot: u64, frame: PhysFrame) -> Result<(), &'static str> {
+    let buffer = unsafe { frame_bytes(frame) };
+    if flags.contains(COMPRESSED) {
+        MEMORY_COMPRESSOR.read_page(slot, buffer)
+    } else {
+        SWAP_MANAGER.read_slot(slot, buffer)
+    }
+}
+
+/// Drop the backing store of a swapped or compressed entry
+fn release_swapped(flags: PageTableFlags, slot: u64) {
+    if flags.contains(COMPRESSED) {
+        MEMORY_COMPRESSOR.discard(slot);
+    } else if flags.contains(SWAPPED) {
+        SWAP_MANAGER.free_slot(slot);
+    }
+}
+
+/// Copy the contents of one physical frame into another
+pub fn copy_frame(source: PhysFrame, destination: PhysFrame) {
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            phys_to_virt(source.start_address()).as_ptr::<u8>(),
+            phys_to_virt(destination.start_address()).as_mut_ptr::<u8>(),
+            4096,
+        );
+    }
+}
+
+fn map_error<S: PageSize>(error: MapToError<S>) -> &'static str {
+    match error {
+        MapToError::FrameAllocationFailed => "Out of memory for page tables",
+        MapToError::ParentEntryHugePage => "Address is covered by a huge page",
+        MapToError::PageAlreadyMapped(_) => "Page already mapped",
+    }
+}
+
+fn unmap_error(error: UnmapError) -> &'static str {
+    match error {
+        UnmapError::ParentEntryHugePage => "Address is covered by a huge page",
+        UnmapError::PageNotMapped => "Page not mapped",
+        UnmapError::InvalidFrameAddress(_) => "Invalid frame address",
+    }
+}
 
 pub struct VirtualMemoryManager {
-    mapper: Option<&'static mut PageTable>,
-    frame_allocator: Option<&'static mut BootInfoFrameAllocator>,
+    /// Read by the TLB shootdown handler, so readers must never wait
+    kernel_pml4: SeqLock<Option<PhysFrame>>,
 }
 
 impl VirtualMemoryManager {
     pub const fn new() -> Self {
         VirtualMemoryManager {
-            mapper: None,
-            frame_allocator: None,
+            kernel_pml4: SeqLock::new(None),
+        }
+    }
+
+    /// Adopt the bootloader's page table as the kernel address space.
+    ///
+    /// Every kernel-half PML4 slot gets a PDPT up front so that mappings the
+    /// kernel adds later are visible in all address spaces cloned from it.
+    pub fn init(&self) -> Result<(), &'static str> {
+        let table = unsafe { active_level_4_table(physical_memory_offset()) };
+        for entry in table.iter_mut().skip(KERNEL_PML4_START) {
+            if entry.is_unused() {
+                let frame = allocate_zeroed_frame().ok_or("Out of memory for kernel page tables")?;
+                entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+            }
         }
+
+        self.kernel_pml4.write(|pml4| *pml4 = Some(Cr3::read().0));
+        Ok(())
     }
 
-    pub fn init(&mut self, mapper: &'static mut PageTable, frame_allocator: &'static mut BootInfoFrameAllocator) {
-        self.mapper = Some(mapper);
-        self.frame_allocator = Some(frame_allocator);
+    /// The address space the kernel booted with
+    pub fn kernel_address_space(&self) -> Option<AddressSpace> {
+        self.kernel_pml4.read().map(|pml4_frame| AddressSpace { pml4_frame })
     }
 
-    pub fn map_page(&mut self, page: Page, flags: PageTableFlags) -> Result<(), ()> {
-        // TODO: Implement page mapping
+    /// Map `page` to a freshly allocated, zeroed frame
+    pub fn map_page(&self, space: &AddressSpace, page: Page, flags: PageTableFlags) -> Result<PhysFrame, &'static str> {
+        let frame = allocate_zeroed_frame().ok_or("Out of physical memory")?;
+        if let Err(e) = self.map_to(space, page, frame, flags) {
+            deallocate_frame(frame);
+            return Err(e);
+        }
+        Ok(frame)
+    }
+
+    /// Map `page` to an existing frame
+    pub fn map_to(&self, space: &AddressSpace, page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), &'static str> {
+        let mut mapper = unsafe { space.mapper() };
+        let flush = unsafe {
+            mapper.map_to(page, frame, flags | PageTableFlags::PRESENT, &mut GlobalFrameAllocator)
+        }.map_err(map_error)?;
+
+        if needs_flush(space, page) {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+        Ok(())
+    }
+
+    /// Map a 2 MiB page to a huge frame
+    pub fn map_huge_page(
+        &self,
+        space: &AddressSpace,
+        page: Page<Size2MiB>,
+        frame: PhysFrame<Size2MiB>,
+        flags: PageTableFlags,
+    ) -> Result<(), &'static str> {
+        let mut mapper = unsafe { space.mapper() };
+        let flush = unsafe {
+            mapper.map_to(page, frame, flags | PageTableFlags::PRESENT, &mut GlobalFrameAllocator)
+        }.map_err(map_error)?;
+
+        if needs_flush(space, page) {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+        Ok(())
+    }
+
+    /// Remove the mapping for `page` and hand back the frame it pointed to.
+    ///
+    /// A huge page covering `page` is split first.
+    pub fn unmap(&self, space: &AddressSpace, page: Page) -> Result<PhysFrame, &'static str> {
+        if self.huge_entry(space, page.start_address()).is_some() {
+            self.split_huge_page(space, page.start_address())?;
+        }
+        let mut mapper = unsafe { space.mapper() };
+        let (frame, flush) = mapper.unmap(page).map_err(unmap_error)?;
+
+        flush.ignore();
+        flush_page(space, page);
+        Ok(frame)
+    }
+
+    /// Remove the mapping for `page` and free its frame
+    pub fn unmap_page(&self, space: &AddressSpace, page: Page) -> Result<(), &'static str> {
+        let frame = self.unmap(space, page)?;
+        deallocate_frame(frame);
+        Ok(())
+    }
+
+    /// Change the flags of an existing mapping, splitting a huge page covering it
+    pub fn update_flags(&self, space: &AddressSpace, page: Page, flags: PageTableFlags) -> Result<(), &'static str> {
+        if self.huge_entry(space, page.start_address()).is_some() {
+            self.split_huge_page(space, page.start_address())?;
+        }
+        let mut mapper = unsafe { space.mapper() };
+        let flush = unsafe { mapper.update_flags(page, flags | PageTableFlags::PRESENT) }
+            .map_err(|_| "Page not mapped")?;
+
+        flush.ignore();
+        flush_page(space, page);
+        Ok(())
+    }
+
+    /// Unmap every user page in `[start, end)`, freeing its frame or swap slot.
+    ///
+    /// Huge pages inside the range are freed whole; ones straddling its edges are split first.
+    pub fn unmap_range(&self, space: &AddressSpace, start: VirtAddr, end: VirtAddr) -> Result<(), &'static str> {
+        let mut addr = start;
+        while addr < end {
+            let next = (addr.align_down(Size2MiB::SIZE) + Size2MiB::SIZE).min(end);
+            if let Some(entry) = self.huge_entry(space, addr) {
+                if next - addr == Size2MiB::SIZE {
+                    let frame = PhysFrame::containing_address(entry.addr());
+                    entry.set_unused();
+                    let huge = Page::<Size2MiB>::containing_address(addr);
+                    flush_page(space, huge);
+                    HUGE_PAGE_ALLOCATOR.free_2mb(frame);
+                    addr = next;
+                    continue;
+                }
+                self.split_huge_page(space, addr)?;
+            }
+            if self.table_entry(space, addr, 1).is_some() {
+                for page in Page::range(Page::containing_address(addr), Page::containing_address(next)) {
+                    self.clear_page(space, page);
+                }
+            }
+            addr = next;
+        }
+        Ok(())
+    }
+
+    /// Drop whatever user mapping `page` has: a frame, a swap slot or nothing
+    fn clear_page(&self, space: &AddressSpace, page: Page) {
+        let Some(entry) = self.leaf_entry(space, page) else { return };
+        let flags = entry.flags();
+        if entry.is_unused() || flags.contains(KERNEL_SHARED) {
+            return;
+        }
+        if flags.contains(PageTableFlags::PRESENT) {
+            let frame = PhysFrame::containing_address(entry.addr());
+            entry.set_unused();
+            flush_page(space, page);
+            deallocate_frame(frame);
+        } else {
+            release_swapped(flags, entry.addr().as_u64() >> 12);
+            entry.set_unused();
+        }
+    }
+
+    /// Give every user mapping in `[start, end)` new protection flags.
+    ///
+    /// Accessed and dirty bits survive, and copy-on-write pages stay read-only
+    /// until they are written. Swapped pages keep the new flags for swap-in.
+    pub fn protect_range(&self, space: &AddressSpace, start: VirtAddr, end: VirtAddr, flags: PageTableFlags) -> Result<(), &'static str> {
+        let kept = PageTableFlags::ACCESSED | PageTableFlags::DIRTY;
+        let mut addr = start;
+        while addr < end {
+            let next = (addr.align_down(Size2MiB::SIZE) + Size2MiB::SIZE).min(end);
+            if let Some(entry) = self.huge_entry(space, addr) {
+                if next - addr == Size2MiB::SIZE {
+                    entry.set_flags(flags | (entry.flags() & kept) | PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE);
+                    let huge = Page::<Size2MiB>::containing_address(addr);
+                    flush_page(space, huge);
+                    addr = next;
+                    continue;
+                }
+                self.split_huge_page(space, addr)?;
+            }
+            if self.table_entry(space, addr, 1).is_some() {
+                for page in Page::range(Page::containing_address(addr), Page::containing_address(next)) {
+                    let Some(entry) = self.leaf_entry(space, page) else { continue };
+                    let old = entry.flags();
+                    if entry.is_unused() || old.contains(KERNEL_SHARED) {
+                        continue;
+                    }
+                    let mut new = flags | (old & (kept | COPY_ON_WRITE));
+                    if old.contains(COPY_ON_WRITE) {
+                        new -= PageTableFlags::WRITABLE;
+                    }
+                    if old.contains(PageTableFlags::PRESENT) {
+                        entry.set_flags(new);
+                        flush_page(space, page);
+                    } else {
+                        entry.set_flags((new - PageTableFlags::PRESENT) | (old & (SWAPPED | COMPRESSED)));
+                    }
+                }
+            }
+            addr = next;
+        }
         Ok(())
     }
 
-    pub fn unmap_page(&mut self, page: Page) -> Result<(), ()> {
-        // TODO: Implement page unmapping
+    /// Lowest address in `[start, end)` where the lower half maps kernel memory, if any
+    pub fn kernel_mapping_in(&self, space: &AddressSpace, start: VirtAddr, end: VirtAddr) -> Option<VirtAddr> {
+        let mut addr = start.as_u64();
+        while addr < end.as_u64() {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+            let indices = [page.p4_index(), page.p3_index(), page.p2_index(), page.p1_index()];
+            let mut table = unsafe { table_at(space.pml4_frame) };
+            // Bytes covered by one entry of the current table
+            let mut span = 1u64 << 39;
+            for index in indices {
+                let flags = table[index].flags();
+                if flags.contains(KERNEL_SHARED) {
+                    return Some(VirtAddr::new(addr));
+                }
+                if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) || span == Size4KiB::SIZE {
+                    break;
+                }
+                table = unsafe { table_at(PhysFrame::containing_address(table[index].addr())) };
+                span /= 512;
+            }
+            addr = (addr & !(span - 1)) + span;
+        }
+        None
+    }
+
+    pub fn translate(&self, space: &AddressSpace, addr: VirtAddr) -> Option<PhysAddr> {
+        let mapper = unsafe { space.mapper() };
+        mapper.translate_addr(addr)
+    }
+
+    /// Look up the frame and flags backing a 4 KiB page
+    pub fn translate_page(&self, space: &AddressSpace, page: Page) -> Option<(PhysFrame, PageTableFlags)> {
+        let mapper = unsafe { space.mapper() };
+        match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped { frame, offset, flags } => {
+                Some((PhysFrame::containing_address(frame.start_address() + offset), flags))
+            }
+            _ => None,
+        }
+    }
+
+    /// Find the entry for `addr` in the table of the given level without creating any tables
+    fn table_entry(&self, space: &AddressSpace, addr: VirtAddr, level: usize) -> Option<&'static mut PageTableEntry> {
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let indices = [page.p4_index(), page.p3_index(), page.p2_index(), page.p1_index()];
+        let mut table = unsafe { table_at(space.pml4_frame) };
+        for index in &indices[..4 - level] {
+            let entry = &table[*index];
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+                return None;
+            }
+            table = unsafe { table_at(PhysFrame::containing_address(entry.addr())) };
+        }
+        Some(&mut table[indices[4 - level]])
+    }
+
+    /// Find the level 1 entry for `page` without creating any tables
+    fn leaf_entry(&self, space: &AddressSpace, page: Page) -> Option<&'static mut PageTableEntry> {
+        self.table_entry(space, page.start_address(), 1)
+    }
+
+    /// The level 2 entry of a 2 MiB user page covering `addr`
+    fn huge_entry(&self, space: &AddressSpace, addr: VirtAddr) -> Option<&'static mut PageTableEntry> {
+        self.table_entry(space, addr, 2).filter(|entry| {
+            let flags = entry.flags();
+            flags.contains(PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE | PageTableFlags::USER_ACCESSIBLE)
+                && !flags.contains(KERNEL_SHARED)
+        })
+    }
+
+    /// Whether nothing at all is mapped in the 2 MiB region of `page`
+    pub fn huge_slot_empty(&self, space: &AddressSpace, page: Page<Size2MiB>) -> bool {
+        self.table_entry(space, page.start_address(), 2).map_or(true, |entry| entry.is_unused())
+    }
+
+    /// Replace a 2 MiB user mapping with a page table mapping the same frames
+    fn split_entry(&self, entry: &mut PageTableEntry) -> Result<(), &'static str> {
+        let table_frame = allocate_zeroed_frame().ok_or("Out of memory for page tables")?;
+        let flags = entry.flags() - PageTableFlags::HUGE_PAGE;
+        let base = entry.addr();
+        let table = unsafe { table_at(table_frame) };
+        for (i, pte) in table.iter_mut().enumerate() {
+            pte.set_addr(base + i as u64 * Size4KiB::SIZE, flags);
+        }
+        entry.set_frame(table_frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE);
         Ok(())
     }
+
+    /// Break the 2 MiB user page covering `addr` back into 4 KiB pages
+    pub fn split_huge_page(&self, space: &AddressSpace, addr: VirtAddr) -> Result<(), &'static str> {
+        let entry = self.huge_entry(space, addr).ok_or("Not a huge page")?;
+        self.split_entry(entry)?;
+
+        let huge = Page::<Size2MiB>::containing_address(addr);
+        flush_page(space, huge);
+        let first = Page::<Size4KiB>::containing_address(huge.start_address());
+        for page in Page::range(first, first + 512) {
+            PAGE_RECLAIMER.add_page(space, page);
+        }
+        HUGE_PAGE_ALLOCATOR.count_split();
+        Ok(())
+    }
+
+    /// Replace the 4 KiB pages of a 2 MiB region with a single huge page mapped with `flags`.
+    ///
+    /// Only regions of private, resident pages with at most `max_empty` holes
+    /// qualify; returns false for anything else.
+    pub fn collapse_huge_page(
+        &self,
+        space: &AddressSpace,
+        huge: Page<Size2MiB>,
+        flags: PageTableFlags,
+        max_empty: usize,
+    ) -> Result<bool, &'static str> {
+        let Some(pd_entry) = self.table_entry(space, huge.start_address(), 2) else {
+            return Ok(false);
+        };
+        let pd_flags = pd_entry.flags();
+        if !pd_flags.contains(PageTableFlags::PRESENT) || pd_flags.intersects(PageTableFlags::HUGE_PAGE | KERNEL_SHARED) {
+            return Ok(false);
+        }
+
+        let table_frame = PhysFrame::containing_address(pd_entry.addr());
+        let table = unsafe { table_at(table_frame) };
+        let mut empty = 0;
+        for entry in table.iter() {
+            if entry.is_unused() {
+                empty += 1;
+                continue;
+            }
+            let entry_flags = entry.flags();
+            if !entry_flags.contains(PageTableFlags::PRESENT)
+                || entry_flags.intersects(KERNEL_SHARED | COPY_ON_WRITE)
+                || PMM.frame_ref_count(PhysFrame::containing_address(entry.addr())) > 1
+            {
+                return Ok(false);
+            }
+        }
+        if empty > max_empty {
+            return Ok(false);
+        }
+
+        let frame = HUGE_PAGE_ALLOCATOR.allocate_2mb().ok_or("No huge frame available")?;
+        // Unmapped before the copy, or a thread running on another CPU could
+        // write to a small page after it was copied. Until the huge page is
+        // in, its faults wait for the map lock the caller holds.
+        pd_entry.set_unused();
+        flush_space(space);
+        for (i, entry) in table.iter().enumerate() {
+            let destination = PhysFrame::containing_address(frame.start_address() + i as u64 * Size4KiB::SIZE);
+            if entry.is_unused() {
+                unsafe { frame_bytes(destination) }.fill(0);
+            } else {
+                copy_frame(PhysFrame::containing_address(entry.addr()), destination);
+            }
+        }
+        pd_entry.set_addr(frame.start_address(), flags | PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE);
+
+        for entry in table.iter_mut().filter(|entry| !entry.is_unused()) {
+            deallocate_frame(PhysFrame::containing_address(entry.addr()));
+            entry.set_unused();
+        }
+        deallocate_frame(table_frame);
+        // Reclaim tracks the huge page's small pages again if it is ever split
+        PAGE_RECLAIMER.forget_range(space, huge.start_address(), huge.start_address() + Size2MiB::SIZE);
+        Ok(true)
+    }
+
+    /// Call `f` with every level 1 entry in the user half that is in use
+    fn for_each_user_entry(&self, space: &AddressSpace, mut f: impl FnMut(Page, &mut PageTableEntry)) {
+        let walkable = |entry: &PageTableEntry| {
+            let flags = entry.flags();
+            flags.contains(PageTableFlags::PRESENT) && !flags.intersects(PageTableFlags::HUGE_PAGE | KERNEL_SHARED)
+        };
+        let pml4 = unsafe { table_at(space.pml4_frame) };
+        for (i4, e4) in pml4.iter().enumerate().take(KERNEL_PML4_START).filter(|(_, e)| walkable(e)) {
+            let pdpt = unsafe { table_at(PhysFrame::containing_address(e4.addr())) };
+            for (i3, e3) in pdpt.iter().enumerate().filter(|(_, e)| walkable(e)) {
+                let pd = unsafe { table_at(PhysFrame::containing_address(e3.addr())) };
+                for (i2, e2) in pd.iter().enumerate().filter(|(_, e)| walkable(e)) {
+                    let pt = unsafe { table_at(PhysFrame::containing_address(e2.addr())) };
+                    for (i1, e1) in pt.iter_mut().enumerate() {
+                        if e1.is_unused() || e1.flags().contains(KERNEL_SHARED) {
+                            continue;
+                        }
+                        let page = Page::from_page_table_indices(
+                            PageTableIndex::new(i4 as u16),
+                            PageTableIndex::new(i3 as u16),
+                            PageTableIndex::new(i2 as u16),
+                            PageTableIndex::new(i1 as u16),
+                        );
+                        f(page, e1);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Call `f` with every level 2 entry in the user half that maps a 2 MiB page
+    fn for_each_user_huge_entry(&self, space: &AddressSpace, mut f: impl FnMut(Page<Size2MiB>, &mut PageTableEntry)) {
+        let walkable = |entry: &PageTableEntry| {
+            let flags = entry.flags();
+            flags.contains(PageTableFlags::PRESENT) && !flags.intersects(PageTableFlags::HUGE_PAGE | KERNEL_SHARED)
+        };
+        let pml4 = unsafe { table_at(space.pml4_frame) };
+        for (i4, e4) in pml4.iter().enumerate().take(KERNEL_PML4_START).filter(|(_, e)| walkable(e)) {
+            let pdpt = unsafe { table_at(PhysFrame::containing_address(e4.addr())) };
+            for (i3, e3) in pdpt.iter().enumerate().filter(|(_, e)| walkable(e)) {
+                let pd = unsafe { table_at(PhysFrame::containing_address(e3.addr())) };
+                for (i2, e2) in pd.iter_mut().enumerate() {
+                    let flags = e2.flags();
+                    if !flags.contains(PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE) || flags.contains(KERNEL_SHARED) {
+                        continue;
+                    }
+                    let page = Page::from_page_table_indices_2mib(
+                        PageTableIndex::new(i4 as u16),
+                        PageTableIndex::new(i3 as u16),
+                        PageTableIndex::new(i2 as u16),
+                    );
+                    f(page, e2);
+                }
+            }
+        }
+    }
+
+    /// 2 MiB user pages of `space`
+    pub fn huge_pages(&self, space: &AddressSpace) -> Vec<Page<Size2MiB>> {
+        let mut pages = Vec::new();
+        self.for_each_user_huge_entry(space, |page, _| pages.push(page));
+        pages
+    }
+
+    /// Resident user pages of `space` and the frames backing them
+    pub fn resident_pages(&self, space: &AddressSpace) -> Vec<(Page, PhysFrame)> {
+        let mut pages = Vec::new();
+        self.for_each_user_entry(space, |page, entry| {
+            if entry.flags().contains(PageTableFlags::PRESENT) {
+                pages.push((page, PhysFrame::containing_address(entry.addr())));
+            }
+        });
+        pages
+    }
+
+    /// Resident and swapped-out user pages of `space`, counting huge pages as 512
+    pub fn memory_usage(&self, space: &AddressSpace) -> (usize, usize) {
+        let (mut resident, mut swapped) = (0, 0);
+        self.for_each_user_entry(space, |_, entry| {
+            let flags = entry.flags();
+            if flags.contains(PageTableFlags::PRESENT) {
+                resident += 1;
+            } else if flags.intersects(SWAPPED | COMPRESSED) {
+                swapped += 1;
+            }
+        });
+        self.for_each_user_huge_entry(space, |_, _| resident += 512);
+        (resident, swapped)
+    }
+
+    /// Report whether a resident page was accessed since the last call, clearing the bit
+    pub fn test_and_clear_accessed(&self, space: &AddressSpace, page: Page) -> Option<bool> {
+        let entry = self.leaf_entry(space, page)?;
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        if !flags.contains(PageTableFlags::ACCESSED) {
+            return Some(false);
+        }
+        entry.set_flags(flags - PageTableFlags::ACCESSED);
+        // Other CPUs keep their entries; a stale accessed bit only delays reclaim
+        if needs_flush(space, page) {
+            tlb::flush(page.start_address());
+        }
+        Some(true)
+    }
+
+    /// Pages of `space` that live on a swap device in a slot accepted by `filter`
+    pub fn swapped_pages(&self, space: &AddressSpace, filter: impl Fn(u64) -> bool) -> Vec<(Page, u64)> {
+        let mut pages = Vec::new();
+        self.for_each_user_entry(space, |page, entry| {
+            let flags = entry.flags();
+            let slot = entry.addr().as_u64() >> 12;
+            if !flags.contains(PageTableFlags::PRESENT) && flags.contains(SWAPPED) && filter(slot) {
+                pages.push((page, slot));
+            }
+        });
+        pages
+    }
+
+    /// Swap entry of a page that is not resident: the entry's flags and its slot
+    pub fn swap_entry(&self, space: &AddressSpace, page: Page) -> Option<(PageTableFlags, u64)> {
+        let entry = self.leaf_entry(space, page)?;
+        let flags = entry.flags();
+        if flags.contains(PageTableFlags::PRESENT) || !flags.intersects(SWAPPED | COMPRESSED) {
+            return None;
+        }
+        Some((flags, entry.addr().as_u64() >> 12))
+    }
+
+    /// Empty the entry of a resident user page and flush it from every CPU,
+    /// returning the frame and the flags the entry had last.
+    ///
+    /// Once this returns no CPU can write the frame, and the dirty bit covers
+    /// every write made before, so the contents can be saved. The entry is
+    /// left for `set_swap_entry` or `restore_mapping`. A huge page covering
+    /// `page` is split first.
+    pub fn clear_mapping(&self, space: &AddressSpace, page: Page) -> Result<(PhysFrame, PageTableFlags), &'static str> {
+        if self.huge_entry(space, page.start_address()).is_some() {
+            self.split_huge_page(space, page.start_address())?;
+        }
+        let entry = self.leaf_entry(space, page).ok_or("Page not mapped")?;
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return Err("Page not mapped");
+        }
+        // Swapped in one go: the CPU may set the dirty bit right up to the shootdown
+        let old = unsafe { &*(entry as *mut PageTableEntry as *const AtomicU64) }.swap(0, Ordering::AcqRel);
+        flush_page(space, page);
+        let flags = PageTableFlags::from_bits_truncate(old);
+        Ok((PhysFrame::containing_address(PhysAddr::new(old & 0x000f_ffff_ffff_f000)), flags))
+    }
+
+    /// Map `page` again as it was before `clear_mapping`
+    pub fn restore_mapping(&self, space: &AddressSpace, page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), &'static str> {
+        let entry = self.leaf_entry(space, page).ok_or("Page not mapped")?;
+        entry.set_addr(frame.start_address(), flags);
+        Ok(())
+    }
+
+    /// Clear the dirty bit of a resident page and flush it from every CPU,
+    /// returning its frame if it was dirty. A write from then on sets the bit
+    /// again, so the frame can be saved without losing one.
+    pub fn take_dirty(&self, space: &AddressSpace, page: Page) -> Option<PhysFrame> {
+        let entry = self.leaf_entry(space, page)?;
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        let old = unsafe { &*(entry as *mut PageTableEntry as *const AtomicU64) }
+            .fetch_and(!PageTableFlags::DIRTY.bits(), Ordering::AcqRel);
+        if !PageTableFlags::from_bits_truncate(old).contains(PageTableFlags::DIRTY) {
+            return None;
+        }
+        flush_page(space, page);
+        Some(PhysFrame::containing_address(PhysAddr::new(old & 0x000f_ffff_ffff_f000)))
+    }
+
+    /// Mark a resident page dirty again after saving it failed
+    pub fn set_dirty(&self, space: &AddressSpace, page: Page) {
+        if let Some(entry) = self.leaf_entry(space, page).filter(|entry| entry.flags().contains(PageTableFlags::PRESENT)) {
+            unsafe { &*(entry as *mut PageTableEntry as *const AtomicU64) }
+                .fetch_or(PageTableFlags::DIRTY.bits(), Ordering::AcqRel);
+        }
+    }
+
+    /// Fill the entry `clear_mapping` emptied with a swap entry, keeping the
+    /// page's flags for swap-in. `kind` is either `SWAPPED` or `COMPRESSED`.
+    pub fn set_swap_entry(&self, space: &AddressSpace, page: Page, flags: PageTableFlags, kind: PageTableFlags, slot: u64) -> Result<(), &'static str> {
+        let entry = self.leaf_entry(space, page).ok_or("Page not mapped")?;
+        entry.set_addr(PhysAddr::new(slot << 12), (flags - PageTableFlags::PRESENT) | kind);
+        Ok(())
+    }
+
+    /// Bring a swapped or compressed page back into a fresh frame and map it
+    pub fn swap_in(&self, space: &AddressSpace, page: Page) -> Result<PhysFrame, &'static str> {
+        let (flags, slot) = self.swap_entry(space, page).ok_or("Page not swapped")?;
+        let frame = allocate_user_frame().ok_or("Out of physical memory")?;
+        if let Err(e) = read_swapped(flags, slot, frame) {
+            deallocate_frame(frame);
+            return Err(e);
+        }
+
+        if let Some(entry) = self.leaf_entry(space, page) {
+            entry.set_unused();
+        }
+        if let Err(e) = self.map_to(space, page, frame, flags - SWAPPED - COMPRESSED) {
+            deallocate_frame(frame);
+            return Err(e);
+        }
+        release_swapped(flags, slot);
+        Ok(frame)
+    }
+
+    /// Give `page` a private, writable frame after a write to a copy-on-write mapping
+    pub fn break_cow(&self, space: &AddressSpace, page: Page) -> Result<(), &'static str> {
+        let entry = self.leaf_entry(space, page).ok_or("Page not mapped")?;
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) || !flags.contains(COPY_ON_WRITE) {
+            return Err("Page is not copy-on-write");
+        }
+
+        let frame = PhysFrame::containing_address(entry.addr());
+        let writable = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+        if PMM.frame_ref_count(frame) == 1 {
+            // Every other mapping is gone, so the frame is ours already
+            entry.set_flags(writable);
+        } else {
+            let private = allocate_user_frame().ok_or("Out of physical memory")?;
+            copy_frame(frame, private);
+            entry.set_frame(private, writable);
+            deallocate_frame(frame);
+        }
+
+        flush_page(space, page);
+        Ok(())
+    }
+
+    /// Create an empty user address space that shares the kernel's mappings
+    pub fn create_address_space(&self) -> Result<AddressSpace, &'static str> {
+        let kernel_pml4 = self.kernel_pml4.read().ok_or("VMM not initialized")?;
+        let pml4_frame = allocate_zeroed_frame().ok_or("Out of physical memory")?;
+        let space = AddressSpace { pml4_frame };
+
+        let (kernel_table, table) = unsafe { (table_at(kernel_pml4), table_at(pml4_frame)) };
+        for i in KERNEL_PML4_START..512 {
+            table[i] = kernel_table[i].clone();
+        }
+
+        // The bootloader also maps the kernel image and physical memory in the
+        // lower half, where user mappings may share tables with them. Give every
+        // address space its own copy of those tables, flagging the kernel's
+        // entries so that teardown leaves the underlying frames alone.
+        for i in 0..KERNEL_PML4_START {
+            let entry = &kernel_table[i];
+            if let Ok(frame) = entry.frame() {
+                match self.share_table(frame, 3) {
+                    Ok(copy) => table[i].set_frame(copy, entry.flags()),
+                    Err(e) => {
+                        self.destroy_address_space(space);
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        Ok(space)
+    }
+
+    /// Copy a kernel page table, pointing at the same frames as the original
+    fn share_table(&self, source: PhysFrame, level: u8) -> Result<PhysFrame, &'static str> {
+        let copy = allocate_zeroed_frame().ok_or("Out of physical memory")?;
+        let (source_table, copy_table) = unsafe { (table_at(source), table_at(copy)) };
+
+        for (source_entry, copy_entry) in source_table.iter().zip(copy_table.iter_mut()) {
+            if source_entry.is_unused() {
+                continue;
+            }
+            let flags = source_entry.flags();
+            if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+                copy_entry.set_addr(source_entry.addr(), flags | KERNEL_SHARED);
+                continue;
+            }
+            match self.share_table(PhysFrame::containing_address(source_entry.addr()), level - 1) {
+                Ok(frame) => copy_entry.set_frame(frame, flags),
+                Err(e) => {
+                    self.free_table(copy, level);
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(copy)
+    }
+
+    /// Create a copy of `parent` whose user pages are shared copy-on-write
+    pub fn clone_address_space(&self, parent: &AddressSpace) -> Result<AddressSpace, &'static str> {
+        // Huge pages are shared as their 4 KiB parts
+        for huge in self.huge_pages(parent) {
+            self.split_huge_page(parent, huge.start_address())?;
+        }
+
+        let pml4_frame = allocate_zeroed_frame().ok_or("Out of physical memory")?;
+        let child = AddressSpace { pml4_frame };
+
+        let (parent_table, child_table) = unsafe { (table_at(parent.pml4_frame), table_at(pml4_frame)) };
+        for i in KERNEL_PML4_START..512 {
+            child_table[i] = parent_table[i].clone();
+        }
+
+        for i in 0..KERNEL_PML4_START {
+            let entry = &parent_table[i];
+            if let Ok(frame) = entry.frame() {
+                match self.copy_table(frame, 3) {
+                    Ok(copy) => child_table[i].set_frame(copy, entry.flags()),
+                    Err(e) => {
+                        self.destroy_address_space(child);
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        // The parent's writable pages were just made read-only
+        flush_space(parent);
+        Ok(child)
+    }
+
+    /// Recursively copy a user page table of the given level, sharing the pages it maps
+    fn copy_table(&self, source: PhysFrame, level: u8) -> Result<PhysFrame, &'static str> {
+        let copy = allocate_zeroed_frame().ok_or("Out of physical memory")?;
+        let (source_table, copy_table) = unsafe { (table_at(source), table_at(copy)) };
+
+        for (source_entry, copy_entry) in source_table.iter_mut().zip(copy_table.iter_mut()) {
+            if source_entry.is_unused() {
+                continue;
+            }
+            let flags = source_entry.flags();
+            if level == 1 && flags.intersects(SWAPPED | COMPRESSED) && !flags.contains(PageTableFlags::PRESENT) {
+                // Each copy needs its own backing store, so the child gets the page resident
+                let restored = allocate_frame().ok_or("Out of physical memory").and_then(|page_copy| {
+                    let slot = source_entry.addr().as_u64() >> 12;
+                    read_swapped(flags, slot, page_copy).map(|_| page_copy).map_err(|e| {
+                        deallocate_frame(page_copy);
+                        e
+                    })
+                });
+                match restored {
+                    Ok(page_copy) => {
+                        let resident = (flags - SWAPPED - COMPRESSED) | PageTableFlags::PRESENT;
+                        if flags.contains(SHARED_MAPPING) {
+                            // Both sides must see the same page, so the parent gets it back too
+                            release_swapped(flags, source_entry.addr().as_u64() >> 12);
+                            source_entry.set_frame(page_copy, resident);
+                            PMM.get_frame(page_copy);
+                        }
+                        copy_entry.set_frame(page_copy, resident);
+                        continue;
+                    }
+                    Err(e) => {
+                        self.free_table(copy, level);
+                        return Err(e);
+                    }
+                }
+            }
+            if flags.contains(KERNEL_SHARED) || !flags.contains(PageTableFlags::PRESENT) {
+                copy_entry.set_addr(source_entry.addr(), flags);
+                continue;
+            }
+
+            if level == 1 {
+                // Share the page; private pages become copy-on-write on both sides
+                // so that neither can write to it, even after mprotect
+                let mut shared = flags;
+                if !flags.contains(SHARED_MAPPING) {
+                    shared = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                    source_entry.set_flags(shared);
+                }
+                let frame = PhysFrame::containing_address(source_entry.addr());
+                PMM.get_frame(frame);
+                copy_entry.set_frame(frame, shared);
+                continue;
+            }
+
+            let copied = if flags.contains(PageTableFlags::HUGE_PAGE) {
+                Err("Huge user pages cannot be copied")
+            } else {
+                self.copy_table(PhysFrame::containing_address(source_entry.addr()), level - 1)
+            };
+
+            match copied {
+                Ok(new_frame) => copy_entry.set_frame(new_frame, flags),
+                Err(e) => {
+                    self.free_table(copy, level);
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(copy)
+    }
+
+    /// Free a page table of the given level, every table below it and the user pages they map
+    fn free_table(&self, table_frame: PhysFrame, level: u8) {
+        let table = unsafe { table_at(table_frame) };
+        for entry in table.iter_mut() {
+            if entry.is_unused() {
+                continue;
+            }
+            let flags = entry.flags();
+            let frame = PhysFrame::containing_address(entry.addr());
+            if level == 1 && !flags.contains(PageTableFlags::PRESENT) {
+                release_swapped(flags, entry.addr().as_u64() >> 12);
+            } else if level == 2
+                && flags.contains(PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE)
+                && !flags.contains(KERNEL_SHARED)
+            {
+                HUGE_PAGE_ALLOCATOR.free_2mb(PhysFrame::containing_address(entry.addr()));
+            } else if !flags.contains(PageTableFlags::PRESENT)
+                || flags.contains(KERNEL_SHARED)
+                || flags.contains(PageTableFlags::HUGE_PAGE)
+            {
+                // Not a frame owned by this address space
+            } else if level == 1 {
+                deallocate_frame(frame);
+            } else {
+                self.free_table(frame, level - 1);
+            }
+            entry.set_unused();
+        }
+        deallocate_frame(table_frame);
+    }
+
+    /// Tear down the lower half of an address space and free its PML4
+    pub fn destroy_address_space(&self, space: AddressSpace) {
+        drop(space);
+    }
+
+    fn teardown(&self, pml4_frame: PhysFrame) {
+        if Some(pml4_frame) == self.kernel_pml4.read() {
+            return;
+        }
+        PAGE_RECLAIMER.forget_address_space(pml4_frame);
+        smp::release_address_space(pml4_frame);
+        if Cr3::read().0 == pml4_frame {
+            if let Some(kernel) = self.kernel_address_space() {
+                self.switch_to(&kernel);
+            }
+        }
+
+        let table = unsafe { table_at(pml4_frame) };
+        for entry in table.iter_mut().take(KERNEL_PML4_START) {
+            if let Ok(frame) = entry.frame() {
+                self.free_table(frame, 3);
+            }
+            entry.set_unused();
+        }
+        deallocate_frame(pml4_frame);
+    }
+
+    /// Load the address space into CR3
+    pub fn switch_to(&self, space: &AddressSpace) {
+        if !space.is_active() {
+            without_interrupts(|| {
+                this_cpu().set_active_pml4(space.pml4_frame.start_address().as_u64());
+                unsafe {
+                    Cr3::write(space.pml4_frame, Cr3Flags::empty());
+                }
+            });
+        }
+    }
 }
 
+pub static VMM: VirtualMemoryManager = VirtualMemoryManager::new();
@@ -1,5 +1,5 @@
 use crate::net::ip::{IPv4Packet, IPAddress};
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::vec::Vec;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -16,15 +16,15 @@ pub struct FirewallRule {
 }
 
 pub struct Firewall {
-    rules: Mutex<Vec<FirewallRule>>,
-    default_action: Mutex<FirewallAction>,
+    rules: SpinLock<Vec<FirewallRule>>,
+    default_action: SpinLock<FirewallAction>,
 }
 
 impl Firewall {
     pub const fn new() -> Self {
         Firewall {
-            rules: Mutex::new(Vec::new()),
-            default_action: Mutex::new(FirewallAction::Allow),
+            rules: SpinLock::new(Vec::new()),
+            default_action: SpinLock::new(FirewallAction::Allow),
         }
     }
 
@@ -1,6 +1,6 @@
 use crate::net::ip::{IPv4Packet, IPAddress};
 use crate::net::tcp::TCPHeader;
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::vec::Vec;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -32,17 +32,17 @@ pub enum ConnectionState {
 }
 
 pub struct StatefulFirewall {
-    rules: Mutex<Vec<FirewallRule>>,
-    connections: Mutex<alloc::collections::BTreeMap<(IPAddress, IPAddress, u16, u16), ConnectionState>>,
-    default_action: Mutex<FirewallAction>,
+    rules: SpinLock<Vec<FirewallRule>>,
+    connections: SpinLock<alloc::collections::BTreeMap<(IPAddress, IPAddress, u16, u16), ConnectionState>>,
+    default_action: SpinLock<FirewallAction>,
 }
 
 impl StatefulFirewall {
     pub const fn new() -> Self {
         StatefulFirewall {
-            rules: Mutex::new(Vec::new()),
-            connections: Mutex::new(alloc::collections::BTreeMap::new()),
-            default_action: Mutex::new(FirewallAction::Deny),
+            rules: SpinLock::new(Vec::new()),
+            connections: SpinLock::new(alloc::collections::BTreeMap::new()),
+            default_action: SpinLock::new(FirewallAction::Deny),
         }
     }
 
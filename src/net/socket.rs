@@ -1,9 +1,15 @@
-use spin::Mutex;
-use alloc::collections::BTreeMap;
+use core::mem::{align_of, size_of};
+use crate::sync::SpinLock;
+use alloc::collections::{BTreeMap, VecDeque};
+use crate::memory::{SlabBox, SlabCache};
 use crate::process::ProcessId;
 use crate::net::tcp::{TCPConnection, TCPState};
 use crate::net::udp::UDPPacket;
 use crate::net::ip::IPAddress;
+use crate::wait::WaitQueue;
+
+/// Bytes a socket holds for its reader before it drops new data
+pub const SOCKET_RECV_BUFFER: usize = 64 * 1024;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SocketType {
@@ -29,6 +35,8 @@ pub struct Socket {
     pub remote_port: u16,
     pub owner: ProcessId,
     pub tcp_conn: Option<TCPConnection>,
+    /// Data that arrived and was not received yet
+    pub received: VecDeque<u8>,
 }
 
 impl Socket {
@@ -42,6 +50,7 @@ impl Socket {
             remote_port: 0,
             owner,
             tcp_conn: None,
+            received: VecDeque::new(),
         }
     }
 
@@ -83,37 +92,39 @@ impl Socket {
         }
     }
 
+    /// Take what has arrived, up to the size of `buffer`; 0 if nothing has
     pub fn recv(&mut self, buffer: &mut [u8]) -> Result<usize, &'static str> {
-        match self.socket_type {
-            SocketType::UDP => {
-                // TODO: Receive UDP packet
-                Ok(0)
-            }
-            SocketType::TCP => {
-                if self.state != SocketState::Connected {
-                    return Err("Socket not connected");
-                }
-                // TODO: Receive TCP data
-                Ok(0)
-            }
+        if self.socket_type == SocketType::TCP && self.state != SocketState::Connected && self.received.is_empty() {
+            return Err("Socket not connected");
         }
+        let len = buffer.len().min(self.received.len());
+        for (byte, data) in buffer.iter_mut().zip(self.received.drain(..len)) {
+            *byte = data;
+        }
+        Ok(len)
     }
 }
 
+/// Dedicated slab cache for socket objects
+pub static SOCKET_CACHE: SlabCache = SlabCache::new("socket", size_of::<Socket>(), align_of::<Socket>());
+
 pub struct SocketManager {
-    sockets: Mutex<BTreeMap<u64, Socket>>,
-    next_fd: Mutex<u64>,
+    sockets: SpinLock<BTreeMap<u64, SlabBox<Socket>>>,
+    next_fd: SpinLock<u64>,
+    /// Threads blocked in `recv` on any socket
+    readers: WaitQueue,
 }
 
 impl SocketManager {
     pub const fn new() -> Self {
         SocketManager {
-            sockets: Mutex::new(BTreeMap::new()),
-            next_fd: Mutex::new(3), // Start after stdin, stdout, stderr
+            sockets: SpinLock::new(BTreeMap::new()),
+            next_fd: SpinLock::new(3), // Start after stdin, stdout, stderr
+            readers: WaitQueue::new(),
         }
     }
 
-    pub fn create_socket(&self, socket_type: SocketType, owner: ProcessId) -> u64 {
+    pub fn create_socket(&self, socket_type: SocketType, owner: ProcessId) -> Result<u64, &'static str> {
         let fd = {
             let mut next = self.next_fd.lock();
             let fd = *next;
@@ -121,19 +132,55 @@ impl SocketManager {
             fd
         };
         
-        let socket = Socket::new(socket_type, owner);
+        let socket = SlabBox::new(&SOCKET_CACHE, Socket::new(socket_type, owner))?;
         self.sockets.lock().insert(fd, socket);
-        fd
+        Ok(fd)
     }
 
     pub fn get_socket(&self, fd: u64) -> Option<Socket> {
-        self.sockets.lock().get(&fd).cloned()
+        self.sockets.lock().get(&fd).map(|socket| Socket::clone(socket))
     }
 
-    pub fn get_socket_mut(&self, fd: u64) -> Option<spin::MutexGuard<Socket>> {
+    pub fn get_socket_mut(&self, fd: u64) -> Option<crate::sync::SpinLockGuard<Socket>> {
         // This is a simplified version - in reality we'd need a different structure
         None
     }
+
+    /// Queue data the protocol layer received for socket `fd` and wake its
+    /// readers; returns how much fit in the receive buffer
+    pub fn deliver(&self, fd: u64, data: &[u8]) -> Result<usize, &'static str> {
+        let accepted = {
+            let mut sockets = self.sockets.lock();
+            let socket = sockets.get_mut(&fd).ok_or("Bad socket descriptor")?;
+            let accepted = data.len().min(SOCKET_RECV_BUFFER - socket.received.len());
+            socket.received.extend(&data[..accepted]);
+            accepted
+        };
+        self.readers.wake_all();
+        Ok(accepted)
+    }
+
+    /// Receive from socket `fd` into `buffer`, blocking until data arrives or
+    /// the socket is closed
+    pub fn recv(&self, fd: u64, buffer: &mut [u8]) -> Result<usize, &'static str> {
+        let mut result = Ok(0);
+        self.readers.wait_until_interruptible(|| {
+            let mut sockets = self.sockets.lock();
+            let Some(socket) = sockets.get_mut(&fd) else {
+                result = Err("Bad socket descriptor");
+                return true;
+            };
+            result = socket.recv(buffer);
+            !matches!(result, Ok(0)) || socket.state == SocketState::Closed || buffer.is_empty()
+        })?;
+        result
+    }
+
+    /// Close every socket owned by a process that exited, waking anyone blocked on them
+    pub fn close_process(&self, owner: ProcessId) {
+        self.sockets.lock().retain(|_, socket| socket.owner != owner);
+        self.readers.wake_all();
+    }
 }
 
 pub static SOCKET_MANAGER: SocketManager = SocketManager::new();
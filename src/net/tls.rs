@@ -1,5 +1,5 @@
 use crate::security::random::SecureRandom;
-use spin::Mutex;
+use crate::sync::SpinLock;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TlsVersion {
@@ -61,15 +61,15 @@ impl TlsSession {
 }
 
 pub struct TlsManager {
-    sessions: Mutex<alloc::collections::BTreeMap<u64, TlsSession>>,
-    next_session_id: Mutex<u64>,
+    sessions: SpinLock<alloc::collections::BTreeMap<u64, TlsSession>>,
+    next_session_id: SpinLock<u64>,
 }
 
 impl TlsManager {
     pub const fn new() -> Self {
         TlsManager {
-            sessions: Mutex::new(alloc::collections::BTreeMap::new()),
-            next_session_id: Mutex::new(1),
+            sessions: SpinLock::new(alloc::collections::BTreeMap::new()),
+            next_session_id: SpinLock::new(1),
         }
     }
 
@@ -90,7 +90,7 @@ impl TlsManager {
         self.sessions.lock().get(&session_id).copied()
     }
 
-    pub fn get_session_mut(&self, session_id: u64) -> Option<spin::MutexGuard<TlsSession>> {
+    pub fn get_session_mut(&self, session_id: u64) -> Option<crate::sync::SpinLockGuard<TlsSession>> {
         // Simplified - would need different structure for mutable access
         None
     }
@@ -1,6 +1,6 @@
 use crate::net::ip::{IPAddress, IPv4Packet};
 use crate::net::tls::TlsManager;
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -19,15 +19,15 @@ pub struct VpnTunnel {
 }
 
 pub struct VpnManager {
-    tunnels: Mutex<BTreeMap<u64, VpnTunnel>>,
-    next_tunnel_id: Mutex<u64>,
+    tunnels: SpinLock<BTreeMap<u64, VpnTunnel>>,
+    next_tunnel_id: SpinLock<u64>,
 }
 
 impl VpnManager {
     pub const fn new() -> Self {
         VpnManager {
-            tunnels: Mutex::new(BTreeMap::new()),
-            next_tunnel_id: Mutex::new(1),
+            tunnels: SpinLock::new(BTreeMap::new()),
+            next_tunnel_id: SpinLock::new(1),
         }
     }
 
@@ -1,4 +1,4 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
 
 pub struct CacheEntry<T> {
@@ -8,14 +8,14 @@ pub struct CacheEntry<T> {
 }
 
 pub struct LRUCache<K, V> {
-    entries: Mutex<BTreeMap<K, CacheEntry<V>>>,
+    entries: SpinLock<BTreeMap<K, CacheEntry<V>>>,
     max_size: usize,
 }
 
 impl<K: core::cmp::Ord, V> LRUCache<K, V> {
     pub fn new(max_size: usize) -> Self {
         LRUCache {
-            entries: Mutex::new(BTreeMap::new()),
+            entries: SpinLock::new(BTreeMap::new()),
             max_size,
         }
     }
@@ -1,4 +1,4 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::VecDeque;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -16,15 +16,15 @@ pub struct IORequest {
 }
 
 pub struct IOScheduler {
-    scheduler_type: Mutex<IOSchedulerType>,
-    request_queue: Mutex<VecDeque<IORequest, 256>>,
+    scheduler_type: SpinLock<IOSchedulerType>,
+    request_queue: SpinLock<VecDeque<IORequest, 256>>,
 }
 
 impl IOScheduler {
     pub const fn new() -> Self {
         IOScheduler {
-            scheduler_type: Mutex::new(IOSchedulerType::Deadline),
-            request_queue: Mutex::new(VecDeque::new()),
+            scheduler_type: SpinLock::new(IOSchedulerType::Deadline),
+            request_queue: SpinLock::new(VecDeque::new()),
         }
     }
 
@@ -1,4 +1,4 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
 use core::sync::atomic::{AtomicU64, Ordering};
 
@@ -12,14 +12,14 @@ pub struct ProfileEntry {
 }
 
 pub struct Profiler {
-    entries: Mutex<BTreeMap<heapless::String<64>, ProfileEntry>>,
+    entries: SpinLock<BTreeMap<heapless::String<64>, ProfileEntry>>,
     enabled: AtomicU64,
 }
 
 impl Profiler {
     pub const fn new() -> Self {
         Profiler {
-            entries: Mutex::new(BTreeMap::new()),
+            entries: SpinLock::new(BTreeMap::new()),
             enabled: AtomicU64::new(0),
         }
     }
@@ -1,69 +1,460 @@
-use crate::process::ProcessId;
-use crate::scheduler::SCHEDULER;
-use spin::Mutex;
-use alloc::collections::BinaryHeap;
-use core::cmp::Ordering;
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-struct ScheduledProcess {
-    pid: ProcessId,
-    priority: u64,
+use crate::hardware::cpu::MAX_CPUS;
+use crate::thread::ThreadId;
+use crate::sync::{SpinLock, SpinLockGuard};
+use alloc::collections::BTreeSet;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+
+pub const NICE_MIN: i8 = -20;
+pub const NICE_MAX: i8 = 19;
+
+/// Load weight of a nice 0 task
+pub const NICE_0_WEIGHT: u64 = 1024;
+
+/// Weights for nice -20 to 19; each step changes a task's CPU share by about 10%
+const NICE_TO_WEIGHT: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291,
+    29154, 23254, 18705, 14949, 11916,
+    9548, 7620, 6100, 4904, 3906,
+    3121, 2501, 1991, 1586, 1277,
+    1024, 820, 655, 526, 423,
+    335, 272, 215, 172, 137,
+    110, 87, 70, 56, 45,
+    36, 29, 23, 18, 15,
+];
+
+/// Default period in which every runnable task should run once
+pub const DEFAULT_TARGET_LATENCY_NS: u64 = 24_000_000;
+/// Default shortest slice a task gets, however many are runnable
+pub const DEFAULT_MIN_GRANULARITY_NS: u64 = 3_000_000;
+/// Default virtual run time lead a waking task needs to preempt the running one
+pub const DEFAULT_WAKEUP_GRANULARITY_NS: u64 = 4_000_000;
+
+pub fn nice_to_weight(nice: i8) -> u64 {
+    NICE_TO_WEIGHT[(nice.clamp(NICE_MIN, NICE_MAX) - NICE_MIN) as usize]
+}
+
+/// `delta` of real time scaled to virtual time for a task of `weight`
+fn calc_delta_fair(delta: u64, weight: u64) -> u64 {
+    if weight == NICE_0_WEIGHT {
+        return delta;
+    }
+    (delta as u128 * NICE_0_WEIGHT as u128 / weight.max(1) as u128) as u64
+}
+
+/// Fair scheduling state of one task, kept with the task
+#[derive(Debug, Clone, Copy)]
+pub struct SchedEntity {
+    nice: i8,
+    weight: u64,
+    /// Run time in nanoseconds, scaled by `NICE_0_WEIGHT / weight`
     vruntime: u64,
+    /// Clock reading the run time was last charged up to
+    exec_start: u64,
+    sum_exec_runtime: u64,
+    /// `sum_exec_runtime` when the task was last picked, to measure its slice
+    prev_sum_exec_runtime: u64,
+    /// Queued or running on the run queue of `cpu`
+    on_rq: bool,
+    cpu: usize,
 }
 
-impl PartialOrd for ScheduledProcess {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+impl SchedEntity {
+    pub const fn new() -> Self {
+        SchedEntity {
+            nice: 0,
+            weight: NICE_0_WEIGHT,
+            vruntime: 0,
+            exec_start: 0,
+            sum_exec_runtime: 0,
+            prev_sum_exec_runtime: 0,
+            on_rq: false,
+            cpu: 0,
+        }
+    }
+
+    pub fn nice(&self) -> i8 {
+        self.nice
+    }
+
+    pub fn weight(&self) -> u64 {
+        self.weight
+    }
+
+    pub fn vruntime(&self) -> u64 {
+        self.vruntime
+    }
+
+    /// Total time the task has run, in nanoseconds
+    pub fn sum_exec_runtime(&self) -> u64 {
+        self.sum_exec_runtime
+    }
+
+    pub fn on_rq(&self) -> bool {
+        self.on_rq
+    }
+
+    /// CPU whose run queue the entity is on
+    pub fn cpu(&self) -> usize {
+        self.cpu
     }
+
+    /// Nice value for an entity that is on no run queue; `OptimizedScheduler::reweight` otherwise
+    pub fn set_nice(&mut self, nice: i8) {
+        self.nice = nice.clamp(NICE_MIN, NICE_MAX);
+        self.weight = nice_to_weight(self.nice);
+    }
+}
+
+/// Fair run queue of one CPU
+struct CfsRunQueue {
+    /// Runnable tasks ordered by virtual run time; the running one is not in it
+    timeline: BTreeSet<(u64, ThreadId)>,
+    curr: Option<ThreadId>,
+    /// Virtual run time of `curr` as of its last update
+    curr_vruntime: u64,
+    /// Total weight of the queued tasks and `curr`
+    load_weight: u64,
+    nr_running: usize,
+    /// Never decreases; where newly queued tasks are placed
+    min_vruntime: u64,
 }
 
-impl Ord for ScheduledProcess {
-    fn cmp(&self, other: &Self) -> Ordering {
-        // Lower vruntime = higher priority
-        other.vruntime.cmp(&self.vruntime)
+impl CfsRunQueue {
+    const fn new() -> Self {
+        CfsRunQueue {
+            timeline: BTreeSet::new(),
+            curr: None,
+            curr_vruntime: 0,
+            load_weight: 0,
+            nr_running: 0,
+            min_vruntime: 0,
+        }
+    }
+
+    fn leftmost(&self) -> Option<(u64, ThreadId)> {
+        self.timeline.first().copied()
+    }
+
+    fn update_min_vruntime(&mut self) {
+        let candidate = match (self.curr.map(|_| self.curr_vruntime), self.leftmost()) {
+            (Some(curr), Some((left, _))) => curr.min(left),
+            (Some(curr), None) => curr,
+            (None, Some((left, _))) => left,
+            (None, None) => return,
+        };
+        self.min_vruntime = self.min_vruntime.max(candidate);
+    }
+
+    /// Charge the running task for the time since it was last charged
+    fn update_curr(&mut self, se: &mut SchedEntity, now: u64) {
+        let delta = now.saturating_sub(se.exec_start);
+        se.exec_start = now;
+        se.sum_exec_runtime += delta;
+        se.vruntime += calc_delta_fair(delta, se.weight);
+        self.curr_vruntime = se.vruntime;
+        self.update_min_vruntime();
+    }
+
+    fn account_enqueue(&mut self, se: &SchedEntity) {
+        self.load_weight += se.weight;
+        self.nr_running += 1;
+    }
+
+    fn account_dequeue(&mut self, se: &SchedEntity) {
+        self.load_weight -= se.weight;
+        self.nr_running -= 1;
     }
 }
 
+/// Completely fair scheduler: each CPU runs the queued task that has had
+/// the least weighted run time, for a share of the target latency in
+/// proportion to its weight. Tasks sit in a tree ordered by virtual run
+/// time; `crate::scheduler::SCHEDULER` drives it and owns the entities.
 pub struct OptimizedScheduler {
-    runqueue: Mutex<BinaryHeap<ScheduledProcess>>,
-    min_vruntime: Mutex<u64>,
+    cpus: [SpinLock<CfsRunQueue>; MAX_CPUS],
+    target_latency: AtomicU64,
+    min_granularity: AtomicU64,
+    wakeup_granularity: AtomicU64,
 }
 
 impl OptimizedScheduler {
     pub const fn new() -> Self {
         OptimizedScheduler {
-            runqueue: Mutex::new(BinaryHeap::new()),
-            min_vruntime: Mutex::new(0),
+            cpus: [const { SpinLock::new(CfsRunQueue::new()) }; MAX_CPUS],
+            target_latency: AtomicU64::new(DEFAULT_TARGET_LATENCY_NS),
+            min_granularity: AtomicU64::new(DEFAULT_MIN_GRANULARITY_NS),
+            wakeup_granularity: AtomicU64::new(DEFAULT_WAKEUP_GRANULARITY_NS),
+        }
+    }
+
+    fn queue(&self, cpu: usize) -> SpinLockGuard<'_, CfsRunQueue> {
+        self.cpus[cpu].lock()
+    }
+
+    pub fn target_latency(&self) -> u64 {
+        self.target_latency.load(Ordering::Relaxed)
+    }
+
+    pub fn min_granularity(&self) -> u64 {
+        self.min_granularity.load(Ordering::Relaxed)
+    }
+
+    pub fn wakeup_granularity(&self) -> u64 {
+        self.wakeup_granularity.load(Ordering::Relaxed)
+    }
+
+    pub fn set_target_latency(&self, ns: u64) -> Result<(), &'static str> {
+        if ns < self.min_granularity() {
+            return Err("Target latency is below the minimum granularity");
+        }
+        self.target_latency.store(ns, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn set_min_granularity(&self, ns: u64) -> Result<(), &'static str> {
+        if ns == 0 || ns > self.target_latency() {
+            return Err("Minimum granularity must be between 0 and the target latency");
+        }
+        self.min_granularity.store(ns, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn set_wakeup_granularity(&self, ns: u64) {
+        self.wakeup_granularity.store(ns, Ordering::Relaxed);
+    }
+
+    /// Period in which each of `nr_running` tasks runs once; stretched when
+    /// the target latency would give them less than the minimum granularity
+    fn period(&self, nr_running: usize) -> u64 {
+        let latency = self.target_latency();
+        let granularity = self.min_granularity();
+        if nr_running as u64 > latency / granularity {
+            nr_running as u64 * granularity
+        } else {
+            latency
         }
     }
 
-    pub fn enqueue(&self, pid: ProcessId, priority: u64) {
-        let vruntime = *self.min_vruntime.lock();
-        let proc = ScheduledProcess {
-            pid,
-            priority,
-            vruntime,
+    /// Wall-clock slice of `se` on `rq`, counting `se` if it is not queued yet
+    fn slice(&self, rq: &CfsRunQueue, se: &SchedEntity) -> u64 {
+        let (nr_running, load) = if se.on_rq {
+            (rq.nr_running, rq.load_weight)
+        } else {
+            (rq.nr_running + 1, rq.load_weight + se.weight)
         };
-        self.runqueue.lock().push(proc);
+        (self.period(nr_running) as u128 * se.weight as u128 / load.max(1) as u128) as u64
     }
 
-    pub fn schedule_next(&self) -> Option<ProcessId> {
-        let mut queue = self.runqueue.lock();
-        if let Some(proc) = queue.pop() {
-            // Update vruntime based on execution time
-            let time_slice = 10; // 10ms time slice
-            let new_vruntime = proc.vruntime + (time_slice * 1000) / proc.priority.max(1);
-            *self.min_vruntime.lock() = new_vruntime;
-            Some(proc.pid)
+    /// Pick a starting virtual run time: a new task goes one slice behind
+    /// the queue so forking cannot starve the others; a waking task keeps
+    /// its own unless that is more than half a latency behind, which bounds
+    /// the credit a long sleep earns
+    fn place(&self, rq: &CfsRunQueue, se: &mut SchedEntity) {
+        if se.sum_exec_runtime == 0 {
+            se.vruntime = rq.min_vruntime + calc_delta_fair(self.slice(rq, se), se.weight);
         } else {
-            None
+            let credit = self.target_latency() / 2;
+            se.vruntime = se.vruntime.max(rq.min_vruntime.saturating_sub(credit));
+        }
+    }
+
+    /// Make `tid` runnable on `cpu`. Returns whether it should preempt the
+    /// task running there.
+    pub fn enqueue(&self, cpu: usize, tid: ThreadId, se: &mut SchedEntity) -> bool {
+        if se.on_rq {
+            return false;
+        }
+        without_interrupts(|| {
+            // Run queues keep unrelated virtual clocks, so a task moving to
+            // another CPU keeps its lag behind the queue it left, not its
+            // vruntime; otherwise it is starved or starves the others there
+            let left_min = (se.sum_exec_runtime != 0 && se.cpu != cpu).then(|| self.queue(se.cpu).min_vruntime);
+            let mut rq = self.queue(cpu);
+            rq.update_min_vruntime();
+            if let Some(left_min) = left_min {
+                se.vruntime = (se.vruntime + rq.min_vruntime).saturating_sub(left_min);
+            }
+            self.place(&rq, se);
+            rq.timeline.insert((se.vruntime, tid));
+            rq.account_enqueue(se);
+            se.on_rq = true;
+            se.cpu = cpu;
+            match rq.curr {
+                None => true,
+                Some(_) => {
+                    let lead = rq.curr_vruntime.saturating_sub(se.vruntime);
+                    lead > calc_delta_fair(self.wakeup_granularity(), se.weight)
+                }
+            }
+        })
+    }
+
+    /// Take `tid` off its run queue, whether queued or running
+    pub fn dequeue(&self, tid: ThreadId, se: &mut SchedEntity) {
+        if !se.on_rq {
+            return;
+        }
+        without_interrupts(|| {
+            let mut rq = self.queue(se.cpu);
+            if rq.curr == Some(tid) {
+                rq.curr = None;
+            } else {
+                rq.timeline.remove(&(se.vruntime, tid));
+            }
+            rq.account_dequeue(se);
+            rq.update_min_vruntime();
+        });
+        se.on_rq = false;
+    }
+
+    /// Drop a task that no longer exists from `cpu`'s tree
+    pub fn forget(&self, cpu: usize, tid: ThreadId) {
+        without_interrupts(|| self.queue(cpu).timeline.retain(|&(_, queued)| queued != tid));
+    }
+
+    /// Queued task with the least virtual run time; it stays queued until `set_next`
+    pub fn pick_next(&self, cpu: usize) -> Option<ThreadId> {
+        without_interrupts(|| self.queue(cpu).leftmost().map(|(_, tid)| tid))
+    }
+
+    /// Start running `tid`, picked from `cpu`'s tree
+    pub fn set_next(&self, cpu: usize, tid: ThreadId, se: &mut SchedEntity, now: u64) {
+        without_interrupts(|| {
+            let mut rq = self.queue(cpu);
+            if !se.on_rq {
+                rq.account_enqueue(se);
+                se.on_rq = true;
+                se.cpu = cpu;
+            }
+            rq.timeline.remove(&(se.vruntime, tid));
+            rq.curr = Some(tid);
+            rq.curr_vruntime = se.vruntime;
+        });
+        se.exec_start = now;
+        se.prev_sum_exec_runtime = se.sum_exec_runtime;
+    }
+
+    /// Stop running `tid`: charge its run time and put it back in the tree,
+    /// or take it off the queue if it is no longer runnable
+    pub fn put_prev(&self, cpu: usize, tid: ThreadId, se: &mut SchedEntity, now: u64, runnable: bool) {
+        without_interrupts(|| {
+            let mut rq = self.queue(cpu);
+            if rq.curr == Some(tid) {
+                rq.update_curr(se, now);
+                rq.curr = None;
+                if runnable {
+                    rq.timeline.insert((se.vruntime, tid));
+                } else {
+                    rq.account_dequeue(se);
+                    se.on_rq = false;
+                }
+            } else if runnable && !se.on_rq {
+                // Ran without being accounted, like the boot flow before the first switch
+                se.exec_start = now;
+                self.place(&rq, se);
+                rq.timeline.insert((se.vruntime, tid));
+                rq.account_enqueue(se);
+                se.on_rq = true;
+                se.cpu = cpu;
+            }
+            rq.update_min_vruntime();
+        })
+    }
+
+    /// Charge a timer tick to the running task. Returns whether it has used
+    /// up its slice, or has fallen a slice behind the leftmost task.
+    pub fn tick(&self, cpu: usize, tid: ThreadId, se: &mut SchedEntity, now: u64) -> bool {
+        without_interrupts(|| {
+            let mut rq = self.queue(cpu);
+            if rq.curr != Some(tid) {
+                return !rq.timeline.is_empty();
+            }
+            rq.update_curr(se, now);
+            let Some((left_vruntime, _)) = rq.leftmost() else { return false };
+            let ideal = self.slice(&rq, se);
+            let ran = se.sum_exec_runtime - se.prev_sum_exec_runtime;
+            if ran > ideal {
+                return true;
+            }
+            ran >= self.min_granularity() && se.vruntime.saturating_sub(left_vruntime) > ideal
+        })
+    }
+
+    /// Change the nice value of `se`, keeping its queue's load in step
+    pub fn reweight(&self, se: &mut SchedEntity, nice: i8) {
+        if !se.on_rq {
+            se.set_nice(nice);
+            return;
         }
+        without_interrupts(|| {
+            let mut rq = self.queue(se.cpu);
+            rq.load_weight -= se.weight;
+            se.set_nice(nice);
+            rq.load_weight += se.weight;
+        })
+    }
+
+    /// Tasks queued or running on `cpu`
+    pub fn nr_running(&self, cpu: usize) -> usize {
+        without_interrupts(|| self.queue(cpu).nr_running)
+    }
+
+    /// Tasks waiting to run on `cpu`
+    pub fn nr_queued(&self, cpu: usize) -> usize {
+        without_interrupts(|| self.queue(cpu).timeline.len())
     }
 
+    pub fn min_vruntime(&self, cpu: usize) -> u64 {
+        without_interrupts(|| self.queue(cpu).min_vruntime)
+    }
+
+    /// Tasks queued or running on every CPU
     pub fn get_load(&self) -> usize {
-        self.runqueue.lock().len()
+        (0..MAX_CPUS).map(|cpu| self.nr_running(cpu)).sum()
     }
 }
 
 pub static OPTIMIZED_SCHEDULER: OptimizedScheduler = OptimizedScheduler::new();
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run two always-runnable tasks of the given nice values on one CPU in
+    /// 1 ms turns, returning the time each of them got
+    fn share(nice: [i8; 2], turns: usize) -> [u64; 2] {
+        let scheduler = OptimizedScheduler::new();
+        let tids = [ThreadId::from_u64(1), ThreadId::from_u64(2)];
+        let mut entities = [SchedEntity::new(); 2];
+        for i in 0..2 {
+            entities[i].set_nice(nice[i]);
+            scheduler.enqueue(0, tids[i], &mut entities[i]);
+        }
+
+        let mut now = 0;
+        for _ in 0..turns {
+            let tid = scheduler.pick_next(0).expect("nothing runnable");
+            let i = tids.iter().position(|&queued| queued == tid).expect("unknown task picked");
+            scheduler.set_next(0, tid, &mut entities[i], now);
+            now += 1_000_000;
+            scheduler.put_prev(0, tid, &mut entities[i], now, true);
+        }
+        [entities[0].sum_exec_runtime(), entities[1].sum_exec_runtime()]
+    }
+
+    #[test_case]
+    fn equal_nice_shares_equally() {
+        let [a, b] = share([0, 0], 1000);
+        assert!(a.abs_diff(b) * 20 < a + b, "shares {} and {} differ by over 5%", a, b);
+    }
+
+    #[test_case]
+    fn share_follows_nice_weight() {
+        // Weights 1024 and 335: the nice 0 task should get about 3.06 times as much
+        let [a, b] = share([0, 5], 4000);
+        assert!(a * 10 > b * 29 && a * 10 < b * 32, "shares {} and {} do not match the weights", a, b);
+        let [a, b] = share([5, 0], 4000);
+        assert!(b * 10 > a * 29 && b * 10 < a * 32, "shares {} and {} do not match the weights", a, b);
+    }
+}
@@ -1,9 +1,52 @@
+use core::ops::ControlFlow;
 use core::sync::atomic::{AtomicUsize, Ordering};
-use spin::Mutex;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::PhysFrame;
 use x86_64::VirtAddr;
+use crate::memory::fault::{self, FaultResolution};
+use crate::context::UserFrame;
+use crate::hardware::cpu::{current_cpu, MAX_CPUS};
+use crate::containers::cgroup::CGROUP_MANAGER;
+use crate::ipc::{MESSAGE_QUEUE, SHARED_MEMORY};
+use crate::memory::uaccess;
+use crate::memory::mmap::{self, MemoryLayout, MAP_ANONYMOUS, MAP_SHARED, PROT_EXEC, PROT_READ, PROT_WRITE};
+use crate::memory::oom::{OOM_SCORE_ADJ_MAX, OOM_SCORE_ADJ_MIN};
+use crate::memory::reclaim::PAGE_RECLAIMER;
+use crate::net::socket::SOCKET_MANAGER;
+use crate::realtime::RtEntity;
+use crate::scheduler::SCHEDULER;
+use crate::signal::{self, SigAction, SignalHandlers, CLD_EXITED, CLD_KILLED, SA_NOCLDWAIT, SIGCHLD, SIG_IGN};
+use crate::thread::{
+    ThreadId, CLONE_CHILD_CLEARTID, CLONE_CHILD_SETTID, CLONE_FILES, CLONE_PARENT_SETTID, CLONE_SETTLS,
+    CLONE_SIGHAND, CLONE_THREAD, CLONE_VM, THREAD_MANAGER,
+};
+use crate::memory::{AddressSpace, NUMA_MANAGER, SlabBox, SlabCache, Vma, VmaFlags, VmaKind, VmaTree, VMM};
+use crate::userspace::env::ENV_MANAGER;
+use crate::sync::SpinLock;
+use crate::wait::WaitQueue;
 
 static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
 
+/// Size of the demand-paged user stack below a process's initial stack pointer
+pub const USER_STACK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// `wait4` option: return 0 instead of blocking when no child has exited yet
+pub const WNOHANG: u64 = 1;
+
+/// Wait status of a process that exited with `code`, as `wait4` reports it
+pub const fn exit_status(code: i32) -> i32 {
+    (code & 0xff) << 8
+}
+
+/// Wait status of a process killed by `sig`
+pub const fn signal_status(sig: u8) -> i32 {
+    sig as i32 & 0x7f
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ProcessId(usize);
 
@@ -11,6 +54,21 @@ impl ProcessId {
     pub fn new() -> Self {
         ProcessId(NEXT_PID.fetch_add(1, Ordering::Relaxed))
     }
+
+    /// The boot flow that runs the shell; never a real process
+    pub const KERNEL: ProcessId = ProcessId(0);
+
+    /// The first process started; orphans are re-parented to it
+    pub const INIT: ProcessId = ProcessId(1);
+
+    /// Id named by user space, which may not exist
+    pub fn from_u64(raw: u64) -> Self {
+        ProcessId(raw as usize)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0 as u64
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -18,54 +76,764 @@ pub enum ProcessState {
     Running,
     Ready,
     Blocked,
+    /// Stopped by a signal until `SIGCONT`
+    Stopped,
     Terminated,
+    /// Exited and released everything but its wait status, until the parent reaps it
+    Zombie,
+}
+
+pub const O_RDONLY: u32 = 0;
+pub const O_WRONLY: u32 = 1;
+pub const O_RDWR: u32 = 2;
+const O_ACCMODE: u32 = 3;
+
+/// An open file in a process's descriptor table
+#[derive(Debug, Clone, Copy)]
+pub struct FileDescriptor {
+    pub inode: u64,
+    pub offset: u64,
+    pub flags: u32,
+}
+
+impl FileDescriptor {
+    pub fn readable(&self) -> bool {
+        self.flags & O_ACCMODE != O_WRONLY
+    }
+
+    pub fn writable(&self) -> bool {
+        matches!(self.flags & O_ACCMODE, O_WRONLY | O_RDWR)
+    }
+}
+
+/// Open files by descriptor number; shared between processes created with `CLONE_FILES`
+pub type FileTable = BTreeMap<u64, FileDescriptor>;
+
+/// User and group a process acts as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Credentials {
+    pub const ROOT: Credentials = Credentials { uid: 0, gid: 0 };
+}
+
+/// CPU time and peak memory of a process, as `wait4` reports them
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResourceUsage {
+    /// Nanoseconds its threads ran
+    pub cpu_time_ns: u64,
+    /// Largest resident set seen, in pages
+    pub max_rss_pages: usize,
+}
+
+impl ResourceUsage {
+    /// Size of `struct rusage`
+    pub const SIZE: usize = 144;
+
+    /// Fold in the usage of a reaped child
+    pub fn add(&mut self, other: &ResourceUsage) {
+        self.cpu_time_ns += other.cpu_time_ns;
+        self.max_rss_pages = self.max_rss_pages.max(other.max_rss_pages);
+    }
+
+    /// `struct rusage` with the user time and maximum resident set filled in
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        let micros = self.cpu_time_ns / 1000;
+        bytes[0..8].copy_from_slice(&(micros / 1_000_000).to_ne_bytes());
+        bytes[8..16].copy_from_slice(&(micros % 1_000_000).to_ne_bytes());
+        // ru_maxrss is in kilobytes
+        bytes[32..40].copy_from_slice(&(self.max_rss_pages as u64 * 4).to_ne_bytes());
+        bytes
+    }
 }
 
 pub struct Process {
     pub pid: ProcessId,
+    pub parent: Option<ProcessId>,
+    pub children: Vec<ProcessId>,
     pub state: ProcessState,
     pub stack_pointer: VirtAddr,
     pub instruction_pointer: VirtAddr,
+    /// Shared by all threads of the process; released when it exits
+    pub mm: Option<Arc<Mm>>,
+    pub files: Arc<SpinLock<FileTable>>,
+    pub credentials: Credentials,
+    /// Added to the OOM badness score, from -1000 (never kill) to 1000
+    pub oom_score_adj: i16,
+    pub signal_handlers: SignalHandlers,
+    /// Wait status the process exits with, set when it starts exiting
+    pub exit_status: i32,
+    pub usage: ResourceUsage,
+    /// Summed usage of the children it has reaped
+    pub children_usage: ResourceUsage,
+}
+
+/// Memory of a process. The process list is only held to find it; faults,
+/// `mmap` and the rest then work under the lock of its map.
+pub struct Mm {
+    pub address_space: AddressSpace,
+    /// Held while the page tables or the areas change, and by reclaim while it
+    /// evicts one of the pages, so that neither sees the other half done
+    pub map: SpinLock<MemoryMap>,
+}
+
+/// The areas of an address space and where new ones go
+pub struct MemoryMap {
+    pub vmas: VmaTree,
+    /// Randomized placement of the heap and mmap area
+    pub layout: MemoryLayout,
+}
+
+impl Mm {
+    pub fn new(address_space: AddressSpace, vmas: VmaTree, layout: MemoryLayout) -> Self {
+        Mm {
+            address_space,
+            map: SpinLock::new(MemoryMap { vmas, layout }),
+        }
+    }
+
+    /// Resident and swapped pages. The OOM killer asks from a fault, holding
+    /// the map of the faulting process, so another map is taken nested.
+    pub fn memory_usage(&self) -> (usize, usize) {
+        if self.map.held_by_this_cpu() {
+            // Held further up, by code that is not changing it meanwhile
+            return VMM.memory_usage(&self.address_space);
+        }
+        let _map = self.map.lock_nested(1);
+        VMM.memory_usage(&self.address_space)
+    }
+}
+
+/// Memory footprint of a process, as seen by the OOM killer
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessMemoryInfo {
+    pub pid: ProcessId,
+    pub parent: Option<ProcessId>,
+    pub resident_pages: usize,
+    pub swapped_pages: usize,
+    pub oom_score_adj: i16,
+    /// Killed or exiting, with its memory not yet released
+    pub exiting: bool,
 }
 
 impl Process {
-    pub fn new(entry_point: VirtAddr, stack_top: VirtAddr) -> Self {
+    pub fn new(entry_point: VirtAddr, stack_top: VirtAddr, mm: Arc<Mm>) -> Self {
         Process {
             pid: ProcessId::new(),
+            parent: None,
+            children: Vec::new(),
             state: ProcessState::Ready,
             stack_pointer: stack_top,
             instruction_pointer: entry_point,
+            mm: Some(mm),
+            files: Arc::new(SpinLock::new(BTreeMap::new())),
+            credentials: Credentials::ROOT,
+            oom_score_adj: 0,
+            signal_handlers: SignalHandlers::new(),
+            exit_status: 0,
+            usage: ResourceUsage::default(),
+            children_usage: ResourceUsage::default(),
         }
     }
 }
 
+/// Arguments of `clone` besides the caller's registers
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CloneArgs {
+    pub flags: u64,
+    /// Stack pointer of the new thread; 0 keeps the caller's
+    pub stack: u64,
+    pub parent_tid: u64,
+    pub child_tid: u64,
+    pub tls: u64,
+}
+
+pub static PROCESS_CACHE: SlabCache =
+    SlabCache::new("process", core::mem::size_of::<Process>(), core::mem::align_of::<Process>());
+
 pub struct ProcessManager {
-    processes: Mutex<Vec<Process>>,
-    current_pid: Mutex<Option<ProcessId>>,
+    processes: SpinLock<Vec<SlabBox<Process>>>,
+    /// Parents blocked in `wait`, woken whenever a child becomes a zombie
+    child_exit: WaitQueue,
+    /// Raw id of the process running on each CPU, 0 while the kernel runs; set from the timer interrupt
+    current_pid: [AtomicUsize; MAX_CPUS],
 }
 
 impl ProcessManager {
     pub const fn new() -> Self {
         ProcessManager {
-            processes: Mutex::new(Vec::new()),
-            current_pid: Mutex::new(None),
+            processes: SpinLock::new(Vec::new()),
+            child_exit: WaitQueue::new(),
+            current_pid: [const { AtomicUsize::new(0) }; MAX_CPUS],
         }
     }
 
-    pub fn create_process(&self, entry_point: VirtAddr, stack_top: VirtAddr) -> ProcessId {
-        let process = Process::new(entry_point, stack_top);
+    pub fn create_process(&self, entry_point: VirtAddr, stack_top: VirtAddr) -> Result<ProcessId, &'static str> {
+        let stack_end = stack_top.align_up(4096u64);
+        let stack_start = VirtAddr::new(stack_end.as_u64().saturating_sub(USER_STACK_SIZE));
+        let mut vmas = VmaTree::new();
+        vmas.insert(Vma::new(stack_start, stack_end, VmaFlags::READ | VmaFlags::WRITE, VmaKind::Stack))?;
+        let mm = Arc::new(Mm::new(VMM.create_address_space()?, vmas, MemoryLayout::randomized()));
+        let pml4_frame = mm.address_space.pml4_frame();
+        let process = SlabBox::new(&PROCESS_CACHE, Process::new(entry_point, stack_top, mm))?;
         let pid = process.pid;
         self.processes.lock().push(process);
-        pid
+        if let Err(e) = THREAD_MANAGER.create_user(ThreadId::main_of(pid), pid, pml4_frame, UserFrame::new(entry_point, stack_top), 0) {
+            self.destroy_process(pid).ok();
+            return Err(e);
+        }
+        Ok(pid)
     }
 
+    /// Duplicate `parent_pid`; the child shares the parent's pages copy-on-write
+    /// and resumes from `frame`, the registers the parent called `fork` with
+    pub fn fork(&self, parent_pid: ProcessId, frame: UserFrame) -> Result<ProcessId, &'static str> {
+        let tid = self.clone(parent_pid, &CloneArgs::default(), frame)?;
+        Ok(ProcessId(tid.as_u64() as usize))
+    }
+
+    /// Create a thread that resumes from `frame` with a return value of 0,
+    /// either in `parent_pid` (`CLONE_THREAD`) or in a new child process.
+    /// The new thread is made runnable right away.
+    pub fn clone(&self, parent_pid: ProcessId, args: &CloneArgs, mut frame: UserFrame) -> Result<ThreadId, &'static str> {
+        let flags = args.flags;
+        if flags & CLONE_THREAD != 0 && flags & CLONE_SIGHAND == 0 {
+            return Err("CLONE_THREAD requires CLONE_SIGHAND");
+        }
+        if flags & CLONE_SIGHAND != 0 && flags & CLONE_VM == 0 {
+            return Err("CLONE_SIGHAND requires CLONE_VM");
+        }
+        if flags & CLONE_VM != 0 && flags & CLONE_THREAD == 0 {
+            return Err("CLONE_VM is only supported for threads");
+        }
+
+        frame.rax = 0;
+        if args.stack != 0 {
+            frame.rsp = args.stack;
+        }
+        let (signal_mask, nice, rt) = SCHEDULER.current_thread()
+            .and_then(|tid| THREAD_MANAGER.with_thread(tid, |thread| (thread.signal_mask, thread.sched.nice(), thread.rt.inherit())))
+            .unwrap_or((0, 0, RtEntity::new()));
+
+        let (pid, tid) = if flags & CLONE_THREAD != 0 {
+            let pml4_frame = self.mm(parent_pid)?.address_space.pml4_frame();
+            let tid = THREAD_MANAGER.create_user(ThreadId::new(), parent_pid, pml4_frame, frame, signal_mask)?;
+            (parent_pid, tid)
+        } else {
+            let pid = self.copy_process(parent_pid, flags)?;
+            let pml4_frame = self.mm(pid)?.address_space.pml4_frame();
+            let tid = ThreadId::main_of(pid);
+            if let Err(e) = THREAD_MANAGER.create_user(tid, pid, pml4_frame, frame, signal_mask) {
+                self.destroy_process(pid).ok();
+                return Err(e);
+            }
+            (pid, tid)
+        };
+
+        THREAD_MANAGER.with_thread(tid, |thread| {
+            thread.sched.set_nice(nice);
+            thread.rt = rt;
+        });
+        if flags & CLONE_SETTLS != 0 {
+            THREAD_MANAGER.with_thread(tid, |thread| thread.context.set_fs_base(args.tls));
+        }
+        if flags & CLONE_CHILD_CLEARTID != 0 {
+            THREAD_MANAGER.with_thread(tid, |thread| thread.clear_child_tid = VirtAddr::try_new(args.child_tid).ok());
+        }
+        let tid_bytes = (tid.as_u64() as u32).to_ne_bytes();
+        let set_tid = || {
+            if flags & CLONE_PARENT_SETTID != 0 {
+                self.write_user(parent_pid, args.parent_tid, &tid_bytes)?;
+            }
+            if flags & CLONE_CHILD_SETTID != 0 {
+                self.write_user(pid, args.child_tid, &tid_bytes)?;
+            }
+            Ok(())
+        };
+        if let Err(e) = set_tid() {
+            // It never ran, so it goes without a trace
+            if pid == parent_pid {
+                THREAD_MANAGER.remove(tid).ok();
+            } else {
+                self.destroy_process(pid).ok();
+            }
+            return Err(e);
+        }
+
+        SCHEDULER.enqueue(tid);
+        Ok(tid)
+    }
+
+    /// New process sharing the parent's pages copy-on-write, without any threads yet
+    fn copy_process(&self, parent_pid: ProcessId, flags: u64) -> Result<ProcessId, &'static str> {
+        let parent_mm = self.mm(parent_pid)?;
+        let mm = {
+            let map = parent_mm.map.lock();
+            let address_space = VMM.clone_address_space(&parent_mm.address_space)?;
+            Arc::new(Mm::new(address_space, map.vmas.clone(), map.layout))
+        };
+        PAGE_RECLAIMER.add_address_space(&mm.address_space);
+
+        let child = {
+            let processes = self.processes.lock();
+            let parent = processes.iter().find(|p| p.pid == parent_pid).ok_or("Process not found")?;
+            let mut child = Process::new(parent.instruction_pointer, parent.stack_pointer, mm);
+            child.parent = Some(parent_pid);
+            child.files = if flags & CLONE_FILES != 0 {
+                parent.files.clone()
+            } else {
+                Arc::new(SpinLock::new(parent.files.lock().clone()))
+            };
+            child.credentials = parent.credentials;
+            child.oom_score_adj = parent.oom_score_adj;
+            child.signal_handlers = parent.signal_handlers.clone();
+            child
+        };
+
+        let pid = child.pid;
+        let child = SlabBox::new(&PROCESS_CACHE, child)?;
+        ENV_MANAGER.set_env(pid, ENV_MANAGER.get_env(parent_pid));
+        NUMA_MANAGER.inherit_policy(parent_pid, pid);
+        // Before the child is visible: it may fault on or detach a segment right away
+        SHARED_MEMORY.inherit(parent_pid, pid);
+        {
+            let mut processes = self.processes.lock();
+            if let Some(parent) = processes.iter_mut().find(|p| p.pid == parent_pid) {
+                parent.children.push(pid);
+            }
+            processes.push(child);
+        }
+        Ok(pid)
+    }
+
+    /// Remove a process and release its address space, without leaving a zombie
+    pub fn destroy_process(&self, pid: ProcessId) -> Result<(), &'static str> {
+        let mut process = {
+            let mut processes = self.processes.lock();
+            let pos = processes.iter().position(|p| p.pid == pid).ok_or("Process not found")?;
+            let process = processes.remove(pos);
+            if let Some(parent) = process.parent.and_then(|ppid| processes.iter_mut().find(|p| p.pid == ppid)) {
+                parent.children.retain(|&child| child != pid);
+            }
+            process
+        };
+
+        // Threads go first: they point at the page tables about to be freed
+        THREAD_MANAGER.remove_process(pid);
+        if self.get_current_process() == Some(pid) {
+            self.set_current_process(ProcessId::KERNEL);
+        }
+        if let Some(mm) = process.mm.take() {
+            let map = mm.map.lock();
+            mmap::writeback_all(&mm.address_space, &map.vmas);
+        }
+        let children = process.children.clone();
+        // Dropping the last reference to its memory releases its address space
+        drop(process);
+        self.release(pid);
+        self.reparent(pid, children);
+        // A parent waiting for it may have no children left
+        self.child_exit.wake_all();
+        Ok(())
+    }
+
+    /// Drop what other subsystems keep for `pid`; its mappings went with its address space
+    fn release(&self, pid: ProcessId) {
+        NUMA_MANAGER.remove_policy(pid);
+        SHARED_MEMORY.forget_process(pid);
+        MESSAGE_QUEUE.forget_process(pid);
+        SOCKET_MANAGER.close_process(pid);
+        ENV_MANAGER.remove_env(pid);
+        CGROUP_MANAGER.remove_process(pid);
+    }
+
+    /// Start the exit of `pid` with wait status `status`; returns false if it
+    /// was exiting already, in which case the first status stands
+    pub fn begin_exit(&self, pid: ProcessId, status: i32) -> bool {
+        let mut processes = self.processes.lock();
+        match processes.iter_mut().find(|p| p.pid == pid) {
+            Some(process) if process.state != ProcessState::Terminated && process.state != ProcessState::Zombie => {
+                process.state = ProcessState::Terminated;
+                process.exit_status = status;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Take the running thread out of its process for good. The last thread
+    /// to leave releases the process and leaves a zombie for the parent.
+    pub fn exit_thread(&self, tid: ThreadId) -> ! {
+        let clear_child_tid = THREAD_MANAGER.with_thread(tid, |thread| (thread.process, thread.clear_child_tid.take()));
+        if let Some((pid, Some(addr))) = clear_child_tid {
+            self.write_user(pid, addr.as_u64(), &0u32.to_ne_bytes()).ok();
+        }
+        if let Some((pid, cpu_time_ns, last)) = THREAD_MANAGER.mark_exiting(tid) {
+            if let Some(process) = self.processes.lock().iter_mut().find(|p| p.pid == pid) {
+                process.usage.cpu_time_ns += cpu_time_ns;
+            }
+            if last {
+                self.exit_process(pid);
+            }
+        }
+
+        // Preempting the thread once it is gone from the list would strand it
+        x86_64::instructions::interrupts::disable();
+        THREAD_MANAGER.remove(tid).ok();
+        SCHEDULER.schedule();
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    /// Release everything `pid` holds but its entry in the process list,
+    /// hand its children to init and tell its parent
+    fn exit_process(&self, pid: ProcessId) {
+        // Threads still on their way out must not switch back to the page tables freed here
+        THREAD_MANAGER.with_threads(|threads| {
+            for thread in threads.iter_mut().filter(|t| t.process == pid) {
+                thread.pml4_frame = None;
+            }
+        });
+        let resident_pages = self.mm(pid).map_or(0, |mm| mm.memory_usage().0);
+        let (mm, files, parent, status, children) = {
+            let mut processes = self.processes.lock();
+            let Some(process) = processes.iter_mut().find(|p| p.pid == pid) else { return };
+            let process: &mut Process = process;
+            process.usage.max_rss_pages = process.usage.max_rss_pages.max(resident_pages);
+            process.state = ProcessState::Zombie;
+            (
+                process.mm.take(),
+                core::mem::replace(&mut process.files, Arc::new(SpinLock::new(BTreeMap::new()))),
+                process.parent,
+                process.exit_status,
+                core::mem::take(&mut process.children),
+            )
+        };
+
+        if let Some(mm) = mm {
+            let map = mm.map.lock();
+            mmap::writeback_all(&mm.address_space, &map.vmas);
+        }
+        drop(files);
+        self.release(pid);
+        self.reparent(pid, children);
+        self.notify_exit(pid, parent, status);
+    }
+
+    /// Hand the children of `pid` to init, or leave them without a parent if
+    /// init is gone
+    fn reparent(&self, pid: ProcessId, children: Vec<ProcessId>) {
+        if children.is_empty() {
+            return;
+        }
+        let (reaper, zombies) = {
+            let mut processes = self.processes.lock();
+            let reaper = Some(ProcessId::INIT).filter(|&init| {
+                init != pid && processes.iter().any(|p| p.pid == init && p.state != ProcessState::Zombie)
+            });
+            let mut zombies = Vec::new();
+            for child in processes.iter_mut().filter(|p| children.contains(&p.pid)) {
+                child.parent = reaper;
+                if child.state == ProcessState::Zombie {
+                    zombies.push((child.pid, child.exit_status));
+                }
+            }
+            if let Some(init) = reaper.and_then(|init| processes.iter_mut().find(|p| p.pid == init)) {
+                init.children.extend(children.iter().copied());
+            }
+            (reaper, zombies)
+        };
+        // Their exit went unnoticed by the old parent
+        for (child, status) in zombies {
+            self.notify_exit(child, reaper, status);
+        }
+    }
+
+    /// Send `SIGCHLD` for zombie `pid`, or reap it right away if nobody will
+    /// wait for it: it has no parent, or the parent ignores `SIGCHLD`
+    fn notify_exit(&self, pid: ProcessId, parent: Option<ProcessId>, status: i32) {
+        let action = parent.and_then(|parent| self.sigaction(parent, SIGCHLD));
+        match action {
+            Some(action) if action.handler != SIG_IGN && action.flags & SA_NOCLDWAIT == 0 => {
+                if status & 0x7f == 0 {
+                    signal::notify_parent(pid, CLD_EXITED, status >> 8 & 0xff);
+                } else {
+                    signal::notify_parent(pid, CLD_KILLED, status & 0x7f);
+                }
+                self.child_exit.wake_all();
+            }
+            _ => {
+                self.reap(pid);
+            }
+        }
+    }
+
+    /// Remove zombie `pid`, returning its wait status and its usage with that
+    /// of its own reaped children
+    fn reap(&self, pid: ProcessId) -> Option<(i32, ResourceUsage)> {
+        let mut processes = self.processes.lock();
+        let pos = processes.iter().position(|p| p.pid == pid && p.state == ProcessState::Zombie)?;
+        let zombie = processes.remove(pos);
+        let mut usage = zombie.usage;
+        usage.add(&zombie.children_usage);
+        if let Some(parent) = zombie.parent.and_then(|ppid| processes.iter_mut().find(|p| p.pid == ppid)) {
+            parent.children.retain(|&child| child != pid);
+            parent.children_usage.add(&usage);
+        }
+        Some((zombie.exit_status, usage))
+    }
+
+    /// Reap an exited child of `parent`: `pid`, or any child if `None`.
+    /// Unless `WNOHANG` is set, waits until one exits; `Ok(None)` means
+    /// none has yet.
+    pub fn wait(&self, parent: ProcessId, pid: Option<ProcessId>, options: u64) -> Result<Option<(ProcessId, i32, ResourceUsage)>, &'static str> {
+        if options & !WNOHANG != 0 {
+            return Err("Unsupported wait options");
+        }
+        loop {
+            // Another thread of the parent may reap it first
+            if let Some(child) = self.find_zombie(parent, pid)? {
+                if let Some((status, usage)) = self.reap(child) {
+                    return Ok(Some((child, status, usage)));
+                }
+            }
+            if options & WNOHANG != 0 {
+                return Ok(None);
+            }
+            self.child_exit.wait_until_interruptible(|| !matches!(self.find_zombie(parent, pid), Ok(None)))?;
+        }
+    }
+
+    /// A zombie among the children of `parent` that `pid` selects
+    fn find_zombie(&self, parent: ProcessId, pid: Option<ProcessId>) -> Result<Option<ProcessId>, &'static str> {
+        let processes = self.processes.lock();
+        let process = processes.iter().find(|p| p.pid == parent).ok_or("Process not found")?;
+        let mut children = process.children.iter().copied().filter(|&child| pid.map_or(true, |pid| pid == child)).peekable();
+        if children.peek().is_none() {
+            return Err("No child processes");
+        }
+        Ok(children.find(|&child| processes.iter().any(|p| p.pid == child && p.state == ProcessState::Zombie)))
+    }
+
+    /// The memory of `pid`. The list is held only to find it; the caller
+    /// locks its map for as long as it works on it.
+    pub fn mm(&self, pid: ProcessId) -> Result<Arc<Mm>, &'static str> {
+        let processes = self.processes.lock();
+        let process = processes.iter().find(|p| p.pid == pid).ok_or("Process not found")?;
+        process.mm.clone().ok_or("Process has exited")
+    }
+
+    /// Resolve a page fault taken while `pid` was running
+    pub fn handle_page_fault(&self, pid: ProcessId, addr: VirtAddr, error_code: PageFaultErrorCode) -> Result<FaultResolution, &'static str> {
+        let mm = self.mm(pid)?;
+        let map = mm.map.lock();
+        fault::handle_fault(&mm.address_space, &map.vmas, addr, error_code)
+    }
+
+    /// Map memory into `pid`, backed by the file open as `fd` unless `MAP_ANONYMOUS` is set
+    pub fn mmap(&self, pid: ProcessId, addr: u64, len: u64, prot: u64, flags: u64, fd: u64, offset: u64) -> Result<VirtAddr, &'static str> {
+        let (file, max_prot) = if flags & MAP_ANONYMOUS != 0 {
+            (None, PROT_READ | PROT_WRITE | PROT_EXEC)
+        } else {
+            let descriptor = {
+                let processes = self.processes.lock();
+                let process = processes.iter().find(|p| p.pid == pid).ok_or("Process not found")?;
+                let descriptor = *process.files.lock().get(&fd).ok_or("Bad file descriptor")?;
+                descriptor
+            };
+            if !descriptor.readable() {
+                return Err("File not open for reading");
+            }
+            // Writes to a shared mapping reach the file; private ones stay in memory
+            let max_prot = if flags & MAP_SHARED != 0 && !descriptor.writable() {
+                PROT_READ | PROT_EXEC
+            } else {
+                PROT_READ | PROT_WRITE | PROT_EXEC
+            };
+            (Some((descriptor.inode, offset)), max_prot)
+        };
+        let mm = self.mm(pid)?;
+        let mut map = mm.map.lock();
+        let map: &mut MemoryMap = &mut map;
+        mmap::mmap(&mm.address_space, &mut map.vmas, &map.layout, addr, len, prot, max_prot, flags, file)
+    }
+
+    pub fn munmap(&self, pid: ProcessId, addr: u64, len: u64) -> Result<(), &'static str> {
+        let mm = self.mm(pid)?;
+        let mut map = mm.map.lock();
+        mmap::munmap(&mm.address_space, &mut map.vmas, addr, len)
+    }
+
+    pub fn mprotect(&self, pid: ProcessId, addr: u64, len: u64, prot: u64) -> Result<(), &'static str> {
+        let mm = self.mm(pid)?;
+        let mut map = mm.map.lock();
+        mmap::mprotect(&mm.address_space, &mut map.vmas, addr, len, prot)
+    }
+
+    /// Move the program break of `pid`, returning the break now in effect
+    pub fn brk(&self, pid: ProcessId, addr: u64) -> Result<VirtAddr, &'static str> {
+        let mm = self.mm(pid)?;
+        let mut map = mm.map.lock();
+        let map: &mut MemoryMap = &mut map;
+        Ok(mmap::brk(&mm.address_space, &mut map.vmas, &mut map.layout, addr))
+    }
+
+    /// Map the frames of shared memory segment `id` into `pid` as one shared area
+    pub fn map_shared_frames(&self, pid: ProcessId, addr: u64, frames: &[PhysFrame], writable: bool, id: u64) -> Result<VirtAddr, &'static str> {
+        let mm = self.mm(pid)?;
+        let mut map = mm.map.lock();
+        let map: &mut MemoryMap = &mut map;
+        let prot = if writable { PROT_READ | PROT_WRITE } else { PROT_READ };
+        let kind = VmaKind::SharedMemory { id };
+        mmap::map_frames(&mm.address_space, &mut map.vmas, &map.layout, addr, frames, prot, kind)
+    }
+
+    /// Unmap what is left of shared memory segment `id` in `[addr, addr + len)` of `pid`
+    pub fn unmap_shared(&self, pid: ProcessId, addr: VirtAddr, len: u64, id: u64) -> Result<(), &'static str> {
+        let mm = self.mm(pid)?;
+        let mut map = mm.map.lock();
+        mmap::unmap_kind(&mm.address_space, &mut map.vmas, addr, len, VmaKind::SharedMemory { id })
+    }
+
+    pub fn credentials(&self, pid: ProcessId) -> Option<Credentials> {
+        self.processes.lock().iter().find(|p| p.pid == pid).map(|p| p.credentials)
+    }
+
+    pub fn set_credentials(&self, pid: ProcessId, credentials: Credentials) -> Result<(), &'static str> {
+        let mut processes = self.processes.lock();
+        let process = processes.iter_mut().find(|p| p.pid == pid).ok_or("Process not found")?;
+        process.credentials = credentials;
+        Ok(())
+    }
+
+    pub fn parent(&self, pid: ProcessId) -> Option<ProcessId> {
+        self.processes.lock().iter().find(|p| p.pid == pid).and_then(|p| p.parent)
+    }
+
+    /// Action `pid` takes on `sig`
+    pub fn sigaction(&self, pid: ProcessId, sig: u8) -> Option<SigAction> {
+        self.processes.lock().iter().find(|p| p.pid == pid).map(|p| p.signal_handlers.get(sig))
+    }
+
+    /// Install `action` for `sig` in `pid`, returning the previous one
+    pub fn set_sigaction(&self, pid: ProcessId, sig: u8, action: SigAction) -> Result<SigAction, &'static str> {
+        let mut processes = self.processes.lock();
+        let process = processes.iter_mut().find(|p| p.pid == pid).ok_or("Process not found")?;
+        process.signal_handlers.set(sig, action)
+    }
+
+    pub fn reset_sigaction(&self, pid: ProcessId, sig: u8) {
+        let mut processes = self.processes.lock();
+        if let Some(process) = processes.iter_mut().find(|p| p.pid == pid) {
+            process.signal_handlers.reset(sig);
+        }
+    }
+
+    /// Copy `bytes` into the memory of `pid` at `addr`
+    pub fn write_user(&self, pid: ProcessId, addr: u64, bytes: &[u8]) -> Result<(), &'static str> {
+        let mm = self.mm(pid)?;
+        let map = mm.map.lock();
+        uaccess::copy_to_user(&mm.address_space, &map.vmas, addr, bytes)
+    }
+
+    /// Fill `buf` from the memory of `pid` at `addr`
+    pub fn read_user(&self, pid: ProcessId, addr: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        let mm = self.mm(pid)?;
+        let map = mm.map.lock();
+        uaccess::copy_from_user(&mm.address_space, &map.vmas, addr, buf)
+    }
+
+    pub fn set_oom_score_adj(&self, pid: ProcessId, adj: i16) -> Result<(), &'static str> {
+        if !(OOM_SCORE_ADJ_MIN..=OOM_SCORE_ADJ_MAX).contains(&adj) {
+            return Err("oom_score_adj out of range");
+        }
+        let mut processes = self.processes.lock();
+        let process = processes.iter_mut().find(|p| p.pid == pid).ok_or("Process not found")?;
+        process.oom_score_adj = adj;
+        Ok(())
+    }
+
+    pub fn oom_score_adj(&self, pid: ProcessId) -> Option<i16> {
+        let processes = self.processes.lock();
+        processes.iter().find(|p| p.pid == pid).map(|p| p.oom_score_adj)
+    }
+
+    /// Memory usage of every process that still has memory
+    pub fn memory_snapshot(&self) -> Vec<ProcessMemoryInfo> {
+        let processes: Vec<_> = self.processes.lock().iter()
+            .filter_map(|p| p.mm.clone().map(|mm| (p.pid, p.parent, p.oom_score_adj, p.state, mm)))
+            .collect();
+        processes.into_iter()
+            .map(|(pid, parent, oom_score_adj, state, mm)| {
+                let (resident_pages, swapped_pages) = mm.memory_usage();
+                ProcessMemoryInfo {
+                    pid,
+                    parent,
+                    resident_pages,
+                    swapped_pages,
+                    oom_score_adj,
+                    exiting: state == ProcessState::Terminated,
+                }
+            })
+            .collect()
+    }
+
+    pub fn contains(&self, pid: ProcessId) -> bool {
+        self.processes.lock().iter().any(|p| p.pid == pid)
+    }
+
+    /// The memory of every process that still has it, to visit one at a time
+    fn mms(&self) -> Vec<Arc<Mm>> {
+        self.processes.lock().iter().filter_map(|p| p.mm.clone()).collect()
+    }
+
+    /// Run `f` with the address space of every process that still has one, its map locked
+    pub fn for_each_address_space(&self, mut f: impl FnMut(&AddressSpace)) {
+        for mm in self.mms() {
+            let _map = mm.map.lock();
+            f(&mm.address_space);
+        }
+    }
+
+    /// Run `f` with the address space and memory areas of every process that
+    /// still has them, one process at a time with its map locked, until `f` breaks
+    pub fn for_each_mm(&self, mut f: impl FnMut(&AddressSpace, &VmaTree) -> ControlFlow<()>) -> ControlFlow<()> {
+        for mm in self.mms() {
+            let map = mm.map.lock();
+            f(&mm.address_space, &map.vmas)?;
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Memory of the process whose page tables start at `pml4_frame`, for
+    /// reclaim, which knows pages only by the PML4 that maps them
+    pub fn mm_of(&self, pml4_frame: PhysFrame) -> Option<Arc<Mm>> {
+        let processes = self.processes.lock();
+        processes.iter()
+            .filter_map(|p| p.mm.as_ref())
+            .find(|mm| mm.address_space.pml4_frame() == pml4_frame)
+            .cloned()
+    }
+
+    /// The process running on this CPU
     pub fn get_current_process(&self) -> Option<ProcessId> {
-        *self.current_pid.lock()
+        match without_interrupts(|| self.current_pid[current_cpu()].load(Ordering::Relaxed)) {
+            0 => None,
+            pid => Some(ProcessId(pid)),
+        }
     }
 
+    /// Record the process running on this CPU; `ProcessId::KERNEL` when the kernel runs
     pub fn set_current_process(&self, pid: ProcessId) {
-        *self.current_pid.lock() = Some(pid);
+        without_interrupts(|| self.current_pid[current_cpu()].store(pid.0, Ordering::Relaxed));
     }
+
+
 }
 
 pub static PROCESS_MANAGER: ProcessManager = ProcessManager::new();
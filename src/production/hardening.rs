@@ -1,15 +1,15 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 
 pub struct ProductionHardening {
-    security_audit_complete: Mutex<bool>,
-    vulnerabilities_fixed: Mutex<u32>,
+    security_audit_complete: SpinLock<bool>,
+    vulnerabilities_fixed: SpinLock<u32>,
 }
 
 impl ProductionHardening {
     pub const fn new() -> Self {
         ProductionHardening {
-            security_audit_complete: Mutex::new(false),
-            vulnerabilities_fixed: Mutex::new(0),
+            security_audit_complete: SpinLock::new(false),
+            vulnerabilities_fixed: SpinLock::new(0),
         }
     }
 
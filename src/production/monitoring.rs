@@ -1,4 +1,4 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
 
 pub struct SystemMetrics {
@@ -10,15 +10,15 @@ pub struct SystemMetrics {
 }
 
 pub struct ProductionMonitoring {
-    metrics: Mutex<BTreeMap<u64, SystemMetrics>>,
-    enabled: Mutex<bool>,
+    metrics: SpinLock<BTreeMap<u64, SystemMetrics>>,
+    enabled: SpinLock<bool>,
 }
 
 impl ProductionMonitoring {
     pub const fn new() -> Self {
         ProductionMonitoring {
-            metrics: Mutex::new(BTreeMap::new()),
-            enabled: Mutex::new(true),
+            metrics: SpinLock::new(BTreeMap::new()),
+            enabled: SpinLock::new(true),
         }
     }
 
@@ -1,13 +1,13 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 
 pub struct ProductionOptimization {
-    optimizations_applied: Mutex<u32>,
+    optimizations_applied: SpinLock<u32>,
 }
 
 impl ProductionOptimization {
     pub const fn new() -> Self {
         ProductionOptimization {
-            optimizations_applied: Mutex::new(0),
+            optimizations_applied: SpinLock::new(0),
         }
     }
 
@@ -0,0 +1,633 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::collections::{BTreeSet, VecDeque};
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::hardware::cpu::{online_count, MAX_CPUS};
+use crate::sync::{SpinLock, SpinLockGuard};
+use crate::thread::ThreadId;
+
+/// Policy numbers of `sched_setscheduler`, as on Linux
+pub const SCHED_NORMAL: u64 = 0;
+pub const SCHED_FIFO: u64 = 1;
+pub const SCHED_RR: u64 = 2;
+pub const SCHED_DEADLINE: u64 = 6;
+
+pub const RT_PRIO_MIN: u8 = 1;
+pub const RT_PRIO_MAX: u8 = 99;
+
+/// Slice a round-robin task runs before the next one of its priority
+pub const RR_TIMESLICE_NS: u64 = 100_000_000;
+
+/// Real-time tasks may use `rt_runtime` of every `rt_period` on each CPU
+pub const DEFAULT_RT_PERIOD_NS: u64 = 1_000_000_000;
+pub const DEFAULT_RT_RUNTIME_NS: u64 = 950_000_000;
+
+/// Shortest runtime a deadline task may ask for
+const DL_MIN_RUNTIME_NS: u64 = 1 << 10;
+
+/// Fixed-point unit of CPU bandwidth: all of one CPU
+const BW_UNIT: u64 = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedPolicy {
+    /// The fair scheduler
+    Normal,
+    /// Fixed priority, runs until it blocks or yields
+    Fifo,
+    /// Fixed priority, time-sliced among tasks of the same priority
+    RoundRobin,
+    /// Earliest deadline first, with a guaranteed runtime every period
+    Deadline,
+}
+
+impl SchedPolicy {
+    pub fn from_u64(policy: u64) -> Option<Self> {
+        match policy {
+            SCHED_NORMAL => Some(SchedPolicy::Normal),
+            SCHED_FIFO => Some(SchedPolicy::Fifo),
+            SCHED_RR => Some(SchedPolicy::RoundRobin),
+            SCHED_DEADLINE => Some(SchedPolicy::Deadline),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(self) -> u64 {
+        match self {
+            SchedPolicy::Normal => SCHED_NORMAL,
+            SchedPolicy::Fifo => SCHED_FIFO,
+            SchedPolicy::RoundRobin => SCHED_RR,
+            SchedPolicy::Deadline => SCHED_DEADLINE,
+        }
+    }
+}
+
+/// Parameters of `sched_setscheduler`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SchedParam {
+    /// 1 to 99 for FIFO and round-robin, higher first; 0 otherwise
+    pub priority: u8,
+    /// Deadline tasks: CPU time guaranteed each period, in nanoseconds
+    pub runtime: u64,
+    /// Deadline tasks: time from the start of a period by which the runtime is delivered
+    pub deadline: u64,
+    /// Deadline tasks: 0 means the same as `deadline`
+    pub period: u64,
+}
+
+impl SchedParam {
+    /// Share of a CPU a deadline task reserves
+    fn bandwidth(&self) -> u64 {
+        if self.period == 0 {
+            return 0;
+        }
+        (self.runtime as u128 * BW_UNIT as u128 / self.period as u128) as u64
+    }
+}
+
+/// Real-time scheduling state of one task, kept with the task
+#[derive(Debug, Clone, Copy)]
+pub struct RtEntity {
+    policy: SchedPolicy,
+    param: SchedParam,
+    /// Round-robin time left of the current slice
+    rr_remaining: u64,
+    /// Deadline runtime left in the current period; negative after an overrun
+    dl_runtime_left: i64,
+    /// Absolute deadline of the current period
+    dl_deadline: u64,
+    /// Out of runtime until the next period
+    dl_throttled: bool,
+    /// Go to the back of its priority level when it stops running
+    yielded: bool,
+    exec_start: u64,
+    /// Queued or running on the real-time queue of `cpu`
+    on_rq: bool,
+    cpu: usize,
+}
+
+impl RtEntity {
+    pub const fn new() -> Self {
+        RtEntity {
+            policy: SchedPolicy::Normal,
+            param: SchedParam { priority: 0, runtime: 0, deadline: 0, period: 0 },
+            rr_remaining: RR_TIMESLICE_NS,
+            dl_runtime_left: 0,
+            dl_deadline: 0,
+            dl_throttled: false,
+            yielded: false,
+            exec_start: 0,
+            on_rq: false,
+            cpu: 0,
+        }
+    }
+
+    /// Entity for a child created by `clone`: FIFO and round-robin carry
+    /// over, deadline reservations do not
+    pub fn inherit(&self) -> Self {
+        let mut child = RtEntity::new();
+        if matches!(self.policy, SchedPolicy::Fifo | SchedPolicy::RoundRobin) {
+            child.policy = self.policy;
+            child.param.priority = self.param.priority;
+        }
+        child
+    }
+
+    pub fn policy(&self) -> SchedPolicy {
+        self.policy
+    }
+
+    pub fn param(&self) -> SchedParam {
+        self.param
+    }
+
+    pub fn on_rq(&self) -> bool {
+        self.on_rq
+    }
+
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+
+    /// Whether the task is scheduled by this module rather than the fair scheduler
+    pub fn is_realtime(&self) -> bool {
+        self.policy != SchedPolicy::Normal
+    }
+
+    pub fn mark_yield(&mut self) {
+        self.yielded = true;
+    }
+
+    fn period(&self) -> u64 {
+        match self.param.period {
+            0 => self.param.deadline,
+            period => period,
+        }
+    }
+}
+
+/// The real-time task running on a CPU
+#[derive(Debug, Clone, Copy)]
+struct RtCurr {
+    tid: ThreadId,
+    policy: SchedPolicy,
+    priority: u8,
+    deadline: u64,
+}
+
+/// Real-time run queue of one CPU
+struct RtRunQueue {
+    /// FIFO and round-robin tasks, one queue per priority
+    levels: [VecDeque<ThreadId>; RT_PRIO_MAX as usize + 1],
+    /// Bit `n` set when `levels[n]` is not empty
+    bitmap: u128,
+    /// Deadline tasks with runtime left, by absolute deadline
+    deadlines: BTreeSet<(u64, ThreadId)>,
+    /// Deadline tasks out of runtime, by when they get it back
+    throttled: BTreeSet<(u64, ThreadId)>,
+    curr: Option<RtCurr>,
+    nr_running: usize,
+    /// FIFO and round-robin run time in the current bandwidth period
+    rt_time: u64,
+    period_start: u64,
+    /// FIFO and round-robin tasks used up `rt_runtime`; they wait for the next period
+    rt_throttled: bool,
+}
+
+impl RtRunQueue {
+    const fn new() -> Self {
+        RtRunQueue {
+            levels: [const { VecDeque::new() }; RT_PRIO_MAX as usize + 1],
+            bitmap: 0,
+            deadlines: BTreeSet::new(),
+            throttled: BTreeSet::new(),
+            curr: None,
+            nr_running: 0,
+            rt_time: 0,
+            period_start: 0,
+            rt_throttled: false,
+        }
+    }
+
+    fn push_level(&mut self, tid: ThreadId, priority: u8, front: bool) {
+        let level = &mut self.levels[priority as usize];
+        if front {
+            level.push_front(tid);
+        } else {
+            level.push_back(tid);
+        }
+        self.bitmap |= 1 << priority;
+    }
+
+    fn remove_level(&mut self, tid: ThreadId, priority: u8) {
+        let level = &mut self.levels[priority as usize];
+        level.retain(|&queued| queued != tid);
+        if level.is_empty() {
+            self.bitmap &= !(1 << priority);
+        }
+    }
+
+    fn highest_priority(&self) -> Option<u8> {
+        match self.bitmap {
+            0 => None,
+            bitmap => Some(127 - bitmap.leading_zeros() as u8),
+        }
+    }
+
+    /// Put a runnable task in the queue of its policy
+    fn insert(&mut self, tid: ThreadId, rt: &RtEntity, front: bool) {
+        match rt.policy {
+            SchedPolicy::Deadline if rt.dl_throttled => {
+                let replenish_at = rt.dl_deadline - rt.param.deadline + rt.period();
+                self.throttled.insert((replenish_at, tid));
+            }
+            SchedPolicy::Deadline => {
+                self.deadlines.insert((rt.dl_deadline, tid));
+            }
+            SchedPolicy::Fifo | SchedPolicy::RoundRobin => self.push_level(tid, rt.param.priority, front),
+            SchedPolicy::Normal => {}
+        }
+    }
+
+    fn remove(&mut self, tid: ThreadId, rt: &RtEntity) {
+        match rt.policy {
+            SchedPolicy::Deadline => {
+                self.deadlines.remove(&(rt.dl_deadline, tid));
+                self.throttled.retain(|&(_, throttled)| throttled != tid);
+            }
+            SchedPolicy::Fifo | SchedPolicy::RoundRobin => self.remove_level(tid, rt.param.priority),
+            SchedPolicy::Normal => {}
+        }
+    }
+
+    /// Charge the running task for the time since it was last charged
+    fn update_curr(&mut self, rt: &mut RtEntity, now: u64) {
+        let delta = now.saturating_sub(rt.exec_start);
+        rt.exec_start = now;
+        match rt.policy {
+            SchedPolicy::Deadline => {
+                rt.dl_runtime_left -= delta as i64;
+                if rt.dl_runtime_left <= 0 {
+                    rt.dl_throttled = true;
+                }
+            }
+            SchedPolicy::RoundRobin => {
+                rt.rr_remaining = rt.rr_remaining.saturating_sub(delta);
+                self.rt_time += delta;
+            }
+            SchedPolicy::Fifo => self.rt_time += delta,
+            SchedPolicy::Normal => {}
+        }
+        if let Some(curr) = self.curr.as_mut() {
+            curr.deadline = rt.dl_deadline;
+        }
+    }
+
+    /// Whether a newly runnable task should take the CPU from the running one
+    fn preempts(&self, rt: &RtEntity) -> bool {
+        let Some(curr) = self.curr else { return true };
+        match (rt.policy, curr.policy) {
+            (SchedPolicy::Deadline, SchedPolicy::Deadline) => rt.dl_deadline < curr.deadline,
+            (SchedPolicy::Deadline, _) => true,
+            (_, SchedPolicy::Deadline) => false,
+            _ => rt.param.priority > curr.priority,
+        }
+    }
+}
+
+/// FIFO, round-robin and deadline scheduling classes. They sit above the
+/// fair scheduler: a CPU runs the deadline task with the earliest deadline,
+/// then the highest priority FIFO or round-robin task, and only then fair
+/// tasks. `crate::scheduler::SCHEDULER` drives it and owns the entities.
+///
+/// A runaway task cannot take over a CPU: FIFO and round-robin tasks share
+/// `rt_runtime` of every `rt_period`, and a deadline task gets only its
+/// reserved runtime each period. Reservations are admitted only while their
+/// total fits in the real-time share of the online CPUs.
+pub struct RealtimeScheduler {
+    cpus: [SpinLock<RtRunQueue>; MAX_CPUS],
+    rt_period: AtomicU64,
+    rt_runtime: AtomicU64,
+    /// Bandwidth reserved by deadline tasks, in `BW_UNIT`s
+    dl_bandwidth: AtomicU64,
+}
+
+impl RealtimeScheduler {
+    pub const fn new() -> Self {
+        RealtimeScheduler {
+            cpus: [const { SpinLock::new(RtRunQueue::new()) }; MAX_CPUS],
+            rt_period: AtomicU64::new(DEFAULT_RT_PERIOD_NS),
+            rt_runtime: AtomicU64::new(DEFAULT_RT_RUNTIME_NS),
+            dl_bandwidth: AtomicU64::new(0),
+        }
+    }
+
+    fn queue(&self, cpu: usize) -> SpinLockGuard<'_, RtRunQueue> {
+        self.cpus[cpu].lock()
+    }
+
+    pub fn rt_period(&self) -> u64 {
+        self.rt_period.load(Ordering::Relaxed)
+    }
+
+    pub fn rt_runtime(&self) -> u64 {
+        self.rt_runtime.load(Ordering::Relaxed)
+    }
+
+    /// Limit FIFO and round-robin tasks to `runtime` of every `period` on each CPU;
+    /// a runtime equal to the period turns throttling off
+    pub fn set_rt_bandwidth(&self, period: u64, runtime: u64) -> Result<(), &'static str> {
+        if period == 0 || runtime > period {
+            return Err("Real-time runtime must not exceed its period");
+        }
+        self.rt_period.store(period, Ordering::Relaxed);
+        self.rt_runtime.store(runtime, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Bandwidth deadline tasks may reserve in total
+    fn dl_bandwidth_limit(&self) -> u64 {
+        let per_cpu = (self.rt_runtime() as u128 * BW_UNIT as u128 / self.rt_period() as u128) as u64;
+        per_cpu * online_count() as u64
+    }
+
+    /// Check new parameters and reserve the bandwidth a deadline task needs.
+    /// On success the entity's old reservation, if any, is released.
+    pub fn admit(&self, rt: &RtEntity, policy: SchedPolicy, param: &SchedParam) -> Result<(), &'static str> {
+        match policy {
+            SchedPolicy::Fifo | SchedPolicy::RoundRobin => {
+                if !(RT_PRIO_MIN..=RT_PRIO_MAX).contains(&param.priority) {
+                    return Err("Real-time priority must be between 1 and 99");
+                }
+            }
+            SchedPolicy::Normal | SchedPolicy::Deadline => {
+                if param.priority != 0 {
+                    return Err("Priority must be 0 for this policy");
+                }
+            }
+        }
+        let new_bw = if policy == SchedPolicy::Deadline {
+            let period = if param.period == 0 { param.deadline } else { param.period };
+            if param.runtime < DL_MIN_RUNTIME_NS || param.runtime > param.deadline || param.deadline > period {
+                return Err("Deadline parameters must satisfy runtime <= deadline <= period");
+            }
+            SchedParam { period, ..*param }.bandwidth()
+        } else {
+            0
+        };
+        let old_bw = if rt.policy == SchedPolicy::Deadline {
+            SchedParam { period: rt.period(), ..rt.param }.bandwidth()
+        } else {
+            0
+        };
+        let limit = self.dl_bandwidth_limit();
+        self.dl_bandwidth
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                let total = used - old_bw + new_bw;
+                (new_bw <= old_bw || total <= limit).then_some(total)
+            })
+            .map(|_| ())
+            .map_err(|_| "Not enough deadline bandwidth left")
+    }
+
+    /// Give up a deadline task's reservation, when it exits
+    pub fn release(&self, rt: &mut RtEntity) {
+        if rt.policy == SchedPolicy::Deadline {
+            let bw = SchedParam { period: rt.period(), ..rt.param }.bandwidth();
+            self.dl_bandwidth.fetch_sub(bw, Ordering::AcqRel);
+        }
+        rt.policy = SchedPolicy::Normal;
+    }
+
+    /// Switch an entity that is on no run queue to a policy passed by `admit`
+    pub fn set_policy(&self, rt: &mut RtEntity, policy: SchedPolicy, param: SchedParam) {
+        rt.policy = policy;
+        rt.param = param;
+        rt.rr_remaining = RR_TIMESLICE_NS;
+        rt.dl_runtime_left = 0;
+        rt.dl_deadline = 0;
+        rt.dl_throttled = false;
+    }
+
+    /// Make `tid` runnable on `cpu`. Returns whether it should preempt the
+    /// task running there, which is any fair task.
+    pub fn enqueue(&self, cpu: usize, tid: ThreadId, rt: &mut RtEntity, now: u64) -> bool {
+        if rt.on_rq || !rt.is_realtime() {
+            return false;
+        }
+        if rt.policy == SchedPolicy::Deadline && !rt.dl_throttled {
+            // A waking task whose leftover runtime would exceed its bandwidth
+            // before the old deadline starts a new period
+            let overflow = rt.dl_runtime_left.max(0) as u128 * rt.period() as u128
+                > rt.dl_deadline.saturating_sub(now) as u128 * rt.param.runtime as u128;
+            if rt.dl_deadline <= now || overflow {
+                rt.dl_deadline = now + rt.param.deadline;
+                rt.dl_runtime_left = rt.param.runtime as i64;
+            }
+        }
+        without_interrupts(|| {
+            let mut rq = self.queue(cpu);
+            rq.insert(tid, rt, false);
+            rq.nr_running += 1;
+            rt.on_rq = true;
+            rt.cpu = cpu;
+            !rt.dl_throttled && rq.preempts(rt)
+        })
+    }
+
+    /// Take `tid` off its run queue, whether queued or running
+    pub fn dequeue(&self, tid: ThreadId, rt: &mut RtEntity) {
+        if !rt.on_rq {
+            return;
+        }
+        without_interrupts(|| {
+            let mut rq = self.queue(rt.cpu);
+            if rq.curr.map(|curr| curr.tid) == Some(tid) {
+                rq.curr = None;
+            } else {
+                rq.remove(tid, rt);
+            }
+            rq.nr_running -= 1;
+        });
+        rt.on_rq = false;
+    }
+
+    /// Drop a task that no longer exists from `cpu`'s queues
+    pub fn forget(&self, cpu: usize, tid: ThreadId) {
+        without_interrupts(|| {
+            let mut rq = self.queue(cpu);
+            for priority in 0..=RT_PRIO_MAX {
+                rq.remove_level(tid, priority);
+            }
+            rq.deadlines.retain(|&(_, queued)| queued != tid);
+            rq.throttled.retain(|&(_, queued)| queued != tid);
+        })
+    }
+
+    /// Deadline task with the earliest deadline, else the first FIFO or
+    /// round-robin task of the highest priority unless they are throttled
+    pub fn pick_next(&self, cpu: usize) -> Option<ThreadId> {
+        without_interrupts(|| {
+            let rq = self.queue(cpu);
+            if let Some(&(_, tid)) = rq.deadlines.first() {
+                return Some(tid);
+            }
+            if rq.rt_throttled {
+                return None;
+            }
+            rq.highest_priority().and_then(|priority| rq.levels[priority as usize].front().copied())
+        })
+    }
+
+    /// Whether `pick_next` would find a task
+    pub fn has_ready(&self, cpu: usize) -> bool {
+        self.pick_next(cpu).is_some()
+    }
+
+    /// Whether a real-time task runs on `cpu`
+    pub fn is_running(&self, cpu: usize) -> bool {
+        without_interrupts(|| self.queue(cpu).curr.is_some())
+    }
+
+    /// Start running `tid`, picked from `cpu`'s queues
+    pub fn set_next(&self, cpu: usize, tid: ThreadId, rt: &mut RtEntity, now: u64) {
+        without_interrupts(|| {
+            let mut rq = self.queue(cpu);
+            if !rt.on_rq {
+                rq.nr_running += 1;
+                rt.on_rq = true;
+                rt.cpu = cpu;
+            }
+            rq.remove(tid, rt);
+            rq.curr = Some(RtCurr { tid, policy: rt.policy, priority: rt.param.priority, deadline: rt.dl_deadline });
+        });
+        rt.exec_start = now;
+    }
+
+    /// Stop running `tid`: charge its run time and queue it again, or take
+    /// it off the queue if it is no longer runnable. A preempted task stays
+    /// at the front of its priority; one that yielded or used up its
+    /// round-robin slice goes to the back.
+    pub fn put_prev(&self, cpu: usize, tid: ThreadId, rt: &mut RtEntity, now: u64, runnable: bool) {
+        without_interrupts(|| {
+            let mut rq = self.queue(cpu);
+            if rq.curr.map(|curr| curr.tid) == Some(tid) {
+                rq.update_curr(rt, now);
+                rq.curr = None;
+                if runnable {
+                    let expired = rt.policy == SchedPolicy::RoundRobin && rt.rr_remaining == 0;
+                    if expired {
+                        rt.rr_remaining = RR_TIMESLICE_NS;
+                    }
+                    let front = !(expired || rt.yielded);
+                    rq.insert(tid, rt, front);
+                } else {
+                    rq.nr_running -= 1;
+                    rt.on_rq = false;
+                }
+            } else if runnable && !rt.on_rq {
+                rq.insert(tid, rt, false);
+                rq.nr_running += 1;
+                rt.on_rq = true;
+                rt.cpu = cpu;
+            }
+            rt.yielded = false;
+        })
+    }
+
+    /// Charge a timer tick to the running real-time task. Returns whether it
+    /// has to give up the CPU: its round-robin slice or deadline runtime is
+    /// used up, or the CPU's real-time bandwidth is.
+    pub fn tick(&self, cpu: usize, tid: ThreadId, rt: &mut RtEntity, now: u64) -> bool {
+        without_interrupts(|| {
+            let mut rq = self.queue(cpu);
+            if rq.curr.map(|curr| curr.tid) != Some(tid) {
+                return true;
+            }
+            rq.update_curr(rt, now);
+            match rt.policy {
+                SchedPolicy::Deadline => rt.dl_throttled,
+                SchedPolicy::Fifo | SchedPolicy::RoundRobin => {
+                    if rq.rt_time > self.rt_runtime() && self.rt_runtime() < self.rt_period() {
+                        rq.rt_throttled = true;
+                        return true;
+                    }
+                    rt.policy == SchedPolicy::RoundRobin
+                        && rt.rr_remaining == 0
+                        && !rq.levels[rt.param.priority as usize].is_empty()
+                }
+                SchedPolicy::Normal => true,
+            }
+        })
+    }
+
+    /// Start a new bandwidth period on `cpu` once the last one is over.
+    /// Returns whether throttled tasks may run again.
+    pub fn period_tick(&self, cpu: usize, now: u64) -> bool {
+        without_interrupts(|| {
+            let mut rq = self.queue(cpu);
+            if now.saturating_sub(rq.period_start) < self.rt_period() {
+                return false;
+            }
+            rq.period_start = now;
+            rq.rt_time = 0;
+            let unthrottled = rq.rt_throttled && rq.bitmap != 0;
+            rq.rt_throttled = false;
+            unthrottled
+        })
+    }
+
+    /// A throttled deadline task on `cpu` whose next period has begun
+    pub fn next_replenish(&self, cpu: usize, now: u64) -> Option<ThreadId> {
+        without_interrupts(|| {
+            let mut rq = self.queue(cpu);
+            match rq.throttled.first() {
+                Some(&(at, tid)) if at <= now => {
+                    rq.throttled.remove(&(at, tid));
+                    Some(tid)
+                }
+                _ => None,
+            }
+        })
+    }
+
+    /// Refill the runtime of a throttled deadline task for its next period
+    /// and queue it. Returns whether it should preempt the running task.
+    pub fn replenish(&self, cpu: usize, tid: ThreadId, rt: &mut RtEntity, now: u64) -> bool {
+        if rt.policy != SchedPolicy::Deadline || !rt.dl_throttled {
+            return false;
+        }
+        while rt.dl_runtime_left <= 0 {
+            rt.dl_deadline += rt.period();
+            rt.dl_runtime_left += rt.param.runtime as i64;
+        }
+        if rt.dl_deadline <= now {
+            rt.dl_deadline = now + rt.param.deadline;
+            rt.dl_runtime_left = rt.param.runtime as i64;
+        }
+        rt.dl_throttled = false;
+        without_interrupts(|| {
+            let mut rq = self.queue(cpu);
+            rq.insert(tid, rt, false);
+            rq.preempts(rt)
+        })
+    }
+
+    /// Real-time tasks queued or running on `cpu`
+    pub fn nr_running(&self, cpu: usize) -> usize {
+        without_interrupts(|| self.queue(cpu).nr_running)
+    }
+
+    /// Real-time tasks waiting to run on `cpu`
+    pub fn nr_queued(&self, cpu: usize) -> usize {
+        without_interrupts(|| {
+            let rq = self.queue(cpu);
+            rq.nr_running - rq.curr.is_some() as usize
+        })
+    }
+
+    /// Share of the CPUs reserved by deadline tasks, in parts per million
+    pub fn dl_utilization(&self) -> u64 {
+        self.dl_bandwidth.load(Ordering::Relaxed) * 1_000_000 / BW_UNIT
+    }
+}
+
+pub static REALTIME: RealtimeScheduler = RealtimeScheduler::new();
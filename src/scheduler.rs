@@ -1,44 +1,544 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::context::{self, KernelStack, TaskContext};
+use crate::hardware::cpu::{current_cpu, online_count, online_mask, this_cpu, MAX_CPUS};
+use crate::hardware::smp;
+use crate::memory::vmm::borrow_address_space;
+use crate::memory::VMM;
+use crate::performance::scheduler_opt::{SchedEntity, NICE_MAX, NICE_MIN, OPTIMIZED_SCHEDULER};
 use crate::process::{ProcessId, ProcessState, PROCESS_MANAGER};
-use spin::Mutex;
-use alloc::collections::VecDeque;
+use crate::realtime::{RtEntity, SchedParam, SchedPolicy, REALTIME};
+use crate::sync::{self, SpinLock};
+use crate::thread::{Thread, ThreadId, THREAD_MANAGER};
+use crate::timer;
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use x86_64::structures::paging::PhysFrame;
 
+/// CPU that runs the boot flow
+const BOOT_CPU: usize = 0;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SchedulerStats {
+    pub context_switches: u64,
+    pub preemptions: u64,
+    pub migrations: u64,
+    pub ready_tasks: usize,
+    pub online_cpus: usize,
+}
+
+/// Scheduling state of one CPU; the fair run queue itself is in `OPTIMIZED_SCHEDULER`
+struct RunQueue {
+    current: SpinLock<Option<ThreadId>>,
+    /// Saved state of the CPU's own flow while a thread runs: the boot flow
+    /// on the bootstrap processor, the idle loop on the others
+    own_context: SpinLock<TaskContext>,
+    /// Where the state of a task destroyed while running is dumped when switching away from it
+    dead_context: SpinLock<TaskContext>,
+    /// Kernel stacks of destroyed threads, freed once this CPU no longer runs on them
+    retired_stacks: SpinLock<Vec<(ThreadId, KernelStack)>>,
+    /// Thread this CPU last switched away from, released by `finish_switch`
+    switched_from: SpinLock<Option<ThreadId>>,
+    need_resched: AtomicBool,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        RunQueue {
+            current: SpinLock::new(None),
+            own_context: SpinLock::new(TaskContext::boot()),
+            dead_context: SpinLock::new(TaskContext::boot()),
+            retired_stacks: SpinLock::new(Vec::new()),
+            switched_from: SpinLock::new(None),
+            need_resched: AtomicBool::new(false),
+        }
+    }
+}
+
+/// What became of an attempt to switch to a thread
+enum Switch {
+    Done,
+    /// The thread exited or blocked while queued
+    Skipped,
+    /// Another CPU is still switching away from the thread; try again later
+    Busy,
+    /// The thread may no longer run on this CPU
+    Migrate,
+}
+
+/// Thread scheduler with one run queue per CPU. Deadline, FIFO and
+/// round-robin threads are picked by `realtime`, ahead of the rest, which
+/// the completely fair scheduler in `performance::scheduler_opt` picks.
+///
+/// A thread is queued on a CPU allowed by its affinity mask, the least
+/// loaded one when it is made runnable, and stays there until its affinity
+/// changes. The boot flow, which runs the shell, is queued on the bootstrap
+/// processor as `ThreadId::BOOT` and reported as no current thread; other
+/// CPUs fall back to their idle loop. Every lock here is taken with
+/// interrupts disabled, since the timer interrupt schedules.
 pub struct Scheduler {
-    ready_queue: Mutex<VecDeque<ProcessId>>,
-    current_process: Mutex<Option<ProcessId>>,
+    cpus: [RunQueue; MAX_CPUS],
+    /// Scheduling state of the boot flow, which has no `Thread`; it always uses the fair scheduler
+    boot_entity: SpinLock<(SchedEntity, RtEntity)>,
+    context_switches: AtomicU64,
+    preemptions: AtomicU64,
+    migrations: AtomicU64,
 }
 
 impl Scheduler {
     pub const fn new() -> Self {
         Scheduler {
-            ready_queue: Mutex::new(VecDeque::new()),
-            current_process: Mutex::new(None),
+            cpus: [const { RunQueue::new() }; MAX_CPUS],
+            boot_entity: SpinLock::new((SchedEntity::new(), RtEntity::new())),
+            context_switches: AtomicU64::new(0),
+            preemptions: AtomicU64::new(0),
+            migrations: AtomicU64::new(0),
         }
     }
 
-    pub fn enqueue(&self, pid: ProcessId) {
-        self.ready_queue.lock().push_back(pid);
+    /// Run queue of the calling CPU; only stable while interrupts are disabled
+    fn this_rq(&self) -> &RunQueue {
+        &self.cpus[current_cpu()]
+    }
+
+    /// Least loaded online CPU in `affinity`, preferring `last` on a tie
+    fn select_cpu(&self, last: usize, affinity: u64) -> usize {
+        let online = online_mask();
+        let allowed = match affinity & online {
+            0 => online,
+            allowed => allowed,
+        };
+        let load = |cpu: usize| OPTIMIZED_SCHEDULER.nr_running(cpu) + REALTIME.nr_running(cpu);
+        let first = if allowed & 1 << last != 0 { last } else { allowed.trailing_zeros() as usize };
+        let (mut best, mut best_load) = (first, load(first));
+        for cpu in (0..MAX_CPUS).filter(|&cpu| allowed & 1 << cpu != 0 && cpu != first) {
+            let cpu_load = load(cpu);
+            if cpu_load < best_load {
+                best = cpu;
+                best_load = cpu_load;
+            }
+        }
+        best
+    }
+
+    /// Run `f` on the fair and real-time scheduling state of a task
+    fn with_entity<R>(&self, tid: ThreadId, f: impl FnOnce(&mut SchedEntity, &mut RtEntity) -> R) -> Option<R> {
+        if tid == ThreadId::BOOT {
+            return Some(without_interrupts(|| {
+                let mut boot = self.boot_entity.lock();
+                let (se, rt) = &mut *boot;
+                f(se, rt)
+            }));
+        }
+        THREAD_MANAGER.with_thread(tid, |thread| f(&mut thread.sched, &mut thread.rt))
+    }
+
+    /// Queue a task in its class on `cpu`; returns whether it should preempt the running task
+    fn class_enqueue(&self, cpu: usize, tid: ThreadId, se: &mut SchedEntity, rt: &mut RtEntity, now: u64) -> bool {
+        if rt.is_realtime() {
+            REALTIME.enqueue(cpu, tid, rt, now)
+        } else {
+            OPTIMIZED_SCHEDULER.enqueue(cpu, tid, se) && !REALTIME.is_running(cpu)
+        }
+    }
+
+    /// Take a task off whichever run queue it is on
+    fn class_dequeue(&self, tid: ThreadId, se: &mut SchedEntity, rt: &mut RtEntity) {
+        OPTIMIZED_SCHEDULER.dequeue(tid, se);
+        REALTIME.dequeue(tid, rt);
+    }
+
+    fn class_set_next(&self, cpu: usize, tid: ThreadId, se: &mut SchedEntity, rt: &mut RtEntity, now: u64) {
+        if rt.is_realtime() {
+            REALTIME.set_next(cpu, tid, rt, now);
+        } else {
+            OPTIMIZED_SCHEDULER.set_next(cpu, tid, se, now);
+        }
     }
 
-    pub fn schedule_next(&self) -> Option<ProcessId> {
-        let mut queue = self.ready_queue.lock();
-        let next = queue.pop_front();
-        if let Some(pid) = next {
-            *self.current_process.lock() = Some(pid);
+    fn class_put_prev(&self, cpu: usize, tid: ThreadId, se: &mut SchedEntity, rt: &mut RtEntity, now: u64, runnable: bool) {
+        if rt.is_realtime() {
+            REALTIME.put_prev(cpu, tid, rt, now, runnable);
+        } else {
+            OPTIMIZED_SCHEDULER.put_prev(cpu, tid, se, now, runnable);
         }
-        next
     }
 
-    pub fn get_current(&self) -> Option<ProcessId> {
-        *self.current_process.lock()
+    fn class_tick(&self, cpu: usize, tid: ThreadId, se: &mut SchedEntity, rt: &mut RtEntity, now: u64) -> bool {
+        if rt.is_realtime() {
+            REALTIME.tick(cpu, tid, rt, now)
+        } else {
+            OPTIMIZED_SCHEDULER.tick(cpu, tid, se, now)
+        }
+    }
+
+    /// Make `cpu` schedule at its next opportunity
+    fn resched_cpu(&self, cpu: usize) {
+        without_interrupts(|| {
+            if cpu == current_cpu() {
+                self.cpus[cpu].need_resched.store(true, Ordering::Relaxed);
+            } else {
+                smp::send_reschedule(cpu);
+            }
+        });
+    }
+
+    /// Put a thread on `cpu`'s run queue unless it is already queued or
+    /// running, and preempt what runs there if the thread should run first
+    fn enqueue_on(&self, cpu: usize, tid: ThreadId) {
+        let now = timer::now_ns();
+        let preempt = self.with_entity(tid, |se, rt| self.class_enqueue(cpu, tid, se, rt, now)).unwrap_or(false);
+        if preempt {
+            self.resched_cpu(cpu);
+        }
+    }
+
+    /// Make a task runnable; the boot flow is always runnable and needs no enqueueing
+    pub fn enqueue(&self, tid: ThreadId) {
+        if tid == ThreadId::BOOT {
+            self.enqueue_on(BOOT_CPU, tid);
+            return;
+        }
+        let Some((last, affinity)) = THREAD_MANAGER.with_thread(tid, |t| (t.cpu, t.affinity)) else { return };
+        let cpu = self.select_cpu(last, affinity);
+        if cpu != last {
+            self.migrations.fetch_add(1, Ordering::Relaxed);
+        }
+        self.enqueue_on(cpu, tid);
+    }
+
+    /// Make every thread of a process runnable
+    pub fn enqueue_process(&self, pid: ProcessId) {
+        for tid in THREAD_MANAGER.threads_of(pid) {
+            self.enqueue(tid);
+        }
+    }
+
+    /// Take a thread off its run queue
+    pub fn remove(&self, tid: ThreadId) {
+        self.with_entity(tid, |se, rt| self.class_dequeue(tid, se, rt));
+    }
+
+    /// Forget a thread that is leaving the thread list, releasing any
+    /// bandwidth it reserved
+    pub(crate) fn detach(&self, thread: &mut Thread) {
+        self.class_dequeue(thread.tid, &mut thread.sched, &mut thread.rt);
+        REALTIME.release(&mut thread.rt);
+    }
+
+    /// Drop every thread of a process from the ready queues
+    pub fn remove_process(&self, pid: ProcessId) {
+        for tid in THREAD_MANAGER.threads_of(pid) {
+            self.remove(tid);
+        }
+    }
+
+    /// The thread running on this CPU, or `None` while the boot flow or idle loop runs
+    pub fn current_thread(&self) -> Option<ThreadId> {
+        without_interrupts(|| *self.this_rq().current.lock())
+    }
+
+    /// Whether this CPU has anything it could switch to
+    pub fn has_ready(&self) -> bool {
+        without_interrupts(|| {
+            let cpu = current_cpu();
+            REALTIME.has_ready(cpu) || OPTIMIZED_SCHEDULER.nr_queued(cpu) != 0
+        })
     }
 
+    /// Give up the CPU to the next task; a real-time task goes behind the
+    /// others of its priority
     pub fn yield_cpu(&self) {
-        if let Some(current) = self.get_current() {
-            self.enqueue(current);
-            *self.current_process.lock() = None;
+        if let Some(tid) = self.current_thread() {
+            self.with_entity(tid, |_, rt| rt.mark_yield());
+        }
+        self.schedule();
+    }
+
+    /// Nice value of a thread
+    pub fn nice(&self, tid: ThreadId) -> Option<i8> {
+        self.with_entity(tid, |se, _| se.nice())
+    }
+
+    /// Change a thread's nice value, and with it its share of the CPU
+    pub fn set_nice(&self, tid: ThreadId, nice: i8) -> Result<(), &'static str> {
+        if !(NICE_MIN..=NICE_MAX).contains(&nice) {
+            return Err("Nice value out of range");
+        }
+        self.with_entity(tid, |se, _| OPTIMIZED_SCHEDULER.reweight(se, nice)).ok_or("Thread not found")
+    }
+
+    /// Scheduling policy and parameters of a thread
+    pub fn scheduler(&self, tid: ThreadId) -> Option<(SchedPolicy, SchedParam)> {
+        self.with_entity(tid, |_, rt| (rt.policy(), rt.param()))
+    }
+
+    /// Move a thread to another scheduling class or change its parameters.
+    /// Deadline parameters are refused if the CPUs cannot guarantee them.
+    pub fn set_scheduler(&self, tid: ThreadId, policy: SchedPolicy, param: SchedParam) -> Result<(), &'static str> {
+        if tid == ThreadId::BOOT {
+            return Err("The boot flow always uses the fair scheduler");
+        }
+        let now = timer::now_ns();
+        let cpu = THREAD_MANAGER.with_thread(tid, |thread| {
+            REALTIME.admit(&thread.rt, policy, &param)?;
+            let queued_on = if thread.rt.on_rq() {
+                Some(thread.rt.cpu())
+            } else if thread.sched.on_rq() {
+                Some(thread.sched.cpu())
+            } else {
+                None
+            };
+            self.class_dequeue(tid, &mut thread.sched, &mut thread.rt);
+            REALTIME.set_policy(&mut thread.rt, policy, param);
+            if let Some(cpu) = queued_on {
+                self.class_enqueue(cpu, tid, &mut thread.sched, &mut thread.rt, now);
+            }
+            // A running thread is queued again in its new class; its CPU picks anew
+            Ok::<_, &'static str>(queued_on)
+        }).ok_or("Thread not found")??;
+        if let Some(cpu) = cpu {
+            self.resched_cpu(cpu);
+        }
+        Ok(())
+    }
+
+    /// Task the scheduler accounts the time on this CPU to: the boot flow
+    /// counts as a task, the idle loops of the other CPUs do not
+    fn running_task(&self, cpu: usize, current: Option<ThreadId>) -> Option<ThreadId> {
+        match current {
+            None if cpu == BOOT_CPU => Some(ThreadId::BOOT),
+            current => current,
+        }
+    }
+
+    /// Charge a timer tick to the running task and preempt it once its slice is used up
+    pub fn timer_tick(&self) {
+        let cpu = current_cpu();
+        let rq = &self.cpus[cpu];
+        let now = timer::now_ns();
+        let mut preempt = REALTIME.period_tick(cpu, now);
+        while let Some(tid) = REALTIME.next_replenish(cpu, now) {
+            preempt |= self.with_entity(tid, |_, rt| REALTIME.replenish(cpu, tid, rt, now)).unwrap_or(false);
+        }
+        preempt |= match self.running_task(cpu, *rq.current.lock()) {
+            Some(tid) => self.with_entity(tid, |se, rt| self.class_tick(cpu, tid, se, rt, now)).unwrap_or(true),
+            None => self.has_ready(),
+        };
+        if preempt {
+            rq.need_resched.store(true, Ordering::Relaxed);
+        }
+        self.preempt();
+    }
+
+    /// Another CPU asked this one to schedule
+    pub fn reschedule_interrupt(&self) {
+        self.cpus[current_cpu()].need_resched.store(true, Ordering::Relaxed);
+        self.preempt();
+    }
+
+    /// Switch away from the interrupted task if it is due to. A task holding a
+    /// spinlock keeps running with `need_resched` still set, so the next tick
+    /// or reschedule interrupt tries again.
+    pub fn preempt(&self) {
+        let rq = &self.cpus[current_cpu()];
+        if rq.need_resched.load(Ordering::Relaxed) && !sync::in_atomic() {
+            self.preemptions.fetch_add(1, Ordering::Relaxed);
+            self.schedule();
+        }
+    }
+
+    /// Body of an application processor once it is up: run whatever is
+    /// queued, and sleep until the next interrupt when nothing is
+    pub fn idle(&self) -> ! {
+        loop {
+            self.schedule();
+            interrupts::disable();
+            if self.has_ready() {
+                interrupts::enable();
+            } else {
+                interrupts::enable_and_hlt();
+            }
+        }
+    }
+
+    /// Save the running task and resume the queued one that has run least,
+    /// returning when the caller is scheduled again. A task that is not
+    /// running any more, because it blocked or was terminated, leaves the
+    /// run queue.
+    #[track_caller]
+    pub fn schedule(&self) {
+        if cfg!(debug_assertions) && sync::in_atomic() {
+            panic!("scheduling while atomic on CPU {}", current_cpu());
+        }
+        without_interrupts(|| {
+            let cpu = current_cpu();
+            let rq = &self.cpus[cpu];
+            let Some(kernel_pml4) = VMM.kernel_address_space().map(|space| space.pml4_frame()) else { return };
+            rq.need_resched.store(false, Ordering::Relaxed);
+            let prev = *rq.current.lock();
+            let prev_task = self.running_task(cpu, prev);
+            let now = timer::now_ns();
+            // Charge prev and let it compete with the queued tasks
+            let runnable = self.is_running(prev);
+            if let Some(tid) = prev_task {
+                self.with_entity(tid, |se, rt| self.class_put_prev(cpu, tid, se, rt, now, runnable));
+            }
+            loop {
+                let next = match REALTIME.pick_next(cpu).or_else(|| OPTIMIZED_SCHEDULER.pick_next(cpu)) {
+                    Some(next) => next,
+                    // A thread that stopped running leaves an idle CPU to its own flow
+                    None if !runnable => ThreadId::BOOT,
+                    None => return,
+                };
+                if Some(next) == prev_task {
+                    self.with_entity(next, |se, rt| self.class_set_next(cpu, next, se, rt, now));
+                    return;
+                }
+                match self.switch_to(prev, next, now, kernel_pml4) {
+                    Switch::Done => return,
+                    Switch::Skipped | Switch::Migrate => continue,
+                    // The other CPU is about to finish switching away from it
+                    Switch::Busy => core::hint::spin_loop(),
+                }
+            }
+        })
+    }
+
+    fn is_running(&self, tid: Option<ThreadId>) -> bool {
+        let Some(tid) = tid else { return true };
+        THREAD_MANAGER.with_threads(|threads| {
+            threads.iter().any(|t| t.tid == tid && t.state == ProcessState::Running)
+        })
+    }
+
+    /// Switch from `prev`, already put back by `schedule`, to `next` on this CPU
+    fn switch_to(&self, prev: Option<ThreadId>, next: ThreadId, now: u64, kernel_pml4: PhysFrame) -> Switch {
+        let cpu = current_cpu();
+        let rq = &self.cpus[cpu];
+        let own_context = &mut *rq.own_context.lock() as *mut TaskContext;
+        let dead_context = &mut *rq.dead_context.lock() as *mut TaskContext;
+
+        let switch = THREAD_MANAGER.with_threads(|threads| {
+            let next_task = if next == ThreadId::BOOT {
+                if cpu == BOOT_CPU {
+                    OPTIMIZED_SCHEDULER.set_next(cpu, next, &mut self.boot_entity.lock().0, now);
+                }
+                (own_context as *const TaskContext, kernel_pml4, None, ProcessId::KERNEL)
+            } else {
+                let Some(thread) = threads.iter_mut().map(|t| &mut **t).find(|t| t.tid == next) else {
+                    OPTIMIZED_SCHEDULER.forget(cpu, next);
+                    REALTIME.forget(cpu, next);
+                    return Err(Switch::Skipped);
+                };
+                if thread.state != ProcessState::Ready {
+                    self.class_dequeue(next, &mut thread.sched, &mut thread.rt);
+                    return Err(Switch::Skipped);
+                }
+                if thread.on_cpu {
+                    return Err(Switch::Busy);
+                }
+                if thread.affinity & 1 << cpu == 0 {
+                    self.class_dequeue(next, &mut thread.sched, &mut thread.rt);
+                    return Err(Switch::Migrate);
+                }
+                self.class_set_next(cpu, next, &mut thread.sched, &mut thread.rt, now);
+                thread.state = ProcessState::Running;
+                thread.on_cpu = true;
+                thread.cpu = cpu;
+                let pml4 = thread.pml4_frame.unwrap_or(kernel_pml4);
+                (&thread.context as *const TaskContext, pml4, thread.context.kernel_stack_top(), thread.process)
+            };
+
+            // Whether prev has to be queued again: it must move to another
+            // CPU, or was woken after `schedule` found it no longer running
+            let prev_task = match prev {
+                None => (own_context, false),
+                Some(tid) => match threads.iter_mut().map(|t| &mut **t).find(|t| t.tid == tid) {
+                    Some(thread) => {
+                        let mut requeue = false;
+                        if thread.state == ProcessState::Running {
+                            thread.state = ProcessState::Ready;
+                            if thread.affinity & 1 << cpu == 0 {
+                                self.class_dequeue(tid, &mut thread.sched, &mut thread.rt);
+                                requeue = true;
+                            }
+                        } else if thread.state == ProcessState::Ready {
+                            requeue = !thread.sched.on_rq() && !thread.rt.on_rq();
+                        }
+                        (&mut thread.context as *mut TaskContext, requeue)
+                    }
+                    None => (dead_context, false),
+                },
+            };
+            Ok((prev_task, next_task))
+        });
+        let ((prev_context, requeue_prev), (next_context, pml4, kernel_stack, process)) = match switch {
+            Ok(switch) => switch,
+            Err(Switch::Migrate) => {
+                // Queued here before its affinity changed; let `enqueue` place it again
+                self.enqueue(next);
+                return Switch::Skipped;
+            }
+            Err(result) => return result,
+        };
+
+        if let Some(prev) = prev.filter(|_| requeue_prev) {
+            // Another CPU picking it spins until `finish_switch` releases it
+            self.enqueue(prev);
+        }
+        let current = if next == ThreadId::BOOT { None } else { Some(next) };
+        *rq.current.lock() = current;
+        this_cpu().set_current_thread(current.map(ThreadId::as_u64));
+        *rq.switched_from.lock() = prev;
+        PROCESS_MANAGER.set_current_process(process);
+        if let Some(top) = kernel_stack {
+            crate::gdt::set_kernel_stack(top);
+        }
+        let space = unsafe { borrow_address_space(pml4) };
+        VMM.switch_to(&space);
+        self.context_switches.fetch_add(1, Ordering::Relaxed);
+
+        unsafe {
+            context::switch(prev_context, next_context);
+        }
+        // Possibly on another CPU by now
+        self.finish_switch();
+        Switch::Done
+    }
+
+    /// Hand over the kernel stack of a thread destroyed while `cpu` runs it,
+    /// to be freed once that CPU has switched away
+    pub fn retire_stack(&self, cpu: usize, tid: ThreadId, stack: KernelStack) {
+        without_interrupts(|| self.cpus[cpu].retired_stacks.lock().push((tid, stack)))
+    }
+
+    /// Clean-up a task runs right after being switched to: the thread this
+    /// CPU left may run elsewhere now, and stacks of dead threads it left
+    /// behind can go
+    pub fn finish_switch(&self) {
+        let (prev, retired) = without_interrupts(|| {
+            let rq = self.this_rq();
+            let current = *rq.current.lock();
+            let mut stacks = rq.retired_stacks.lock();
+            let (keep, retired): (Vec<_>, Vec<_>) = stacks.drain(..).partition(|(tid, _)| Some(*tid) == current);
+            *stacks = keep;
+            (rq.switched_from.lock().take(), retired)
+        });
+        if let Some(prev) = prev {
+            THREAD_MANAGER.with_thread(prev, |thread| thread.on_cpu = false);
+        }
+        drop(retired);
+    }
+
+    pub fn stats(&self) -> SchedulerStats {
+        SchedulerStats {
+            context_switches: self.context_switches.load(Ordering::Relaxed),
+            preemptions: self.preemptions.load(Ordering::Relaxed),
+            migrations: self.migrations.load(Ordering::Relaxed),
+            ready_tasks: (0..MAX_CPUS).map(|cpu| OPTIMIZED_SCHEDULER.nr_queued(cpu) + REALTIME.nr_queued(cpu)).sum(),
+            online_cpus: online_count(),
         }
     }
 }
 
 pub static SCHEDULER: Scheduler = Scheduler::new();
-
@@ -1,6 +1,6 @@
 use x86_64::VirtAddr;
 use core::sync::atomic::{AtomicU64, Ordering};
-use crate::random::SecureRandom;
+use crate::security::random::SecureRandom;
 
 static ASLR_ENABLED: AtomicU64 = AtomicU64::new(0);
 static BASE_OFFSET: AtomicU64 = AtomicU64::new(0);
@@ -32,6 +32,17 @@ impl ASLR {
         }
     }
 
+    /// A page-aligned offset below `range` to slide a region by, or 0 with ASLR off
+    pub fn random_page_offset(range: u64) -> u64 {
+        let pages = range / 4096;
+        if !Self::is_enabled() || pages == 0 {
+            return 0;
+        }
+        let mut rng = SecureRandom::new();
+        let value = rng.next_u64() ^ BASE_OFFSET.load(Ordering::Relaxed);
+        (value % pages) * 4096
+    }
+
     pub fn get_base_offset() -> u64 {
         BASE_OFFSET.load(Ordering::Relaxed)
     }
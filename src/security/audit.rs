@@ -1,4 +1,4 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::VecDeque;
 use crate::process::ProcessId;
 
@@ -22,15 +22,15 @@ pub enum AuditEventType {
 }
 
 pub struct AuditLogger {
-    events: Mutex<VecDeque<AuditEvent, 1024>>,
-    enabled: Mutex<bool>,
+    events: SpinLock<VecDeque<AuditEvent, 1024>>,
+    enabled: SpinLock<bool>,
 }
 
 impl AuditLogger {
     pub const fn new() -> Self {
         AuditLogger {
-            events: Mutex::new(VecDeque::new()),
-            enabled: Mutex::new(true),
+            events: SpinLock::new(VecDeque::new()),
+            enabled: SpinLock::new(true),
         }
     }
 
@@ -1,6 +1,6 @@
 use bitflags::bitflags;
 use crate::process::ProcessId;
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
 
 bitflags! {
@@ -41,13 +41,13 @@ bitflags! {
 }
 
 pub struct CapabilityManager {
-    process_caps: Mutex<BTreeMap<ProcessId, Capabilities>>,
+    process_caps: SpinLock<BTreeMap<ProcessId, Capabilities>>,
 }
 
 impl CapabilityManager {
     pub const fn new() -> Self {
         CapabilityManager {
-            process_caps: Mutex::new(BTreeMap::new()),
+            process_caps: SpinLock::new(BTreeMap::new()),
         }
     }
 
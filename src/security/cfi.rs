@@ -1,16 +1,16 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeSet;
 
 pub struct ControlFlowIntegrity {
-    enabled: Mutex<bool>,
-    valid_targets: Mutex<BTreeSet<u64>>,
+    enabled: SpinLock<bool>,
+    valid_targets: SpinLock<BTreeSet<u64>>,
 }
 
 impl ControlFlowIntegrity {
     pub const fn new() -> Self {
         ControlFlowIntegrity {
-            enabled: Mutex::new(false),
-            valid_targets: Mutex::new(BTreeSet::new()),
+            enabled: SpinLock::new(false),
+            valid_targets: SpinLock::new(BTreeSet::new()),
         }
     }
 
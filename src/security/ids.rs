@@ -1,6 +1,6 @@
 use crate::net::ip::{IPv4Packet, IPAddress};
 use crate::process::ProcessId;
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -21,17 +21,17 @@ pub struct ThreatEvent {
 }
 
 pub struct IntrusionDetectionSystem {
-    events: Mutex<alloc::collections::VecDeque<ThreatEvent, 1024>>,
-    enabled: Mutex<bool>,
-    thresholds: Mutex<BTreeMap<ThreatLevel, u64>>,
+    events: SpinLock<alloc::collections::VecDeque<ThreatEvent, 1024>>,
+    enabled: SpinLock<bool>,
+    thresholds: SpinLock<BTreeMap<ThreatLevel, u64>>,
 }
 
 impl IntrusionDetectionSystem {
     pub const fn new() -> Self {
         IntrusionDetectionSystem {
-            events: Mutex::new(alloc::collections::VecDeque::new()),
-            enabled: Mutex::new(true),
-            thresholds: Mutex::new(BTreeMap::new()),
+            events: SpinLock::new(alloc::collections::VecDeque::new()),
+            enabled: SpinLock::new(true),
+            thresholds: SpinLock::new(BTreeMap::new()),
         }
     }
     
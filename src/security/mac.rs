@@ -1,6 +1,6 @@
 use crate::process::ProcessId;
 use crate::fs::inode::Inode;
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
 use heapless::String;
 
@@ -19,17 +19,17 @@ pub struct MacPolicy {
 }
 
 pub struct MandatoryAccessControl {
-    policies: Mutex<BTreeMap<(String<64>, String<64>), MacPolicy>>,
-    process_labels: Mutex<BTreeMap<ProcessId, String<64>>>,
-    object_labels: Mutex<BTreeMap<u64, String<64>>>, // inode -> label
+    policies: SpinLock<BTreeMap<(String<64>, String<64>), MacPolicy>>,
+    process_labels: SpinLock<BTreeMap<ProcessId, String<64>>>,
+    object_labels: SpinLock<BTreeMap<u64, String<64>>>, // inode -> label
 }
 
 impl MandatoryAccessControl {
     pub const fn new() -> Self {
         MandatoryAccessControl {
-            policies: Mutex::new(BTreeMap::new()),
-            process_labels: Mutex::new(BTreeMap::new()),
-            object_labels: Mutex::new(BTreeMap::new()),
+            policies: SpinLock::new(BTreeMap::new()),
+            process_labels: SpinLock::new(BTreeMap::new()),
+            object_labels: SpinLock::new(BTreeMap::new()),
         }
     }
 
@@ -1,18 +1,18 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use crate::security::random::SecureRandom;
 
 pub struct SecureBoot {
-    enabled: Mutex<bool>,
-    verified: Mutex<bool>,
-    boot_hash: Mutex<[u8; 32]>,
+    enabled: SpinLock<bool>,
+    verified: SpinLock<bool>,
+    boot_hash: SpinLock<[u8; 32]>,
 }
 
 impl SecureBoot {
     pub const fn new() -> Self {
         SecureBoot {
-            enabled: Mutex::new(false),
-            verified: Mutex::new(false),
-            boot_hash: Mutex::new([0; 32]),
+            enabled: SpinLock::new(false),
+            verified: SpinLock::new(false),
+            boot_hash: SpinLock::new([0; 32]),
         }
     }
 
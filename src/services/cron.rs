@@ -1,4 +1,4 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
 
 #[derive(Debug, Clone)]
@@ -12,15 +12,15 @@ pub struct CronJob {
 }
 
 pub struct CronScheduler {
-    jobs: Mutex<alloc::vec::Vec<CronJob>>,
-    last_minute: Mutex<u8>,
+    jobs: SpinLock<alloc::vec::Vec<CronJob>>,
+    last_minute: SpinLock<u8>,
 }
 
 impl CronScheduler {
     pub const fn new() -> Self {
         CronScheduler {
-            jobs: Mutex::new(alloc::vec::Vec::new()),
-            last_minute: Mutex::new(255),
+            jobs: SpinLock::new(alloc::vec::Vec::new()),
+            last_minute: SpinLock::new(255),
         }
     }
 
@@ -1,5 +1,5 @@
 use crate::services::service::SERVICE_MANAGER;
-use spin::Mutex;
+use crate::sync::SpinLock;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Runlevel {
@@ -13,15 +13,15 @@ pub enum Runlevel {
 }
 
 pub struct InitSystem {
-    current_runlevel: Mutex<Runlevel>,
-    target_runlevel: Mutex<Runlevel>,
+    current_runlevel: SpinLock<Runlevel>,
+    target_runlevel: SpinLock<Runlevel>,
 }
 
 impl InitSystem {
     pub const fn new() -> Self {
         InitSystem {
-            current_runlevel: Mutex::new(Runlevel::SingleUser),
-            target_runlevel: Mutex::new(Runlevel::MultiUserNetwork),
+            current_runlevel: SpinLock::new(Runlevel::SingleUser),
+            target_runlevel: SpinLock::new(Runlevel::MultiUserNetwork),
         }
     }
 
@@ -1,4 +1,4 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
 use heapless::String;
 
@@ -28,13 +28,13 @@ pub enum RestartPolicy {
 }
 
 pub struct ServiceManager {
-    services: Mutex<BTreeMap<String<64>, Service>>,
+    services: SpinLock<BTreeMap<String<64>, Service>>,
 }
 
 impl ServiceManager {
     pub const fn new() -> Self {
         ServiceManager {
-            services: Mutex::new(BTreeMap::new()),
+            services: SpinLock::new(BTreeMap::new()),
         }
     }
 
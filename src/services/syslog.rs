@@ -1,4 +1,4 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::VecDeque;
 use heapless::String;
 
@@ -23,15 +23,15 @@ pub struct LogEntry {
 }
 
 pub struct Syslog {
-    entries: Mutex<VecDeque<LogEntry, 1024>>,
-    min_level: Mutex<LogLevel>,
+    entries: SpinLock<VecDeque<LogEntry, 1024>>,
+    min_level: SpinLock<LogLevel>,
 }
 
 impl Syslog {
     pub const fn new() -> Self {
         Syslog {
-            entries: Mutex::new(VecDeque::new()),
-            min_level: Mutex::new(LogLevel::Debug),
+            entries: SpinLock::new(VecDeque::new()),
+            min_level: SpinLock::new(LogLevel::Debug),
         }
     }
 
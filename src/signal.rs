@@ -0,0 +1,749 @@
+use core::arch::global_asm;
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::{InterruptStackFrame, InterruptStackFrameValue};
+use x86_64::VirtAddr;
+use crate::context::{FpuState, UserFrame};
+use crate::hardware::cpu::current_cpu;
+use crate::hardware::smp;
+use crate::memory::mmap::USER_END;
+use crate::process::{self, ProcessId, ProcessState, PROCESS_MANAGER};
+use crate::scheduler::SCHEDULER;
+use crate::thread::{Thread, ThreadId, THREAD_MANAGER};
+
+/// Highest signal number; 32 and up are the real-time signals, which are not queued
+pub const NSIG: u8 = 64;
+
+pub const SIGHUP: u8 = 1;
+pub const SIGINT: u8 = 2;
+pub const SIGQUIT: u8 = 3;
+pub const SIGILL: u8 = 4;
+pub const SIGTRAP: u8 = 5;
+pub const SIGABRT: u8 = 6;
+pub const SIGBUS: u8 = 7;
+pub const SIGFPE: u8 = 8;
+pub const SIGKILL: u8 = 9;
+pub const SIGUSR1: u8 = 10;
+pub const SIGSEGV: u8 = 11;
+pub const SIGUSR2: u8 = 12;
+pub const SIGPIPE: u8 = 13;
+pub const SIGALRM: u8 = 14;
+pub const SIGTERM: u8 = 15;
+pub const SIGSTKFLT: u8 = 16;
+pub const SIGCHLD: u8 = 17;
+pub const SIGCONT: u8 = 18;
+pub const SIGSTOP: u8 = 19;
+pub const SIGTSTP: u8 = 20;
+pub const SIGTTIN: u8 = 21;
+pub const SIGTTOU: u8 = 22;
+pub const SIGURG: u8 = 23;
+pub const SIGXCPU: u8 = 24;
+pub const SIGXFSZ: u8 = 25;
+pub const SIGVTALRM: u8 = 26;
+pub const SIGPROF: u8 = 27;
+pub const SIGWINCH: u8 = 28;
+pub const SIGIO: u8 = 29;
+pub const SIGPWR: u8 = 30;
+pub const SIGSYS: u8 = 31;
+
+/// Handler values with a special meaning
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+/// Do not send `SIGCHLD` when a child stops or continues
+pub const SA_NOCLDSTOP: u64 = 0x0000_0001;
+/// Reap children as they exit instead of leaving zombies
+pub const SA_NOCLDWAIT: u64 = 0x0000_0002;
+/// The handler takes a `siginfo` and the saved registers as well
+pub const SA_SIGINFO: u64 = 0x0000_0004;
+/// `restorer` holds the trampoline the handler returns to; required for handlers
+pub const SA_RESTORER: u64 = 0x0400_0000;
+/// Leave the signal unblocked while its handler runs
+pub const SA_NODEFER: u64 = 0x4000_0000;
+/// Go back to the default action once the handler has been called
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+/// `sigprocmask` operations
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+/// `siginfo` codes: who sent a signal, or why the kernel raised it
+pub const SI_USER: i32 = 0;
+pub const SI_KERNEL: i32 = 0x80;
+pub const SI_TKILL: i32 = -6;
+pub const SEGV_MAPERR: i32 = 1;
+pub const SEGV_ACCERR: i32 = 2;
+pub const ILL_ILLOPN: i32 = 2;
+pub const FPE_INTDIV: i32 = 1;
+pub const TRAP_BRKPT: i32 = 1;
+pub const CLD_EXITED: i32 = 1;
+pub const CLD_KILLED: i32 = 2;
+pub const CLD_STOPPED: i32 = 5;
+pub const CLD_CONTINUED: i32 = 6;
+
+/// Size of `siginfo` in user memory
+pub const SIGINFO_SIZE: usize = 128;
+
+/// Below the interrupted stack pointer, left alone for the interrupted code
+const RED_ZONE: u64 = 128;
+
+/// Flags `sigreturn` takes from the saved frame; the others keep their current value
+const USER_RFLAGS_MASK: u64 = 0x0000_0000_0005_0DD5;
+const RFLAGS_TF: u64 = 1 << 8;
+const RFLAGS_DF: u64 = 1 << 10;
+
+/// RFLAGS `signal_interrupt_return` starts with: interrupts stay disabled
+/// until the frame is complete
+const STUB_RFLAGS: u64 = 0x2;
+
+/// Bit of `sig` in a signal set
+pub const fn sigmask(sig: u8) -> u64 {
+    1 << (sig - 1)
+}
+
+/// Signals that can be neither blocked, caught nor ignored
+pub const UNBLOCKABLE: u64 = sigmask(SIGKILL) | sigmask(SIGSTOP);
+
+/// Signals whose default action stops the process
+const STOP_SIGNALS: u64 = sigmask(SIGSTOP) | sigmask(SIGTSTP) | sigmask(SIGTTIN) | sigmask(SIGTTOU);
+
+/// Signal number named by user space, if valid
+pub fn from_u64(sig: u64) -> Option<u8> {
+    (1..=NSIG as u64).contains(&sig).then_some(sig as u8)
+}
+
+/// What a signal does to a process when its handler is `SIG_DFL`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction {
+    Terminate,
+    /// Terminate; no core file is written
+    Core,
+    Stop,
+    /// Resume a stopped process, which happens as the signal is sent
+    Continue,
+    Ignore,
+}
+
+pub fn default_action(sig: u8) -> DefaultAction {
+    match sig {
+        SIGCHLD | SIGURG | SIGWINCH => DefaultAction::Ignore,
+        SIGCONT => DefaultAction::Continue,
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+        SIGQUIT | SIGILL | SIGTRAP | SIGABRT | SIGBUS | SIGFPE | SIGSEGV | SIGXCPU | SIGXFSZ | SIGSYS => DefaultAction::Core,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+/// Disposition of one signal, laid out as the kernel `sigaction` of Linux x86-64
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SigAction {
+    pub handler: u64,
+    pub flags: u64,
+    pub restorer: u64,
+    /// Signals blocked while the handler runs, on top of the signal itself
+    pub mask: u64,
+}
+
+impl SigAction {
+    pub const DEFAULT: SigAction = SigAction { handler: SIG_DFL, flags: 0, restorer: 0, mask: 0 };
+
+    pub const SIZE: usize = 32;
+
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        let field = |i: usize| u64::from_ne_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+        SigAction { handler: field(0), flags: field(1), restorer: field(2), mask: field(3) }
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        for (i, field) in [self.handler, self.flags, self.restorer, self.mask].into_iter().enumerate() {
+            bytes[i * 8..i * 8 + 8].copy_from_slice(&field.to_ne_bytes());
+        }
+        bytes
+    }
+
+    /// Whether delivering `sig` with this action would do nothing
+    pub fn ignores(&self, sig: u8) -> bool {
+        match self.handler {
+            SIG_IGN => true,
+            SIG_DFL => default_action(sig) == DefaultAction::Ignore,
+            _ => false,
+        }
+    }
+}
+
+/// Disposition of every signal. Threads sharing handlers (`CLONE_SIGHAND`)
+/// always share a process, so the table lives in the process.
+#[derive(Clone)]
+pub struct SignalHandlers {
+    actions: [SigAction; NSIG as usize],
+}
+
+impl SignalHandlers {
+    pub const fn new() -> Self {
+        SignalHandlers { actions: [SigAction::DEFAULT; NSIG as usize] }
+    }
+
+    pub fn get(&self, sig: u8) -> SigAction {
+        self.actions[sig as usize - 1]
+    }
+
+    /// Install `action` for `sig`, returning the previous one
+    pub fn set(&mut self, sig: u8, action: SigAction) -> Result<SigAction, &'static str> {
+        if sigmask(sig) & UNBLOCKABLE != 0 {
+            return Err("SIGKILL and SIGSTOP cannot be caught or ignored");
+        }
+        if action.handler > SIG_IGN && action.flags & SA_RESTORER == 0 {
+            return Err("A signal handler needs SA_RESTORER");
+        }
+        if action.handler >= USER_END || action.restorer >= USER_END {
+            return Err("Signal handler outside user space");
+        }
+        Ok(core::mem::replace(&mut self.actions[sig as usize - 1], action))
+    }
+
+    /// Reset `sig` to its default action, even for the signals `set` refuses
+    pub fn reset(&mut self, sig: u8) {
+        self.actions[sig as usize - 1] = SigAction::DEFAULT;
+    }
+}
+
+/// What a signal carries besides its number
+#[derive(Debug, Clone, Copy)]
+pub struct SigInfo {
+    pub signo: u8,
+    pub code: i32,
+    /// Sender, or the child a `SIGCHLD` is about
+    pub pid: ProcessId,
+    pub uid: u32,
+    /// Exit status or signal of the child a `SIGCHLD` is about
+    pub status: i32,
+    /// Faulting address
+    pub addr: u64,
+}
+
+impl SigInfo {
+    /// Sent by `kill` from `pid`
+    pub fn user(signo: u8, pid: ProcessId, uid: u32) -> Self {
+        SigInfo { signo, code: SI_USER, pid, uid, status: 0, addr: 0 }
+    }
+
+    /// Raised by the kernel on its own behalf
+    pub fn kernel(signo: u8) -> Self {
+        SigInfo { signo, code: SI_KERNEL, pid: ProcessId::KERNEL, uid: 0, status: 0, addr: 0 }
+    }
+
+    /// Raised by a fault at `addr`
+    pub fn fault(signo: u8, code: i32, addr: u64) -> Self {
+        SigInfo { signo, code, pid: ProcessId::KERNEL, uid: 0, status: 0, addr }
+    }
+
+    /// Tells a parent that child `pid` exited, was killed, stopped or continued
+    pub fn child(pid: ProcessId, uid: u32, code: i32, status: i32) -> Self {
+        SigInfo { signo: SIGCHLD, code, pid, uid, status, addr: 0 }
+    }
+
+    fn is_fault(&self) -> bool {
+        matches!(self.signo, SIGSEGV | SIGBUS | SIGILL | SIGFPE | SIGTRAP) && self.code > 0 && self.code < SI_KERNEL
+    }
+
+    /// Linux `siginfo` layout: number, errno and code, then either the fault
+    /// address or the sender's pid, uid and the child's status
+    pub fn to_bytes(&self) -> [u8; SIGINFO_SIZE] {
+        let mut bytes = [0u8; SIGINFO_SIZE];
+        bytes[0..4].copy_from_slice(&(self.signo as i32).to_ne_bytes());
+        bytes[8..12].copy_from_slice(&self.code.to_ne_bytes());
+        if self.is_fault() {
+            bytes[16..24].copy_from_slice(&self.addr.to_ne_bytes());
+        } else {
+            bytes[16..20].copy_from_slice(&(self.pid.as_u64() as i32).to_ne_bytes());
+            bytes[20..24].copy_from_slice(&self.uid.to_ne_bytes());
+            bytes[24..28].copy_from_slice(&self.status.to_ne_bytes());
+        }
+        bytes
+    }
+}
+
+/// A stop or exit of its whole process that a thread has yet to carry out,
+/// which it does on its next return to user mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupAction {
+    Stop,
+    Exit,
+}
+
+/// Signals sent to a thread and not delivered yet. As with Linux's standard
+/// signals, a signal that is already pending is not queued a second time.
+pub struct PendingSignals {
+    mask: u64,
+    info: Vec<SigInfo>,
+    pub group: Option<GroupAction>,
+}
+
+impl PendingSignals {
+    pub const fn new() -> Self {
+        PendingSignals { mask: 0, info: Vec::new(), group: None }
+    }
+
+    pub fn mask(&self) -> u64 {
+        self.mask
+    }
+
+    /// Record a signal; returns false if it was already pending
+    pub fn add(&mut self, info: SigInfo) -> bool {
+        if self.mask & sigmask(info.signo) != 0 {
+            return false;
+        }
+        self.mask |= sigmask(info.signo);
+        self.info.push(info);
+        true
+    }
+
+    /// Forget the pending signals in `set`
+    pub fn discard(&mut self, set: u64) {
+        self.mask &= !set;
+        self.info.retain(|info| set & sigmask(info.signo) == 0);
+    }
+
+    /// Take the lowest-numbered pending signal outside `blocked`
+    pub fn take(&mut self, blocked: u64) -> Option<SigInfo> {
+        let ready = self.mask & !blocked;
+        if ready == 0 {
+            return None;
+        }
+        let signo = ready.trailing_zeros() as u8 + 1;
+        self.mask &= !sigmask(signo);
+        let pos = self.info.iter().position(|info| info.signo == signo)?;
+        Some(self.info.swap_remove(pos))
+    }
+
+    /// Whether the thread has something to do on its way back to user mode
+    pub fn deliverable(&self, blocked: u64) -> bool {
+        self.group.is_some() || self.mask & !blocked != 0
+    }
+}
+
+/// What `deliver` pushes on the user stack before running a handler. The
+/// handler starts with the stack pointer at `restorer`, as if called from
+/// there, so its `ret` lands in the trampoline, which calls `sigreturn`.
+#[repr(C)]
+struct SignalFrame {
+    restorer: u64,
+    info: [u8; SIGINFO_SIZE],
+    /// Signals blocked before the handler ran
+    blocked: u64,
+    regs: UserFrame,
+    fpu: [u8; FpuState::SIZE],
+}
+
+// An interrupt that found a signal for the user thread it interrupted returns
+// here instead, in ring 0 at the top of the thread's kernel stack with the
+// user registers untouched. Pushing them next to the slots of the interrupt
+// frame completes a `UserFrame`, as `syscall_entry` does, and the signal is
+// delivered like on the way out of a system call.
+global_asm!(
+    ".global signal_interrupt_return",
+    "signal_interrupt_return:",
+    "sub rsp, 40",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "cld",
+    "call finish_signal_interrupt",
+    "jmp return_to_user",
+);
+
+extern "C" {
+    fn signal_interrupt_return();
+}
+
+/// Whether `tid` has a signal to take, so a blocking call should return early
+pub fn signal_pending(tid: ThreadId) -> bool {
+    THREAD_MANAGER.with_thread(tid, |thread| thread.pending_signals.deliverable(thread.signal_mask)).unwrap_or(false)
+}
+
+/// Called last by interrupt handlers. If the interrupt came from user mode
+/// and the thread has a signal to take, return through `signal_interrupt_return`.
+pub fn interrupt_exit(stack_frame: &mut InterruptStackFrame) {
+    if stack_frame.code_segment & 3 != 3 {
+        return;
+    }
+    let Some(tid) = SCHEDULER.current_thread() else { return };
+    let selectors = crate::gdt::selectors();
+    let interrupted = **stack_frame;
+    THREAD_MANAGER.with_thread(tid, |thread| {
+        if !thread.pending_signals.deliverable(thread.signal_mask) {
+            return;
+        }
+        let Some(top) = thread.context.kernel_stack_top() else { return };
+        thread.interrupted = Some(interrupted);
+        unsafe {
+            stack_frame.as_mut().update(|frame| {
+                frame.instruction_pointer = VirtAddr::new(signal_interrupt_return as usize as u64);
+                frame.code_segment = selectors.kernel_code.0 as u64;
+                frame.cpu_flags = STUB_RFLAGS;
+                frame.stack_pointer = top;
+                frame.stack_segment = selectors.kernel_data.0 as u64;
+            });
+        }
+    });
+}
+
+#[no_mangle]
+extern "C" fn finish_signal_interrupt(frame: &mut UserFrame) {
+    let interrupted: Option<InterruptStackFrameValue> = SCHEDULER.current_thread()
+        .and_then(|tid| THREAD_MANAGER.with_thread(tid, |thread| thread.interrupted.take()))
+        .flatten();
+    let interrupted = interrupted.expect("signal return without an interrupted frame");
+    frame.rip = interrupted.instruction_pointer.as_u64();
+    frame.cs = interrupted.code_segment;
+    frame.rflags = interrupted.cpu_flags;
+    frame.rsp = interrupted.stack_pointer.as_u64();
+    frame.ss = interrupted.stack_segment;
+    // Like a system call, delivery may take locks and be preempted
+    interrupts::enable();
+    deliver(frame);
+}
+
+/// Side effects a signal has on its target process as it is sent, before
+/// any thread takes it
+fn prepare(pid: ProcessId, sig: u8) {
+    match sig {
+        SIGKILL => group_exit(pid, process::signal_status(SIGKILL)),
+        SIGCONT => {
+            let mut was_stopped = false;
+            let woken = THREAD_MANAGER.with_threads(|threads| {
+                let mut woken = Vec::new();
+                for thread in threads.iter_mut().filter(|t| t.process == pid) {
+                    thread.pending_signals.discard(STOP_SIGNALS);
+                    if thread.pending_signals.group == Some(GroupAction::Stop) {
+                        thread.pending_signals.group = None;
+                    }
+                    if thread.state == ProcessState::Stopped {
+                        thread.state = ProcessState::Ready;
+                        woken.push(thread.tid);
+                        was_stopped = true;
+                    }
+                }
+                woken
+            });
+            for tid in woken {
+                SCHEDULER.enqueue(tid);
+            }
+            if was_stopped {
+                notify_parent(pid, CLD_CONTINUED, SIGCONT as i32);
+            }
+        }
+        sig if sigmask(sig) & STOP_SIGNALS != 0 => {
+            THREAD_MANAGER.with_threads(|threads| {
+                for thread in threads.iter_mut().filter(|t| t.process == pid) {
+                    thread.pending_signals.discard(sigmask(SIGCONT));
+                }
+            });
+        }
+        _ => {}
+    }
+}
+
+/// Queue `info` on a thread unless it would be ignored. Returns the CPU to
+/// interrupt so the thread notices, if it runs on another one.
+fn queue(thread: &mut Thread, info: SigInfo, action: &SigAction) -> Option<Notify> {
+    let blocked = thread.signal_mask & sigmask(info.signo) != 0;
+    if (action.ignores(info.signo) && !blocked) || !thread.pending_signals.add(info) {
+        return None;
+    }
+    // A blocked signal is only looked at once it is unblocked
+    if blocked {
+        return None;
+    }
+    notice(thread)
+}
+
+/// What it takes for a thread to notice a signal or group action
+enum Notify {
+    /// Interrupt the CPU it runs on
+    Kick(usize),
+    /// Wake it from a sleep, which an interruptible wait then cuts short
+    Wake(ThreadId),
+}
+
+impl Notify {
+    fn send(self) {
+        match self {
+            Notify::Kick(cpu) => smp::send_reschedule(cpu),
+            Notify::Wake(tid) => SCHEDULER.enqueue(tid),
+        }
+    }
+}
+
+/// Make `thread` runnable if it sleeps; called with the thread list locked,
+/// the returned `Notify` is sent once it is unlocked
+fn notice(thread: &mut Thread) -> Option<Notify> {
+    if thread.state == ProcessState::Blocked {
+        thread.state = ProcessState::Ready;
+        return Some(Notify::Wake(thread.tid));
+    }
+    (thread.on_cpu && thread.cpu != current_cpu()).then_some(Notify::Kick(thread.cpu))
+}
+
+/// Send `info` to a process, as `kill` does. The first thread that does not
+/// block the signal takes it; if all block it, it waits on the main thread.
+pub fn send_to_process(pid: ProcessId, info: SigInfo) -> Result<(), &'static str> {
+    let action = PROCESS_MANAGER.sigaction(pid, info.signo).ok_or("Process not found")?;
+    prepare(pid, info.signo);
+    if info.signo == SIGKILL {
+        return Ok(());
+    }
+    let notify = THREAD_MANAGER.with_threads(|threads| {
+        let mut live = threads.iter_mut().filter(|t| t.process == pid && t.state != ProcessState::Terminated);
+        let mut first = None;
+        for thread in &mut live {
+            if thread.signal_mask & sigmask(info.signo) == 0 {
+                return queue(thread, info, &action);
+            }
+            first.get_or_insert(thread);
+        }
+        first.and_then(|thread| queue(thread, info, &action))
+    });
+    if let Some(notify) = notify {
+        notify.send();
+    }
+    Ok(())
+}
+
+/// Send `info` to one thread, as `tkill` does
+pub fn send_to_thread(tid: ThreadId, info: SigInfo) -> Result<(), &'static str> {
+    let pid = THREAD_MANAGER.with_thread(tid, |thread| thread.process).ok_or("Thread not found")?;
+    let action = PROCESS_MANAGER.sigaction(pid, info.signo).ok_or("Process not found")?;
+    prepare(pid, info.signo);
+    if info.signo == SIGKILL {
+        return Ok(());
+    }
+    if let Some(notify) = THREAD_MANAGER.with_thread(tid, |thread| queue(thread, info, &action)).flatten() {
+        notify.send();
+    }
+    Ok(())
+}
+
+/// Raise a fault signal on the running thread. It is delivered even if
+/// blocked or ignored: those go back to the default action, as in Linux.
+pub fn force_fault(signo: u8, code: i32, addr: u64) {
+    let Some(tid) = SCHEDULER.current_thread() else { return };
+    let Some(pid) = PROCESS_MANAGER.get_current_process() else { return };
+    let ignored = PROCESS_MANAGER.sigaction(pid, signo).map_or(false, |action| action.handler == SIG_IGN);
+    let blocked = THREAD_MANAGER.with_thread(tid, |thread| {
+        let blocked = thread.signal_mask & sigmask(signo) != 0;
+        thread.signal_mask &= !sigmask(signo);
+        // An earlier fault may still be pending if its handler could not be set up
+        thread.pending_signals.discard(sigmask(signo));
+        thread.pending_signals.add(SigInfo::fault(signo, code, addr));
+        blocked
+    }).unwrap_or(false);
+    if blocked || ignored {
+        PROCESS_MANAGER.reset_sigaction(pid, signo);
+    }
+}
+
+/// Tell the parent of `pid` about a change of state, unless it asked not to
+/// hear about stops and continues
+pub fn notify_parent(pid: ProcessId, code: i32, status: i32) {
+    let Some(parent) = PROCESS_MANAGER.parent(pid) else { return };
+    if matches!(code, CLD_STOPPED | CLD_CONTINUED) {
+        let action = PROCESS_MANAGER.sigaction(parent, SIGCHLD).unwrap_or(SigAction::DEFAULT);
+        if action.flags & SA_NOCLDSTOP != 0 {
+            return;
+        }
+    }
+    let uid = PROCESS_MANAGER.credentials(pid).map_or(0, |c| c.uid);
+    send_to_process(parent, SigInfo::child(pid, uid, code, status)).ok();
+}
+
+/// Make every thread of `pid` exit at its next return to user mode, the
+/// process leaving wait status `status` for its parent
+pub fn group_exit(pid: ProcessId, status: i32) {
+    if !PROCESS_MANAGER.begin_exit(pid, status) {
+        return;
+    }
+    if status & 0x7f != 0 {
+        crate::io::println!("Process {} killed by signal {}", pid.as_u64(), status & 0x7f);
+    }
+    let notify = THREAD_MANAGER.with_threads(|threads| {
+        let mut notify = Vec::new();
+        for thread in threads.iter_mut().filter(|t| t.process == pid) {
+            thread.pending_signals.group = Some(GroupAction::Exit);
+            if thread.state == ProcessState::Stopped {
+                thread.state = ProcessState::Ready;
+                notify.push(Notify::Wake(thread.tid));
+            } else {
+                notify.extend(notice(thread));
+            }
+        }
+        notify
+    });
+    for notify in notify {
+        notify.send();
+    }
+}
+
+/// Stop every thread of `pid`; the caller stops right away, the others on
+/// their next return to user mode
+fn group_stop(pid: ProcessId, tid: ThreadId, sig: u8) {
+    let notify = THREAD_MANAGER.with_threads(|threads| {
+        let mut notify = Vec::new();
+        for thread in threads.iter_mut().filter(|t| t.process == pid) {
+            if thread.tid == tid {
+                thread.state = ProcessState::Stopped;
+            } else if thread.state != ProcessState::Terminated && thread.pending_signals.group.is_none() {
+                thread.pending_signals.group = Some(GroupAction::Stop);
+                notify.extend(notice(thread));
+            }
+        }
+        notify
+    });
+    for notify in notify {
+        notify.send();
+    }
+    notify_parent(pid, CLD_STOPPED, sig as i32);
+}
+
+enum Pending {
+    Exit,
+    Stopped,
+    Signal(SigInfo, u64),
+}
+
+/// Act on the signals of the running thread before it returns to user mode
+/// with `frame`. Default actions are carried out here; for a caught signal
+/// the frame is redirected to its handler.
+pub fn deliver(frame: &mut UserFrame) {
+    let Some(tid) = SCHEDULER.current_thread() else { return };
+    let Some(pid) = PROCESS_MANAGER.get_current_process() else { return };
+    loop {
+        let pending = THREAD_MANAGER.with_thread(tid, |thread| {
+            match thread.pending_signals.group {
+                Some(GroupAction::Exit) => Some(Pending::Exit),
+                Some(GroupAction::Stop) => {
+                    thread.pending_signals.group = None;
+                    thread.state = ProcessState::Stopped;
+                    Some(Pending::Stopped)
+                }
+                None => {
+                    let blocked = thread.signal_mask;
+                    thread.pending_signals.take(blocked).map(|info| Pending::Signal(info, blocked))
+                }
+            }
+        }).flatten();
+        let (info, blocked) = match pending {
+            None => return,
+            Some(Pending::Exit) => PROCESS_MANAGER.exit_thread(tid),
+            // Returns once the process is continued
+            Some(Pending::Stopped) => {
+                SCHEDULER.schedule();
+                continue;
+            }
+            Some(Pending::Signal(info, blocked)) => (info, blocked),
+        };
+
+        let action = PROCESS_MANAGER.sigaction(pid, info.signo).unwrap_or(SigAction::DEFAULT);
+        match action.handler {
+            SIG_IGN => {}
+            SIG_DFL => match default_action(info.signo) {
+                DefaultAction::Ignore | DefaultAction::Continue => {}
+                DefaultAction::Stop => {
+                    group_stop(pid, tid, info.signo);
+                    SCHEDULER.schedule();
+                }
+                DefaultAction::Terminate | DefaultAction::Core => {
+                    group_exit(pid, process::signal_status(info.signo));
+                    PROCESS_MANAGER.exit_thread(tid);
+                }
+            },
+            _ => match setup_frame(pid, tid, frame, &info, &action, blocked) {
+                Ok(()) => return,
+                // The stack cannot take the frame; the process dies of SIGSEGV
+                Err(_) => {
+                    PROCESS_MANAGER.reset_sigaction(pid, SIGSEGV);
+                    THREAD_MANAGER.with_thread(tid, |thread| {
+                        thread.signal_mask &= !sigmask(SIGSEGV);
+                        thread.pending_signals.add(SigInfo::kernel(SIGSEGV));
+                    });
+                }
+            },
+        }
+    }
+}
+
+/// Push a `SignalFrame` below the interrupted stack pointer and point `frame` at the handler
+fn setup_frame(pid: ProcessId, tid: ThreadId, frame: &mut UserFrame, info: &SigInfo, action: &SigAction, blocked: u64) -> Result<(), &'static str> {
+    let mut fpu = FpuState::new();
+    fpu.save();
+    let signal_frame = SignalFrame {
+        restorer: action.restorer,
+        info: info.to_bytes(),
+        blocked,
+        regs: *frame,
+        fpu: *fpu.as_bytes(),
+    };
+    let size = core::mem::size_of::<SignalFrame>() as u64;
+    // 16-byte aligned once the restorer is popped, as after a call
+    let addr = (frame.rsp.checked_sub(RED_ZONE + size).ok_or("User stack overflow")? & !15).checked_sub(8).ok_or("User stack overflow")?;
+    let bytes = unsafe { core::slice::from_raw_parts(&signal_frame as *const SignalFrame as *const u8, size as usize) };
+    PROCESS_MANAGER.write_user(pid, addr, bytes)?;
+
+    frame.rip = action.handler;
+    frame.rsp = addr;
+    frame.rdi = info.signo as u64;
+    frame.rsi = addr + core::mem::offset_of!(SignalFrame, info) as u64;
+    frame.rdx = addr + core::mem::offset_of!(SignalFrame, regs) as u64;
+    frame.rax = 0;
+    frame.rflags &= !(RFLAGS_DF | RFLAGS_TF);
+
+    let mut mask = blocked | action.mask;
+    if action.flags & SA_NODEFER == 0 {
+        mask |= sigmask(info.signo);
+    }
+    THREAD_MANAGER.with_thread(tid, |thread| thread.signal_mask = mask & !UNBLOCKABLE);
+    if action.flags & SA_RESETHAND != 0 {
+        PROCESS_MANAGER.reset_sigaction(pid, info.signo);
+    }
+    Ok(())
+}
+
+/// Restore the registers, FPU state and blocked signals saved by `setup_frame`.
+/// The handler's `ret` popped the restorer, so the frame starts just below
+/// the stack pointer the trampoline made the system call with.
+pub fn sigreturn(frame: &mut UserFrame) {
+    let Some(tid) = SCHEDULER.current_thread() else { return };
+    let Some(pid) = PROCESS_MANAGER.get_current_process() else { return };
+    let mut bytes = [0u8; core::mem::size_of::<SignalFrame>()];
+    let read = PROCESS_MANAGER.read_user(pid, frame.rsp.wrapping_sub(8), &mut bytes);
+    let saved = unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const SignalFrame) };
+    // `iretq` to a non-canonical address would fault in the kernel
+    if read.is_err() || saved.regs.rip >= USER_END || saved.regs.rsp >= USER_END {
+        force_fault(SIGSEGV, SI_KERNEL, 0);
+        return;
+    }
+
+    let (cs, ss, rflags) = (frame.cs, frame.ss, frame.rflags);
+    *frame = saved.regs;
+    frame.cs = cs;
+    frame.ss = ss;
+    frame.rflags = (rflags & !USER_RFLAGS_MASK) | (saved.regs.rflags & USER_RFLAGS_MASK);
+    FpuState::from_bytes(saved.fpu).restore();
+    THREAD_MANAGER.with_thread(tid, |thread| thread.signal_mask = saved.blocked & !UNBLOCKABLE);
+}
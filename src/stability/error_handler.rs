@@ -1,4 +1,4 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::VecDeque;
 
 #[derive(Debug, Clone)]
@@ -9,15 +9,15 @@ pub struct ErrorRecord {
 }
 
 pub struct ErrorHandler {
-    errors: Mutex<VecDeque<ErrorRecord, 1024>>,
-    panic_on_error: Mutex<bool>,
+    errors: SpinLock<VecDeque<ErrorRecord, 1024>>,
+    panic_on_error: SpinLock<bool>,
 }
 
 impl ErrorHandler {
     pub const fn new() -> Self {
         ErrorHandler {
-            errors: Mutex::new(VecDeque::new()),
-            panic_on_error: Mutex::new(false),
+            errors: SpinLock::new(VecDeque::new()),
+            panic_on_error: SpinLock::new(false),
         }
     }
 
@@ -1,4 +1,5 @@
 use core::sync::atomic::{AtomicU64, Ordering};
+use crate::memory::PMM;
 
 static MEMORY_USAGE: AtomicU64 = AtomicU64::new(0);
 static MAX_MEMORY: AtomicU64 = AtomicU64::new(1024 * 1024 * 1024); // 1GB default
@@ -9,9 +10,18 @@ pub struct ResourceMonitor;
 
 impl ResourceMonitor {
     pub fn check_memory() -> bool {
-        let usage = MEMORY_USAGE.load(Ordering::Relaxed);
+        let usage = Self::memory_usage();
         let max = MAX_MEMORY.load(Ordering::Relaxed);
-        usage < max
+        usage < max && (PMM.total_frame_count() == 0 || PMM.free_frame_count() > 0)
+    }
+
+    /// Bytes of physical memory in use, falling back to the manual counter before the PMM is up
+    fn memory_usage() -> u64 {
+        if PMM.total_frame_count() > 0 {
+            PMM.used_frame_count() as u64 * 4096
+        } else {
+            MEMORY_USAGE.load(Ordering::Relaxed)
+        }
     }
 
     pub fn check_processes() -> bool {
@@ -38,7 +48,7 @@ impl ResourceMonitor {
 
     pub fn get_stats() -> (u64, u64, u64, u64) {
         (
-            MEMORY_USAGE.load(Ordering::Relaxed),
+            Self::memory_usage(),
             MAX_MEMORY.load(Ordering::Relaxed),
             PROCESS_COUNT.load(Ordering::Relaxed),
             MAX_PROCESSES.load(Ordering::Relaxed),
@@ -0,0 +1,50 @@
+//! Kernel locks. Spinning locks (`SpinLock`, `RwLock`, `SeqLock`) keep the
+//! CPU from being preempted while held and may be taken anywhere; sleeping
+//! ones (`Mutex`, `Semaphore`) park the thread on a wait queue and may only be
+//! taken where sleeping is allowed. Debug builds check both rules, and report
+//! every acquisition to the lock validator in `stability::deadlock_detector`.
+
+mod mutex;
+mod rwlock;
+mod semaphore;
+mod seqlock;
+mod spinlock;
+
+pub use mutex::{Mutex, MutexGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::Semaphore;
+pub use seqlock::SeqLock;
+pub use spinlock::{IrqSpinLockGuard, SpinLock, SpinLockGuard};
+
+use x86_64::instructions::interrupts;
+use crate::hardware::cpu::{current_cpu, this_cpu};
+use crate::hardware::smp;
+
+/// Owner value of a spinning lock no CPU holds
+const NO_CPU: usize = usize::MAX;
+
+/// Debug check for code about to sleep: it must not hold a spinlock or run
+/// with interrupts disabled, as in an interrupt handler
+#[track_caller]
+pub fn might_sleep() {
+    if cfg!(debug_assertions) {
+        let depth = this_cpu().atomic_depth();
+        assert!(depth == 0, "sleeping while atomic: {} spinlocks held on CPU {}", depth, current_cpu());
+        assert!(interrupts::are_enabled(), "sleeping with interrupts disabled on CPU {}", current_cpu());
+    }
+}
+
+/// Whether this CPU holds a spinning lock
+pub fn in_atomic() -> bool {
+    this_cpu().atomic_depth() != 0
+}
+
+/// One round of waiting for a spinning lock. A CPU spinning with interrupts
+/// disabled, as in a page fault, would never take the TLB shootdown IPI, and
+/// the holder may be waiting for that very CPU to answer one; so it answers here.
+fn spin_wait() {
+    if !interrupts::are_enabled() {
+        smp::handle_shootdown();
+    }
+    core::hint::spin_loop();
+}
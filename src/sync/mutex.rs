@@ -0,0 +1,116 @@
+use core::cell::UnsafeCell;
+use core::panic::Location;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::scheduler::SCHEDULER;
+use crate::stability::deadlock_detector::{LockClass, DEADLOCK_DETECTOR};
+use crate::thread::ThreadId;
+use crate::wait::WaitQueue;
+use super::might_sleep;
+
+/// Owner value of a mutex nobody holds
+const NO_OWNER: u64 = u64::MAX;
+
+/// Sleeping lock: a thread that finds it taken blocks until it is released.
+/// Only for code that may sleep, never in interrupt handlers or under a spinlock.
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    /// Thread holding the lock, for the debug recursion check
+    owner: AtomicU64,
+    waiters: WaitQueue,
+    class: LockClass,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Mutex {
+            locked: AtomicBool::new(false),
+            owner: AtomicU64::new(NO_OWNER),
+            waiters: WaitQueue::new(),
+            class: LockClass::new(Location::caller()),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<T> {
+        might_sleep();
+        if cfg!(debug_assertions) && self.owner.load(Ordering::Relaxed) == current().as_u64() {
+            panic!("recursive mutex acquisition by thread {}", current().as_u64());
+        }
+        DEADLOCK_DETECTOR.acquire(self.addr(), self.class, Location::caller(), false);
+        if !self.try_acquire() {
+            self.waiters.wait_until(|| self.try_acquire());
+        }
+        MutexGuard { lock: self }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        if !self.try_acquire() {
+            return None;
+        }
+        DEADLOCK_DETECTOR.acquire(self.addr(), self.class, Location::caller(), true);
+        Some(MutexGuard { lock: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    fn try_acquire(&self) -> bool {
+        if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            return false;
+        }
+        self.owner.store(current().as_u64(), Ordering::Relaxed);
+        true
+    }
+
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn unlock(&self) {
+        DEADLOCK_DETECTOR.release(self.addr());
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+        self.locked.store(false, Ordering::Release);
+        self.waiters.wake_one();
+    }
+}
+
+/// The running thread; the boot flow counts as one
+fn current() -> ThreadId {
+    SCHEDULER.current_thread().unwrap_or(ThreadId::BOOT)
+}
+
+pub struct MutexGuard<'a, T> {
+    lock: &'a Mutex<T>,
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.unlock();
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
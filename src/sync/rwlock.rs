@@ -0,0 +1,191 @@
+use core::cell::UnsafeCell;
+use core::panic::Location;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use crate::hardware::cpu::{current_cpu, this_cpu};
+use crate::stability::deadlock_detector::{LockClass, DEADLOCK_DETECTOR};
+use super::{spin_wait, NO_CPU};
+
+/// Set while a writer holds the lock
+const WRITER: u32 = 1 << 31;
+/// Set while a writer spins for it, holding off new readers so writers are not starved
+const WRITER_WAITING: u32 = 1 << 30;
+const READERS: u32 = WRITER_WAITING - 1;
+
+/// Spinning reader-writer lock: any number of readers or one writer. The
+/// holder is not preempted.
+pub struct RwLock<T> {
+    /// Writer and waiting-writer bits, and the number of readers below them
+    state: AtomicU32,
+    /// CPU holding the write side, and CPUs holding the read side, for the
+    /// debug recursion check
+    writer: AtomicUsize,
+    readers: AtomicU64,
+    class: LockClass,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+unsafe impl<T: Send> Send for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        RwLock {
+            state: AtomicU32::new(0),
+            writer: AtomicUsize::new(NO_CPU),
+            readers: AtomicU64::new(0),
+            class: LockClass::new(Location::caller()),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    #[track_caller]
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        this_cpu().enter_atomic();
+        // A second read could wait forever behind a writer that waits on the first
+        self.check_recursion("read");
+        // For the same reason reads are ordered like writes
+        DEADLOCK_DETECTOR.acquire(self.addr(), self.class, Location::caller(), false);
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & (WRITER | WRITER_WAITING) == 0
+                && self.state.compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                break;
+            }
+            spin_wait();
+        }
+        self.readers.fetch_or(cpu_bit(), Ordering::Relaxed);
+        RwLockReadGuard { lock: self }
+    }
+
+    #[track_caller]
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        this_cpu().enter_atomic();
+        self.check_recursion("write");
+        DEADLOCK_DETECTOR.acquire(self.addr(), self.class, Location::caller(), false);
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & (WRITER | READERS) == 0 {
+                if self.state.compare_exchange_weak(state, WRITER, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                    break;
+                }
+            } else if state & WRITER_WAITING == 0 {
+                self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            }
+            spin_wait();
+        }
+        self.writer.store(current_cpu(), Ordering::Relaxed);
+        RwLockWriteGuard { lock: self }
+    }
+
+    #[track_caller]
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        this_cpu().enter_atomic();
+        let state = self.state.load(Ordering::Relaxed);
+        if state & (WRITER | WRITER_WAITING) != 0
+            || self.state.compare_exchange(state, state + 1, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            this_cpu().exit_atomic();
+            return None;
+        }
+        self.readers.fetch_or(cpu_bit(), Ordering::Relaxed);
+        DEADLOCK_DETECTOR.acquire(self.addr(), self.class, Location::caller(), true);
+        Some(RwLockReadGuard { lock: self })
+    }
+
+    #[track_caller]
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        this_cpu().enter_atomic();
+        let state = self.state.load(Ordering::Relaxed);
+        if state & (WRITER | READERS) != 0
+            || self.state.compare_exchange(state, WRITER, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            this_cpu().exit_atomic();
+            return None;
+        }
+        self.writer.store(current_cpu(), Ordering::Relaxed);
+        DEADLOCK_DETECTOR.acquire(self.addr(), self.class, Location::caller(), true);
+        Some(RwLockWriteGuard { lock: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    #[track_caller]
+    fn check_recursion(&self, side: &str) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        let cpu = current_cpu();
+        if self.writer.load(Ordering::Relaxed) == cpu {
+            panic!("recursive rwlock {} while writing on CPU {}", side, cpu);
+        }
+        if self.readers.load(Ordering::Relaxed) & cpu_bit() != 0 {
+            panic!("recursive rwlock {} while reading on CPU {}", side, cpu);
+        }
+    }
+
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn read_unlock(&self) {
+        DEADLOCK_DETECTOR.release(self.addr());
+        // Another reader on this CPU is ruled out by the recursion check
+        self.readers.fetch_and(!cpu_bit(), Ordering::Relaxed);
+        self.state.fetch_sub(1, Ordering::Release);
+        this_cpu().exit_atomic();
+    }
+
+    fn write_unlock(&self) {
+        DEADLOCK_DETECTOR.release(self.addr());
+        self.writer.store(NO_CPU, Ordering::Relaxed);
+        // Clears a waiting-writer bit too; a writer still spinning sets it again
+        self.state.store(0, Ordering::Release);
+        this_cpu().exit_atomic();
+    }
+}
+
+fn cpu_bit() -> u64 {
+    1 << current_cpu()
+}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<'a, T> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.read_unlock();
+    }
+}
+
+impl<'a, T> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<'a, T> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.write_unlock();
+    }
+}
+
+impl<'a, T> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
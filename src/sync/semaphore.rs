@@ -0,0 +1,52 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::wait::WaitQueue;
+use super::might_sleep;
+
+/// Counting semaphore: `wait` takes one unit, sleeping while there are none,
+/// and `signal` gives one back. Units are not owned by whoever took them, so
+/// there is no recursion to check; waiting still requires a context that may sleep.
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Semaphore {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Take a unit, sleeping until one is signalled if there are none
+    #[track_caller]
+    pub fn wait(&self) {
+        might_sleep();
+        if !self.try_wait() {
+            self.waiters.wait_until(|| self.try_wait());
+        }
+    }
+
+    /// Take a unit, giving up if a signal arrives first
+    #[track_caller]
+    pub fn wait_interruptible(&self) -> Result<(), &'static str> {
+        might_sleep();
+        if self.try_wait() {
+            return Ok(());
+        }
+        self.waiters.wait_until_interruptible(|| self.try_wait())
+    }
+
+    pub fn try_wait(&self) -> bool {
+        self.count.fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| count.checked_sub(1)).is_ok()
+    }
+
+    pub fn signal(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
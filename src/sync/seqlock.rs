@@ -0,0 +1,66 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{fence, AtomicU64, AtomicUsize, Ordering};
+use crate::hardware::cpu::current_cpu;
+use super::{SpinLock, NO_CPU};
+
+/// Sequence lock for small, often-read data such as the clock. Readers never
+/// block the writer: they copy the data and retry if a write overlapped.
+pub struct SeqLock<T: Copy> {
+    /// Odd while a write is in progress
+    seq: AtomicU64,
+    writer: SpinLock<()>,
+    /// CPU in the middle of a write, for the debug check on readers
+    writing: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+unsafe impl<T: Copy + Send> Send for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    /// The writer lock's class is named after the caller
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        SeqLock {
+            seq: AtomicU64::new(0),
+            writer: SpinLock::new(()),
+            writing: AtomicUsize::new(NO_CPU),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// A consistent copy of the data
+    #[track_caller]
+    pub fn read(&self) -> T {
+        // It would wait forever for its own write to finish
+        if cfg!(debug_assertions) && self.writing.load(Ordering::Relaxed) == current_cpu() {
+            panic!("seqlock read inside its own write on CPU {}", current_cpu());
+        }
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq & 1 != 0 {
+                core::hint::spin_loop();
+                continue;
+            }
+            let data = unsafe { core::ptr::read_volatile(self.data.get()) };
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == seq {
+                return data;
+            }
+        }
+    }
+
+    /// Update the data; writers are serialised among themselves
+    #[track_caller]
+    pub fn write(&self, update: impl FnOnce(&mut T)) {
+        let _writer = self.writer.lock();
+        self.writing.store(current_cpu(), Ordering::Relaxed);
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        let mut data = unsafe { core::ptr::read_volatile(self.data.get()) };
+        update(&mut data);
+        unsafe { core::ptr::write_volatile(self.data.get(), data) };
+        self.seq.fetch_add(1, Ordering::Release);
+        self.writing.store(NO_CPU, Ordering::Relaxed);
+    }
+}
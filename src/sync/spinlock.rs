@@ -0,0 +1,186 @@
+use core::cell::UnsafeCell;
+use core::panic::Location;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+use crate::hardware::cpu::{current_cpu, this_cpu};
+use crate::stability::deadlock_detector::{LockClass, DEADLOCK_DETECTOR};
+use super::{spin_wait, NO_CPU};
+
+/// Fair spinlock: CPUs take tickets and get the lock in the order they asked
+/// for it. The holder is not preempted.
+pub struct SpinLock<T> {
+    next_ticket: AtomicU32,
+    now_serving: AtomicU32,
+    /// CPU holding the lock, for the debug recursion check
+    owner: AtomicUsize,
+    /// `None` for a lock the validator does not track
+    class: Option<LockClass>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    /// Where this is called from names the lock's class for lock validation
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self::with_class(data, Some(LockClass::new(Location::caller())))
+    }
+
+    /// Lock the validator does not track, for the allocator it depends on
+    /// itself; locks taken while one is held go unvalidated too
+    pub const fn new_raw(data: T) -> Self {
+        Self::with_class(data, None)
+    }
+
+    const fn with_class(data: T, class: Option<LockClass>) -> Self {
+        SpinLock {
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+            owner: AtomicUsize::new(NO_CPU),
+            class,
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        self.lock_nested(0)
+    }
+
+    /// Lock while holding another lock of the same class, which the caller
+    /// orders some other way; `subclass` tells the validator the levels apart
+    #[track_caller]
+    pub fn lock_nested(&self, subclass: u8) -> SpinLockGuard<T> {
+        this_cpu().enter_atomic();
+        self.check_recursion();
+        self.validate_acquire(false, subclass);
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            spin_wait();
+        }
+        self.owner.store(current_cpu(), Ordering::Relaxed);
+        SpinLockGuard { lock: self }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        this_cpu().enter_atomic();
+        let ticket = self.now_serving.load(Ordering::Relaxed);
+        if self.next_ticket.compare_exchange(ticket, ticket.wrapping_add(1), Ordering::Acquire, Ordering::Relaxed).is_err() {
+            this_cpu().exit_atomic();
+            return None;
+        }
+        self.owner.store(current_cpu(), Ordering::Relaxed);
+        self.validate_acquire(true, 0);
+        Some(SpinLockGuard { lock: self })
+    }
+
+    /// Lock with interrupts disabled, for data an interrupt handler also takes;
+    /// the guard restores the interrupt flag
+    #[track_caller]
+    pub fn lock_irqsave(&self) -> IrqSpinLockGuard<T> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IrqSpinLockGuard { guard: Some(self.lock()), enabled }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+
+    /// Whether the running code holds the lock: its holder cannot be
+    /// preempted, so only it runs on the owning CPU
+    pub fn held_by_this_cpu(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) == current_cpu()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// A CPU waiting for a lock it holds itself would spin forever
+    #[track_caller]
+    fn check_recursion(&self) {
+        if cfg!(debug_assertions) && self.owner.load(Ordering::Relaxed) == current_cpu() {
+            panic!("recursive spinlock acquisition on CPU {}", current_cpu());
+        }
+    }
+
+    #[track_caller]
+    fn validate_acquire(&self, trylock: bool, subclass: u8) {
+        match self.class {
+            Some(class) => DEADLOCK_DETECTOR.acquire(self.addr(), class.nested(subclass), Location::caller(), trylock),
+            None => DEADLOCK_DETECTOR.raw_acquire(),
+        }
+    }
+
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn unlock(&self) {
+        match self.class {
+            Some(_) => DEADLOCK_DETECTOR.release(self.addr()),
+            None => DEADLOCK_DETECTOR.raw_release(),
+        }
+        self.owner.store(NO_CPU, Ordering::Relaxed);
+        self.now_serving.fetch_add(1, Ordering::Release);
+        this_cpu().exit_atomic();
+    }
+}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.unlock();
+    }
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+/// `SpinLockGuard` that also keeps interrupts disabled until it is dropped
+pub struct IrqSpinLockGuard<'a, T> {
+    guard: Option<SpinLockGuard<'a, T>>,
+    /// Whether interrupts were enabled before locking
+    enabled: bool,
+}
+
+impl<'a, T> Drop for IrqSpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        // Unlock first: an interrupt taken right after could want the lock
+        drop(self.guard.take());
+        if self.enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+impl<'a, T> Deref for IrqSpinLockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for IrqSpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
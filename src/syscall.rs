@@ -1,4 +1,64 @@
+use core::arch::global_asm;
+use x86_64::registers::model_specific::FsBase;
 use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+use crate::context::UserFrame;
+use crate::drivers::keyboard::KEYBOARD;
+use crate::ipc::{IPC_RMID, SHARED_MEMORY};
+use crate::performance::scheduler_opt::{NICE_MAX, NICE_MIN};
+use crate::process::{self, CloneArgs, ProcessId, PROCESS_MANAGER};
+use crate::realtime::{SchedParam, SchedPolicy};
+use crate::scheduler::SCHEDULER;
+use crate::signal::{self, SigAction, SigInfo, SIG_BLOCK, SIG_SETMASK, SIG_UNBLOCK, UNBLOCKABLE};
+use crate::thread::{ThreadId, THREAD_MANAGER};
+use crate::wait;
+
+/// `arch_prctl` code that sets the FS base
+pub const ARCH_SET_FS: u64 = 0x1002;
+/// `arch_prctl` code that reads the FS base
+pub const ARCH_GET_FS: u64 = 0x1003;
+
+/// Size of `struct timespec`: seconds, then nanoseconds, as `i64`s
+pub const TIMESPEC_SIZE: usize = 16;
+
+/// `setpriority`/`getpriority` target: one thread, the only kind supported
+pub const PRIO_PROCESS: u64 = 0;
+
+/// Size of the `sched_param` user structure: `i32` priority, 4 bytes of
+/// padding, then the deadline runtime, deadline and period as `u64`s.
+/// FIFO, round-robin and normal policies only use the first 4 bytes.
+pub const SCHED_PARAM_SIZE: usize = 32;
+
+// `int 0x80` lands here on the thread's kernel stack, right below what the CPU
+// pushed. Saving the remaining registers completes a `UserFrame`, which the
+// dispatcher gets by reference and `return_to_user` pops on the way out.
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "cld",
+    "call syscall_dispatch",
+    "jmp return_to_user",
+);
+
+extern "C" {
+    pub fn syscall_entry();
+}
 
 #[repr(u64)]
 #[derive(Debug, Clone, Copy)]
@@ -18,6 +78,26 @@ pub enum SyscallNumber {
     Munmap = 13,
     Brk = 14,
     Ioctl = 15,
+    Mprotect = 16,
+    Clone = 17,
+    GetTid = 18,
+    ArchPrctl = 19,
+    SchedSetAffinity = 20,
+    SchedGetAffinity = 21,
+    SetPriority = 22,
+    GetPriority = 23,
+    SchedSetScheduler = 24,
+    SchedGetParam = 25,
+    SchedGetScheduler = 26,
+    SigAction = 27,
+    SigProcMask = 28,
+    SigReturn = 29,
+    Tkill = 30,
+    Wait4 = 31,
+    ShmGet = 32,
+    ShmAt = 33,
+    ShmDt = 34,
+    ShmCtl = 35,
 }
 
 pub struct SyscallContext {
@@ -30,12 +110,59 @@ pub struct SyscallContext {
     pub arg6: u64,
 }
 
+/// Number in rax; arguments in rdi, rsi, rdx, r10, r8 and r9; result in rax
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &mut UserFrame) {
+    // `sigreturn` replaces every register, rax included
+    if frame.rax == SyscallNumber::SigReturn as u64 {
+        signal::sigreturn(frame);
+    } else {
+        let context = SyscallContext {
+            syscall_number: frame.rax,
+            arg1: frame.rdi,
+            arg2: frame.rsi,
+            arg3: frame.rdx,
+            arg4: frame.r10,
+            arg5: frame.r8,
+            arg6: frame.r9,
+        };
+        frame.rax = handle_syscall(context);
+    }
+    signal::deliver(frame);
+}
+
 pub fn handle_syscall(context: SyscallContext) -> u64 {
     match context.syscall_number {
         1 => sys_exit(context.arg1 as i32),
         2 => sys_read(context.arg1, context.arg2 as *mut u8, context.arg3),
         3 => sys_write(context.arg1, context.arg2 as *const u8, context.arg3),
+        6 => sys_fork(),
+        8 => sys_wait4(context.arg1, context.arg2, context.arg3, 0),
+        9 => sys_kill(context.arg1, context.arg2),
         10 => sys_getpid(),
+        11 => sys_nanosleep(context.arg1, context.arg2),
+        12 => sys_mmap(context.arg1, context.arg2, context.arg3, context.arg4, context.arg5, context.arg6),
+        13 => sys_munmap(context.arg1, context.arg2),
+        14 => sys_brk(context.arg1),
+        16 => sys_mprotect(context.arg1, context.arg2, context.arg3),
+        17 => sys_clone(context.arg1, context.arg2, context.arg3, context.arg4, context.arg5),
+        18 => sys_gettid(),
+        19 => sys_arch_prctl(context.arg1, context.arg2),
+        20 => sys_sched_setaffinity(context.arg1, context.arg2, context.arg3),
+        21 => sys_sched_getaffinity(context.arg1, context.arg2, context.arg3),
+        22 => sys_setpriority(context.arg1, context.arg2, context.arg3 as i64),
+        23 => sys_getpriority(context.arg1, context.arg2),
+        24 => sys_sched_setscheduler(context.arg1, context.arg2, context.arg3),
+        25 => sys_sched_getparam(context.arg1, context.arg2),
+        26 => sys_sched_getscheduler(context.arg1),
+        27 => sys_sigaction(context.arg1, context.arg2, context.arg3),
+        28 => sys_sigprocmask(context.arg1, context.arg2, context.arg3),
+        30 => sys_tkill(context.arg1, context.arg2),
+        31 => sys_wait4(context.arg1, context.arg2, context.arg3, context.arg4),
+        32 => sys_shmget(context.arg1, context.arg2, context.arg3),
+        33 => sys_shmat(context.arg1, context.arg2, context.arg3),
+        34 => sys_shmdt(context.arg1),
+        35 => sys_shmctl(context.arg1, context.arg2),
         _ => {
             crate::io::println!("Unknown syscall: {}", context.syscall_number);
             !0u64
@@ -43,16 +170,29 @@ pub fn handle_syscall(context: SyscallContext) -> u64 {
     }
 }
 
+/// Ends every thread of the calling process; never returns to it
 fn sys_exit(status: i32) -> u64 {
-    crate::io::println!("Process exiting with status: {}", status);
-    // TODO: Clean up process resources
-    0
+    let (Some(pid), Some(tid)) = (PROCESS_MANAGER.get_current_process(), SCHEDULER.current_thread()) else {
+        return !0u64;
+    };
+    signal::group_exit(pid, process::exit_status(status));
+    PROCESS_MANAGER.exit_thread(tid)
 }
 
+/// Standard input reads the keyboard, blocking until a key is typed
 fn sys_read(fd: u64, buf: *mut u8, count: u64) -> u64 {
-    // TODO: Implement read syscall
-    crate::io::println!("sys_read: fd={}, count={}", fd, count);
-    0
+    let Some(pid) = PROCESS_MANAGER.get_current_process() else { return !0u64 };
+    if fd != 0 {
+        // TODO: Read from files
+        crate::io::println!("sys_read: fd={}, count={}", fd, count);
+        return !0u64;
+    }
+    let mut bytes = [0u8; 256];
+    let len = (count as usize).min(bytes.len());
+    match KEYBOARD.read(&mut bytes[..len]) {
+        Ok(read) if PROCESS_MANAGER.write_user(pid, buf as u64, &bytes[..read]).is_ok() => read as u64,
+        _ => !0u64,
+    }
 }
 
 fn sys_write(fd: u64, buf: *const u8, count: u64) -> u64 {
@@ -61,11 +201,421 @@ fn sys_write(fd: u64, buf: *const u8, count: u64) -> u64 {
     count
 }
 
+fn sys_fork() -> u64 {
+    let parent = match crate::process::PROCESS_MANAGER.get_current_process() {
+        Some(pid) => pid,
+        None => return !0u64,
+    };
+    // The child resumes from the same system call, seeing 0; without the
+    // caller's registers there is nothing for it to resume from
+    let frame = match THREAD_MANAGER.current_user_frame() {
+        Some(frame) => frame,
+        None => return !0u64,
+    };
+    match PROCESS_MANAGER.fork(parent, frame) {
+        Ok(child) => child.as_u64(),
+        Err(e) => {
+            crate::io::println!("sys_fork failed: {}", e);
+            !0u64
+        }
+    }
+}
+
+/// `waitpid` is this without `rusage`. `pid` -1 waits for any child; process
+/// groups are not supported. Returns the reaped child, or 0 under `WNOHANG`
+/// while none has exited.
+fn sys_wait4(pid: u64, status: u64, options: u64, rusage: u64) -> u64 {
+    let Some(parent) = PROCESS_MANAGER.get_current_process() else { return !0u64 };
+    let target = match pid as i64 {
+        -1 => None,
+        pid if pid > 0 => Some(ProcessId::from_u64(pid as u64)),
+        _ => return !0u64,
+    };
+    match PROCESS_MANAGER.wait(parent, target, options) {
+        Ok(Some((child, wait_status, usage))) => {
+            if status != 0 && PROCESS_MANAGER.write_user(parent, status, &wait_status.to_ne_bytes()).is_err() {
+                return !0u64;
+            }
+            if rusage != 0 && PROCESS_MANAGER.write_user(parent, rusage, &usage.to_bytes()).is_err() {
+                return !0u64;
+            }
+            child.as_u64()
+        }
+        Ok(None) => 0,
+        Err(_) => !0u64,
+    }
+}
+
+/// Sleeps for the `timespec` at `req`. Cut short by a signal, it fails and
+/// stores the time left at `rem` unless that is 0.
+fn sys_nanosleep(req: u64, rem: u64) -> u64 {
+    let Some(pid) = PROCESS_MANAGER.get_current_process() else { return !0u64 };
+    let mut bytes = [0u8; TIMESPEC_SIZE];
+    if PROCESS_MANAGER.read_user(pid, req, &mut bytes).is_err() {
+        return !0u64;
+    }
+    let secs = i64::from_ne_bytes(bytes[0..8].try_into().unwrap());
+    let nanos = i64::from_ne_bytes(bytes[8..16].try_into().unwrap());
+    if secs < 0 || !(0..1_000_000_000).contains(&nanos) {
+        return !0u64;
+    }
+    let ns = (secs as u64).saturating_mul(1_000_000_000).saturating_add(nanos as u64);
+    let Err(left) = wait::sleep(ns, true) else { return 0 };
+    if rem != 0 {
+        bytes[0..8].copy_from_slice(&((left / 1_000_000_000) as i64).to_ne_bytes());
+        bytes[8..16].copy_from_slice(&((left % 1_000_000_000) as i64).to_ne_bytes());
+        PROCESS_MANAGER.write_user(pid, rem, &bytes).ok();
+    }
+    !0u64
+}
+
 fn sys_getpid() -> u64 {
     if let Some(pid) = crate::process::PROCESS_MANAGER.get_current_process() {
-        pid.0 as u64
+        pid.as_u64()
     } else {
         0
     }
 }
 
+
+fn sys_mmap(addr: u64, len: u64, prot: u64, flags: u64, fd: u64, offset: u64) -> u64 {
+    let pid = match crate::process::PROCESS_MANAGER.get_current_process() {
+        Some(pid) => pid,
+        None => return !0u64,
+    };
+    match crate::process::PROCESS_MANAGER.mmap(pid, addr, len, prot, flags, fd, offset) {
+        Ok(start) => start.as_u64(),
+        Err(e) => {
+            crate::io::println!("sys_mmap failed: {}", e);
+            !0u64
+        }
+    }
+}
+
+fn sys_munmap(addr: u64, len: u64) -> u64 {
+    let pid = match crate::process::PROCESS_MANAGER.get_current_process() {
+        Some(pid) => pid,
+        None => return !0u64,
+    };
+    match crate::process::PROCESS_MANAGER.munmap(pid, addr, len) {
+        Ok(()) => 0,
+        Err(e) => {
+            crate::io::println!("sys_munmap failed: {}", e);
+            !0u64
+        }
+    }
+}
+
+fn sys_mprotect(addr: u64, len: u64, prot: u64) -> u64 {
+    let pid = match crate::process::PROCESS_MANAGER.get_current_process() {
+        Some(pid) => pid,
+        None => return !0u64,
+    };
+    match crate::process::PROCESS_MANAGER.mprotect(pid, addr, len, prot) {
+        Ok(()) => 0,
+        Err(e) => {
+            crate::io::println!("sys_mprotect failed: {}", e);
+            !0u64
+        }
+    }
+}
+
+/// Returns the new program break, or the old one if it could not be moved
+fn sys_brk(addr: u64) -> u64 {
+    let pid = match crate::process::PROCESS_MANAGER.get_current_process() {
+        Some(pid) => pid,
+        None => return !0u64,
+    };
+    crate::process::PROCESS_MANAGER.brk(pid, addr).map_or(!0u64, |brk| brk.as_u64())
+}
+
+/// Linux argument order: flags, new stack, parent TID pointer, child TID pointer, TLS
+fn sys_clone(flags: u64, stack: u64, parent_tid: u64, child_tid: u64, tls: u64) -> u64 {
+    let pid = match PROCESS_MANAGER.get_current_process() {
+        Some(pid) => pid,
+        None => return !0u64,
+    };
+    let frame = match THREAD_MANAGER.current_user_frame() {
+        Some(frame) => frame,
+        None => return !0u64,
+    };
+    let args = CloneArgs { flags, stack, parent_tid, child_tid, tls };
+    match PROCESS_MANAGER.clone(pid, &args, frame) {
+        Ok(tid) => tid.as_u64(),
+        Err(e) => {
+            crate::io::println!("sys_clone failed: {}", e);
+            !0u64
+        }
+    }
+}
+
+fn sys_gettid() -> u64 {
+    SCHEDULER.current_thread().map_or(0, |tid| tid.as_u64())
+}
+
+fn sys_arch_prctl(code: u64, addr: u64) -> u64 {
+    let Some(pid) = PROCESS_MANAGER.get_current_process() else { return !0u64 };
+    match code {
+        ARCH_SET_FS => {
+            let Ok(base) = VirtAddr::try_new(addr) else { return !0u64 };
+            // Takes effect now; the context switch saves it from the register
+            FsBase::write(base);
+            0
+        }
+        ARCH_GET_FS => {
+            let base = FsBase::read().as_u64().to_ne_bytes();
+            PROCESS_MANAGER.write_user(pid, addr, &base).map_or(!0u64, |()| 0)
+        }
+        _ => !0u64,
+    }
+}
+
+/// Thread a scheduling call names; 0 is the caller
+fn target_thread(tid: u64) -> Option<ThreadId> {
+    match tid {
+        0 => SCHEDULER.current_thread(),
+        tid => Some(ThreadId::from_u64(tid)),
+    }
+}
+
+/// Whether `caller` may change how thread `tid` is scheduled: root may
+/// change any thread, others only threads of their own user
+fn may_reschedule(caller: ProcessId, tid: ThreadId) -> bool {
+    let Some(target) = THREAD_MANAGER.with_thread(tid, |thread| thread.process) else { return false };
+    match (PROCESS_MANAGER.credentials(caller), PROCESS_MANAGER.credentials(target)) {
+        (Some(caller), Some(target)) => caller.uid == 0 || caller.uid == target.uid,
+        _ => false,
+    }
+}
+
+/// Linux argument order: thread id, mask size in bytes, pointer to the CPU mask
+fn sys_sched_setaffinity(tid: u64, len: u64, mask: u64) -> u64 {
+    let Some(pid) = PROCESS_MANAGER.get_current_process() else { return !0u64 };
+    let Some(tid) = target_thread(tid) else { return !0u64 };
+    if !may_reschedule(pid, tid) {
+        return !0u64;
+    }
+    let mut bytes = [0u8; 8];
+    let len = (len as usize).min(bytes.len());
+    if PROCESS_MANAGER.read_user(pid, mask, &mut bytes[..len]).is_err() {
+        return !0u64;
+    }
+    match THREAD_MANAGER.set_affinity(tid, u64::from_ne_bytes(bytes)) {
+        Ok(()) => 0,
+        Err(e) => {
+            crate::io::println!("sys_sched_setaffinity failed: {}", e);
+            !0u64
+        }
+    }
+}
+
+/// Returns the number of mask bytes written
+fn sys_sched_getaffinity(tid: u64, len: u64, mask: u64) -> u64 {
+    let Some(pid) = PROCESS_MANAGER.get_current_process() else { return !0u64 };
+    let Some(affinity) = target_thread(tid).and_then(|tid| THREAD_MANAGER.affinity(tid)) else { return !0u64 };
+    let bytes = affinity.to_ne_bytes();
+    if (len as usize) < bytes.len() {
+        return !0u64;
+    }
+    PROCESS_MANAGER.write_user(pid, mask, &bytes).map_or(!0u64, |()| bytes.len() as u64)
+}
+
+/// Set the nice value of a thread, 0 being the caller
+fn sys_setpriority(which: u64, who: u64, nice: i64) -> u64 {
+    if which != PRIO_PROCESS {
+        return !0u64;
+    }
+    let Some(pid) = PROCESS_MANAGER.get_current_process() else { return !0u64 };
+    let Some(tid) = target_thread(who) else { return !0u64 };
+    if !may_reschedule(pid, tid) {
+        return !0u64;
+    }
+    let nice = nice.clamp(NICE_MIN as i64, NICE_MAX as i64) as i8;
+    // A lower nice value takes CPU time from everyone else, so only root may lower it
+    let lowering = SCHEDULER.nice(tid).map_or(true, |current| nice < current);
+    if lowering && PROCESS_MANAGER.credentials(pid).map(|c| c.uid) != Some(0) {
+        return !0u64;
+    }
+    SCHEDULER.set_nice(tid, nice).map_or(!0u64, |()| 0)
+}
+
+/// Returns `20 - nice`, as Linux does, so that no valid result looks like an error
+fn sys_getpriority(which: u64, who: u64) -> u64 {
+    if which != PRIO_PROCESS {
+        return !0u64;
+    }
+    target_thread(who)
+        .and_then(|tid| SCHEDULER.nice(tid))
+        .map_or(!0u64, |nice| (20 - nice as i64) as u64)
+}
+
+/// Linux argument order: thread id (0 for the caller), policy, pointer to a `sched_param`
+fn sys_sched_setscheduler(tid: u64, policy: u64, param: u64) -> u64 {
+    let Some(pid) = PROCESS_MANAGER.get_current_process() else { return !0u64 };
+    let Some(tid) = target_thread(tid) else { return !0u64 };
+    let Some(policy) = SchedPolicy::from_u64(policy) else { return !0u64 };
+    if !may_reschedule(pid, tid) {
+        return !0u64;
+    }
+    // Real-time classes can starve everything else, so only root may enter them
+    if policy != SchedPolicy::Normal && PROCESS_MANAGER.credentials(pid).map(|c| c.uid) != Some(0) {
+        return !0u64;
+    }
+    let mut bytes = [0u8; SCHED_PARAM_SIZE];
+    let len = if policy == SchedPolicy::Deadline { SCHED_PARAM_SIZE } else { 4 };
+    if PROCESS_MANAGER.read_user(pid, param, &mut bytes[..len]).is_err() {
+        return !0u64;
+    }
+    let field = |offset: usize| u64::from_ne_bytes(bytes[offset..offset + 8].try_into().unwrap());
+    let priority = i32::from_ne_bytes(bytes[..4].try_into().unwrap());
+    let Ok(priority) = u8::try_from(priority) else { return !0u64 };
+    let param = SchedParam { priority, runtime: field(8), deadline: field(16), period: field(24) };
+    match SCHEDULER.set_scheduler(tid, policy, param) {
+        Ok(()) => 0,
+        Err(e) => {
+            crate::io::println!("sys_sched_setscheduler failed: {}", e);
+            !0u64
+        }
+    }
+}
+
+/// Store a thread's `sched_param`; the deadline fields only for deadline threads
+fn sys_sched_getparam(tid: u64, param: u64) -> u64 {
+    let Some(pid) = PROCESS_MANAGER.get_current_process() else { return !0u64 };
+    let Some((policy, sched)) = target_thread(tid).and_then(|tid| SCHEDULER.scheduler(tid)) else { return !0u64 };
+    let mut bytes = [0u8; SCHED_PARAM_SIZE];
+    bytes[..4].copy_from_slice(&(sched.priority as i32).to_ne_bytes());
+    bytes[8..16].copy_from_slice(&sched.runtime.to_ne_bytes());
+    bytes[16..24].copy_from_slice(&sched.deadline.to_ne_bytes());
+    bytes[24..32].copy_from_slice(&sched.period.to_ne_bytes());
+    let len = if policy == SchedPolicy::Deadline { SCHED_PARAM_SIZE } else { 4 };
+    PROCESS_MANAGER.write_user(pid, param, &bytes[..len]).map_or(!0u64, |()| 0)
+}
+
+fn sys_sched_getscheduler(tid: u64) -> u64 {
+    target_thread(tid)
+        .and_then(|tid| SCHEDULER.scheduler(tid))
+        .map_or(!0u64, |(policy, _)| policy.as_u64())
+}
+
+/// Whether `sender` may send signals to `target`: root may signal
+/// anything, others only processes of their own user
+fn may_signal(sender: ProcessId, target: ProcessId) -> bool {
+    match (PROCESS_MANAGER.credentials(sender), PROCESS_MANAGER.credentials(target)) {
+        (Some(sender), Some(target)) => sender.uid == 0 || sender.uid == target.uid,
+        _ => false,
+    }
+}
+
+/// Signal 0 only checks that the process exists and may be signalled
+fn sys_kill(pid: u64, sig: u64) -> u64 {
+    let Some(sender) = PROCESS_MANAGER.get_current_process() else { return !0u64 };
+    let target = ProcessId::from_u64(pid);
+    if pid == 0 || !may_signal(sender, target) {
+        return !0u64;
+    }
+    if sig == 0 {
+        return 0;
+    }
+    let Some(sig) = signal::from_u64(sig) else { return !0u64 };
+    let uid = PROCESS_MANAGER.credentials(sender).map_or(0, |c| c.uid);
+    signal::send_to_process(target, SigInfo::user(sig, sender, uid)).map_or(!0u64, |()| 0)
+}
+
+fn sys_tkill(tid: u64, sig: u64) -> u64 {
+    let Some(sender) = PROCESS_MANAGER.get_current_process() else { return !0u64 };
+    let tid = ThreadId::from_u64(tid);
+    let Some(target) = THREAD_MANAGER.with_thread(tid, |thread| thread.process) else { return !0u64 };
+    let Some(sig) = signal::from_u64(sig) else { return !0u64 };
+    if !may_signal(sender, target) {
+        return !0u64;
+    }
+    let uid = PROCESS_MANAGER.credentials(sender).map_or(0, |c| c.uid);
+    let info = SigInfo { code: signal::SI_TKILL, ..SigInfo::user(sig, sender, uid) };
+    signal::send_to_thread(tid, info).map_or(!0u64, |()| 0)
+}
+
+/// Segment id for `key`; `flags` takes `IPC_CREAT`, `IPC_EXCL` and the mode bits
+fn sys_shmget(key: u64, size: u64, flags: u64) -> u64 {
+    let Some(pid) = PROCESS_MANAGER.get_current_process() else { return !0u64 };
+    SHARED_MEMORY.get(pid, key, size as usize, flags as u32).unwrap_or(!0u64)
+}
+
+/// Attach segment `id` at `addr`, or where the kernel picks when it is 0
+fn sys_shmat(id: u64, addr: u64, flags: u64) -> u64 {
+    let Some(pid) = PROCESS_MANAGER.get_current_process() else { return !0u64 };
+    SHARED_MEMORY.attach(id, pid, addr, flags as u32).map_or(!0u64, |start| start.as_u64())
+}
+
+fn sys_shmdt(addr: u64) -> u64 {
+    let Some(pid) = PROCESS_MANAGER.get_current_process() else { return !0u64 };
+    let Ok(addr) = VirtAddr::try_new(addr) else { return !0u64 };
+    SHARED_MEMORY.detach(pid, addr).map_or(!0u64, |()| 0)
+}
+
+/// Only `IPC_RMID` is supported
+fn sys_shmctl(id: u64, cmd: u64) -> u64 {
+    let Some(pid) = PROCESS_MANAGER.get_current_process() else { return !0u64 };
+    if cmd != IPC_RMID {
+        return !0u64;
+    }
+    SHARED_MEMORY.remove(id, pid).map_or(!0u64, |()| 0)
+}
+
+/// Install the `sigaction` at `act` unless it is 0, storing the previous one at `oldact` unless that is 0
+fn sys_sigaction(sig: u64, act: u64, oldact: u64) -> u64 {
+    let Some(pid) = PROCESS_MANAGER.get_current_process() else { return !0u64 };
+    let Some(sig) = signal::from_u64(sig) else { return !0u64 };
+    let old = if act != 0 {
+        let mut bytes = [0u8; SigAction::SIZE];
+        if PROCESS_MANAGER.read_user(pid, act, &mut bytes).is_err() {
+            return !0u64;
+        }
+        let action = SigAction::from_bytes(&bytes);
+        match PROCESS_MANAGER.set_sigaction(pid, sig, SigAction { mask: action.mask & !UNBLOCKABLE, ..action }) {
+            Ok(old) => old,
+            Err(e) => {
+                crate::io::println!("sys_sigaction failed: {}", e);
+                return !0u64;
+            }
+        }
+    } else {
+        match PROCESS_MANAGER.sigaction(pid, sig) {
+            Some(action) => action,
+            None => return !0u64,
+        }
+    };
+    if oldact != 0 && PROCESS_MANAGER.write_user(pid, oldact, &old.to_bytes()).is_err() {
+        return !0u64;
+    }
+    0
+}
+
+/// Change the caller's blocked signals by `how` with the set at `set` unless
+/// it is 0, storing the previous set at `oldset` unless that is 0
+fn sys_sigprocmask(how: u64, set: u64, oldset: u64) -> u64 {
+    let Some(pid) = PROCESS_MANAGER.get_current_process() else { return !0u64 };
+    let Some(tid) = SCHEDULER.current_thread() else { return !0u64 };
+    let mut bytes = [0u8; 8];
+    if set != 0 && PROCESS_MANAGER.read_user(pid, set, &mut bytes).is_err() {
+        return !0u64;
+    }
+    let set = (set != 0).then(|| u64::from_ne_bytes(bytes));
+    let old = THREAD_MANAGER.with_thread(tid, |thread| {
+        let old = thread.signal_mask;
+        let mask = match (how, set) {
+            (_, None) => old,
+            (SIG_BLOCK, Some(set)) => old | set,
+            (SIG_UNBLOCK, Some(set)) => old & !set,
+            (SIG_SETMASK, Some(set)) => set,
+            _ => return None,
+        };
+        thread.signal_mask = mask & !UNBLOCKABLE;
+        Some(old)
+    }).flatten();
+    let Some(old) = old else { return !0u64 };
+    if oldset != 0 && PROCESS_MANAGER.write_user(pid, oldset, &old.to_ne_bytes()).is_err() {
+        return !0u64;
+    }
+    0
+}
@@ -1,10 +1,10 @@
 use core::sync::atomic::{AtomicU64, Ordering};
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
 
 static ALLOC_COUNT: AtomicU64 = AtomicU64::new(0);
 static DEALLOC_COUNT: AtomicU64 = AtomicU64::new(0);
-static ALLOCATIONS: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+static ALLOCATIONS: SpinLock<BTreeMap<usize, usize>> = SpinLock::new(BTreeMap::new());
 
 pub struct MemoryChecker;
 
@@ -37,6 +37,8 @@ impl MemoryChecker {
         crate::io::println!("  Allocations: {}", alloc);
         crate::io::println!("  Deallocations: {}", dealloc);
         crate::io::println!("  Potential leaks: {}", leaks);
+        #[cfg(feature = "kasan")]
+        crate::io::println!("  KASAN reports: {}", crate::memory::kasan::report_count());
     }
 }
 
@@ -13,7 +13,10 @@ impl StressTester {
         for i in 0..100 {
             let entry = VirtAddr::new(0x400000 + (i * 0x1000));
             let stack = VirtAddr::new(0x800000 + (i * 0x1000));
-            PROCESS_MANAGER.create_process(entry, stack);
+            if PROCESS_MANAGER.create_process(entry, stack).is_err() {
+                crate::io::println!("Process creation failed after {} processes", i);
+                return;
+            }
         }
         
         crate::io::println!("Created 100 processes");
@@ -26,13 +29,14 @@ impl StressTester {
         for i in 0..50 {
             let entry = VirtAddr::new(0x400000 + (i * 0x1000));
             let stack = VirtAddr::new(0x800000 + (i * 0x1000));
-            let pid = PROCESS_MANAGER.create_process(entry, stack);
-            SCHEDULER.enqueue(pid);
+            if let Ok(pid) = PROCESS_MANAGER.create_process(entry, stack) {
+                SCHEDULER.enqueue_process(pid);
+            }
         }
         
-        // Run scheduler
+        // Switch through them; each faults on its unmapped entry point and is killed
         for _ in 0..1000 {
-            SCHEDULER.schedule_next();
+            SCHEDULER.yield_cpu();
         }
         
         crate::io::println!("Scheduler stress test completed");
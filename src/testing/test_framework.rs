@@ -1,4 +1,4 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::vec::Vec;
 
 #[derive(Debug, Clone)]
@@ -9,13 +9,13 @@ pub struct TestResult {
 }
 
 pub struct TestFramework {
-    tests: Mutex<Vec<TestResult>>,
+    tests: SpinLock<Vec<TestResult>>,
 }
 
 impl TestFramework {
     pub const fn new() -> Self {
         TestFramework {
-            tests: Mutex::new(Vec::new()),
+            tests: SpinLock::new(Vec::new()),
         }
     }
 
@@ -0,0 +1,300 @@
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::InterruptStackFrameValue;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+use crate::context::{TaskContext, UserFrame};
+use crate::hardware::cpu::{current_cpu, online_mask, ALL_CPUS};
+use crate::hardware::smp;
+use crate::memory::{SlabBox, SlabCache};
+use crate::performance::scheduler_opt::SchedEntity;
+use crate::process::{ProcessId, ProcessState};
+use crate::realtime::RtEntity;
+use crate::scheduler::SCHEDULER;
+use crate::signal::PendingSignals;
+use crate::sync::SpinLock;
+
+/// Share the address space
+pub const CLONE_VM: u64 = 0x0000_0100;
+/// Share the file descriptor table
+pub const CLONE_FILES: u64 = 0x0000_0400;
+/// Share signal handlers
+pub const CLONE_SIGHAND: u64 = 0x0000_0800;
+/// Create a thread in the caller's process instead of a new process
+pub const CLONE_THREAD: u64 = 0x0001_0000;
+/// Set the new thread's FS base to the `tls` argument
+pub const CLONE_SETTLS: u64 = 0x0008_0000;
+/// Store the new TID at `parent_tid` in the caller's memory
+pub const CLONE_PARENT_SETTID: u64 = 0x0010_0000;
+/// Clear the TID at `child_tid` when the new thread exits
+pub const CLONE_CHILD_CLEARTID: u64 = 0x0020_0000;
+/// Store the new TID at `child_tid` in the new thread's memory
+pub const CLONE_CHILD_SETTID: u64 = 0x0100_0000;
+
+/// Thread ids share the process id space; a process's first thread has the process's id
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(usize);
+
+impl ThreadId {
+    /// The boot flow that runs the shell; never a real thread
+    pub const BOOT: ThreadId = ThreadId(0);
+
+    pub fn new() -> Self {
+        ThreadId(ProcessId::new().as_u64() as usize)
+    }
+
+    /// Id of the first thread of `pid`
+    pub fn main_of(pid: ProcessId) -> Self {
+        ThreadId(pid.as_u64() as usize)
+    }
+
+    /// Id named by user space, which may not exist
+    pub fn from_u64(raw: u64) -> Self {
+        ThreadId(raw as usize)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0 as u64
+    }
+}
+
+/// A schedulable execution context. User threads belong to a process, which
+/// holds what they share; kernel threads belong to `ProcessId::KERNEL`.
+pub struct Thread {
+    pub tid: ThreadId,
+    pub process: ProcessId,
+    pub name: &'static str,
+    pub state: ProcessState,
+    /// Kernel stack and saved registers while the thread is switched out
+    pub context: TaskContext,
+    /// Page table root of the owning process; `None` for kernel threads
+    pub pml4_frame: Option<PhysFrame>,
+    /// Blocked signals, one bit per signal number
+    pub signal_mask: u64,
+    /// Signals sent to this thread and not delivered yet
+    pub pending_signals: PendingSignals,
+    /// Where an interrupt that found a signal pending was going to return to
+    /// in user mode, until `signal_interrupt_return` picks it up
+    pub interrupted: Option<InterruptStackFrameValue>,
+    /// Cleared when the thread exits, for `CLONE_CHILD_CLEARTID`
+    pub clear_child_tid: Option<VirtAddr>,
+    /// Set once the thread starts exiting; the last thread of a process to
+    /// set it tears the process down
+    pub exiting: bool,
+    /// CPUs the thread may run on, one bit per CPU index
+    pub affinity: u64,
+    /// CPU the thread runs or last ran on
+    pub cpu: usize,
+    /// Set from the moment a CPU picks the thread until it has fully switched
+    /// away from it; no other CPU may resume it meanwhile
+    pub on_cpu: bool,
+    /// Nice value and run time accounting of the fair scheduler
+    pub sched: SchedEntity,
+    /// Real-time policy and its state; `SchedPolicy::Normal` leaves the thread to `sched`
+    pub rt: RtEntity,
+}
+
+impl Thread {
+    pub fn is_kernel(&self) -> bool {
+        self.process == ProcessId::KERNEL
+    }
+}
+
+pub static THREAD_CACHE: SlabCache =
+    SlabCache::new("thread", core::mem::size_of::<Thread>(), core::mem::align_of::<Thread>());
+
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadInfo {
+    pub tid: ThreadId,
+    pub process: ProcessId,
+    pub name: &'static str,
+    pub state: ProcessState,
+    pub cpu: usize,
+}
+
+/// Every thread in the system. Threads sit in slab objects, so the scheduler
+/// can hold on to their contexts while the list changes.
+pub struct ThreadManager {
+    threads: SpinLock<Vec<SlabBox<Thread>>>,
+}
+
+impl ThreadManager {
+    pub const fn new() -> Self {
+        ThreadManager {
+            threads: SpinLock::new(Vec::new()),
+        }
+    }
+
+    /// Add a thread that starts in user mode with the registers in `frame`; it is not scheduled yet
+    pub fn create_user(
+        &self,
+        tid: ThreadId,
+        process: ProcessId,
+        pml4_frame: PhysFrame,
+        frame: UserFrame,
+        signal_mask: u64,
+    ) -> Result<ThreadId, &'static str> {
+        let thread = Thread {
+            tid,
+            process,
+            name: "user",
+            state: ProcessState::Ready,
+            context: TaskContext::new_user(frame)?,
+            pml4_frame: Some(pml4_frame),
+            signal_mask,
+            pending_signals: PendingSignals::new(),
+            interrupted: None,
+            clear_child_tid: None,
+            exiting: false,
+            affinity: ALL_CPUS,
+            cpu: current_cpu(),
+            on_cpu: false,
+            sched: SchedEntity::new(),
+            rt: RtEntity::new(),
+        };
+        self.insert(thread)
+    }
+
+    /// Start a kernel thread running `entry`
+    pub fn spawn_kernel(&self, name: &'static str, entry: fn() -> !) -> Result<ThreadId, &'static str> {
+        let thread = Thread {
+            tid: ThreadId::new(),
+            process: ProcessId::KERNEL,
+            name,
+            state: ProcessState::Ready,
+            context: TaskContext::new_kernel(entry)?,
+            pml4_frame: None,
+            signal_mask: 0,
+            pending_signals: PendingSignals::new(),
+            interrupted: None,
+            clear_child_tid: None,
+            exiting: false,
+            affinity: ALL_CPUS,
+            cpu: current_cpu(),
+            on_cpu: false,
+            sched: SchedEntity::new(),
+            rt: RtEntity::new(),
+        };
+        let tid = self.insert(thread)?;
+        SCHEDULER.enqueue(tid);
+        Ok(tid)
+    }
+
+    fn insert(&self, thread: Thread) -> Result<ThreadId, &'static str> {
+        let tid = thread.tid;
+        let thread = SlabBox::new(&THREAD_CACHE, thread)?;
+        without_interrupts(|| self.threads.lock().push(thread));
+        Ok(tid)
+    }
+
+    /// Run `f` on one thread
+    pub fn with_thread<R>(&self, tid: ThreadId, f: impl FnOnce(&mut Thread) -> R) -> Option<R> {
+        without_interrupts(|| {
+            let mut threads = self.threads.lock();
+            threads.iter_mut().find(|t| t.tid == tid).map(|t| f(t))
+        })
+    }
+
+    /// Run `f` on the whole thread list. Every holder of the list lock keeps
+    /// interrupts disabled, so the scheduler can take it from the timer interrupt.
+    pub(crate) fn with_threads<R>(&self, f: impl FnOnce(&mut [SlabBox<Thread>]) -> R) -> R {
+        without_interrupts(|| f(&mut self.threads.lock()))
+    }
+
+    /// Threads of `pid`
+    pub fn threads_of(&self, pid: ProcessId) -> Vec<ThreadId> {
+        without_interrupts(|| {
+            self.threads.lock().iter().filter(|t| t.process == pid).map(|t| t.tid).collect()
+        })
+    }
+
+    /// Stop every thread of `pid` from being scheduled again
+    pub fn terminate_process(&self, pid: ProcessId) {
+        without_interrupts(|| {
+            for thread in self.threads.lock().iter_mut().filter(|t| t.process == pid) {
+                thread.state = ProcessState::Terminated;
+            }
+        });
+    }
+
+    /// Mark a thread as exiting; returns its process, the CPU time it used and
+    /// whether it was the last thread of that process not exiting yet
+    pub fn mark_exiting(&self, tid: ThreadId) -> Option<(ProcessId, u64, bool)> {
+        self.with_threads(|threads| {
+            let thread = threads.iter_mut().find(|t| t.tid == tid)?;
+            thread.exiting = true;
+            let (pid, cpu_time_ns) = (thread.process, thread.sched.sum_exec_runtime());
+            let last = !threads.iter().any(|t| t.process == pid && !t.exiting);
+            Some((pid, cpu_time_ns, last))
+        })
+    }
+
+    /// Remove a thread and free its kernel stack, or hand the stack to the
+    /// scheduler if a CPU is still running on it
+    pub fn remove(&self, tid: ThreadId) -> Result<(), &'static str> {
+        let mut thread = without_interrupts(|| {
+            let mut threads = self.threads.lock();
+            let pos = threads.iter().position(|t| t.tid == tid).ok_or("Thread not found")?;
+            SCHEDULER.detach(&mut threads[pos]);
+            Ok::<_, &'static str>(threads.remove(pos))
+        })?;
+        if thread.on_cpu {
+            if let Some(stack) = thread.context.take_kernel_stack() {
+                SCHEDULER.retire_stack(thread.cpu, tid, stack);
+            }
+            // Stop the other CPU from running a thread that no longer exists
+            smp::send_reschedule(thread.cpu);
+        }
+        drop(thread);
+        Ok(())
+    }
+
+    /// Restrict a thread to the CPUs in `mask`, moving it off a CPU it may no longer use
+    pub fn set_affinity(&self, tid: ThreadId, mask: u64) -> Result<(), &'static str> {
+        let mask = mask & ALL_CPUS;
+        if mask & online_mask() == 0 {
+            return Err("Affinity mask has no online CPU");
+        }
+        let (running_on, on_cpu) = self.with_thread(tid, |thread| {
+            thread.affinity = mask;
+            (thread.cpu, thread.on_cpu)
+        }).ok_or("Thread not found")?;
+        if on_cpu && mask & 1 << running_on == 0 {
+            if running_on == current_cpu() {
+                SCHEDULER.yield_cpu();
+            } else {
+                smp::send_reschedule(running_on);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn affinity(&self, tid: ThreadId) -> Option<u64> {
+        self.with_thread(tid, |thread| thread.affinity)
+    }
+
+    /// Remove every thread of a process that is being destroyed
+    pub fn remove_process(&self, pid: ProcessId) {
+        for tid in self.threads_of(pid) {
+            self.remove(tid).ok();
+        }
+    }
+
+    /// Copy of the user registers the running thread entered the kernel with
+    pub fn current_user_frame(&self) -> Option<UserFrame> {
+        let tid = SCHEDULER.current_thread()?;
+        self.with_thread(tid, |thread| {
+            thread.context.user_frame().map(|frame| unsafe { *frame })
+        })?
+    }
+
+    pub fn list(&self) -> Vec<ThreadInfo> {
+        without_interrupts(|| {
+            self.threads.lock().iter()
+                .map(|t| ThreadInfo { tid: t.tid, process: t.process, name: t.name, state: t.state, cpu: t.cpu })
+                .collect()
+        })
+    }
+}
+
+pub static THREAD_MANAGER: ThreadManager = ThreadManager::new();
@@ -1,11 +1,23 @@
 use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::vec::Vec;
 use x86_64::instructions::port::Port;
+use crate::sync::{SeqLock, SpinLock};
 
 const PIT_FREQUENCY: u64 = 1193182;
 const TARGET_FREQUENCY: u64 = 100; // 100 Hz
 
-static TICKS: AtomicU64 = AtomicU64::new(0);
-static TIME_MS: AtomicU64 = AtomicU64::new(0);
+const NS_PER_TICK: u64 = 1_000_000_000 / TARGET_FREQUENCY;
+
+/// Timekeeping state, updated by the tick and read from anywhere
+#[derive(Clone, Copy)]
+struct Clock {
+    ticks: u64,
+    /// Time stamp counter at the last tick, and its increase over the tick before
+    tsc: u64,
+    tsc_per_tick: u64,
+}
+
+static CLOCK: SeqLock<Clock> = SeqLock::new(Clock { ticks: 0, tsc: 0, tsc_per_tick: 0 });
 
 pub fn init() {
     let divisor = (PIT_FREQUENCY / TARGET_FREQUENCY) as u16;
@@ -23,22 +35,174 @@ pub fn init() {
 }
 
 pub fn tick() {
-    TICKS.fetch_add(1, Ordering::Relaxed);
-    TIME_MS.fetch_add(10, Ordering::Relaxed); // 100 Hz = 10ms per tick
+    let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+    let mut ticks = 0;
+    CLOCK.write(|clock| {
+        if clock.tsc != 0 {
+            clock.tsc_per_tick = tsc.saturating_sub(clock.tsc);
+        }
+        clock.tsc = tsc;
+        clock.ticks += 1;
+        ticks = clock.ticks;
+    });
+    TIMERS.run(ticks);
 }
 
 pub fn get_ticks() -> u64 {
-    TICKS.load(Ordering::Relaxed)
+    CLOCK.read().ticks
 }
 
 pub fn get_time_ms() -> u64 {
-    TIME_MS.load(Ordering::Relaxed)
+    get_ticks() * NS_PER_TICK / 1_000_000
+}
+
+/// Nanoseconds since the timer started, interpolated between ticks with the time stamp counter
+pub fn now_ns() -> u64 {
+    let clock = CLOCK.read();
+    let base = clock.ticks * NS_PER_TICK;
+    if clock.tsc_per_tick == 0 {
+        return base;
+    }
+    let since = unsafe { core::arch::x86_64::_rdtsc() }.saturating_sub(clock.tsc);
+    base + (since.min(clock.tsc_per_tick) as u128 * NS_PER_TICK as u128 / clock.tsc_per_tick as u128) as u64
+}
+
+/// Ticks covering at least `ns` nanoseconds
+pub fn ns_to_ticks(ns: u64) -> u64 {
+    ns.div_ceil(NS_PER_TICK)
 }
 
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    ticks * NS_PER_TICK
+}
+
+/// Sleep for at least `ms` milliseconds; a thread blocks, the boot flow halts until then
 pub fn sleep_ms(ms: u64) {
-    let start = get_time_ms();
-    while get_time_ms() - start < ms {
-        x86_64::instructions::hlt();
+    crate::wait::sleep(ms * 1_000_000, false).ok();
+}
+
+/// Slots per wheel level, and the bits of the expiry time each level indexes by
+const WHEEL_BITS: u32 = 6;
+const WHEEL_SIZE: usize = 1 << WHEEL_BITS;
+/// Level `n` has slots `64^n` ticks wide; the top level reaches about 2 days out
+/// at 100 Hz, and timers further away are parked in its last slot until they come closer
+const WHEEL_LEVELS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u64);
+
+struct Timer {
+    id: TimerId,
+    /// Tick the timer fires on
+    expires: u64,
+    callback: fn(usize),
+    data: usize,
+}
+
+struct WheelState {
+    /// Last tick the wheel has run
+    now: u64,
+    slots: [[Vec<Timer>; WHEEL_SIZE]; WHEEL_LEVELS],
+}
+
+impl WheelState {
+    /// File a timer by how far away it is. One due on the tick being run goes
+    /// to the level 0 slot that is about to run.
+    fn place(&mut self, timer: Timer) {
+        let expires = timer.expires.max(self.now);
+        let delta = expires - self.now;
+        let level = (0..WHEEL_LEVELS)
+            .find(|&level| delta < 1 << (WHEEL_BITS * (level as u32 + 1)))
+            .unwrap_or(WHEEL_LEVELS - 1);
+        let span = (1u64 << (WHEEL_BITS * WHEEL_LEVELS as u32)) - 1;
+        let index = (expires.min(self.now + span) >> (WHEEL_BITS * level as u32)) as usize % WHEEL_SIZE;
+        self.slots[level][index].push(timer);
+    }
+}
+
+/// Hierarchical timer wheel driven by the timer interrupt. Level 0 has a slot
+/// per tick; each slot of a higher level spans a whole turn of the level
+/// below and is spread over it as that turn begins, so adding, cancelling
+/// and running a timer never walks more than a few slots.
+pub struct TimerWheel {
+    state: SpinLock<WheelState>,
+    next_id: AtomicU64,
+}
+
+impl TimerWheel {
+    pub const fn new() -> Self {
+        TimerWheel {
+            state: SpinLock::new(WheelState {
+                now: 0,
+                slots: [const { [const { Vec::new() }; WHEEL_SIZE] }; WHEEL_LEVELS],
+            }),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Call `callback(data)` from the timer interrupt once tick `expires` is
+    /// reached. The callback runs with interrupts disabled and must not block.
+    pub fn add(&self, expires: u64, callback: fn(usize), data: usize) -> TimerId {
+        let id = TimerId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let mut state = self.state.lock_irqsave();
+        // Due already: the next tick runs it
+        let expires = expires.max(state.now + 1);
+        state.place(Timer { id, expires, callback, data });
+        id
+    }
+
+    /// Take a timer off the wheel; returns false if it fired already
+    pub fn cancel(&self, id: TimerId) -> bool {
+        let mut state = self.state.lock_irqsave();
+        for slot in state.slots.iter_mut().flatten() {
+            if let Some(pos) = slot.iter().position(|timer| timer.id == id) {
+                slot.swap_remove(pos);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Advance the wheel to tick `now`, running the timers that expire on the way
+    fn run(&self, now: u64) {
+        let mut expired = Vec::new();
+        {
+            let mut state = self.state.lock();
+            while state.now < now {
+                state.now += 1;
+                let tick = state.now;
+                // Spread the slot of each level whose turn begins now over the levels below
+                for level in 1..WHEEL_LEVELS {
+                    if tick & ((1 << (WHEEL_BITS * level as u32)) - 1) != 0 {
+                        break;
+                    }
+                    let index = (tick >> (WHEEL_BITS * level as u32)) as usize % WHEEL_SIZE;
+                    for timer in core::mem::take(&mut state.slots[level][index]) {
+                        state.place(timer);
+                    }
+                }
+                let index = tick as usize % WHEEL_SIZE;
+                let slot = core::mem::take(&mut state.slots[0][index]);
+                for timer in slot {
+                    if timer.expires <= tick {
+                        expired.push(timer);
+                    } else {
+                        state.place(timer);
+                    }
+                }
+            }
+        }
+        // Callbacks may add timers of their own
+        for timer in expired {
+            (timer.callback)(timer.data);
+        }
+    }
+
+    /// Timers waiting to fire
+    pub fn pending(&self) -> usize {
+        self.state.lock_irqsave().slots.iter().flatten().map(Vec::len).sum()
     }
 }
 
+pub static TIMERS: TimerWheel = TimerWheel::new();
+
@@ -1,5 +1,5 @@
 use crate::process::ProcessId;
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
 
 #[derive(Debug, Clone)]
@@ -10,17 +10,17 @@ pub struct Breakpoint {
 }
 
 pub struct KernelDebugger {
-    breakpoints: Mutex<BTreeMap<u64, Breakpoint>>,
-    attached_processes: Mutex<alloc::vec::Vec<ProcessId>>,
-    enabled: Mutex<bool>,
+    breakpoints: SpinLock<BTreeMap<u64, Breakpoint>>,
+    attached_processes: SpinLock<alloc::vec::Vec<ProcessId>>,
+    enabled: SpinLock<bool>,
 }
 
 impl KernelDebugger {
     pub const fn new() -> Self {
         KernelDebugger {
-            breakpoints: Mutex::new(BTreeMap::new()),
-            attached_processes: Mutex::new(alloc::vec::Vec::new()),
-            enabled: Mutex::new(false),
+            breakpoints: SpinLock::new(BTreeMap::new()),
+            attached_processes: SpinLock::new(alloc::vec::Vec::new()),
+            enabled: SpinLock::new(false),
         }
     }
 
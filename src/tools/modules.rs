@@ -1,5 +1,5 @@
 use crate::security::secure_boot::SECURE_BOOT;
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
 use heapless::String;
 
@@ -12,13 +12,13 @@ pub struct KernelModule {
 }
 
 pub struct ModuleManager {
-    modules: Mutex<BTreeMap<String<64>, KernelModule>>,
+    modules: SpinLock<BTreeMap<String<64>, KernelModule>>,
 }
 
 impl ModuleManager {
     pub const fn new() -> Self {
         ModuleManager {
-            modules: Mutex::new(BTreeMap::new()),
+            modules: SpinLock::new(BTreeMap::new()),
         }
     }
 
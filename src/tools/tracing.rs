@@ -1,4 +1,4 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
 
 #[derive(Debug, Clone)]
@@ -10,17 +10,17 @@ pub struct TraceEvent {
 }
 
 pub struct Tracing {
-    events: Mutex<alloc::collections::VecDeque<TraceEvent, 2048>>,
-    enabled: Mutex<bool>,
-    trace_functions: Mutex<alloc::collections::BTreeSet<heapless::String<64>>>,
+    events: SpinLock<alloc::collections::VecDeque<TraceEvent, 2048>>,
+    enabled: SpinLock<bool>,
+    trace_functions: SpinLock<alloc::collections::BTreeSet<heapless::String<64>>>,
 }
 
 impl Tracing {
     pub const fn new() -> Self {
         Tracing {
-            events: Mutex::new(alloc::collections::VecDeque::new()),
-            enabled: Mutex::new(false),
-            trace_functions: Mutex::new(alloc::collections::BTreeSet::new()),
+            events: SpinLock::new(alloc::collections::VecDeque::new()),
+            enabled: SpinLock::new(false),
+            trace_functions: SpinLock::new(alloc::collections::BTreeSet::new()),
         }
     }
 
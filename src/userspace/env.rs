@@ -1,8 +1,9 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
 use crate::process::ProcessId;
 use heapless::String;
 
+#[derive(Clone)]
 pub struct Environment {
     vars: BTreeMap<heapless::String<64>, heapless::String<256>>,
 }
@@ -34,13 +35,13 @@ impl Environment {
 }
 
 pub struct EnvironmentManager {
-    process_envs: Mutex<alloc::collections::BTreeMap<ProcessId, Environment>>,
+    process_envs: SpinLock<alloc::collections::BTreeMap<ProcessId, Environment>>,
 }
 
 impl EnvironmentManager {
     pub const fn new() -> Self {
         EnvironmentManager {
-            process_envs: Mutex::new(alloc::collections::BTreeMap::new()),
+            process_envs: SpinLock::new(alloc::collections::BTreeMap::new()),
         }
     }
 
@@ -54,6 +55,10 @@ impl EnvironmentManager {
     pub fn set_env(&self, pid: ProcessId, env: Environment) {
         self.process_envs.lock().insert(pid, env);
     }
+
+    pub fn remove_env(&self, pid: ProcessId) {
+        self.process_envs.lock().remove(&pid);
+    }
 }
 
 pub static ENV_MANAGER: EnvironmentManager = EnvironmentManager::new();
@@ -45,7 +45,7 @@ impl ProgramLoader {
         let entry_point = VirtAddr::new(0x400000);
         let stack_top = VirtAddr::new(0x800000);
         
-        let pid = PROCESS_MANAGER.create_process(entry_point, stack_top);
+        let pid = PROCESS_MANAGER.create_process(entry_point, stack_top)?;
         Ok(pid)
     }
 
@@ -1,4 +1,4 @@
-use spin::Mutex;
+use crate::sync::SpinLock;
 use alloc::collections::BTreeMap;
 use heapless::String;
 
@@ -11,17 +11,17 @@ pub struct User {
 }
 
 pub struct UserManager {
-    users: Mutex<BTreeMap<u32, User>>,
-    current_user: Mutex<Option<u32>>,
-    next_uid: Mutex<u32>,
+    users: SpinLock<BTreeMap<u32, User>>,
+    current_user: SpinLock<Option<u32>>,
+    next_uid: SpinLock<u32>,
 }
 
 impl UserManager {
     pub const fn new() -> Self {
         UserManager {
-            users: Mutex::new(BTreeMap::new()),
-            current_user: Mutex::new(None),
-            next_uid: Mutex::new(1000),
+            users: SpinLock::new(BTreeMap::new()),
+            current_user: SpinLock::new(None),
+            next_uid: SpinLock::new(1000),
         }
     }
 
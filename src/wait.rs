@@ -0,0 +1,169 @@
+use alloc::collections::VecDeque;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::process::ProcessState;
+use crate::scheduler::SCHEDULER;
+use crate::signal;
+use crate::sync::{might_sleep, SpinLock};
+use crate::thread::{ThreadId, THREAD_MANAGER};
+use crate::timer::{self, TIMERS};
+
+/// Why a wait ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitResult {
+    /// The condition holds
+    Done,
+    /// A signal is pending; only interruptible waits end this way
+    Interrupted,
+    TimedOut,
+}
+
+/// Threads blocked until some condition holds. Whoever makes the condition
+/// true calls `wake_one` or `wake_all`; a woken thread checks the condition
+/// again and goes back to sleep if it still does not hold.
+pub struct WaitQueue {
+    /// Also taken by interrupt handlers that wake waiters, so always with interrupts off
+    waiters: SpinLock<VecDeque<ThreadId>>,
+}
+
+impl WaitQueue {
+    /// Where this is called from names the class of the queue's lock, so
+    /// that queues in different places can be taken inside each other
+    #[track_caller]
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: SpinLock::new(VecDeque::new()),
+        }
+    }
+
+    /// Block the running thread until `condition` holds
+    #[track_caller]
+    pub fn wait_until(&self, condition: impl FnMut() -> bool) {
+        self.wait(condition, false, None);
+    }
+
+    /// Block the running thread until `condition` holds or a signal arrives
+    #[track_caller]
+    pub fn wait_until_interruptible(&self, condition: impl FnMut() -> bool) -> Result<(), &'static str> {
+        match self.wait(condition, true, None) {
+            WaitResult::Done => Ok(()),
+            _ => Err("Interrupted by a signal"),
+        }
+    }
+
+    /// Block the running thread until `condition` holds, a signal arrives if
+    /// `interruptible`, or the timer reaches tick `deadline`
+    #[track_caller]
+    pub fn wait_until_deadline(&self, condition: impl FnMut() -> bool, interruptible: bool, deadline: u64) -> WaitResult {
+        self.wait(condition, interruptible, Some(deadline))
+    }
+
+    #[track_caller]
+    fn wait(&self, mut condition: impl FnMut() -> bool, interruptible: bool, deadline: Option<u64>) -> WaitResult {
+        might_sleep();
+        let expired = |deadline: Option<u64>| deadline.map_or(false, |deadline| timer::get_ticks() >= deadline);
+        let Some(tid) = SCHEDULER.current_thread() else {
+            // The boot flow is not a thread and cannot sleep; it waits for interrupts instead
+            loop {
+                if condition() {
+                    return WaitResult::Done;
+                }
+                if expired(deadline) {
+                    return WaitResult::TimedOut;
+                }
+                x86_64::instructions::hlt();
+            }
+        };
+
+        let timer = deadline.map(|deadline| TIMERS.add(deadline, wake_timer, tid.as_u64() as usize));
+        let result = loop {
+            // Queued and marked blocked before the check, so a wakeup in between
+            // is not lost. Interrupts stay off until it sleeps: preempting a
+            // blocked thread would take it off its run queue with nobody to wake it.
+            let result = without_interrupts(|| {
+                self.waiters.lock().push_back(tid);
+                THREAD_MANAGER.with_thread(tid, |thread| thread.state = ProcessState::Blocked);
+                let result = if condition() {
+                    Some(WaitResult::Done)
+                } else if interruptible && signal::signal_pending(tid) {
+                    Some(WaitResult::Interrupted)
+                } else if expired(deadline) {
+                    Some(WaitResult::TimedOut)
+                } else {
+                    None
+                };
+                if result.is_none() {
+                    SCHEDULER.schedule();
+                }
+                self.finish(tid);
+                result
+            });
+            if let Some(result) = result {
+                break result;
+            }
+        };
+        if let Some(timer) = timer {
+            TIMERS.cancel(timer);
+        }
+        result
+    }
+
+    /// Take the running thread off the queue. It may not have slept at all:
+    /// a wakeup that comes first leaves it on its run queue as it was.
+    fn finish(&self, tid: ThreadId) {
+        without_interrupts(|| {
+            self.waiters.lock().retain(|&waiter| waiter != tid);
+            THREAD_MANAGER.with_thread(tid, |thread| thread.state = ProcessState::Running);
+        });
+    }
+
+    /// Wake the longest waiting thread; returns false if none was asleep
+    pub fn wake_one(&self) -> bool {
+        loop {
+            let Some(tid) = self.waiters.lock_irqsave().pop_front() else { return false };
+            // One that is awake already, after a signal or timeout, does not count
+            if wake_up(tid) {
+                return true;
+            }
+        }
+    }
+
+    /// Wake every waiting thread, returning how many were asleep
+    pub fn wake_all(&self) -> usize {
+        let waiters = core::mem::take(&mut *self.waiters.lock_irqsave());
+        waiters.into_iter().filter(|&tid| wake_up(tid)).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock_irqsave().is_empty()
+    }
+}
+
+/// Make a blocked thread runnable; returns false if it was not blocked
+pub fn wake_up(tid: ThreadId) -> bool {
+    let woken = THREAD_MANAGER.with_thread(tid, |thread| {
+        if thread.state != ProcessState::Blocked {
+            return false;
+        }
+        thread.state = ProcessState::Ready;
+        true
+    }).unwrap_or(false);
+    if woken {
+        SCHEDULER.enqueue(tid);
+    }
+    woken
+}
+
+fn wake_timer(tid: usize) {
+    wake_up(ThreadId::from_u64(tid as u64));
+}
+
+/// Block the running thread for at least `ns` nanoseconds. An interruptible
+/// sleep cut short by a signal returns the time that was left.
+pub fn sleep(ns: u64, interruptible: bool) -> Result<(), u64> {
+    let deadline = timer::get_ticks() + timer::ns_to_ticks(ns);
+    let queue = WaitQueue::new();
+    match queue.wait_until_deadline(|| false, interruptible, deadline) {
+        WaitResult::Interrupted => Err(timer::ticks_to_ns(deadline.saturating_sub(timer::get_ticks()))),
+        _ => Ok(()),
+    }
+}